@@ -0,0 +1,123 @@
+//! Interpreter throughput benchmark. Runs small ARM loops from the
+//! flash for a fixed number of instructions and reports the number of
+//! emulated instructions per second.
+//!
+//! Each loop runs with and without the decoded instruction cache.
+//!
+//! `cargo bench` runs the full benchmark, `cargo test --benches` only
+//! runs a few instructions of each loop to make sure the code still
+//! works.
+
+extern crate pockystation;
+
+use std::env;
+use std::time::Instant;
+
+use pockystation::cpu::Cpu;
+use pockystation::dac::{Dac, Backend};
+use pockystation::memory::Interconnect;
+use pockystation::memory::bios::Bios;
+use pockystation::memory::flash::{Flash, FLASH_SIZE};
+
+/// Copy 256 bytes of RAM word by word in a loop
+const MEMCPY: [u32; 8] = [
+    0xe3a00000, // mov   r0, #0
+    0xe3a01c02, // mov   r1, #0x200
+    0xe3a02040, // mov   r2, #64
+    0xe4903004, // ldr   r3, [r0], #4
+    0xe4813004, // str   r3, [r1], #4
+    0xe2522001, // subs  r2, r2, #1
+    0x1afffffb, // bne   0x0c
+    0xeafffff7, // b     0x00
+];
+
+/// Register-only arithmetic loop
+const ARITHMETIC: [u32; 7] = [
+    0xe3a00001, // mov   r0, #1
+    0xe3a01000, // mov   r1, #0
+    0xe0811000, // add   r1, r1, r0
+    0xe0200181, // eor   r0, r0, r1, lsl #3
+    0xe2900003, // adds  r0, r0, #3
+    0xe0010190, // mul   r1, r0, r1
+    0xeafffffa, // b     0x08
+];
+
+/// Number of instructions executed by each benchmark
+const BENCH_INSTRUCTIONS: u32 = 50_000_000;
+
+/// Number of instructions executed in test mode
+const SMOKE_INSTRUCTIONS: u32 = 1000;
+
+struct NullBackend;
+
+impl Backend for NullBackend {
+    fn push_sample(&mut self, _: i16) {
+    }
+}
+
+fn build_cpu(program: &[u32]) -> Cpu {
+    let bios = Bios::empty();
+
+    let mut image = vec![0; FLASH_SIZE];
+
+    for (i, w) in program.iter().enumerate() {
+        for b in 0..4 {
+            image[i * 4 + b] = (w >> (b * 8)) as u8;
+        }
+    }
+
+    let flash = Flash::new(&image).unwrap();
+    let dac = Dac::new(Box::new(NullBackend));
+
+    let mut cpu = Cpu::new(Interconnect::new(bios, flash, dac));
+
+    cpu.fast_boot(0x02000000);
+
+    cpu
+}
+
+fn run(name: &str, program: &[u32], instructions: u32) {
+    bench(name, program, instructions, false);
+    bench(name, program, instructions, true);
+}
+
+fn bench(name: &str, program: &[u32], instructions: u32, cache: bool) {
+    let mut cpu = build_cpu(program);
+
+    cpu.set_decode_cache(cache);
+
+    let start = Instant::now();
+
+    cpu.run_unbounded(&mut (), instructions);
+
+    let elapsed = start.elapsed();
+    let secs = elapsed.as_secs() as f64 +
+        elapsed.subsec_nanos() as f64 * 1e-9;
+
+    let cache =
+        if cache {
+            "decode cache"
+        } else {
+            "no cache"
+        };
+
+    println!("{} ({}): {} instructions in {:.3}s, {:.1} MIPS",
+             name,
+             cache,
+             instructions,
+             secs,
+             instructions as f64 / secs / 1e6);
+}
+
+fn main() {
+    // `cargo bench` passes `--bench` to the benchmark binaries
+    let instructions =
+        if env::args().any(|a| a == "--bench") {
+            BENCH_INSTRUCTIONS
+        } else {
+            SMOKE_INSTRUCTIONS
+        };
+
+    run("memcpy", &MEMCPY, instructions);
+    run("arithmetic", &ARITHMETIC, instructions);
+}
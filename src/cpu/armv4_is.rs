@@ -5,15 +5,118 @@ use std::fmt;
 use memory::{Word, HalfWord, Byte};
 use debugger::Debugger;
 
-use super::{Cpu, RegisterIndex};
-
-pub fn execute<D>(cpu: &mut Cpu, debugger: &mut D, instruction: u32)
+use super::{Cpu, RegisterIndex, NextPc, StepError};
+use super::{add_with_flags, sub_with_flags};
+use super::{semihosting, hle};
+
+/// Execute an instruction previously returned by `decode`
+pub fn execute_decoded<D>(cpu: &mut Cpu,
+                          debugger: &mut D,
+                          instruction: Instruction,
+                          handler: Handler)
     where D: Debugger {
+    instruction.execute(debugger, cpu, handler);
+}
+
+/// Look up the handler for `instruction`
+pub fn decode(instruction: u32) -> (Instruction, Handler) {
     let instruction = Instruction(instruction);
 
-    instruction.execute(debugger, cpu);
+    (instruction, OPCODE_LUT[instruction.opcode() as usize])
+}
+
+/// Return a short description of the class of `instruction`. We
+/// don't have a real disassembler yet.
+pub fn describe(instruction: u32) -> &'static str {
+    Instruction(instruction).class()
+}
+
+/// Return the control flow of `instruction` located at address `pc`,
+/// see `Cpu::predict_next_pc`
+pub fn next_pc(instruction: u32, pc: u32) -> NextPc {
+    let instruction = Instruction(instruction);
+    let i = instruction.0;
+    let cond = instruction.condition_code();
+    let rd_is_pc = instruction.rd().is_pc();
+    let load = (i >> 20) & 1 != 0;
+
+    let flow =
+        if cond == 0xf {
+            // Only BLX immediate uses this condition on ARMv5, it's
+            // unconditional and the H bit selects the halfword
+            if (i >> 25) & 7 == 0b101 {
+                let offset = instruction.branch_imm_offset();
+                let h = (i >> 23) & 2;
+
+                let target = pc.wrapping_add(8)
+                    .wrapping_add(offset)
+                    .wrapping_add(h);
+
+                return NextPc::Branch(target);
+            }
+
+            return NextPc::Sequential;
+        } else if (i >> 25) & 7 == 0b101 {
+            // B/BL
+            let offset = instruction.branch_imm_offset();
+
+            Some(pc.wrapping_add(8).wrapping_add(offset))
+        } else if (i & 0x0fffffd0) == 0x012fff10 {
+            // BX/BLX register
+            None
+        } else if (i >> 24) & 0xf == 0xf {
+            // SWI
+            None
+        } else if (i >> 25) & 7 == 0b100 {
+            // LDM with PC in the list
+            if load && (i & (1 << 15)) != 0 {
+                None
+            } else {
+                return NextPc::Sequential;
+            }
+        } else if (i >> 26) & 3 == 0b01 {
+            // LDR/LDRB
+            if load && rd_is_pc {
+                None
+            } else {
+                return NextPc::Sequential;
+            }
+        } else if (i >> 26) & 3 == 0b00 {
+            let opcode = (i >> 21) & 0xf;
+
+            if (i >> 25) & 1 == 0 && (i & 0x90) == 0x90 {
+                // Multiplies and extra load/stores. Only the loads
+                // can write to PC.
+                if load && rd_is_pc && (i >> 5) & 3 != 0 {
+                    None
+                } else {
+                    return NextPc::Sequential;
+                }
+            } else if rd_is_pc && (opcode >> 2) != 0b10 {
+                // Data processing writing to PC. TST, TEQ, CMP and
+                // CMN don't have a destination register.
+                None
+            } else {
+                return NextPc::Sequential;
+            }
+        } else {
+            return NextPc::Sequential;
+        };
+
+    match flow {
+        Some(target) =>
+            if cond == 0xe {
+                NextPc::Branch(target)
+            } else {
+                NextPc::Conditional(target)
+            },
+        None => NextPc::Indirect,
+    }
 }
 
+/// Instruction handler function
+pub type Handler = fn (Instruction, &mut Debugger, &mut Cpu);
+
 /// Wrapper around a 32bit instruction word
 #[derive(Copy, Clone, PartialEq, Eq)]
 pub struct Instruction(u32);
@@ -48,6 +151,10 @@ impl Instruction {
         RegisterIndex(self.0 & 0xf)
     }
 
+    /// Byte offset of a B/BL instruction: the 24bit immediate
+    /// sign-extended and multiplied by 4, so within [-0x2000000,
+    /// 0x1fffffc]. The caller must use wrapping arithmetic since the
+    /// target can wrap around the address space.
     fn branch_imm_offset(self) -> u32 {
         // offset must be sign-extented
         let offset = (self.0 << 8) as i32;
@@ -57,6 +164,12 @@ impl Instruction {
         (offset >> 6) as u32
     }
 
+    /// Comment field of a SWI instruction, used by the software to
+    /// specify the system call number
+    fn swi_comment(self) -> u32 {
+        self.0 & 0xffffff
+    }
+
     fn msr_field_mask(self) -> u32 {
         (self.0 >> 16) & 0xf
     }
@@ -66,8 +179,59 @@ impl Instruction {
         self.0 & 0xffff
     }
 
+    /// Return a short description of the class of this instruction
+    /// based on the encoding tables of the ARM reference manual.
+    /// Without a full disassembler it's still a lot more helpful
+    /// than the raw opcode when hitting an unimplemented instruction.
+    fn class(self) -> &'static str {
+        let i = self.0;
+
+        let misc = ((i >> 23) & 3) == 0b10 && ((i >> 20) & 1) == 0;
+
+        match (i >> 25) & 7 {
+            0b000 =>
+                if (i >> 4) & 0xf == 0b1001 {
+                    match (i >> 23) & 3 {
+                        0b00 => "multiply",
+                        0b01 => "multiply long",
+                        0b10 => "swap",
+                        _ => "undefined",
+                    }
+                } else if (i >> 4) & 9 == 9 {
+                    "halfword or signed data transfer"
+                } else if misc {
+                    "status register access or branch exchange"
+                } else {
+                    "data processing (register)"
+                },
+            0b001 =>
+                if misc {
+                    "move immediate to status register"
+                } else {
+                    "data processing (immediate)"
+                },
+            0b010 => "load/store (immediate offset)",
+            0b011 =>
+                if (i >> 4) & 1 != 0 {
+                    "undefined"
+                } else {
+                    "load/store (register offset)"
+                },
+            0b100 => "load/store multiple",
+            0b101 => "branch",
+            0b110 => "coprocessor load/store",
+            0b111 =>
+                if (i >> 24) & 1 != 0 {
+                    "software interrupt"
+                } else {
+                    "coprocessor"
+                },
+            _ => unreachable!(),
+        }
+    }
+
     /// Execute this instruction
-    fn execute<D>(self, debugger: &mut D, cpu: &mut Cpu)
+    fn execute<D>(self, debugger: &mut D, cpu: &mut Cpu, handler: Handler)
         where D: Debugger {
 
         let n = cpu.n();
@@ -110,22 +274,32 @@ impl Instruction {
                 0b1101 => z || (n != v),
                 // Always (AL)
                 0b1110 => true,
-                // This condition code is "unpredictable".
-                0b1111 => panic!("Unexpected ARM condition 0b1111"),
+                // This condition code is "unpredictable" on ARMv4,
+                // ARMv5 uses it to encode the unconditional BLX
+                0b1111 => {
+                    let arch = cpu.arch_version();
+
+                    if arch.has_blx() && (self.0 >> 25) & 7 == 0b101 {
+                        blx_imm(self, debugger, cpu);
+                        return;
+                    }
+
+                    let msg = format!("Unexpected ARM condition 0b1111 in {}",
+                                      self);
+
+                    return cpu.undefined(msg);
+                }
                 _ => unreachable!(),
             };
 
+        // The condition is checked before the handler is called so a
+        // coprocessor or undefined instruction that fails its
+        // condition is a no-op and never traps, like on the real
+        // CPU.
         if cond_true {
-            self.decode_and_execute(debugger, cpu);
+            handler(self, debugger, cpu);
         }
     }
-
-    fn decode_and_execute<D>(self, debugger: &mut D, cpu: &mut Cpu)
-        where D: Debugger {
-        let handler = OPCODE_LUT[self.opcode() as usize];
-
-        handler(self, debugger, cpu);
-    }
 }
 
 impl fmt::Display for Instruction {
@@ -240,7 +414,8 @@ impl Mode1Addressing for Mode1LsrImm {
         let val   = cpu.reg(rm);
 
         match shift {
-            // Shift 0 means shift by 32
+            // Shift 0 means shift by 32: the result is 0 and the
+            // carry is the last bit shifted out, i.e. bit 31 of Rm
             0 => (0, (val as i32) < 0),
             _ => {
                 let carry = (val >> (shift - 1)) & 1 != 0;
@@ -275,8 +450,18 @@ impl Mode1Addressing for Mode1LslReg {
         }
     }
 
-    fn value_carry(_instruction: Instruction, _cpu: &Cpu) -> (u32, bool) {
-        unimplemented!();
+    fn value_carry(instruction: Instruction, cpu: &Cpu) -> (u32, bool) {
+        let rm    = instruction.rm();
+        let rs    = instruction.rs();
+        let val   = cpu.reg(rm);
+        let shift = cpu.reg(rs) & 0xff;
+
+        match shift {
+            0 => (val, cpu.c()),
+            1...31 => (val << shift, ((val >> (32 - shift)) & 1) != 0),
+            32 => (0, (val & 1) != 0),
+            _ => (0, false),
+        }
     }
 
     fn is_valid(instruction: Instruction, opcode: u32, s: bool) -> bool {
@@ -304,8 +489,18 @@ impl Mode1Addressing for Mode1LsrReg {
         }
     }
 
-    fn value_carry(_instruction: Instruction, _cpu: &Cpu) -> (u32, bool) {
-        unimplemented!();
+    fn value_carry(instruction: Instruction, cpu: &Cpu) -> (u32, bool) {
+        let rm    = instruction.rm();
+        let rs    = instruction.rs();
+        let val   = cpu.reg(rm);
+        let shift = cpu.reg(rs) & 0xff;
+
+        match shift {
+            0 => (val, cpu.c()),
+            1...31 => (val >> shift, ((val >> (shift - 1)) & 1) != 0),
+            32 => (0, (val as i32) < 0),
+            _ => (0, false),
+        }
     }
 
     fn is_valid(instruction: Instruction, opcode: u32, s: bool) -> bool {
@@ -347,7 +542,8 @@ impl Mode1Addressing for Mode1AsrImm {
             match shift {
                 // Shift 0 means shift by 32, which is like shifting
                 // by 31 when using a signed value (i.e. the sign bit
-                // is replicated all over the 32bits)
+                // is replicated all over the 32bits). The carry is
+                // bit 31 of Rm, so it always matches the result.
                 0 => (val >> 31, val < 0),
                 _ => (val >> shift, (val >> (shift - 1)) & 1 != 0),
             };
@@ -386,8 +582,19 @@ impl Mode1Addressing for Mode1AsrReg {
         r as u32
     }
 
-    fn value_carry(_instruction: Instruction, _cpu: &Cpu) -> (u32, bool) {
-        unimplemented!();
+    fn value_carry(instruction: Instruction, cpu: &Cpu) -> (u32, bool) {
+        let rm    = instruction.rm();
+        let rs    = instruction.rs();
+        let val   = cpu.reg(rm) as i32;
+        let shift = cpu.reg(rs) & 0xff;
+
+        match shift {
+            0 => (val as u32, cpu.c()),
+            1...31 => ((val >> shift) as u32, ((val >> (shift - 1)) & 1) != 0),
+            // Like for `value` the sign bit is replicated everywhere,
+            // including the carry
+            _ => ((val >> 31) as u32, val < 0),
+        }
     }
 
     fn is_valid(instruction: Instruction, opcode: u32, s: bool) -> bool {
@@ -457,6 +664,11 @@ impl Mode1Addressing for Mode1RorReg {
         let rs    = instruction.rs();
 
         let val   = cpu.reg(rm);
+        // Only the low byte of Rs is used but rotating by a multiple
+        // of 32 leaves the value unchanged so we can directly mask
+        // the bottom 5 bits. This covers the special cases in
+        // `value_carry` (shift of 0 or a multiple of 32) since they
+        // all return `val` unchanged.
         let shift = cpu.reg(rs) & 0x1f;
 
         val.rotate_right(shift)
@@ -492,10 +704,12 @@ impl Mode1Addressing for Mode1RorReg {
 }
 
 fn unimplemented(instruction: Instruction, _: &mut Debugger, cpu: &mut Cpu) {
-    panic!("Unimplemented instruction {} ({:03x})\n{:?}",
-           instruction,
-           instruction.opcode(),
-           cpu);
+    let msg = format!("{} ({:03x}, {})",
+                      instruction,
+                      instruction.opcode(),
+                      instruction.class());
+
+    cpu.unimplemented(msg);
 }
 
 fn and<M>(instruction: Instruction, _: &mut Debugger, cpu: &mut Cpu)
@@ -522,7 +736,7 @@ fn ands<M>(instruction: Instruction, _: &mut Debugger, cpu: &mut Cpu)
     debug_assert!(M::is_valid(instruction, 0, true));
 
     if rd.is_pc() {
-        panic!("*S instruction with PC target");
+        return cpu.undefined("*S instruction with PC target");
     }
 
     let a = cpu.reg(rn);
@@ -560,7 +774,7 @@ fn eors<M>(instruction: Instruction, _: &mut Debugger, cpu: &mut Cpu)
     debug_assert!(M::is_valid(instruction, 1, true));
 
     if rd.is_pc() {
-        panic!("*S instruction with PC target");
+        return cpu.undefined("*S instruction with PC target");
     }
 
     let a = cpu.reg(rn);
@@ -598,23 +812,15 @@ fn subs<M>(instruction: Instruction, _: &mut Debugger, cpu: &mut Cpu)
     debug_assert!(M::is_valid(instruction, 2, true));
 
     if rd.is_pc() {
-        panic!("*S instruction with PC target");
+        return cpu.undefined("*S instruction with PC target");
     }
 
     let a = cpu.reg(rn);
 
-    let val = a.wrapping_sub(b);
-
-    let a_neg = (a as i32) < 0;
-    let b_neg = (b as i32) < 0;
-    let v_neg = (val as i32) < 0;
+    let (val, n, z, c, v) = sub_with_flags(a, b, false);
 
     cpu.set_reg(rd, val);
-
-    cpu.set_n(v_neg);
-    cpu.set_z(val == 0);
-    cpu.set_c(a >= b);
-    cpu.set_v((a_neg ^ b_neg) & (a_neg ^ v_neg));
+    cpu.set_nzcv(n, z, c, v);
 }
 
 fn rsb<M>(instruction: Instruction, _: &mut Debugger, cpu: &mut Cpu)
@@ -641,23 +847,15 @@ fn rsbs<M>(instruction: Instruction, _: &mut Debugger, cpu: &mut Cpu)
     debug_assert!(M::is_valid(instruction, 3, true));
 
     if rd.is_pc() {
-        panic!("*S instruction with PC target");
+        return cpu.undefined("*S instruction with PC target");
     }
 
     let b = cpu.reg(rn);
 
-    let val = a.wrapping_sub(b);
-
-    let a_neg = (a as i32) < 0;
-    let b_neg = (b as i32) < 0;
-    let v_neg = (val as i32) < 0;
+    let (val, n, z, c, v) = sub_with_flags(a, b, false);
 
     cpu.set_reg(rd, val);
-
-    cpu.set_n(v_neg);
-    cpu.set_z(val == 0);
-    cpu.set_c(a >= b);
-    cpu.set_v((a_neg ^ b_neg) & (a_neg ^ v_neg));
+    cpu.set_nzcv(n, z, c, v);
 }
 
 fn add<M>(instruction: Instruction, _: &mut Debugger, cpu: &mut Cpu)
@@ -684,23 +882,15 @@ fn adds<M>(instruction: Instruction, _: &mut Debugger, cpu: &mut Cpu)
     debug_assert!(M::is_valid(instruction, 4, true));
 
     if rd.is_pc() {
-        panic!("*S instruction with PC target");
+        return cpu.undefined("*S instruction with PC target");
     }
 
     let a = cpu.reg(rn);
 
-    let (val, c) = a.overflowing_add(b);
-
-    let a_neg = (a as i32) < 0;
-    let b_neg = (b as i32) < 0;
-    let v_neg = (val as i32) < 0;
+    let (val, n, z, c, v) = add_with_flags(a, b, false);
 
     cpu.set_reg(rd, val);
-
-    cpu.set_n(v_neg);
-    cpu.set_z(val == 0);
-    cpu.set_c(c);
-    cpu.set_v((a_neg == b_neg) & (a_neg ^ v_neg));
+    cpu.set_nzcv(n, z, c, v);
 }
 
 fn adc<M>(instruction: Instruction, _: &mut Debugger, cpu: &mut Cpu)
@@ -719,6 +909,99 @@ fn adc<M>(instruction: Instruction, _: &mut Debugger, cpu: &mut Cpu)
     cpu.set_reg(dst, val);
 }
 
+fn adcs<M>(instruction: Instruction, _: &mut Debugger, cpu: &mut Cpu)
+    where M: Mode1Addressing {
+    let rd = instruction.rd();
+    let rn = instruction.rn();
+    let b  = M::value(instruction, cpu);
+
+    debug_assert!(M::is_valid(instruction, 5, true));
+
+    if rd.is_pc() {
+        return cpu.undefined("*S instruction with PC target");
+    }
+
+    let a     = cpu.reg(rn);
+    let carry = cpu.c();
+
+    let (val, n, z, c, v) = add_with_flags(a, b, carry);
+
+    cpu.set_reg(rd, val);
+    cpu.set_nzcv(n, z, c, v);
+}
+
+fn sbc<M>(instruction: Instruction, _: &mut Debugger, cpu: &mut Cpu)
+    where M: Mode1Addressing {
+    let rd = instruction.rd();
+    let rn = instruction.rn();
+    let b  = M::value(instruction, cpu);
+
+    debug_assert!(M::is_valid(instruction, 6, false));
+
+    let a = cpu.reg(rn);
+
+    let val = a.wrapping_sub(b).wrapping_sub(!cpu.c() as u32);
+
+    cpu.set_reg(rd, val);
+}
+
+fn sbcs<M>(instruction: Instruction, _: &mut Debugger, cpu: &mut Cpu)
+    where M: Mode1Addressing {
+    let rd = instruction.rd();
+    let rn = instruction.rn();
+    let b  = M::value(instruction, cpu);
+
+    debug_assert!(M::is_valid(instruction, 6, true));
+
+    if rd.is_pc() {
+        return cpu.undefined("*S instruction with PC target");
+    }
+
+    let a      = cpu.reg(rn);
+    let borrow = !cpu.c();
+
+    let (val, n, z, c, v) = sub_with_flags(a, b, borrow);
+
+    cpu.set_reg(rd, val);
+    cpu.set_nzcv(n, z, c, v);
+}
+
+fn rsc<M>(instruction: Instruction, _: &mut Debugger, cpu: &mut Cpu)
+    where M: Mode1Addressing {
+    let rd = instruction.rd();
+    let rn = instruction.rn();
+    let a  = M::value(instruction, cpu);
+
+    debug_assert!(M::is_valid(instruction, 7, false));
+
+    let b = cpu.reg(rn);
+
+    let val = a.wrapping_sub(b).wrapping_sub(!cpu.c() as u32);
+
+    cpu.set_reg(rd, val);
+}
+
+fn rscs<M>(instruction: Instruction, _: &mut Debugger, cpu: &mut Cpu)
+    where M: Mode1Addressing {
+    let rd = instruction.rd();
+    let rn = instruction.rn();
+    let a  = M::value(instruction, cpu);
+
+    debug_assert!(M::is_valid(instruction, 7, true));
+
+    if rd.is_pc() {
+        return cpu.undefined("*S instruction with PC target");
+    }
+
+    let b      = cpu.reg(rn);
+    let borrow = !cpu.c();
+
+    let (val, n, z, c, v) = sub_with_flags(a, b, borrow);
+
+    cpu.set_reg(rd, val);
+    cpu.set_nzcv(n, z, c, v);
+}
+
 fn tst<M>(instruction: Instruction, _: &mut Debugger, cpu: &mut Cpu)
     where M: Mode1Addressing {
     let rn     = instruction.rn();
@@ -728,8 +1011,8 @@ fn tst<M>(instruction: Instruction, _: &mut Debugger, cpu: &mut Cpu)
     debug_assert!(M::is_valid(instruction, 8, true));
 
     if rd != RegisterIndex(0) {
-        // "should be zero"
-        panic!("TST instruction with non-0 Rd");
+        // "should be zero", the field is ignored by the hardware
+        warn!("TST instruction with non-0 Rd: {}", instruction);
     }
 
     let a = cpu.reg(rn);
@@ -750,8 +1033,8 @@ fn teq<M>(instruction: Instruction, _: &mut Debugger, cpu: &mut Cpu)
     debug_assert!(M::is_valid(instruction, 9, true));
 
     if rd != RegisterIndex(0) {
-        // "should be zero"
-        panic!("TEQ instruction with non-0 Rd");
+        // "should be zero", the field is ignored by the hardware
+        warn!("TEQ instruction with non-0 Rd: {}", instruction);
     }
 
     let a = cpu.reg(rn);
@@ -772,22 +1055,15 @@ fn cmp<M>(instruction: Instruction, _: &mut Debugger, cpu: &mut Cpu)
     debug_assert!(M::is_valid(instruction, 10, true));
 
     if rd != RegisterIndex(0) {
-        // "should be zero"
-        panic!("CMP instruction with non-0 Rd");
+        // "should be zero", the field is ignored by the hardware
+        warn!("CMP instruction with non-0 Rd: {}", instruction);
     }
 
     let a = cpu.reg(rn);
 
-    let val = a.wrapping_sub(b);
-
-    let a_neg = (a as i32) < 0;
-    let b_neg = (b as i32) < 0;
-    let v_neg = (val as i32) < 0;
+    let (_, n, z, c, v) = sub_with_flags(a, b, false);
 
-    cpu.set_n(v_neg);
-    cpu.set_z(val == 0);
-    cpu.set_c(a >= b);
-    cpu.set_v((a_neg ^ b_neg) & (a_neg ^ v_neg));
+    cpu.set_nzcv(n, z, c, v);
 }
 
 fn cmn<M>(instruction: Instruction, _: &mut Debugger, cpu: &mut Cpu)
@@ -799,22 +1075,15 @@ fn cmn<M>(instruction: Instruction, _: &mut Debugger, cpu: &mut Cpu)
     debug_assert!(M::is_valid(instruction, 11, true));
 
     if rd != RegisterIndex(0) {
-        // "should be zero"
-        panic!("CMP instruction with non-0 Rd");
+        // "should be zero", the field is ignored by the hardware
+        warn!("CMN instruction with non-0 Rd: {}", instruction);
     }
 
     let a = cpu.reg(rn);
 
-    let val = a.wrapping_add(b);
-
-    let a_neg = (a as i32) < 0;
-    let b_neg = (b as i32) < 0;
-    let v_neg = (val as i32) < 0;
+    let (_, n, z, c, v) = add_with_flags(a, b, false);
 
-    cpu.set_n(v_neg);
-    cpu.set_z(val == 0);
-    cpu.set_c(a >= b);
-    cpu.set_v((a_neg ^ b_neg) & (a_neg ^ v_neg));
+    cpu.set_nzcv(n, z, c, v);
 }
 
 fn orr<M>(instruction: Instruction, _: &mut Debugger, cpu: &mut Cpu)
@@ -841,7 +1110,7 @@ fn orrs<M>(instruction: Instruction, _: &mut Debugger, cpu: &mut Cpu)
     debug_assert!(M::is_valid(instruction, 12, true));
 
     if rd.is_pc() {
-        panic!("*S instruction with PC target");
+        return cpu.undefined("*S instruction with PC target");
     }
 
     let a = cpu.reg(rn);
@@ -864,8 +1133,8 @@ fn mov<M>(instruction: Instruction, _: &mut Debugger, cpu: &mut Cpu)
     debug_assert!(M::is_valid(instruction, 13, false));
 
     if rn != RegisterIndex(0) {
-        // "should be zero"
-        panic!("CMP instruction with non-0 Rn");
+        // "should be zero", the field is ignored by the hardware
+        warn!("MOV instruction with non-0 Rn: {}", instruction);
     }
 
     cpu.set_reg(rd, val);
@@ -879,7 +1148,15 @@ fn movs<M>(instruction: Instruction, _: &mut Debugger, cpu: &mut Cpu)
     debug_assert!(M::is_valid(instruction, 13, true));
 
     if rd.is_pc() {
-        panic!("*S instruction with PC target");
+        // `MOVS PC, LR` is the usual way to return from an
+        // exception: the SPSR of the current mode is copied to the
+        // CPSR instead of setting the flags. XXX The other *S
+        // instructions with a PC target aren't implemented yet.
+        if let Some(spsr) = cpu.spsr() {
+            cpu.set_pc_cpsr(val, spsr);
+        }
+
+        return;
     }
 
     cpu.set_reg(rd, val);
@@ -913,7 +1190,7 @@ fn bics<M>(instruction: Instruction, _: &mut Debugger, cpu: &mut Cpu)
     debug_assert!(M::is_valid(instruction, 14, true));
 
     if rd.is_pc() {
-        panic!("*S instruction with PC target");
+        return cpu.undefined("*S instruction with PC target");
     }
 
     let a = cpu.reg(rn);
@@ -936,8 +1213,8 @@ fn mvn<M>(instruction: Instruction, _: &mut Debugger, cpu: &mut Cpu)
     debug_assert!(M::is_valid(instruction, 15, false));
 
     if rn != RegisterIndex(0) {
-        // "should be zero"
-        panic!("MVN instruction with non-0 Rn");
+        // "should be zero", the field is ignored by the hardware
+        warn!("MVN instruction with non-0 Rn: {}", instruction);
     }
 
     cpu.set_reg(dst, !val);
@@ -951,7 +1228,7 @@ fn mul<S>(instruction: Instruction, _: &mut Debugger, cpu: &mut Cpu)
     let rd  = instruction.rn();
 
     if rd.is_pc() || rm.is_pc() || rs.is_pc() {
-        panic!("Unpredictable MUL");
+        return cpu.undefined("Unpredictable MUL");
     }
 
     let val = cpu.reg(rm).wrapping_mul(cpu.reg(rs));
@@ -974,7 +1251,7 @@ fn mla<S>(instruction: Instruction, _: &mut Debugger, cpu: &mut Cpu)
     let rn  = instruction.rd();
 
     if rd.is_pc() || rm.is_pc() || rs.is_pc() || rn.is_pc() {
-        panic!("Unpredictable MLA");
+        return cpu.undefined("Unpredictable MLA");
     }
 
     let val = cpu.reg(rm).wrapping_mul(cpu.reg(rs)).wrapping_add(cpu.reg(rn));
@@ -988,15 +1265,166 @@ fn mla<S>(instruction: Instruction, _: &mut Debugger, cpu: &mut Cpu)
     }
 }
 
+/// Return the signed top halfword of `v` if `T` is set, the bottom
+/// one otherwise. Used by the ARMv5TE DSP multiplies.
+fn dsp_halfword<T>(v: u32) -> i32
+    where T: ModeFlag {
+    if T::is_set() {
+        (v as i32) >> 16
+    } else {
+        v as i16 as i32
+    }
+}
+
+/// Signed multiply-accumulate halfwords (ARMv5TE only). `X` selects
+/// the half of Rm and `Y` the half of Rs.
+fn smla<X, Y>(instruction: Instruction, debugger: &mut Debugger, cpu: &mut Cpu)
+    where X: ModeFlag, Y: ModeFlag {
+    if !cpu.arch_version().has_dsp_extensions() {
+        return unimplemented(instruction, debugger, cpu);
+    }
+
+    let rm  = instruction.rm();
+    let rs  = instruction.rs();
+    // This instruction switches Rd and Rn
+    let rd  = instruction.rn();
+    let rn  = instruction.rd();
+
+    if rd.is_pc() || rm.is_pc() || rs.is_pc() || rn.is_pc() {
+        return cpu.undefined("Unpredictable SMLA");
+    }
+
+    let a = dsp_halfword::<X>(cpu.reg(rm));
+    let b = dsp_halfword::<Y>(cpu.reg(rs));
+
+    // The product of two halfwords always fits in 32 bits, only the
+    // accumulation can overflow
+    let (val, overflow) = (a * b).overflowing_add(cpu.reg(rn) as i32);
+
+    cpu.set_reg(rd, val as u32);
+
+    if overflow {
+        cpu.set_q();
+    }
+}
+
+/// Signed multiply-accumulate word by halfword (ARMv5TE only). Only
+/// the top 32 bits of the 48bit product are accumulated.
+fn smlaw<Y>(instruction: Instruction, debugger: &mut Debugger, cpu: &mut Cpu)
+    where Y: ModeFlag {
+    if !cpu.arch_version().has_dsp_extensions() {
+        return unimplemented(instruction, debugger, cpu);
+    }
+
+    let rm  = instruction.rm();
+    let rs  = instruction.rs();
+    // This instruction switches Rd and Rn
+    let rd  = instruction.rn();
+    let rn  = instruction.rd();
+
+    if rd.is_pc() || rm.is_pc() || rs.is_pc() || rn.is_pc() {
+        return cpu.undefined("Unpredictable SMLAW");
+    }
+
+    let a = cpu.reg(rm) as i32 as i64;
+    let b = dsp_halfword::<Y>(cpu.reg(rs)) as i64;
+
+    let product = ((a * b) >> 16) as i32;
+
+    let (val, overflow) = product.overflowing_add(cpu.reg(rn) as i32);
+
+    cpu.set_reg(rd, val as u32);
+
+    if overflow {
+        cpu.set_q();
+    }
+}
+
+/// Signed multiply word by halfword (ARMv5TE only)
+fn smulw<Y>(instruction: Instruction, debugger: &mut Debugger, cpu: &mut Cpu)
+    where Y: ModeFlag {
+    if !cpu.arch_version().has_dsp_extensions() {
+        return unimplemented(instruction, debugger, cpu);
+    }
+
+    let rm  = instruction.rm();
+    let rs  = instruction.rs();
+    // This instruction places Rd where Rn usually is
+    let rd  = instruction.rn();
+
+    if rd.is_pc() || rm.is_pc() || rs.is_pc() {
+        return cpu.undefined("Unpredictable SMULW");
+    }
+
+    let a = cpu.reg(rm) as i32 as i64;
+    let b = dsp_halfword::<Y>(cpu.reg(rs)) as i64;
+
+    let val = ((a * b) >> 16) as i32;
+
+    cpu.set_reg(rd, val as u32);
+}
+
+/// Signed multiply-accumulate long halfwords (ARMv5TE only). The
+/// 64bit accumulation wraps around and never sets the Q flag.
+fn smlal<X, Y>(instruction: Instruction,
+               debugger: &mut Debugger,
+               cpu: &mut Cpu)
+    where X: ModeFlag, Y: ModeFlag {
+    if !cpu.arch_version().has_dsp_extensions() {
+        return unimplemented(instruction, debugger, cpu);
+    }
+
+    let rm   = instruction.rm();
+    let rs   = instruction.rs();
+    let rdhi = instruction.rn();
+    let rdlo = instruction.rd();
+
+    if rdhi.is_pc() || rdlo.is_pc() || rm.is_pc() || rs.is_pc() {
+        return cpu.undefined("Unpredictable SMLAL");
+    }
+
+    if rdhi == rdlo {
+        return cpu.undefined("Unpredictable SMLAL with RdHi == RdLo");
+    }
+
+    let a = dsp_halfword::<X>(cpu.reg(rm));
+    let b = dsp_halfword::<Y>(cpu.reg(rs));
+
+    let acc = ((cpu.reg(rdhi) as u64) << 32) | cpu.reg(rdlo) as u64;
+
+    let val = (acc as i64).wrapping_add((a * b) as i64) as u64;
+
+    cpu.set_reg(rdlo, val as u32);
+    cpu.set_reg(rdhi, (val >> 32) as u32);
+}
+
+/// Signed multiply halfwords (ARMv5TE only)
+fn smul<X, Y>(instruction: Instruction, debugger: &mut Debugger, cpu: &mut Cpu)
+    where X: ModeFlag, Y: ModeFlag {
+    if !cpu.arch_version().has_dsp_extensions() {
+        return unimplemented(instruction, debugger, cpu);
+    }
+
+    let rm  = instruction.rm();
+    let rs  = instruction.rs();
+    // This instruction places Rd where Rn usually is
+    let rd  = instruction.rn();
+
+    if rd.is_pc() || rm.is_pc() || rs.is_pc() {
+        return cpu.undefined("Unpredictable SMUL");
+    }
+
+    let a = dsp_halfword::<X>(cpu.reg(rm));
+    let b = dsp_halfword::<Y>(cpu.reg(rs));
+
+    cpu.set_reg(rd, (a * b) as u32);
+}
+
 /// Since we can't use boolean varibales as a generic parameter I use
 /// this trait to create a boolean "metatype"
 trait ModeFlag {
     #[inline(always)]
     fn is_set() -> bool;
-    #[inline(always)]
-    fn is_clear() -> bool {
-        !Self::is_set()
-    }
 }
 
 struct Set;
@@ -1017,10 +1445,17 @@ impl ModeFlag for Clear {
     }
 }
 
+/// Error returned by the addressing modes for the unpredictable
+/// encodings
+fn unpredictable(msg: &str) -> StepError {
+    StepError::UndefinedInstruction(msg.to_string())
+}
+
 /// Addressing mode 2: Load and Store Word or Unsigned Byte
 trait Mode2Addressing {
     /// Decode the address and update the registers
-    fn address<U>(instruction: Instruction, cpu: &mut Cpu) -> u32
+    fn address<U>(instruction: Instruction,
+                  cpu: &mut Cpu) -> Result<u32, StepError>
         where U: ModeFlag;
 
     /// Used to validate that the addressing mode matches the
@@ -1032,18 +1467,22 @@ trait Mode2Addressing {
 struct Mode2Imm;
 
 impl Mode2Addressing for Mode2Imm {
-    fn address<U>(instruction: Instruction, cpu: &mut Cpu) -> u32
+    fn address<U>(instruction: Instruction,
+                  cpu: &mut Cpu) -> Result<u32, StepError>
         where U: ModeFlag {
         let rn     = instruction.rn();
         let offset = instruction.0 & 0xfff;
 
         let base = cpu.reg(rn);
 
-        if U::is_set() {
-            base.wrapping_add(offset)
-        } else {
-            base.wrapping_sub(offset)
-        }
+        let addr =
+            if U::is_set() {
+                base.wrapping_add(offset)
+            } else {
+                base.wrapping_sub(offset)
+            };
+
+        Ok(addr)
     }
 
     fn is_valid<U>(instruction: Instruction, load: bool, byte: bool) -> bool
@@ -1060,7 +1499,8 @@ impl Mode2Addressing for Mode2Imm {
 struct Mode2ImmPre;
 
 impl Mode2Addressing for Mode2ImmPre {
-    fn address<U>(instruction: Instruction, cpu: &mut Cpu) -> u32
+    fn address<U>(instruction: Instruction,
+                  cpu: &mut Cpu) -> Result<u32, StepError>
         where U: ModeFlag {
         let rd     = instruction.rd();
         let rn     = instruction.rn();
@@ -1068,12 +1508,15 @@ impl Mode2Addressing for Mode2ImmPre {
 
         if rn.is_pc() {
             // Unpredictable
-            panic!("PC pre-indexed");
+            return Err(unpredictable("PC pre-indexed"));
         }
 
-        if rd == rn {
-            // Unpredictable
-            panic!("Writeback indexing with Rd == Rn");
+        let load = (instruction.0 >> 20) & 1 != 0;
+
+        if load && rd == rn {
+            // Unpredictable for loads. Stores write the original
+            // value of the base register.
+            return Err(unpredictable("Load writeback indexing with Rd == Rn"));
         }
 
         let base = cpu.reg(rn);
@@ -1088,7 +1531,7 @@ impl Mode2Addressing for Mode2ImmPre {
         // Pre index
         cpu.set_reg(rn, addr);
 
-        addr
+        Ok(addr)
     }
 
     fn is_valid<U>(instruction: Instruction, load: bool, byte: bool) -> bool
@@ -1105,7 +1548,8 @@ impl Mode2Addressing for Mode2ImmPre {
 struct Mode2ImmPost;
 
 impl Mode2Addressing for Mode2ImmPost {
-    fn address<U>(instruction: Instruction, cpu: &mut Cpu) -> u32
+    fn address<U>(instruction: Instruction,
+                  cpu: &mut Cpu) -> Result<u32, StepError>
         where U: ModeFlag {
         let rd     = instruction.rd();
         let rn     = instruction.rn();
@@ -1113,12 +1557,15 @@ impl Mode2Addressing for Mode2ImmPost {
 
         if rn.is_pc() {
             // Unpredictable
-            panic!("PC post-indexed");
+            return Err(unpredictable("PC post-indexed"));
         }
 
-        if rd == rn {
-            // Unpredictable
-            panic!("Writeback indexing with Rd == Rn");
+        let load = (instruction.0 >> 20) & 1 != 0;
+
+        if load && rd == rn {
+            // Unpredictable for loads. Stores write the original
+            // value of the base register.
+            return Err(unpredictable("Load writeback indexing with Rd == Rn"));
         }
 
         let base = cpu.reg(rn);
@@ -1133,7 +1580,7 @@ impl Mode2Addressing for Mode2ImmPost {
         // Post index
         cpu.set_reg(rn, addr);
 
-        base
+        Ok(base)
     }
 
     fn is_valid<U>(instruction: Instruction, load: bool, byte: bool) -> bool
@@ -1150,7 +1597,8 @@ impl Mode2Addressing for Mode2ImmPost {
 struct Mode2LslReg;
 
 impl Mode2Addressing for Mode2LslReg {
-    fn address<U>(instruction: Instruction, cpu: &mut Cpu) -> u32
+    fn address<U>(instruction: Instruction,
+                  cpu: &mut Cpu) -> Result<u32, StepError>
         where U: ModeFlag {
         let rn    = instruction.rn();
         let rm    = instruction.rm();
@@ -1160,11 +1608,14 @@ impl Mode2Addressing for Mode2LslReg {
 
         let base = cpu.reg(rn);
 
-        if U::is_set() {
-            base.wrapping_add(offset)
-        } else {
-            base.wrapping_sub(offset)
-        }
+        let addr =
+            if U::is_set() {
+                base.wrapping_add(offset)
+            } else {
+                base.wrapping_sub(offset)
+            };
+
+        Ok(addr)
     }
 
     fn is_valid<U>(instruction: Instruction, load: bool, byte: bool) -> bool
@@ -1183,12 +1634,22 @@ impl Mode2Addressing for Mode2LslReg {
 struct Mode2LslRegPre;
 
 impl Mode2Addressing for Mode2LslRegPre {
-    fn address<U>(instruction: Instruction, cpu: &mut Cpu) -> u32
+    fn address<U>(instruction: Instruction,
+                  cpu: &mut Cpu) -> Result<u32, StepError>
         where U: ModeFlag {
+        let rd    = instruction.rd();
         let rn    = instruction.rn();
         let rm    = instruction.rm();
         let shift = (instruction.0 >> 7) & 0x1f;
 
+        let load = (instruction.0 >> 20) & 1 != 0;
+
+        if load && rd == rn {
+            // Unpredictable for loads. Stores write the original
+            // value of the base register.
+            return Err(unpredictable("Load writeback indexing with Rd == Rn"));
+        }
+
         let offset = cpu.reg(rm) << shift;
 
         let base = cpu.reg(rn);
@@ -1202,7 +1663,7 @@ impl Mode2Addressing for Mode2LslRegPre {
 
         cpu.set_reg(rn, addr);
 
-        addr
+        Ok(addr)
     }
 
     fn is_valid<U>(instruction: Instruction, load: bool, byte: bool) -> bool
@@ -1221,7 +1682,11 @@ impl Mode2Addressing for Mode2LslRegPre {
 fn ldr<M, U>(instruction: Instruction, debugger: &mut Debugger, cpu: &mut Cpu)
     where M: Mode2Addressing, U: ModeFlag {
     let rd   = instruction.rd();
-    let addr = M::address::<U>(instruction, cpu);
+    let addr =
+        match M::address::<U>(instruction, cpu) {
+            Ok(addr) => addr,
+            Err(e) => return cpu.fault(e),
+        };
 
     debug_assert!(M::is_valid::<U>(instruction, true, false));
 
@@ -1232,30 +1697,39 @@ fn ldr<M, U>(instruction: Instruction, debugger: &mut Debugger, cpu: &mut Cpu)
 
     let val = cpu.load::<Word>(debugger, addr).rotate_right(rot);
 
-    cpu.set_reg_pc_mask(rd, val);
+    cpu.set_reg_load(rd, val);
 }
 
 fn str<M, U>(instruction: Instruction, debugger: &mut Debugger, cpu: &mut Cpu)
     where M: Mode2Addressing, U: ModeFlag {
     let rd   = instruction.rd();
-    let addr = M::address::<U>(instruction, cpu);
+    // We must read the value before the address computation since
+    // Rd may be the base register which gets written back
+    let val  = cpu.reg(rd);
+    let addr =
+        match M::address::<U>(instruction, cpu) {
+            Ok(addr) => addr,
+            Err(e) => return cpu.fault(e),
+        };
 
     debug_assert!(M::is_valid::<U>(instruction, false, false));
 
     if rd.is_pc() {
         // Implementation defined
-        panic!("PC stored in STR");
+        return cpu.undefined("PC stored in STR");
     }
 
-    let val = cpu.reg(rd);
-
     cpu.store::<Word>(debugger, addr, val);
 }
 
 fn ldrb<M, U>(instruction: Instruction, debugger: &mut Debugger, cpu: &mut Cpu)
     where M: Mode2Addressing, U: ModeFlag {
     let rd   = instruction.rd();
-    let addr = M::address::<U>(instruction, cpu);
+    let addr =
+        match M::address::<U>(instruction, cpu) {
+            Ok(addr) => addr,
+            Err(e) => return cpu.fault(e),
+        };
 
     debug_assert!(M::is_valid::<U>(instruction, true, true));
 
@@ -1267,25 +1741,30 @@ fn ldrb<M, U>(instruction: Instruction, debugger: &mut Debugger, cpu: &mut Cpu)
 fn strb<M, U>(instruction: Instruction, debugger: &mut Debugger, cpu: &mut Cpu)
     where M: Mode2Addressing, U: ModeFlag {
     let rd   = instruction.rd();
-    let addr = M::address::<U>(instruction, cpu);
+    // Rd may be the base register, read it before the writeback
+    let val  = cpu.reg(rd);
+    let addr =
+        match M::address::<U>(instruction, cpu) {
+            Ok(addr) => addr,
+            Err(e) => return cpu.fault(e),
+        };
 
     debug_assert!(M::is_valid::<U>(instruction, false, true));
 
     if rd.is_pc() {
         // I think this is actually allowed and should store
         // cur_instruction + 8 since A2.4.3 only mentions STR and STM
-        panic!("PC stored in STRB");
+        return cpu.undefined("PC stored in STRB");
     }
 
-    let val = cpu.reg(rd);
-
     cpu.store::<Byte>(debugger, addr, val);
 }
 
 /// Addressing mode 3: Miscellaneous Loads and Stores
 trait Mode3Addressing {
     /// Decode the address and update the registers
-    fn address<U>(instruction: Instruction, cpu: &mut Cpu) -> u32
+    fn address<U>(instruction: Instruction,
+                  cpu: &mut Cpu) -> Result<u32, StepError>
         where U: ModeFlag;
 
     /// Used to validate that the addressing mode matches the
@@ -1300,7 +1779,8 @@ trait Mode3Addressing {
 struct Mode3Imm;
 
 impl Mode3Addressing for Mode3Imm {
-    fn address<U>(instruction: Instruction, cpu: &mut Cpu) -> u32
+    fn address<U>(instruction: Instruction,
+                  cpu: &mut Cpu) -> Result<u32, StepError>
         where U: ModeFlag {
         let rn = instruction.rn();
         let hi = (instruction.0 >> 8) & 0xf;
@@ -1310,11 +1790,14 @@ impl Mode3Addressing for Mode3Imm {
 
         let base = cpu.reg(rn);
 
-        if U::is_set() {
-            base.wrapping_add(offset)
-        } else {
-            base.wrapping_sub(offset)
-        }
+        let addr =
+            if U::is_set() {
+                base.wrapping_add(offset)
+            } else {
+                base.wrapping_sub(offset)
+            };
+
+        Ok(addr)
     }
 
     fn is_valid<U>(instruction: Instruction,
@@ -1338,7 +1821,8 @@ impl Mode3Addressing for Mode3Imm {
 struct Mode3ImmPre;
 
 impl Mode3Addressing for Mode3ImmPre {
-    fn address<U>(instruction: Instruction, cpu: &mut Cpu) -> u32
+    fn address<U>(instruction: Instruction,
+                  cpu: &mut Cpu) -> Result<u32, StepError>
         where U: ModeFlag {
         let rn = instruction.rn();
         let rd = instruction.rd();
@@ -1347,7 +1831,7 @@ impl Mode3Addressing for Mode3ImmPre {
 
         if rd == rn {
             // Unpredictable
-            panic!("Writeback indexing with Rd == Rn");
+            return Err(unpredictable("Writeback indexing with Rd == Rn"));
         }
 
         let offset = (hi << 4) | lo;
@@ -1363,7 +1847,7 @@ impl Mode3Addressing for Mode3ImmPre {
 
         cpu.set_reg(rn, addr);
 
-        addr
+        Ok(addr)
     }
 
     fn is_valid<U>(instruction: Instruction,
@@ -1387,7 +1871,8 @@ impl Mode3Addressing for Mode3ImmPre {
 struct Mode3ImmPost;
 
 impl Mode3Addressing for Mode3ImmPost {
-    fn address<U>(instruction: Instruction, cpu: &mut Cpu) -> u32
+    fn address<U>(instruction: Instruction,
+                  cpu: &mut Cpu) -> Result<u32, StepError>
         where U: ModeFlag {
         let rn = instruction.rn();
         let rd = instruction.rd();
@@ -1396,7 +1881,7 @@ impl Mode3Addressing for Mode3ImmPost {
 
         if rd == rn {
             // Unpredictable
-            panic!("Writeback indexing with Rd == Rn");
+            return Err(unpredictable("Writeback indexing with Rd == Rn"));
         }
 
         let offset = (hi << 4) | lo;
@@ -1412,7 +1897,7 @@ impl Mode3Addressing for Mode3ImmPost {
 
         cpu.set_reg(rn, wb);
 
-        base
+        Ok(base)
     }
 
     fn is_valid<U>(instruction: Instruction,
@@ -1436,7 +1921,8 @@ impl Mode3Addressing for Mode3ImmPost {
 struct Mode3Reg;
 
 impl Mode3Addressing for Mode3Reg {
-    fn address<U>(instruction: Instruction, cpu: &mut Cpu) -> u32
+    fn address<U>(instruction: Instruction,
+                  cpu: &mut Cpu) -> Result<u32, StepError>
         where U: ModeFlag {
         let rn = instruction.rn();
         let rm = instruction.rm();
@@ -1444,11 +1930,14 @@ impl Mode3Addressing for Mode3Reg {
         let base = cpu.reg(rn);
         let offset = cpu.reg(rm);
 
-        if U::is_set() {
-            base.wrapping_add(offset)
-        } else {
-            base.wrapping_sub(offset)
-        }
+        let addr =
+            if U::is_set() {
+                base.wrapping_add(offset)
+            } else {
+                base.wrapping_sub(offset)
+            };
+
+        Ok(addr)
     }
 
     fn is_valid<U>(instruction: Instruction,
@@ -1472,7 +1961,11 @@ impl Mode3Addressing for Mode3Reg {
 fn ldrh<M, U>(instruction: Instruction, debugger: &mut Debugger, cpu: &mut Cpu)
     where M: Mode3Addressing, U: ModeFlag {
     let rd   = instruction.rd();
-    let addr = M::address::<U>(instruction, cpu);
+    let addr =
+        match M::address::<U>(instruction, cpu) {
+            Ok(addr) => addr,
+            Err(e) => return cpu.fault(e),
+        };
 
     debug_assert!(M::is_valid::<U>(instruction, true, false, false));
 
@@ -1484,7 +1977,11 @@ fn ldrh<M, U>(instruction: Instruction, debugger: &mut Debugger, cpu: &mut Cpu)
 fn ldrsh<M, U>(instruction: Instruction, debugger: &mut Debugger, cpu: &mut Cpu)
     where M: Mode3Addressing, U: ModeFlag {
     let rd   = instruction.rd();
-    let addr = M::address::<U>(instruction, cpu);
+    let addr =
+        match M::address::<U>(instruction, cpu) {
+            Ok(addr) => addr,
+            Err(e) => return cpu.fault(e),
+        };
 
     debug_assert!(M::is_valid::<U>(instruction, true, false, true));
 
@@ -1496,7 +1993,11 @@ fn ldrsh<M, U>(instruction: Instruction, debugger: &mut Debugger, cpu: &mut Cpu)
 fn strh<M, U>(instruction: Instruction, debugger: &mut Debugger, cpu: &mut Cpu)
     where M: Mode3Addressing, U: ModeFlag {
     let rd   = instruction.rd();
-    let addr = M::address::<U>(instruction, cpu);
+    let addr =
+        match M::address::<U>(instruction, cpu) {
+            Ok(addr) => addr,
+            Err(e) => return cpu.fault(e),
+        };
 
     debug_assert!(M::is_valid::<U>(instruction, false, false, false));
 
@@ -1508,7 +2009,11 @@ fn strh<M, U>(instruction: Instruction, debugger: &mut Debugger, cpu: &mut Cpu)
 fn ldrsb<M, U>(instruction: Instruction, debugger: &mut Debugger, cpu: &mut Cpu)
     where M: Mode3Addressing, U: ModeFlag {
     let rd   = instruction.rd();
-    let addr = M::address::<U>(instruction, cpu);
+    let addr =
+        match M::address::<U>(instruction, cpu) {
+            Ok(addr) => addr,
+            Err(e) => return cpu.fault(e),
+        };
 
     debug_assert!(M::is_valid::<U>(instruction, true, true, true));
 
@@ -1566,9 +2071,21 @@ fn ldm<U, P, W>(instruction: Instruction,
             ((i >> 20) & 1) == 1 as u32
     });
 
-    if list == 0 || rn.is_pc() ||
-        (W::is_set() && base_in_list) {
-        panic!("Unpredictable LDM");
+    if list == 0 || rn.is_pc() {
+        return cpu.undefined("Unpredictable LDM");
+    }
+
+    // Without writeback the base is simply overwritten by the loaded
+    // value like any other register in the list. The base address
+    // is read before any register is loaded so the rest of the
+    // transfer is unaffected. With writeback the final value of the
+    // base is unpredictable, `try_step` reports it as an undefined
+    // instruction.
+    if W::is_set() && base_in_list {
+        let msg = format!("Unpredictable LDM with writeback and base in \
+                           list: {}", instruction);
+
+        return cpu.undefined(msg);
     }
 
     let base = cpu.reg(rn);
@@ -1581,7 +2098,7 @@ fn ldm<U, P, W>(instruction: Instruction,
 
             let val = cpu.load::<Word>(debugger, addr);
 
-            cpu.set_reg_pc_mask(reg, val);
+            cpu.set_reg_load(reg, val);
 
             addr = addr.wrapping_add(4);
         }
@@ -1623,15 +2140,17 @@ fn ldms<U, P, W>(instruction: Instruction,
 
     if list == 0 || rn.is_pc() ||
         (W::is_set() && base_in_list) {
-        panic!("Unpredictable LDM");
+        return cpu.undefined("Unpredictable LDM");
     }
 
     // The presence of PC in the list tells us which instruction this
     // is
     let load_spsr = (list & (1 << 15)) != 0;
 
-    if !load_spsr {
-        debug_assert!(W::is_clear());
+    if !load_spsr && W::is_set() {
+        // LDM(2) with writeback is unpredictable
+        return cpu.undefined(format!("LDM user registers with writeback: \
+                                      {}", instruction));
     }
 
     let base = cpu.reg(rn);
@@ -1658,7 +2177,9 @@ fn ldms<U, P, W>(instruction: Instruction,
                 }
             } else {
                 // XXX Implement user-mode loading
-                unimplemented!();
+                let msg = format!("LDM user registers: {}", instruction);
+
+                return cpu.unimplemented(msg);
             }
 
             addr = addr.wrapping_add(4);
@@ -1670,9 +2191,9 @@ fn ldms<U, P, W>(instruction: Instruction,
     }
 
     if load_spsr {
-        let spsr = cpu.spsr();
-
-        cpu.set_pc_cpsr(pc, spsr);
+        if let Some(spsr) = cpu.spsr() {
+            cpu.set_pc_cpsr(pc, spsr);
+        }
     }
 }
 
@@ -1698,13 +2219,13 @@ fn stm<U, P, W>(instruction: Instruction,
 
     if list == 0 || rn.is_pc() ||
         (W::is_set() && base_in_list) {
-        panic!("Unpredictable LDM");
+        return cpu.undefined("Unpredictable LDM");
     }
 
     let pc_in_list = (list & (1 << 15)) != 0;
 
     if pc_in_list {
-        panic!("Implementation-defined STM");
+        return cpu.undefined("Implementation-defined STM");
     }
 
     let base = cpu.reg(rn);
@@ -1718,7 +2239,7 @@ fn stm<U, P, W>(instruction: Instruction,
             let reg = RegisterIndex(i);
 
             if W::is_set() && reg == rn && !first {
-                panic!("Unpredictable STM");
+                return cpu.undefined("Unpredictable STM");
             }
 
             let val = cpu.reg(reg);
@@ -1739,7 +2260,9 @@ fn mrs_cpsr(instruction: Instruction, _: &mut Debugger, cpu: &mut Cpu) {
     let rd = instruction.rd();
 
     if (instruction.0 & 0xf0fff) != 0xf0000 {
-        panic!("Invalid MRS instruction {}", instruction);
+        let msg = format!("Invalid MRS instruction {}", instruction);
+
+        return cpu.undefined(msg);
     }
 
     let cpsr = cpu.cpsr();
@@ -1752,7 +2275,9 @@ fn msr_cpsr(instruction: Instruction, _: &mut Debugger, cpu: &mut Cpu) {
     let mask = instruction.msr_field_mask();
 
     if (instruction.0 & 0xff00) != 0xf000 {
-        panic!("Invalid MSR instruction {}", instruction);
+        let msg = format!("Invalid MSR instruction {}", instruction);
+
+        return cpu.undefined(msg);
     }
 
     let val = cpu.reg(rm);
@@ -1765,9 +2290,14 @@ fn bx(instruction: Instruction, _: &mut Debugger, cpu: &mut Cpu) {
 
     if (instruction.0 & 0xfff00) != 0xfff00 {
         // "should be one"
-        panic!("Invalid BX instruction {}", instruction);
+        let msg = format!("Invalid BX instruction {}", instruction);
+
+        return cpu.undefined(msg);
     }
 
+    // `BX PC` reads the current instruction's address + 8 like any
+    // other R15 read. Since it's always word-aligned in ARM state it
+    // jumps 2 instructions ahead and stays in ARM mode.
     let target = cpu.reg(rm);
 
     // If bit 0 of the target is set we switch to Thumb mode
@@ -1781,12 +2311,29 @@ fn mrs_spsr(instruction: Instruction, _: &mut Debugger, cpu: &mut Cpu) {
     let rd = instruction.rd();
 
     if rd.is_pc() || (instruction.0 & 0xf0fff) != 0xf0000 {
-        panic!("Invalid MSR instruction {}", instruction);
+        let msg = format!("Invalid MSR instruction {}", instruction);
+
+        return cpu.undefined(msg);
     }
 
-    let val = cpu.spsr();
+    if let Some(val) = cpu.spsr() {
+        cpu.set_reg(rd, val);
+    }
+}
 
-    cpu.set_reg(rd, val);
+fn msr_spsr(instruction: Instruction, _: &mut Debugger, cpu: &mut Cpu) {
+    let rm   = instruction.rm();
+    let mask = instruction.msr_field_mask();
+
+    if (instruction.0 & 0xff00) != 0xf000 {
+        let msg = format!("Invalid MSR instruction {}", instruction);
+
+        return cpu.undefined(msg);
+    }
+
+    let val = cpu.reg(rm);
+
+    cpu.msr_spsr(val, mask);
 }
 
 fn b(instruction: Instruction, _: &mut Debugger, cpu: &mut Cpu) {
@@ -1809,11 +2356,85 @@ fn bl(instruction: Instruction, _: &mut Debugger, cpu: &mut Cpu) {
     cpu.set_pc(pc);
 }
 
-fn swi(_: Instruction, _: &mut Debugger, cpu: &mut Cpu) {
-    cpu.swi();
+/// Branch with link and switch to Thumb mode (ARMv5 only). This
+/// instruction is always unconditional.
+fn blx_imm(instruction: Instruction, _: &mut Debugger, cpu: &mut Cpu) {
+    let offset = instruction.branch_imm_offset();
+
+    // The H bit selects the halfword within the target word
+    let h = (instruction.0 >> 23) & 2;
+
+    let pc = cpu.registers[15].wrapping_add(offset).wrapping_add(h);
+
+    let ra = cpu.next_pc;
+
+    cpu.set_reg(RegisterIndex(14), ra);
+
+    cpu.set_pc_thumb(pc, true);
 }
 
-static OPCODE_LUT: [fn (Instruction, &mut Debugger, &mut Cpu); 4096] = [
+/// Branch with link to a register, switching to Thumb mode if bit 0
+/// of the target is set (ARMv5 only)
+fn blx_reg(instruction: Instruction, debugger: &mut Debugger, cpu: &mut Cpu) {
+    if !cpu.arch_version().has_blx() {
+        return unimplemented(instruction, debugger, cpu);
+    }
+
+    let rm = instruction.rm();
+
+    if (instruction.0 & 0xfff00) != 0xfff00 {
+        // "should be one"
+        let msg = format!("Invalid BLX instruction {}", instruction);
+
+        return cpu.undefined(msg);
+    }
+
+    if rm.is_pc() {
+        return cpu.undefined("Unpredictable BLX to PC");
+    }
+
+    // Rm may be LR so we have to read the target first
+    let target = cpu.reg(rm);
+
+    let ra = cpu.next_pc;
+
+    cpu.set_reg(RegisterIndex(14), ra);
+
+    let thumb = (target & 1) != 0;
+    let address = target & !1;
+
+    cpu.set_pc_thumb(address, thumb);
+}
+
+fn swi(instruction: Instruction, debugger: &mut Debugger, cpu: &mut Cpu) {
+    let comment = instruction.swi_comment();
+
+    debugger.swi(cpu, comment);
+
+    if !semihosting::swi(cpu, comment) && !hle::swi(cpu, debugger, comment) {
+        cpu.swi();
+    }
+}
+
+/// Return the name of the handler of each of the 4096 entries of the
+/// decoding LUT, indexed like the LUT (see `Instruction::opcode`).
+/// Useful to check the decoding tables without reading the source.
+pub fn handler_names() -> &'static [&'static str; 4096] {
+    &OPCODE_NAMES
+}
+
+/// Build `OPCODE_LUT` and `OPCODE_NAMES` from the same list of
+/// handlers so that they can't get out of sync
+macro_rules! opcode_lut {
+    ($($handler:expr),* $(,)*) => {
+        static OPCODE_LUT: [Handler; 4096] = [$($handler),*];
+
+        static OPCODE_NAMES: [&'static str; 4096] =
+            [$(stringify!($handler)),*];
+    }
+}
+
+opcode_lut![
     // 0x000
     and::<Mode1LslImm>, and::<Mode1LslReg>,
     and::<Mode1LsrImm>, and::<Mode1LsrReg>,
@@ -1825,10 +2446,14 @@ static OPCODE_LUT: [fn (Instruction, &mut Debugger, &mut Cpu); 4096] = [
     unimplemented, unimplemented,
 
     // 0x010
-    unimplemented, unimplemented, unimplemented, unimplemented,
-    unimplemented, unimplemented, unimplemented, unimplemented,
-    unimplemented, mul::<Set>, unimplemented, unimplemented,
-    unimplemented, unimplemented, unimplemented, unimplemented,
+    ands::<Mode1LslImm>, ands::<Mode1LslReg>,
+    ands::<Mode1LsrImm>, ands::<Mode1LsrReg>,
+    ands::<Mode1AsrImm>, ands::<Mode1AsrReg>,
+    ands::<Mode1RorImm>, ands::<Mode1RorReg>,
+    ands::<Mode1LslImm>, mul::<Set>,
+    ands::<Mode1LsrImm>, unimplemented,
+    ands::<Mode1AsrImm>, unimplemented,
+    ands::<Mode1RorImm>, unimplemented,
 
     // 0x020
     eor::<Mode1LslImm>, eor::<Mode1LslReg>,
@@ -1841,10 +2466,14 @@ static OPCODE_LUT: [fn (Instruction, &mut Debugger, &mut Cpu); 4096] = [
     unimplemented, unimplemented,
 
     // 0x030
-    unimplemented, unimplemented, unimplemented, unimplemented,
-    eors::<Mode1AsrImm>, unimplemented, unimplemented, unimplemented,
-    unimplemented, unimplemented, unimplemented, unimplemented,
-    eors::<Mode1AsrImm>, unimplemented, unimplemented, unimplemented,
+    eors::<Mode1LslImm>, eors::<Mode1LslReg>,
+    eors::<Mode1LsrImm>, eors::<Mode1LsrReg>,
+    eors::<Mode1AsrImm>, eors::<Mode1AsrReg>,
+    eors::<Mode1RorImm>, eors::<Mode1RorReg>,
+    eors::<Mode1LslImm>, unimplemented,
+    eors::<Mode1LsrImm>, unimplemented,
+    eors::<Mode1AsrImm>, unimplemented,
+    eors::<Mode1RorImm>, unimplemented,
 
     // 0x040
     sub::<Mode1LslImm>, unimplemented, sub::<Mode1LsrImm>, unimplemented,
@@ -1891,46 +2520,70 @@ static OPCODE_LUT: [fn (Instruction, &mut Debugger, &mut Cpu); 4096] = [
     unimplemented, unimplemented,
 
     // 0x0a0
-    adc::<Mode1LslImm>, unimplemented, unimplemented, unimplemented,
-    unimplemented, unimplemented, unimplemented, unimplemented,
-    adc::<Mode1LslImm>, unimplemented, unimplemented, unimplemented,
-    unimplemented, unimplemented, unimplemented, unimplemented,
+    adc::<Mode1LslImm>, adc::<Mode1LslReg>,
+    adc::<Mode1LsrImm>, adc::<Mode1LsrReg>,
+    adc::<Mode1AsrImm>, adc::<Mode1AsrReg>,
+    adc::<Mode1RorImm>, adc::<Mode1RorReg>,
+    adc::<Mode1LslImm>, unimplemented,
+    adc::<Mode1LsrImm>, unimplemented,
+    adc::<Mode1AsrImm>, unimplemented,
+    adc::<Mode1RorImm>, unimplemented,
 
     // 0x0b0
-    unimplemented, unimplemented, unimplemented, unimplemented,
-    unimplemented, unimplemented, unimplemented, unimplemented,
-    unimplemented, unimplemented, unimplemented, unimplemented,
-    unimplemented, unimplemented, unimplemented, unimplemented,
+    adcs::<Mode1LslImm>, adcs::<Mode1LslReg>,
+    adcs::<Mode1LsrImm>, adcs::<Mode1LsrReg>,
+    adcs::<Mode1AsrImm>, adcs::<Mode1AsrReg>,
+    adcs::<Mode1RorImm>, adcs::<Mode1RorReg>,
+    adcs::<Mode1LslImm>, unimplemented,
+    adcs::<Mode1LsrImm>, unimplemented,
+    adcs::<Mode1AsrImm>, unimplemented,
+    adcs::<Mode1RorImm>, unimplemented,
 
     // 0x0c0
-    unimplemented, unimplemented, unimplemented, unimplemented,
-    unimplemented, unimplemented, unimplemented, unimplemented,
-    unimplemented, unimplemented, unimplemented, strh::<Mode3ImmPost, Set>,
-    unimplemented, unimplemented, unimplemented, unimplemented,
+    sbc::<Mode1LslImm>, sbc::<Mode1LslReg>,
+    sbc::<Mode1LsrImm>, sbc::<Mode1LsrReg>,
+    sbc::<Mode1AsrImm>, sbc::<Mode1AsrReg>,
+    sbc::<Mode1RorImm>, sbc::<Mode1RorReg>,
+    sbc::<Mode1LslImm>, unimplemented,
+    sbc::<Mode1LsrImm>, strh::<Mode3ImmPost, Set>,
+    sbc::<Mode1AsrImm>, unimplemented,
+    sbc::<Mode1RorImm>, unimplemented,
 
     // 0x0d0
-    unimplemented, unimplemented, unimplemented, unimplemented,
-    unimplemented, unimplemented, unimplemented, unimplemented,
-    unimplemented, unimplemented, unimplemented, ldrh::<Mode3ImmPost, Set>,
-    unimplemented, unimplemented, unimplemented, unimplemented,
+    sbcs::<Mode1LslImm>, sbcs::<Mode1LslReg>,
+    sbcs::<Mode1LsrImm>, sbcs::<Mode1LsrReg>,
+    sbcs::<Mode1AsrImm>, sbcs::<Mode1AsrReg>,
+    sbcs::<Mode1RorImm>, sbcs::<Mode1RorReg>,
+    sbcs::<Mode1LslImm>, unimplemented,
+    sbcs::<Mode1LsrImm>, ldrh::<Mode3ImmPost, Set>,
+    sbcs::<Mode1AsrImm>, unimplemented,
+    sbcs::<Mode1RorImm>, unimplemented,
 
     // 0x0e0
-    unimplemented, unimplemented, unimplemented, unimplemented,
-    unimplemented, unimplemented, unimplemented, unimplemented,
-    unimplemented, unimplemented, unimplemented, unimplemented,
-    unimplemented, unimplemented, unimplemented, unimplemented,
+    rsc::<Mode1LslImm>, rsc::<Mode1LslReg>,
+    rsc::<Mode1LsrImm>, rsc::<Mode1LsrReg>,
+    rsc::<Mode1AsrImm>, rsc::<Mode1AsrReg>,
+    rsc::<Mode1RorImm>, rsc::<Mode1RorReg>,
+    rsc::<Mode1LslImm>, unimplemented,
+    rsc::<Mode1LsrImm>, unimplemented,
+    rsc::<Mode1AsrImm>, unimplemented,
+    rsc::<Mode1RorImm>, unimplemented,
 
     // 0x0f0
-    unimplemented, unimplemented, unimplemented, unimplemented,
-    unimplemented, unimplemented, unimplemented, unimplemented,
-    unimplemented, unimplemented, unimplemented, unimplemented,
-    unimplemented, unimplemented, unimplemented, unimplemented,
+    rscs::<Mode1LslImm>, rscs::<Mode1LslReg>,
+    rscs::<Mode1LsrImm>, rscs::<Mode1LsrReg>,
+    rscs::<Mode1AsrImm>, rscs::<Mode1AsrReg>,
+    rscs::<Mode1RorImm>, rscs::<Mode1RorReg>,
+    rscs::<Mode1LslImm>, unimplemented,
+    rscs::<Mode1LsrImm>, unimplemented,
+    rscs::<Mode1AsrImm>, unimplemented,
+    rscs::<Mode1RorImm>, unimplemented,
 
     // 0x100
     mrs_cpsr, unimplemented, unimplemented, unimplemented,
     unimplemented, unimplemented, unimplemented, unimplemented,
-    unimplemented, unimplemented, unimplemented, unimplemented,
-    unimplemented, unimplemented, unimplemented, unimplemented,
+    smla::<Clear, Clear>, unimplemented, smla::<Set, Clear>, unimplemented,
+    smla::<Clear, Set>, unimplemented, smla::<Set, Set>, unimplemented,
 
     // 0x110
     tst::<Mode1LslImm>, tst::<Mode1LslReg>,
@@ -1943,10 +2596,10 @@ static OPCODE_LUT: [fn (Instruction, &mut Debugger, &mut Cpu); 4096] = [
     tst::<Mode1RorImm>, unimplemented,
 
     // 0x120
-    msr_cpsr, bx, unimplemented, unimplemented,
-    unimplemented, unimplemented, unimplemented, unimplemented,
-    unimplemented, unimplemented, unimplemented, unimplemented,
+    msr_cpsr, bx, unimplemented, blx_reg,
     unimplemented, unimplemented, unimplemented, unimplemented,
+    smlaw::<Clear>, unimplemented, smulw::<Clear>, unimplemented,
+    smlaw::<Set>, unimplemented, smulw::<Set>, unimplemented,
 
     // 0x130
     teq::<Mode1LslImm>, unimplemented, teq::<Mode1LsrImm>, unimplemented,
@@ -1955,10 +2608,14 @@ static OPCODE_LUT: [fn (Instruction, &mut Debugger, &mut Cpu); 4096] = [
     unimplemented, unimplemented, unimplemented, unimplemented,
 
     // 0x140
-    mrs_spsr, unimplemented, unimplemented, unimplemented,
-    unimplemented, unimplemented, unimplemented, unimplemented,
-    unimplemented, unimplemented, unimplemented, strh::<Mode3Imm, Clear>,
-    unimplemented, unimplemented, unimplemented, unimplemented,
+    mrs_spsr, unimplemented,
+    unimplemented, unimplemented,
+    unimplemented, unimplemented,
+    unimplemented, unimplemented,
+    smlal::<Clear, Clear>, unimplemented,
+    smlal::<Set, Clear>, strh::<Mode3Imm, Clear>,
+    smlal::<Clear, Set>, unimplemented,
+    smlal::<Set, Set>, unimplemented,
 
     // 0x150
     cmp::<Mode1LslImm>, unimplemented,
@@ -1971,16 +2628,24 @@ static OPCODE_LUT: [fn (Instruction, &mut Debugger, &mut Cpu); 4096] = [
     unimplemented, ldrsh::<Mode3Imm, Clear>,
 
     // 0x160
-    unimplemented, unimplemented, unimplemented, unimplemented,
-    unimplemented, unimplemented, unimplemented, unimplemented,
-    unimplemented, unimplemented, unimplemented, strh::<Mode3ImmPre, Clear>,
-    unimplemented, unimplemented, unimplemented, unimplemented,
+    msr_spsr, unimplemented,
+    unimplemented, unimplemented,
+    unimplemented, unimplemented,
+    unimplemented, unimplemented,
+    smul::<Clear, Clear>, unimplemented,
+    smul::<Set, Clear>, strh::<Mode3ImmPre, Clear>,
+    smul::<Clear, Set>, unimplemented,
+    smul::<Set, Set>, unimplemented,
 
     // 0x170
-    unimplemented, unimplemented, unimplemented, unimplemented,
-    unimplemented, unimplemented, unimplemented, unimplemented,
-    unimplemented, unimplemented, unimplemented, unimplemented,
-    unimplemented, unimplemented, unimplemented, unimplemented,
+    cmn::<Mode1LslImm>, cmn::<Mode1LslReg>,
+    cmn::<Mode1LsrImm>, cmn::<Mode1LsrReg>,
+    cmn::<Mode1AsrImm>, cmn::<Mode1AsrReg>,
+    cmn::<Mode1RorImm>, cmn::<Mode1RorReg>,
+    cmn::<Mode1LslImm>, unimplemented,
+    cmn::<Mode1LsrImm>, unimplemented,
+    cmn::<Mode1AsrImm>, unimplemented,
+    cmn::<Mode1RorImm>, unimplemented,
 
     // 0x180
     orr::<Mode1LslImm>, orr::<Mode1LslReg>,
@@ -2010,17 +2675,17 @@ static OPCODE_LUT: [fn (Instruction, &mut Debugger, &mut Cpu); 4096] = [
     mov::<Mode1LslImm>, unimplemented,
     mov::<Mode1LsrImm>, unimplemented,
     mov::<Mode1AsrImm>, unimplemented,
-    unimplemented, unimplemented,
+    mov::<Mode1RorImm>, unimplemented,
 
     // 0x1b0
     movs::<Mode1LslImm>, movs::<Mode1LslReg>,
     movs::<Mode1LsrImm>, movs::<Mode1LsrReg>,
-    movs::<Mode1AsrImm>, unimplemented,
-    movs::<Mode1RorImm>, unimplemented,
+    movs::<Mode1AsrImm>, movs::<Mode1AsrReg>,
+    movs::<Mode1RorImm>, movs::<Mode1RorReg>,
     movs::<Mode1LslImm>, unimplemented,
     movs::<Mode1LsrImm>, unimplemented,
     movs::<Mode1AsrImm>, unimplemented,
-    unimplemented, unimplemented,
+    movs::<Mode1RorImm>, unimplemented,
 
     // 0x1c0
     bic::<Mode1LslImm>, bic::<Mode1LslReg>,
@@ -2045,12 +2710,12 @@ static OPCODE_LUT: [fn (Instruction, &mut Debugger, &mut Cpu); 4096] = [
     // 0x1e0
     mvn::<Mode1LslImm>, mvn::<Mode1LslReg>,
     mvn::<Mode1LsrImm>, mvn::<Mode1LsrReg>,
-    mvn::<Mode1AsrImm>, unimplemented,
-    mvn::<Mode1RorImm>, unimplemented,
+    mvn::<Mode1AsrImm>, mvn::<Mode1AsrReg>,
+    mvn::<Mode1RorImm>, mvn::<Mode1RorReg>,
     mvn::<Mode1LslImm>, unimplemented,
     mvn::<Mode1LsrImm>, unimplemented,
     mvn::<Mode1AsrImm>, unimplemented,
-    unimplemented, unimplemented,
+    mvn::<Mode1RorImm>, unimplemented,
 
     // 0x1f0
     unimplemented, unimplemented, unimplemented, unimplemented,
@@ -2119,40 +2784,40 @@ static OPCODE_LUT: [fn (Instruction, &mut Debugger, &mut Cpu); 4096] = [
     adds::<Mode1Imm>, adds::<Mode1Imm>, adds::<Mode1Imm>, adds::<Mode1Imm>,
 
     // 0x2a0
-    unimplemented, unimplemented, unimplemented, unimplemented,
-    unimplemented, unimplemented, unimplemented, unimplemented,
-    unimplemented, unimplemented, unimplemented, unimplemented,
-    unimplemented, unimplemented, unimplemented, unimplemented,
+    adc::<Mode1Imm>, adc::<Mode1Imm>, adc::<Mode1Imm>, adc::<Mode1Imm>,
+    adc::<Mode1Imm>, adc::<Mode1Imm>, adc::<Mode1Imm>, adc::<Mode1Imm>,
+    adc::<Mode1Imm>, adc::<Mode1Imm>, adc::<Mode1Imm>, adc::<Mode1Imm>,
+    adc::<Mode1Imm>, adc::<Mode1Imm>, adc::<Mode1Imm>, adc::<Mode1Imm>,
 
     // 0x2b0
-    unimplemented, unimplemented, unimplemented, unimplemented,
-    unimplemented, unimplemented, unimplemented, unimplemented,
-    unimplemented, unimplemented, unimplemented, unimplemented,
-    unimplemented, unimplemented, unimplemented, unimplemented,
+    adcs::<Mode1Imm>, adcs::<Mode1Imm>, adcs::<Mode1Imm>, adcs::<Mode1Imm>,
+    adcs::<Mode1Imm>, adcs::<Mode1Imm>, adcs::<Mode1Imm>, adcs::<Mode1Imm>,
+    adcs::<Mode1Imm>, adcs::<Mode1Imm>, adcs::<Mode1Imm>, adcs::<Mode1Imm>,
+    adcs::<Mode1Imm>, adcs::<Mode1Imm>, adcs::<Mode1Imm>, adcs::<Mode1Imm>,
 
     // 0x2c0
-    unimplemented, unimplemented, unimplemented, unimplemented,
-    unimplemented, unimplemented, unimplemented, unimplemented,
-    unimplemented, unimplemented, unimplemented, unimplemented,
-    unimplemented, unimplemented, unimplemented, unimplemented,
+    sbc::<Mode1Imm>, sbc::<Mode1Imm>, sbc::<Mode1Imm>, sbc::<Mode1Imm>,
+    sbc::<Mode1Imm>, sbc::<Mode1Imm>, sbc::<Mode1Imm>, sbc::<Mode1Imm>,
+    sbc::<Mode1Imm>, sbc::<Mode1Imm>, sbc::<Mode1Imm>, sbc::<Mode1Imm>,
+    sbc::<Mode1Imm>, sbc::<Mode1Imm>, sbc::<Mode1Imm>, sbc::<Mode1Imm>,
 
     // 0x2d0
-    unimplemented, unimplemented, unimplemented, unimplemented,
-    unimplemented, unimplemented, unimplemented, unimplemented,
-    unimplemented, unimplemented, unimplemented, unimplemented,
-    unimplemented, unimplemented, unimplemented, unimplemented,
+    sbcs::<Mode1Imm>, sbcs::<Mode1Imm>, sbcs::<Mode1Imm>, sbcs::<Mode1Imm>,
+    sbcs::<Mode1Imm>, sbcs::<Mode1Imm>, sbcs::<Mode1Imm>, sbcs::<Mode1Imm>,
+    sbcs::<Mode1Imm>, sbcs::<Mode1Imm>, sbcs::<Mode1Imm>, sbcs::<Mode1Imm>,
+    sbcs::<Mode1Imm>, sbcs::<Mode1Imm>, sbcs::<Mode1Imm>, sbcs::<Mode1Imm>,
 
     // 0x2e0
-    unimplemented, unimplemented, unimplemented, unimplemented,
-    unimplemented, unimplemented, unimplemented, unimplemented,
-    unimplemented, unimplemented, unimplemented, unimplemented,
-    unimplemented, unimplemented, unimplemented, unimplemented,
+    rsc::<Mode1Imm>, rsc::<Mode1Imm>, rsc::<Mode1Imm>, rsc::<Mode1Imm>,
+    rsc::<Mode1Imm>, rsc::<Mode1Imm>, rsc::<Mode1Imm>, rsc::<Mode1Imm>,
+    rsc::<Mode1Imm>, rsc::<Mode1Imm>, rsc::<Mode1Imm>, rsc::<Mode1Imm>,
+    rsc::<Mode1Imm>, rsc::<Mode1Imm>, rsc::<Mode1Imm>, rsc::<Mode1Imm>,
 
     // 0x2f0
-    unimplemented, unimplemented, unimplemented, unimplemented,
-    unimplemented, unimplemented, unimplemented, unimplemented,
-    unimplemented, unimplemented, unimplemented, unimplemented,
-    unimplemented, unimplemented, unimplemented, unimplemented,
+    rscs::<Mode1Imm>, rscs::<Mode1Imm>, rscs::<Mode1Imm>, rscs::<Mode1Imm>,
+    rscs::<Mode1Imm>, rscs::<Mode1Imm>, rscs::<Mode1Imm>, rscs::<Mode1Imm>,
+    rscs::<Mode1Imm>, rscs::<Mode1Imm>, rscs::<Mode1Imm>, rscs::<Mode1Imm>,
+    rscs::<Mode1Imm>, rscs::<Mode1Imm>, rscs::<Mode1Imm>, rscs::<Mode1Imm>,
 
     // 0x300
     unimplemented, unimplemented, unimplemented, unimplemented,
@@ -2675,9 +3340,9 @@ static OPCODE_LUT: [fn (Instruction, &mut Debugger, &mut Cpu); 4096] = [
     unimplemented, unimplemented, unimplemented, unimplemented,
 
     // 0x7a0
+    str::<Mode2LslRegPre, Set>, unimplemented, unimplemented, unimplemented,
     unimplemented, unimplemented, unimplemented, unimplemented,
-    unimplemented, unimplemented, unimplemented, unimplemented,
-    unimplemented, unimplemented, unimplemented, unimplemented,
+    str::<Mode2LslRegPre, Set>, unimplemented, unimplemented, unimplemented,
     unimplemented, unimplemented, unimplemented, unimplemented,
 
     // 0x7b0
@@ -3506,3 +4171,1248 @@ static OPCODE_LUT: [fn (Instruction, &mut Debugger, &mut Cpu); 4096] = [
     swi, swi, swi, swi,
     swi, swi, swi, swi,
 ];
+
+#[cfg(test)]
+mod tests {
+    use cpu::{Cpu, ArchVersion, StepError};
+    use memory::{Byte, Word};
+    use test_util;
+
+    /// Build a data processing instruction with a shifted register
+    /// operand
+    fn data_processing(opcode: u32,
+                       s: bool,
+                       rd: u32,
+                       rn: u32,
+                       shifter: u32) -> u32 {
+        0xe0000000 | (opcode << 21) | ((s as u32) << 20) |
+        (rn << 16) | (rd << 12) | shifter
+    }
+
+    /// Shifter operand `rm` shifted by the immediate `amount`
+    fn shift_imm(shift: u32, amount: u32, rm: u32) -> u32 {
+        (amount << 7) | (shift << 5) | rm
+    }
+
+    /// Shifter operand `rm` shifted by the value of `rs`
+    fn shift_reg(shift: u32, rs: u32, rm: u32) -> u32 {
+        (rs << 8) | (shift << 5) | (1 << 4) | rm
+    }
+
+    const ADC: u32 = 0b0101;
+    const SBC: u32 = 0b0110;
+    const RSC: u32 = 0b0111;
+
+    /// Reference implementation of `op` using wide integers, returns
+    /// the result and the N, Z, C and V flags
+    fn reference(op: u32,
+                 a: u32,
+                 b: u32,
+                 c: bool) -> (u32, bool, bool, bool, bool) {
+        let (a, b) = if op == RSC { (b, a) } else { (a, b) };
+
+        let (wide, signed) =
+            if op == ADC {
+                (a as i64 + b as i64 + c as i64,
+                 a as i32 as i64 + b as i32 as i64 + c as i64)
+            } else {
+                (a as i64 - b as i64 - (!c) as i64,
+                 a as i32 as i64 - b as i32 as i64 - (!c) as i64)
+            };
+
+        let val = wide as u32;
+
+        let carry =
+            if op == ADC {
+                wide > 0xffffffff
+            } else {
+                wide >= 0
+            };
+
+        (val,
+         (val as i32) < 0,
+         val == 0,
+         carry,
+         signed != signed as i32 as i64)
+    }
+
+    /// Run `op` with the flags set on every pair of 8bit operands
+    /// placed in the top byte of the registers so that all the flags
+    /// are exercised. The second operand goes through a register
+    /// shift to test that variant of the addressing mode as well.
+    #[test]
+    fn carry_ops_flags_exhaustive() {
+        let mut cpu = test_util::arm_cpu(&[]);
+
+        for &op in &[ADC, SBC, RSC] {
+            // OP{S} R0, R1, R2, LSL R3
+            let instruction =
+                data_processing(op, true, 0, 1, shift_reg(0, 3, 2));
+
+            cpu.set_register(3, 24);
+
+            for a in 0..0x100u32 {
+                for b in 0..0x100u32 {
+                    for &c in &[false, true] {
+                        let a = a << 24;
+
+                        cpu.set_register(1, a);
+                        cpu.set_register(2, b);
+                        cpu.set_c(c);
+
+                        cpu.execute_raw(&mut (), instruction);
+
+                        let (val, n, z, c_out, v) =
+                            reference(op, a, b << 24, c);
+
+                        assert_eq!((cpu.registers()[0],
+                                    cpu.n(), cpu.z(), cpu.c(), cpu.v()),
+                                   (val, n, z, c_out, v),
+                                   "op {} 0x{:08x} 0x{:08x} c {}",
+                                   op, a, b << 24, c);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Every shifter operand variant of ADC, SBC and RSC (with and
+    /// without the S bit) must be decoded
+    #[test]
+    fn carry_ops_all_shifter_operands() {
+        let mut cpu = test_util::arm_cpu(&[]);
+
+        let rm = 0x82345678u32;
+
+        // (shift type, amount, expected operand). Odd amounts set
+        // bit 7 of the instruction which selects a different row of
+        // the decoding table.
+        let shifts = [(0, 4, rm << 4),
+                      (0, 5, rm << 5),
+                      (1, 4, rm >> 4),
+                      (1, 5, rm >> 5),
+                      (2, 4, ((rm as i32) >> 4) as u32),
+                      (2, 5, ((rm as i32) >> 5) as u32),
+                      (3, 4, rm.rotate_right(4)),
+                      (3, 5, rm.rotate_right(5))];
+
+        for &op in &[ADC, SBC, RSC] {
+            for &s in &[false, true] {
+                for &(shift, amount, operand) in &shifts {
+                    let mut instructions =
+                        vec![data_processing(op, s, 0, 1,
+                                             shift_imm(shift, amount, 2))];
+
+                    // Register shifts only have an even variant
+                    if amount % 2 == 0 {
+                        instructions.push(
+                            data_processing(op, s, 0, 1,
+                                            shift_reg(shift, 3, 2)));
+                    }
+
+                    for &instruction in &instructions {
+                        let a = 0x10000000;
+
+                        cpu.set_register(1, a);
+                        cpu.set_register(2, rm);
+                        cpu.set_register(3, amount);
+                        cpu.set_c(true);
+
+                        cpu.execute_raw(&mut (), instruction);
+
+                        let (expected, _, _, _, _) =
+                            reference(op, a, operand, true);
+
+                        assert_eq!(cpu.registers()[0], expected,
+                                   "instruction 0x{:08x}", instruction);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Build a halfword multiply from the ARMv5TE DSP extensions.
+    /// `op` is the opcode in bits [22:21], `rd` and `rn` are the
+    /// register fields in bits [19:16] and [15:12] respectively.
+    fn dsp_multiply(op: u32,
+                    rd: u32,
+                    rn: u32,
+                    rs: u32,
+                    rm: u32,
+                    x: bool,
+                    y: bool) -> u32 {
+        0xe1000080 | (op << 21) | (rd << 16) | (rn << 12) | (rs << 8) |
+        ((y as u32) << 6) | ((x as u32) << 5) | rm
+    }
+
+    const SMLA: u32 = 0b00;
+    const SMLAL: u32 = 0b10;
+    const SMUL: u32 = 0b11;
+
+    fn dsp_cpu() -> Cpu {
+        let mut cpu = test_util::arm_cpu(&[]);
+
+        cpu.set_arch_version(ArchVersion::V5TE);
+
+        // Top halfword 3, bottom halfword -2
+        cpu.set_register(1, 0x0003fffe);
+        // Top halfword -7, bottom halfword 5
+        cpu.set_register(2, 0xfff90005);
+
+        cpu
+    }
+
+    #[test]
+    fn smul_halfwords() {
+        let mut cpu = dsp_cpu();
+
+        let expected = [(false, false, -2 * 5),
+                        (true, false, 3 * 5),
+                        (false, true, -2 * -7),
+                        (true, true, 3 * -7)];
+
+        for &(x, y, product) in &expected {
+            // SMUL<x><y> R0, R1, R2
+            cpu.execute_raw(&mut (), dsp_multiply(SMUL, 0, 0, 2, 1, x, y));
+
+            assert_eq!(cpu.registers()[0] as i32, product,
+                       "x: {} y: {}", x, y);
+        }
+    }
+
+    #[test]
+    fn smla_accumulate() {
+        let mut cpu = dsp_cpu();
+
+        cpu.set_register(3, 100);
+
+        // SMLATB R0, R1, R2, R3
+        cpu.execute_raw(&mut (), dsp_multiply(SMLA, 0, 3, 2, 1, true, false));
+
+        assert_eq!(cpu.registers()[0], 115);
+        assert_eq!(cpu.cpsr() & (1 << 27), 0);
+
+        // SMLABT R0, R1, R2, R3
+        cpu.execute_raw(&mut (), dsp_multiply(SMLA, 0, 3, 2, 1, false, true));
+
+        assert_eq!(cpu.registers()[0], 114);
+        assert_eq!(cpu.cpsr() & (1 << 27), 0);
+    }
+
+    #[test]
+    fn smla_saturation_sets_q() {
+        let mut cpu = dsp_cpu();
+
+        // -32768 * -32768 = 0x40000000, twice that overflows
+        cpu.set_register(1, 0x8000);
+        cpu.set_register(2, 0x8000);
+        cpu.set_register(3, 0x40000000);
+
+        // SMLABB R0, R1, R2, R3
+        cpu.execute_raw(&mut (), dsp_multiply(SMLA, 0, 3, 2, 1, false, false));
+
+        // The result wraps around, only the Q flag tells the overflow
+        assert_eq!(cpu.registers()[0], 0x80000000);
+        assert!(cpu.cpsr() & (1 << 27) != 0);
+
+        // Q is sticky
+        cpu.set_register(3, 0);
+
+        cpu.execute_raw(&mut (), dsp_multiply(SMLA, 0, 3, 2, 1, false, false));
+
+        assert_eq!(cpu.registers()[0], 0x40000000);
+        assert!(cpu.cpsr() & (1 << 27) != 0);
+    }
+
+    #[test]
+    fn smlal_accumulate() {
+        let mut cpu = dsp_cpu();
+
+        cpu.set_register(3, 0xffffffff);
+        cpu.set_register(4, 0);
+
+        // SMLALTT R3, R4, R1, R2: RdLo is R3, RdHi is R4
+        cpu.execute_raw(&mut (), dsp_multiply(SMLAL, 4, 3, 2, 1, true, true));
+
+        // 0xffffffff - 21 doesn't carry into RdHi
+        assert_eq!(cpu.registers()[3], 0xffffffff - 21);
+        assert_eq!(cpu.registers()[4], 0);
+        assert_eq!(cpu.cpsr() & (1 << 27), 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "Unimplemented instruction")]
+    fn dsp_multiply_undefined_on_v4t() {
+        let mut cpu = test_util::arm_cpu(&[]);
+
+        cpu.execute_raw(&mut (), dsp_multiply(SMUL, 0, 0, 2, 1, false, false));
+    }
+
+    /// Flag-setting logical operations with a register-specified
+    /// shift use the shifter carry out
+    #[test]
+    fn logical_register_shift_carry() {
+        const MOV: u32 = 0b1101;
+
+        let rm = 0x80000001;
+
+        // (shift type, amount, result, carry out). `None` means that
+        // the carry is left unchanged.
+        let cases = [(0, 0, rm, None),
+                     (0, 1, 0x00000002, Some(true)),
+                     (0, 31, 0x80000000, Some(false)),
+                     (0, 32, 0, Some(true)),
+                     (0, 33, 0, Some(false)),
+                     // Only the low byte of Rs is used
+                     (0, 0x100, rm, None),
+                     (1, 0, rm, None),
+                     (1, 1, 0x40000000, Some(true)),
+                     (1, 31, 0x00000001, Some(false)),
+                     (1, 32, 0, Some(true)),
+                     (1, 33, 0, Some(false)),
+                     (2, 0, rm, None),
+                     (2, 1, 0xc0000000, Some(true)),
+                     (2, 31, 0xffffffff, Some(false)),
+                     (2, 32, 0xffffffff, Some(true)),
+                     (2, 200, 0xffffffff, Some(true))];
+
+        let mut cpu = test_util::arm_cpu(&[]);
+
+        for &(shift, amount, result, carry) in &cases {
+            for &c in &[false, true] {
+                cpu.set_register(2, rm);
+                cpu.set_register(3, amount);
+                cpu.set_c(c);
+
+                cpu.execute_raw(&mut (),
+                                data_processing(MOV, true, 0, 0,
+                                                shift_reg(shift, 3, 2)));
+
+                assert_eq!(cpu.registers()[0], result);
+                assert_eq!(cpu.c(), carry.unwrap_or(c));
+            }
+        }
+    }
+
+    #[test]
+    fn ror_register() {
+        const MOV: u32 = 0b1101;
+
+        // (Rm, amount, result, carry out). `None` means that the
+        // carry is left unchanged.
+        let cases = [(0x80000003, 0, 0x80000003, None),
+                     (0x80000003, 1, 0xc0000001, Some(true)),
+                     (0x80000002, 1, 0x40000001, Some(false)),
+                     (0x80000003, 31, 0x00000007, Some(false)),
+                     (0x40000003, 31, 0x80000006, Some(true)),
+                     // Multiples of 32 leave the value unchanged and
+                     // copy bit 31 to the carry
+                     (0x80000003, 32, 0x80000003, Some(true)),
+                     (0x00000003, 32, 0x00000003, Some(false)),
+                     (0x80000003, 64, 0x80000003, Some(true)),
+                     (0x00000003, 64, 0x00000003, Some(false)),
+                     // Only the low byte of Rs is used
+                     (0x80000003, 0x101, 0xc0000001, Some(true))];
+
+        let mut cpu = test_util::arm_cpu(&[]);
+
+        for &(rm, amount, result, carry) in &cases {
+            for &s in &[false, true] {
+                for &c in &[false, true] {
+                    cpu.set_register(2, rm);
+                    cpu.set_register(3, amount);
+                    cpu.set_c(c);
+
+                    let op = shift_reg(3, 3, 2);
+
+                    cpu.execute_raw(&mut (),
+                                    data_processing(MOV, s, 0, 0, op));
+
+                    // `value` and `value_carry` must agree
+                    assert_eq!(cpu.registers()[0], result);
+
+                    let carry = if s { carry.unwrap_or(c) } else { c };
+
+                    assert_eq!(cpu.c(), carry);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn store_writeback_rd_rn() {
+        // (instruction, address written, size in bytes, new R0)
+        let cases = [
+            // STR R0, [R0], #4
+            (0xe4800004, 0x100, 4, 0x104),
+            // STR R0, [R0, #4]!
+            (0xe5a00004, 0x104, 4, 0x104),
+            // STRB R0, [R0], #1
+            (0xe4c00001, 0x100, 1, 0x101),
+            // STRB R0, [R0, #3]!
+            (0xe5e00003, 0x103, 1, 0x103),
+            // STR R0, [R0, R1, LSL #2]!
+            (0xe7a00101, 0x108, 4, 0x108),
+            // STRB R0, [R0, R1]!
+            (0xe7e00001, 0x102, 1, 0x102),
+        ];
+
+        for &(instruction, addr, size, r0) in &cases {
+            let mut cpu = test_util::arm_cpu(&[instruction]);
+
+            cpu.set_register(0, 0x100);
+            cpu.set_register(1, 2);
+
+            cpu.try_step(&mut ()).unwrap();
+
+            let mask = if size == 4 { !0 } else { 0xff };
+            let stored =
+                if size == 4 {
+                    cpu.interconnect().peek::<Word>(addr)
+                } else {
+                    cpu.interconnect().peek::<Byte>(addr)
+                };
+
+            // The original base is stored, then written back
+            assert_eq!(stored, 0x100 & mask);
+            assert_eq!(cpu.registers()[0], r0);
+        }
+    }
+
+    #[test]
+    fn load_writeback_rd_rn() {
+        // LDR R0, [R0], #4; LDR R0, [R0, #4]!; LDRB R0, [R0, R1]!
+        for &instruction in &[0xe4900004, 0xe5b00004, 0xe7f00001] {
+            let mut cpu = test_util::arm_cpu(&[instruction]);
+
+            cpu.set_register(0, 0x100);
+            cpu.set_register(1, 2);
+
+            match cpu.try_step(&mut ()) {
+                Err(StepError::UndefinedInstruction(_)) => (),
+                r => panic!("Unexpected result {:?}", r),
+            }
+        }
+    }
+
+    #[test]
+    fn unimplemented_message() {
+        // STRB R0, [R0, #-1]!
+        let mut cpu = test_util::arm_cpu(&[0xe5600001]);
+
+        match cpu.try_step(&mut ()) {
+            Err(StepError::UnimplementedInstruction(msg)) =>
+                assert_eq!(msg, "0xe5600001 (560, \
+                                 load/store (immediate offset))"),
+            r => panic!("Unexpected result {:?}", r),
+        }
+    }
+
+    #[test]
+    fn mov_mvn_shifts() {
+        const MOV: u32 = 0b1101;
+        const MVN: u32 = 0b1111;
+
+        let rm: u32 = 0x8000001c;
+        let rs = 4;
+
+        let asr = ((rm as i32) >> 4) as u32;
+        let ror4 = rm.rotate_right(4);
+        let ror9 = rm.rotate_right(9);
+
+        // (opcode, S, shifter operand, result, carry out). The carry
+        // is only updated by MOVS.
+        let cases = [(MOV, false, shift_imm(3, 9, 2), ror9, None),
+                     (MOV, true, shift_reg(2, 3, 2), asr, Some(true)),
+                     (MOV, true, shift_reg(3, 3, 2), ror4, Some(true)),
+                     (MOV, true, shift_imm(3, 9, 2), ror9, Some(false)),
+                     (MVN, false, shift_reg(2, 3, 2), !asr, None),
+                     (MVN, false, shift_reg(3, 3, 2), !ror4, None),
+                     (MVN, false, shift_imm(3, 9, 2), !ror9, None)];
+
+        let mut cpu = test_util::arm_cpu(&[]);
+
+        for &(opcode, s, shifter, result, carry) in &cases {
+            for &c in &[false, true] {
+                cpu.set_register(2, rm);
+                cpu.set_register(3, rs);
+                cpu.set_c(c);
+
+                cpu.execute_raw(&mut (),
+                                data_processing(opcode, s, 0, 0, shifter));
+
+                assert_eq!(cpu.registers()[0], result);
+                assert_eq!(cpu.c(), carry.unwrap_or(c));
+            }
+        }
+    }
+
+    /// Return `true` if the CPU is in Thumb state
+    fn thumb(cpu: &Cpu) -> bool {
+        cpu.cpsr() & (1 << 5) != 0
+    }
+
+    /// Build a V5TE CPU running `program` in ARM state followed by a
+    /// Thumb routine at 0x20 setting R0 and returning through
+    /// `BX LR`
+    fn interworking_cpu(program: &[u32]) -> Cpu {
+        let mut words = program.to_vec();
+
+        words.resize(8, 0);
+
+        // 0x20: MOV R0, #0x42; ADD R0, #1
+        words.push(0x3001_2042);
+        // 0x24: BX LR
+        words.push(0x0000_4770);
+
+        let mut cpu = test_util::arm_cpu(&words);
+
+        cpu.set_arch_version(ArchVersion::V5TE);
+        cpu.set_register(0, 0);
+
+        cpu
+    }
+
+    #[test]
+    fn blx_imm_interworking() {
+        // H clear then H set: the same word offset targets both
+        // halfwords of the word at 0x20. Entering at 0x22 skips the
+        // MOV.
+        for &(h, target, steps, r0) in &[(0, 0x20, 3, 0x43),
+                                         (1, 0x22, 2, 0x01)] {
+            let mut cpu = interworking_cpu(&[
+                // BLX 0x20 (+ 2 * H)
+                0xfa000006 | (h << 24),
+                // MOV R1, #1
+                0xe3a01001,
+            ]);
+
+            cpu.run_next_instruction(&mut ());
+
+            assert!(thumb(&cpu));
+            assert_eq!(cpu.registers()[15], target + 2);
+            // Return address in ARM state
+            assert_eq!(cpu.registers()[14], 0x04);
+
+            test_util::run(&mut cpu, steps);
+
+            assert!(!thumb(&cpu));
+            assert_eq!(cpu.registers()[0], r0);
+            assert_eq!(cpu.registers()[15], 0x04 + 4);
+
+            test_util::run(&mut cpu, 1);
+
+            assert_eq!(cpu.registers()[1], 1);
+        }
+    }
+
+    #[test]
+    fn blx_reg_interworking() {
+        let mut cpu = interworking_cpu(&[
+            // MOV R2, #0x21
+            0xe3a02021,
+            // BLX R2
+            0xe12fff32,
+            // MOV R1, #1
+            0xe3a01001,
+        ]);
+
+        test_util::run(&mut cpu, 2);
+
+        // Bit 0 of the target selects the Thumb state
+        assert!(thumb(&cpu));
+        assert_eq!(cpu.registers()[15], 0x20 + 2);
+        assert_eq!(cpu.registers()[14], 0x08);
+
+        test_util::run(&mut cpu, 3);
+
+        assert!(!thumb(&cpu));
+        assert_eq!(cpu.registers()[0], 0x43);
+        assert_eq!(cpu.registers()[15], 0x08 + 4);
+
+        test_util::run(&mut cpu, 1);
+
+        assert_eq!(cpu.registers()[1], 1);
+    }
+
+    #[test]
+    fn blx_reg_to_arm() {
+        let mut cpu = interworking_cpu(&[
+            // MOV R2, #0x10
+            0xe3a02010,
+            // BLX R2
+            0xe12fff32,
+        ]);
+
+        test_util::run(&mut cpu, 2);
+
+        // Bit 0 clear: stay in ARM state
+        assert!(!thumb(&cpu));
+        assert_eq!(cpu.registers()[15], 0x10 + 4);
+        assert_eq!(cpu.registers()[14], 0x08);
+    }
+
+    #[test]
+    fn blx_reg_lr() {
+        let mut cpu = interworking_cpu(&[
+            // MOV LR, #0x25
+            0xe3a0e025,
+            // BLX LR
+            0xe12fff3e,
+        ]);
+
+        test_util::run(&mut cpu, 2);
+
+        // The target is read before LR is overwritten
+        assert!(thumb(&cpu));
+        assert_eq!(cpu.registers()[15], 0x24 + 2);
+        assert_eq!(cpu.registers()[14], 0x08);
+    }
+
+    #[test]
+    #[should_panic(expected = "Unimplemented instruction")]
+    fn blx_reg_undefined_on_v4t() {
+        let mut cpu = test_util::arm_cpu(&[]);
+
+        cpu.execute_raw(&mut (), 0xe12fff32);
+    }
+
+    #[test]
+    fn sbz_fields() {
+        const TST: u32 = 0b1000;
+        const TEQ: u32 = 0b1001;
+        const CMP: u32 = 0b1010;
+        const CMN: u32 = 0b1011;
+        const MOV: u32 = 0b1101;
+        const MVN: u32 = 0b1111;
+
+        // R1 or #0xf0, depending on what the LUT implements
+        let reg = shift_imm(0, 0, 1);
+        let imm = (1 << 25) | 0xf0;
+
+        // (opcode, shifter operand, NZCV). The SBZ field is Rd for the
+        // comparisons and Rn for the moves. TST and TEQ leave V alone
+        // (set on reset).
+        let compares = [(TST, reg, 0b0001),
+                        (TEQ, reg, 0b0101),
+                        (CMP, reg, 0b0110),
+                        (CMN, imm, 0b0000)];
+
+        for &(opcode, shifter, nzcv) in &compares {
+            let instruction = data_processing(opcode, true, 5, 0, shifter);
+
+            let mut cpu = test_util::arm_cpu(&[instruction]);
+
+            cpu.set_register(0, 0xf0);
+            cpu.set_register(1, 0xf0);
+            cpu.set_register(5, 0x12345678);
+            cpu.set_c(false);
+
+            assert_eq!(cpu.try_step(&mut ()), Ok(1));
+
+            // R5 is left untouched
+            assert_eq!(cpu.registers()[5], 0x12345678);
+            assert_eq!(cpu.cpsr() >> 28, nzcv, "opcode {:04b}", opcode);
+        }
+
+        for &(opcode, result) in &[(MOV, 0xf00d), (MVN, !0xf00d)] {
+            let instruction =
+                data_processing(opcode, false, 2, 7, shift_imm(0, 0, 1));
+
+            let mut cpu = test_util::arm_cpu(&[instruction]);
+
+            cpu.set_register(1, 0x0000f00d);
+            cpu.set_register(7, 0x12345678);
+
+            assert_eq!(cpu.try_step(&mut ()), Ok(1));
+
+            assert_eq!(cpu.registers()[2], result);
+            assert_eq!(cpu.registers()[7], 0x12345678);
+        }
+    }
+
+    #[test]
+    fn v_flag() {
+        const SUB: u32 = 0b0010;
+        const ADD: u32 = 0b0100;
+        const CMP: u32 = 0b1010;
+        const CMN: u32 = 0b1011;
+
+        let pairs = [(0x7fffffff, 0x00000001),
+                     (0x80000000, 0x00000001),
+                     (0x80000000, 0xffffffff),
+                     (0x7fffffff, 0xffffffff),
+                     (0x80000000, 0x80000000),
+                     (0x7fffffff, 0x7fffffff),
+                     (0x40000000, 0x40000000),
+                     (0xffffffff, 0xffffffff),
+                     (0xffffffff, 0x7fffffff),
+                     (0x00000000, 0x80000000),
+                     (0x00000001, 0x80000000),
+                     (0x00000000, 0x00000000)];
+
+        // (opcode, writes Rd, reference operation). Without a carry
+        // in ADC is ADD and SBC is SUB.
+        let ops = [(ADD, true, ADC),
+                   (CMN, false, ADC),
+                   (SUB, true, SBC),
+                   (CMP, false, SBC)];
+
+        let mut cpu = test_util::arm_cpu(&[]);
+
+        for &(opcode, writes, op) in &ops {
+            for &(a, b) in &pairs {
+                let (val, n, z, c, v) = reference(op, a, b, op == SBC);
+
+                cpu.set_register(0, a);
+                cpu.set_register(1, b);
+                cpu.set_register(2, 0xdeadbeef);
+
+                // Rd is SBZ for the comparisons
+                let rd = if writes { 2 } else { 0 };
+
+                cpu.execute_raw(&mut (),
+                                data_processing(opcode, true, rd, 0,
+                                                shift_imm(0, 0, 1)));
+
+                let expected = if writes { val } else { 0xdeadbeef };
+                let nzcv = ((n as u32) << 3) | ((z as u32) << 2) |
+                           ((c as u32) << 1) | v as u32;
+
+                assert_eq!(cpu.registers()[2], expected);
+                assert_eq!(cpu.cpsr() >> 28, nzcv,
+                           "{:04b} 0x{:08x} 0x{:08x}", opcode, a, b);
+            }
+        }
+    }
+
+    #[test]
+    fn cmn_shifter_operands() {
+        const CMN: u32 = 0b1011;
+
+        let rm = 0x80000001;
+
+        // (shifter operand, operand value). The register shifts use
+        // R3 as the shift amount.
+        let operands = [(shift_imm(0, 1, 1), 0x00000002),
+                        (shift_imm(1, 1, 1), 0x40000000),
+                        (shift_imm(2, 1, 1), 0xc0000000),
+                        (shift_imm(3, 1, 1), 0xc0000000),
+                        (shift_reg(0, 3, 1), 0x00000002),
+                        (shift_reg(1, 3, 1), 0x40000000),
+                        (shift_reg(2, 3, 1), 0xc0000000),
+                        (shift_reg(3, 3, 1), 0xc0000000)];
+
+        let mut cpu = test_util::arm_cpu(&[]);
+
+        for &(shifter, b) in &operands {
+            for &a in &[0x00000000, 0x40000000, 0x7fffffff, 0xc0000000] {
+                let (_, n, z, c, v) = reference(ADC, a, b, false);
+
+                cpu.set_register(0, a);
+                cpu.set_register(1, rm);
+                cpu.set_register(3, 1);
+
+                cpu.execute_raw(&mut (),
+                                data_processing(CMN, true, 0, 0, shifter));
+
+                let nzcv = ((n as u32) << 3) | ((z as u32) << 2) |
+                           ((c as u32) << 1) | v as u32;
+
+                assert_eq!(cpu.cpsr() >> 28, nzcv,
+                           "0x{:03x} 0x{:08x}", shifter, a);
+            }
+        }
+    }
+
+    #[test]
+    fn load_pc_interworking() {
+        // LDR PC, [R0] and LDMIA R0, {PC}
+        for &instruction in &[0xe590f000, 0xe8908000] {
+            for &(arch, target, pc, t) in
+                &[(ArchVersion::V4T, 0x101, 0x100, false),
+                  (ArchVersion::V4T, 0x103, 0x100, false),
+                  (ArchVersion::V5TE, 0x101, 0x100, true),
+                  (ArchVersion::V5TE, 0x103, 0x102, true),
+                  (ArchVersion::V5TE, 0x104, 0x104, false)] {
+                let mut cpu = test_util::arm_cpu(&[instruction]);
+
+                cpu.set_arch_version(arch);
+                cpu.set_register(0, 0x40);
+                cpu.interconnect_mut().store::<Word>(0x40, target);
+
+                test_util::run(&mut cpu, 1);
+
+                assert_eq!(cpu.next_pc, pc,
+                           "{:08x} {:?} {:x}", instruction, arch, target);
+                assert_eq!(thumb(&cpu), t);
+            }
+        }
+    }
+
+    #[test]
+    fn bx_pc() {
+        // MOV R0, R0 x4 then BX PC at 0x10
+        let mut cpu = test_util::arm_cpu(&[0xe1a00000,
+                                           0xe1a00000,
+                                           0xe1a00000,
+                                           0xe1a00000,
+                                           0xe12fff1f]);
+
+        test_util::run(&mut cpu, 5);
+
+        // PC + 8, bit 0 is clear so we stay in ARM state
+        assert_eq!(cpu.next_pc, 0x18);
+        assert!(!thumb(&cpu));
+    }
+
+    #[test]
+    fn ands_eors_shifts() {
+        const AND: u32 = 0b0000;
+        const EOR: u32 = 0b0001;
+
+        const LSL: u32 = 0;
+        const LSR: u32 = 1;
+        const ASR: u32 = 2;
+        const ROR: u32 = 3;
+
+        let rn = 0xf000000f;
+        let rm = 0x80000001;
+
+        // (opcode, shifter operand, R3, result, carry out). The carry
+        // is unchanged when the shift amount is 0.
+        let cases =
+            [(AND, shift_reg(LSL, 3, 2), 0, 0x80000001, None),
+             (AND, shift_reg(LSL, 3, 2), 1, 0x00000002, Some(true)),
+             (AND, shift_reg(LSL, 3, 2), 0x101, 0x00000002, Some(true)),
+             (AND, shift_reg(LSL, 3, 2), 32, 0, Some(true)),
+             (AND, shift_reg(LSL, 3, 2), 33, 0, Some(false)),
+             (AND, shift_reg(LSR, 3, 2), 1, 0x40000000, Some(true)),
+             (AND, shift_reg(LSR, 3, 2), 32, 0, Some(true)),
+             (AND, shift_reg(ASR, 3, 2), 4, 0xf0000000, Some(false)),
+             (AND, shift_reg(ASR, 3, 2), 40, 0xf000000f, Some(true)),
+             (AND, shift_reg(ROR, 3, 2), 1, 0xc0000000, Some(true)),
+             (AND, shift_reg(ROR, 3, 2), 32, 0x80000001, Some(true)),
+             (AND, shift_imm(LSL, 4, 2), 0, 0, Some(false)),
+             (AND, shift_imm(ROR, 1, 2), 0, 0xc0000000, Some(true)),
+             (EOR, shift_reg(LSL, 3, 2), 1, 0xf000000d, Some(true)),
+             (EOR, shift_reg(LSR, 3, 2), 31, 0xf000000e, Some(false)),
+             (EOR, shift_reg(ASR, 3, 2), 40, 0x0ffffff0, Some(true)),
+             (EOR, shift_reg(ROR, 3, 2), 4, 0xe800000f, Some(false)),
+             (EOR, shift_imm(LSR, 4, 2), 0, 0xf800000f, Some(false)),
+             (EOR, shift_imm(ASR, 1, 2), 0, 0x3000000f, Some(true))];
+
+        let mut cpu = test_util::arm_cpu(&[]);
+
+        for &(opcode, shifter, rs, result, carry) in &cases {
+            for &c in &[false, true] {
+                cpu.set_register(1, rn);
+                cpu.set_register(2, rm);
+                cpu.set_register(3, rs);
+                cpu.set_c(c);
+
+                cpu.execute_raw(&mut (),
+                                data_processing(opcode, true, 0, 1, shifter));
+
+                let nzc = cpu.cpsr() >> 29;
+
+                let expected =
+                    ((result >> 31) << 2) |
+                    (((result == 0) as u32) << 1) |
+                    carry.unwrap_or(c) as u32;
+
+                assert_eq!(cpu.registers()[0], result);
+                assert_eq!(nzc, expected,
+                           "{:x} {:08x} R3={}", opcode, shifter, rs);
+            }
+        }
+    }
+
+    #[test]
+    fn branch_offsets() {
+        // (instruction address, imm24)
+        let cases: &[(u32, u32)] = &[
+            // B .
+            (0x02000100, 0xfffffe),
+            // Short forward and backward branches
+            (0x02000100, 0x000000),
+            (0x02000100, 0x000010),
+            (0x02000100, 0xfffff0),
+            // The +/-32MB extremes
+            (0x02000000, 0x7fffff),
+            (0x04000000, 0x800000),
+            // Wraps around the address space
+            (0x00001000, 0x800000),
+        ];
+
+        for &(addr, imm24) in cases {
+            let offset = (((imm24 << 8) as i32) >> 6) as u32;
+            let target = addr.wrapping_add(8).wrapping_add(offset);
+
+            for &bl in &[false, true] {
+                let mut cpu = test_util::arm_cpu(&[]);
+
+                cpu.set_register(14, 0xdeadbeef);
+                cpu.set_pc(addr);
+
+                let op = 0xea000000 | ((bl as u32) << 24) | imm24;
+
+                cpu.execute_raw(&mut (), op);
+
+                assert_eq!(cpu.next_pc, target,
+                           "{:08x} at {:08x}", op, addr);
+
+                let lr = if bl { addr + 4 } else { 0xdeadbeef };
+
+                assert_eq!(cpu.registers()[14], lr,
+                           "{:08x} at {:08x}", op, addr);
+            }
+        }
+    }
+    /// Bytes and halfwords with and without their sign bit set, at
+    /// 0x100 in RAM
+    fn extension_data(cpu: &mut Cpu) {
+        // Bytes 0x7f, 0x00, 0x80, 0xff
+        cpu.interconnect_mut().store::<Word>(0x100, 0xff80007f);
+        // Halfwords 0x7fff, 0x8000
+        cpu.interconnect_mut().store::<Word>(0x104, 0x80007fff);
+        // Halfwords 0xffff, 0x0000
+        cpu.interconnect_mut().store::<Word>(0x108, 0x0000ffff);
+    }
+
+    #[test]
+    fn load_extension() {
+        const LDRB: u32 = 0xe5d10000;
+        const LDRH: u32 = 0xe1d100b0;
+        const LDRSB: u32 = 0xe1d100d0;
+        const LDRSH: u32 = 0xe1d100f0;
+
+        // (opcode, offset, R0)
+        let cases = [
+            (LDRSB, 0x0, 0x0000007f),
+            (LDRSB, 0x1, 0x00000000),
+            (LDRSB, 0x2, 0xffffff80),
+            (LDRSB, 0x3, 0xffffffff),
+            (LDRB,  0x0, 0x0000007f),
+            (LDRB,  0x2, 0x00000080),
+            (LDRB,  0x3, 0x000000ff),
+            (LDRSH, 0x4, 0x00007fff),
+            (LDRSH, 0x6, 0xffff8000),
+            (LDRSH, 0x8, 0xffffffff),
+            (LDRSH, 0xa, 0x00000000),
+            (LDRH,  0x4, 0x00007fff),
+            (LDRH,  0x6, 0x00008000),
+            (LDRH,  0x8, 0x0000ffff),
+        ];
+
+        for &(op, offset, expected) in &cases {
+            let mut cpu = test_util::arm_cpu(&[]);
+
+            extension_data(&mut cpu);
+
+            cpu.set_register(0, 0x12345678);
+            cpu.set_register(1, 0x100);
+
+            // LDRB takes a 12bit offset, the halfword and signed
+            // loads split it in two nibbles
+            let instruction =
+                if op == LDRB {
+                    op | offset
+                } else {
+                    op | ((offset & 0xf0) << 4) | (offset & 0xf)
+                };
+
+            cpu.execute_raw(&mut (), instruction);
+
+            assert_eq!(cpu.registers()[0], expected,
+                       "{:08x}", instruction);
+        }
+    }
+
+    #[test]
+    fn msr_spsr() {
+        let mut cpu = test_util::arm_cpu(&[
+            // MSR SPSR_f, R0
+            0xe168f000,
+            // MSR SPSR_c, R0
+            0xe161f000,
+            // MSR SPSR_fsxc, R1
+            0xe16ff001,
+        ]);
+
+        cpu.set_register(0, 0x900000b3);
+        cpu.set_register(1, 0x12345678);
+
+        cpu.spsr = 0x000000d3;
+
+        test_util::run(&mut cpu, 1);
+        assert_eq!(cpu.spsr, 0x900000d3);
+
+        // The T bit can be set in the SPSR
+        test_util::run(&mut cpu, 1);
+        assert_eq!(cpu.spsr, 0x900000b3);
+
+        test_util::run(&mut cpu, 1);
+        assert_eq!(cpu.spsr, 0x12345678);
+
+        // There's no SPSR in user mode
+        cpu.fast_boot(0x0);
+
+        match cpu.try_step(&mut ()) {
+            Err(StepError::UndefinedInstruction(_)) => (),
+            r => panic!("Unexpected result {:?}", r),
+        }
+    }
+
+    #[test]
+    fn branch_imm_offset() {
+        // (imm24, byte offset)
+        let cases = [
+            (0x000000, 0),
+            (0x000001, 4),
+            (0x7fffff, 0x1fffffc),
+            (0x800000, -0x2000000),
+            (0x800001, -0x1fffffc),
+            (0xfffffe, -8),
+            (0xffffff, -4),
+        ];
+
+        for &(imm24, offset) in &cases {
+            // The condition and link bit don't matter
+            for &top in &[0xea000000, 0xeb000000, 0x0a000000, 0xfb000000] {
+                let instruction = super::Instruction(top | imm24);
+
+                assert_eq!(instruction.branch_imm_offset(), offset as u32,
+                           "{:08x}", top | imm24);
+            }
+        }
+
+        // B to a "negative" address wraps around
+        let mut cpu = test_util::arm_cpu(&[]);
+
+        // B PC - 0x40
+        cpu.execute_raw(&mut (), 0xeafffff0);
+
+        assert_eq!(cpu.next_pc, 0xffffffc8);
+    }
+
+    #[test]
+    fn ldm_base_in_list() {
+        let mut program = vec![
+            // LDMIA R0, {R0, R1}
+            0xe8900003,
+            // LDMIA R2!, {R0, R1}
+            0xe8b20003,
+            // LDMIA R2, {R1, R2}
+            0xe8920006,
+            // LDMIA R0!, {R0, R1}
+            0xe8b00003,
+        ];
+
+        program.resize(0x40, 0);
+        program.extend_from_slice(&[0x11111111, 0x22222222, 0x33333333]);
+
+        let mut cpu = test_util::arm_cpu(&program);
+
+        // Base in the list without writeback: the base is simply
+        // overwritten by the loaded value
+        cpu.set_register(0, 0x100);
+        test_util::run(&mut cpu, 1);
+        assert_eq!(cpu.registers()[0], 0x11111111);
+        assert_eq!(cpu.registers()[1], 0x22222222);
+
+        // Writeback with the base not in the list
+        cpu.set_register(0, 0);
+        cpu.set_register(1, 0);
+        cpu.set_register(2, 0x100);
+        test_util::run(&mut cpu, 1);
+        assert_eq!(cpu.registers()[0], 0x11111111);
+        assert_eq!(cpu.registers()[1], 0x22222222);
+        assert_eq!(cpu.registers()[2], 0x108);
+
+        // Base last in the list, the addresses are computed from
+        // the original base value
+        cpu.set_register(2, 0x104);
+        test_util::run(&mut cpu, 1);
+        assert_eq!(cpu.registers()[1], 0x22222222);
+        assert_eq!(cpu.registers()[2], 0x33333333);
+
+        // Writeback with the base in the list is unpredictable
+        cpu.set_register(0, 0x100);
+
+        match cpu.try_step(&mut ()) {
+            Err(StepError::UndefinedInstruction(_)) => (),
+            r => panic!("Unexpected result {:?}", r),
+        }
+    }
+
+    #[test]
+    fn ldm_user_writeback() {
+        // LDMIA R0!, {R1}^: user registers with writeback is
+        // unpredictable
+        let mut cpu = test_util::arm_cpu(&[0xe8f00002]);
+
+        cpu.set_register(0, 0x100);
+
+        match cpu.try_step(&mut ()) {
+            Err(StepError::UndefinedInstruction(_)) => (),
+            r => panic!("Unexpected result {:?}", r),
+        }
+
+        assert_eq!(cpu.registers()[0], 0x100);
+    }
+
+    #[test]
+    fn shift_imm_32() {
+        // (instruction, Rm, result, carry)
+        let cases: &[(u32, u32, u32, bool)] = &[
+            // MOVS R0, R1, LSR #32
+            (0xe1b00021, 0x80000000, 0, true),
+            (0xe1b00021, 0x7fffffff, 0, false),
+            // MOVS R0, R1, ASR #32
+            (0xe1b00041, 0x80000000, 0xffffffff, true),
+            (0xe1b00041, 0x7fffffff, 0, false),
+        ];
+
+        for &(instruction, rm, result, carry) in cases {
+            let mut cpu = test_util::arm_cpu(&[instruction]);
+
+            cpu.set_register(1, rm);
+            cpu.set_c(!carry);
+
+            test_util::run(&mut cpu, 1);
+
+            assert_eq!(cpu.registers()[0], result);
+            assert_eq!(cpu.c(), carry);
+            assert_eq!(cpu.z(), result == 0);
+            assert_eq!(cpu.n(), (result as i32) < 0);
+        }
+    }
+
+    #[test]
+    fn carry_ops_imm() {
+        // (instruction, R1, carry in, R0, NZCV). The NZCV of the
+        // non flag-setting variants is the initial value.
+        let cases: &[(u32, u32, bool, u32, u32)] = &[
+            // ADC R0, R1, #0xff
+            (0xe2a100ff, 1, true, 0x101, 0xb),
+            (0xe2a100ff, 1, false, 0x100, 0x9),
+            // ADCS R0, R1, #1
+            (0xe2b10001, 0xffffffff, true, 1, 0x2),
+            (0xe2b10001, 0xfffffffe, true, 0, 0x6),
+            // ADCS R0, R1, #0
+            (0xe2b10000, 0x7fffffff, true, 0x80000000, 0x9),
+            // SBC R0, R1, #1
+            (0xe2c10001, 5, true, 4, 0xb),
+            (0xe2c10001, 5, false, 3, 0x9),
+            // SBCS R0, R1, #1
+            (0xe2d10001, 1, true, 0, 0x6),
+            (0xe2d10001, 0, true, 0xffffffff, 0x8),
+            (0xe2d10001, 0x80000000, true, 0x7fffffff, 0x3),
+            // RSC R0, R1, #0x10
+            (0xe2e10010, 1, true, 0xf, 0xb),
+            (0xe2e10010, 1, false, 0xe, 0x9),
+            // RSCS R0, R1, #0x10
+            (0xe2f10010, 0x10, true, 0, 0x6),
+            (0xe2f10010, 0x10, false, 0xffffffff, 0x8),
+        ];
+
+        for &(instruction, r1, carry, r0, nzcv) in cases {
+            let mut cpu = test_util::arm_cpu(&[instruction]);
+
+            cpu.set_register(1, r1);
+            cpu.set_flags_nibble(if carry { 0xb } else { 0x9 });
+
+            test_util::run(&mut cpu, 1);
+
+            assert_eq!(cpu.registers()[0], r0);
+            assert_eq!(cpu.flags_nibble(), nzcv);
+        }
+
+        // 64bit addition: the carry propagates to the high word
+        let mut cpu = test_util::arm_cpu(&[
+            // ADDS R0, R0, #1
+            0xe2900001,
+            // ADC R1, R1, #0
+            0xe2a11000,
+        ]);
+
+        cpu.set_register(0, 0xffffffff);
+        cpu.set_register(1, 5);
+
+        test_util::run(&mut cpu, 2);
+
+        assert_eq!(cpu.registers()[0], 0);
+        assert_eq!(cpu.registers()[1], 6);
+    }
+
+    #[test]
+    fn condition_false_never_traps() {
+        // MCRNE p15, 0, R0, c1, c0, 0
+        // Undefined, NE condition
+        for &instruction in &[0x1e010f10, 0x17f000f0] {
+            let mut cpu = test_util::arm_cpu(&[instruction]);
+
+            // Condition false: the instruction is skipped
+            cpu.set_z(true);
+
+            assert!(cpu.try_step(&mut ()).is_ok());
+            assert_eq!(cpu.next_pc, 4);
+
+            // Condition true: the instruction traps. We don't emulate
+            // any coprocessor so MCR is reported as unimplemented.
+            cpu.set_pc(0);
+            cpu.set_z(false);
+
+            match cpu.try_step(&mut ()) {
+                Err(StepError::UndefinedInstruction(_)) |
+                Err(StepError::UnimplementedInstruction(_)) => (),
+                r => panic!("Unexpected result {:?}", r),
+            }
+        }
+    }
+
+    #[test]
+    fn handler_names() {
+        let names = super::handler_names();
+
+        // Older versions of `stringify!` put spaces around the
+        // generic parameters
+        let name = |instruction: u32| {
+            let opcode = super::Instruction(instruction).opcode() as usize;
+
+            names[opcode].replace(" ", "")
+        };
+
+        for (opcode, &name) in names.iter().enumerate() {
+            let expected =
+                match opcode {
+                    0xa00...0xaff => Some("b"),
+                    0xb00...0xbff => Some("bl"),
+                    0xf00...0xfff => Some("swi"),
+                    _ => None,
+                };
+
+            match expected {
+                Some(e) => assert_eq!(name, e),
+                None => assert!(name != "b" && name != "bl" && name != "swi",
+                                "{} at 0x{:03x}", name, opcode),
+            }
+        }
+
+        // B 0x18
+        assert_eq!(name(0xea000004), "b");
+        // MSR CPSR_c, R2
+        assert_eq!(name(0xe121f002), "msr_cpsr");
+        // MSR SPSR_f, R0
+        assert_eq!(name(0xe168f000), "msr_spsr");
+        // BX R0
+        assert_eq!(name(0xe12fff10), "bx");
+        // AND R0, R1, R2, LSL #3
+        assert_eq!(name(0xe0010182), "and::<Mode1LslImm>");
+        // ADC R0, R1, #0xff
+        assert_eq!(name(0xe2a100ff), "adc::<Mode1Imm>");
+        // MRC p15, 0, R0, c1, c0, 0
+        assert_eq!(name(0xee110f10), "unimplemented");
+    }
+}
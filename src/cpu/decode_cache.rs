@@ -0,0 +1,129 @@
+//! Cache of recently decoded instructions, indexed by address. This
+//! lets us skip the memory fetch and the opcode decoding in tight
+//! loops.
+//!
+//! Each entry is tagged with the interconnect's code generation for
+//! its address (see `Interconnect::code_generation`) at the time it
+//! was decoded, so the entries are ignored as soon as the memory is
+//! modified, whether by the guest or the frontend.
+
+use rustc_serialize::{Decodable, Encodable, Decoder, Encoder};
+
+use super::armv4_is;
+use super::thumbv1_is;
+
+pub struct DecodeCache {
+    /// The cache is disabled by default
+    enabled: bool,
+    entries: Box<[Entry]>,
+}
+
+impl DecodeCache {
+    pub fn new() -> DecodeCache {
+        DecodeCache {
+            enabled: false,
+            entries: vec![Entry::invalid(); CACHE_SIZE].into_boxed_slice(),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+
+        self.flush();
+    }
+
+    /// Return the cached instruction at `addr` if any. `generation`
+    /// is the current code generation of `addr`.
+    pub fn get(&self,
+               addr: u32,
+               thumb: bool,
+               generation: u32) -> Option<Decoded> {
+        if !self.enabled {
+            return None;
+        }
+
+        let entry = &self.entries[index(addr)];
+
+        if entry.addr != addr || entry.generation != generation {
+            return None;
+        }
+
+        match entry.decoded {
+            Some(d @ Decoded::Arm(_, _)) if !thumb => Some(d),
+            Some(d @ Decoded::Thumb(_, _)) if thumb => Some(d),
+            _ => None,
+        }
+    }
+
+    pub fn insert(&mut self, addr: u32, generation: u32, decoded: Decoded) {
+        if self.enabled {
+            self.entries[index(addr)] = Entry {
+                addr: addr,
+                generation: generation,
+                decoded: Some(decoded),
+            };
+        }
+    }
+
+    /// Invalidate the entire cache
+    pub fn flush(&mut self) {
+        for entry in self.entries.iter_mut() {
+            *entry = Entry::invalid();
+        }
+    }
+}
+
+impl Encodable for DecodeCache {
+    fn encode<S: Encoder>(&self, s: &mut S) -> Result<(), S::Error> {
+        // We only store whether the cache is enabled, the contents
+        // will be rebuilt as the code executes.
+        s.emit_bool(self.enabled)
+    }
+}
+
+impl Decodable for DecodeCache {
+    fn decode<D: Decoder>(d: &mut D) -> Result<DecodeCache, D::Error> {
+        let mut cache = DecodeCache::new();
+
+        cache.enabled = try!(d.read_bool());
+
+        Ok(cache)
+    }
+}
+
+/// A decoded instruction along with its handler
+#[derive(Copy, Clone)]
+pub enum Decoded {
+    Arm(armv4_is::Instruction, armv4_is::Handler),
+    Thumb(thumbv1_is::Instruction, thumbv1_is::Handler),
+}
+
+#[derive(Copy, Clone)]
+struct Entry {
+    addr: u32,
+    /// Code generation of `addr` when the instruction was decoded
+    generation: u32,
+    decoded: Option<Decoded>,
+}
+
+impl Entry {
+    fn invalid() -> Entry {
+        Entry {
+            addr: !0,
+            generation: 0,
+            decoded: None,
+        }
+    }
+}
+
+/// Direct-mapped cache index for `addr`
+fn index(addr: u32) -> usize {
+    ((addr >> 1) as usize) & (CACHE_SIZE - 1)
+}
+
+/// Number of entries in the cache, must be a power of two
+const CACHE_SIZE: usize = 1024;
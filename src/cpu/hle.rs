@@ -0,0 +1,221 @@
+//! High level emulation of the BIOS SWIs. The frontend can register
+//! host closures for given SWI numbers, they're then called instead
+//! of taking the SWI exception which skips the BIOS handler entirely.
+//!
+//! The PocketStation BIOS doesn't provide a division SWI, the
+//! ARM7TDMI has no hardware divider so programs usually link their
+//! own software division routine. Programs that do route divisions
+//! through a SWI can have it registered with `Cpu::set_hle_divide` so
+//! that divisions by zero are reported to `Debugger::on_div_zero`.
+
+use std::collections::HashMap;
+
+use rustc_serialize::{Decodable, Encodable, Decoder, Encoder};
+
+use debugger::Debugger;
+
+use super::Cpu;
+
+/// Host implementation of a SWI. It can access the registers and
+/// memory through the `Cpu` and the return value of the call should
+/// be placed in R0 like the BIOS would. Execution resumes after the
+/// SWI instruction once it returns.
+pub type HleHandler = Box<FnMut(&mut Cpu)>;
+
+pub struct Hle {
+    handlers: HashMap<u32, HleHandler>,
+    /// Comment of the division SWI, if any
+    divide: Option<u32>,
+}
+
+impl Hle {
+    pub fn new() -> Hle {
+        Hle {
+            handlers: HashMap::new(),
+            divide: None,
+        }
+    }
+
+    pub fn set_divide(&mut self, comment: Option<u32>) {
+        self.divide = comment;
+    }
+
+    pub fn set_handler(&mut self, comment: u32, handler: Option<HleHandler>) {
+        match handler {
+            Some(h) => { self.handlers.insert(comment, h); }
+            None => { self.handlers.remove(&comment); }
+        }
+    }
+}
+
+/// Called when the CPU executes a SWI with the given `comment`
+/// field. Returns `true` if an HLE handler was registered for it, in
+/// which case it's been called and the SWI exception must not be
+/// taken.
+pub fn swi(cpu: &mut Cpu, debugger: &mut Debugger, comment: u32) -> bool {
+    // The divisor is in R1 like for the ARM EABI division helpers
+    if cpu.hle.divide == Some(comment) && cpu.registers[1] == 0 {
+        debugger.on_div_zero(cpu, comment);
+    }
+
+    // Remove the handler from the map while it runs since it needs a
+    // mutable reference to the CPU
+    let mut handler =
+        match cpu.hle.handlers.remove(&comment) {
+            Some(h) => h,
+            None => return false,
+        };
+
+    handler(cpu);
+
+    // Don't overwrite the handler if it registered a new one for
+    // this SWI
+    cpu.hle.handlers.entry(comment).or_insert(handler);
+
+    true
+}
+
+impl Encodable for Hle {
+    fn encode<S: Encoder>(&self, s: &mut S) -> Result<(), S::Error> {
+        // We don't serialize the handlers, it'll be up to the
+        // frontend to register them again.
+        s.emit_nil()
+    }
+}
+
+impl Decodable for Hle {
+    fn decode<D: Decoder>(d: &mut D) -> Result<Hle, D::Error> {
+        try!(d.read_nil());
+
+        Ok(Hle::new())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cpu::{Cpu, Mode};
+    use debugger::Debugger;
+    use test_util;
+
+    #[test]
+    fn hle_swi() {
+        // SWI 0x42
+        // MOV R1, R0
+        // SWI 0x43
+        let mut cpu = test_util::arm_cpu(&[0xef000042,
+                                           0xe1a01000,
+                                           0xef000043]);
+
+        cpu.set_register(0, 0);
+
+        cpu.set_hle_swi(0x42, Some(Box::new(|cpu| {
+            let r0 = cpu.registers()[0];
+
+            cpu.set_register(0, r0 + 0xcafe);
+        })));
+
+        test_util::run(&mut cpu, 2);
+
+        // The handler ran and execution went on after the SWI
+        assert_eq!(cpu.registers()[1], 0xcafe);
+        assert_eq!(cpu.mode, Mode::Supervisor);
+        assert_eq!(cpu.next_pc, 8);
+
+        // No handler for 0x43: the SWI exception is taken
+        test_util::run(&mut cpu, 1);
+        assert_eq!(cpu.next_pc, 0x08);
+
+        // The handler stays registered until it's removed
+        let mut cpu = test_util::thumb_cpu(&[0xdf42, 0xdf42]);
+
+        cpu.set_register(0, 0);
+        cpu.set_hle_swi(0x42, Some(Box::new(|cpu| {
+            let r0 = cpu.registers()[0];
+
+            cpu.set_register(0, r0 + 1);
+        })));
+
+        test_util::run(&mut cpu, 1);
+        assert_eq!(cpu.registers()[0], 1);
+
+        cpu.set_hle_swi(0x42, None);
+
+        test_util::run(&mut cpu, 1);
+        assert_eq!(cpu.registers()[0], 1);
+        assert_eq!(cpu.next_pc, 0x08);
+    }
+
+    /// Debugger recording the SWI comments and dividends passed to
+    /// `on_div_zero`
+    struct DivLog(Vec<(u32, u32)>);
+
+    impl Debugger for DivLog {
+        fn trigger_break(&mut self) {
+        }
+
+        fn pc_change(&mut self, _: &mut Cpu) {
+        }
+
+        fn memory_read(&mut self, _: &mut Cpu, _: u32) {
+        }
+
+        fn memory_write(&mut self, _: &mut Cpu, _: u32) {
+        }
+
+        fn on_div_zero(&mut self, cpu: &mut Cpu, comment: u32) {
+            self.0.push((comment, cpu.registers()[0]));
+        }
+    }
+
+    #[test]
+    fn div_zero() {
+        let mut cpu = test_util::arm_cpu(&[]);
+        let mut log = DivLog(Vec::new());
+
+        cpu.set_hle_divide(Some(0x42));
+
+        // R0 / R1 with R1 == 0
+        cpu.set_register(0, 10);
+        cpu.set_register(1, 0);
+        cpu.execute_raw(&mut log, 0xef000042);
+
+        assert_eq!(log.0, [(0x42, 10)]);
+
+        // The SWI is then handled normally by the BIOS...
+        assert_eq!(cpu.mode, Mode::Supervisor);
+        assert_eq!(cpu.next_pc, 0x08);
+
+        // ...or the HLE handler
+        cpu.set_hle_swi(0x42, Some(Box::new(|cpu| {
+            cpu.set_register(0, 0xffffffff);
+        })));
+
+        cpu.set_pc(0x100);
+        cpu.set_register(0, 7);
+        cpu.execute_raw(&mut log, 0xef000042);
+
+        assert_eq!(log.0, [(0x42, 10), (0x42, 7)]);
+        assert_eq!(cpu.registers()[0], 0xffffffff);
+
+        // Non-zero divisor
+        cpu.set_register(0, 10);
+        cpu.set_register(1, 3);
+        cpu.execute_raw(&mut log, 0xef000042);
+
+        // Other SWIs and Thumb
+        cpu.set_register(1, 0);
+        cpu.execute_raw(&mut log, 0xef000043);
+
+        cpu.set_thumb(true);
+        cpu.set_register(0, 5);
+        cpu.execute_raw(&mut log, 0xdf42);
+
+        assert_eq!(log.0, [(0x42, 10), (0x42, 7), (0x42, 5)]);
+
+        // Disabled
+        cpu.set_hle_divide(None);
+        cpu.execute_raw(&mut log, 0xdf42);
+
+        assert_eq!(log.0.len(), 3);
+    }
+}
@@ -1,12 +1,25 @@
 use std::fmt;
+use std::io::Write;
 use std::mem::swap;
 use std::panic;
 
+use rustc_serialize::{Decodable, Encodable, Decoder, Encoder};
+
 use memory::{Interconnect, Addressable, Word, HalfWord};
 use debugger::Debugger;
 
+use self::decode_cache::{DecodeCache, Decoded};
+use self::semihosting::Semihosting;
+use self::hle::Hle;
+
+pub use self::hle::HleHandler;
+pub use self::armv4_is::handler_names as arm_handler_names;
+
 mod armv4_is;
 mod thumbv1_is;
+mod decode_cache;
+mod semihosting;
+mod hle;
 
 #[derive(RustcDecodable, RustcEncodable)]
 pub struct Cpu {
@@ -18,6 +31,8 @@ pub struct Cpu {
     c: bool,
     /// Overflow condition flag
     v: bool,
+    /// Sticky saturation flag, only present on ARMv5TE and later
+    q: bool,
     /// General purpose registers for the current mode. Register 15 is
     /// the PC.
     registers: [u32; 16],
@@ -50,9 +65,37 @@ pub struct Cpu {
     /// If `true` we trigger the debugger when a `bkpt` instruction is
     /// encountered
     debug_on_bkpt: bool,
+    /// Cache of recently decoded instructions
+    decode_cache: DecodeCache,
+    /// Version of the ARM architecture being emulated
+    arch: ArchVersion,
+    /// Semihosting state, disabled by default
+    semihosting: Semihosting,
+    /// High level emulation of the BIOS SWIs
+    hle: Hle,
+    /// Address of the first instruction executed after a reset
+    reset_vector: u32,
+    /// Addresses of the last few instructions executed, used to
+    /// detect self-modifying code
+    recent_pcs: [u32; RECENT_PCS],
+    /// Index of the next entry to be replaced in `recent_pcs`
+    recent_pcs_index: u8,
+    /// Set when the PC is written by the current instruction, which
+    /// causes a pipeline refill
+    pipeline_flushed: bool,
+    /// If `true` executing code outside of the executable memory
+    /// regions is an error
+    exec_protection: bool,
+    /// Error raised by the instruction being executed, see `fault`
+    fault: Fault,
 }
 
 impl Cpu {
+    /// Create a CPU connected to `inter` in its power-on state: ARM
+    /// mode, supervisor mode with IRQs and FIQs disabled and the PC
+    /// at the reset vector (address 0 by default). The general
+    /// purpose registers and condition flags are undefined on the
+    /// real hardware. The interconnect is reset as well.
     pub fn new(inter: Interconnect) -> Cpu {
         let mut cpu =
             Cpu {
@@ -62,6 +105,7 @@ impl Cpu {
                 z: true,
                 c: true,
                 v: true,
+                q: false,
                 registers: [0xdeadbeef; 16],
                 user_system_bank: [0; 2],
                 supervisor_bank: [0; 3],
@@ -80,6 +124,16 @@ impl Cpu {
                 spsr: 0,
                 inter: inter,
                 debug_on_bkpt: false,
+                decode_cache: DecodeCache::new(),
+                arch: ArchVersion::V4T,
+                semihosting: Semihosting::new(),
+                hle: Hle::new(),
+                reset_vector: 0,
+                recent_pcs: [!0; RECENT_PCS],
+                recent_pcs_index: 0,
+                pipeline_flushed: false,
+                exec_protection: false,
+                fault: Fault(None),
             };
 
         cpu.reset();
@@ -91,11 +145,104 @@ impl Cpu {
         self.debug_on_bkpt = enabled
     }
 
+    /// Enable or disable the decoded instruction cache. The cache is
+    /// disabled by default.
+    pub fn set_decode_cache(&mut self, enabled: bool) {
+        self.decode_cache.set_enabled(enabled)
+    }
+
+    /// When enabled, attempting to execute code outside of the
+    /// executable memory regions (see `Interconnect::is_executable`)
+    /// raises a prefetch abort exception instead of decoding the
+    /// register values as instructions. Disabled by default.
+    pub fn set_exec_protection(&mut self, enabled: bool) {
+        self.exec_protection = enabled
+    }
+
+    pub fn decode_cache_enabled(&self) -> bool {
+        self.decode_cache.enabled()
+    }
+
+    /// Invalidate the decoded instruction cache. The cache follows
+    /// the modifications made through the interconnect (see
+    /// `Interconnect::code_generation`) so this is normally not
+    /// necessary.
+    pub fn flush_decode_cache(&mut self) {
+        self.decode_cache.flush()
+    }
+
+    /// Set the address where the CPU starts executing after a reset.
+    /// The PocketStation boots from address 0 (where the BIOS is
+    /// mapped on reset) but alternate boot ROMs might not. Takes
+    /// effect at the next reset.
+    pub fn set_reset_vector(&mut self, addr: u32) {
+        self.reset_vector = addr
+    }
+
+    /// Select the version of the ARM architecture to emulate. The
+    /// PocketStation's ARM7TDMI is an ARMv4T but it can be useful to
+    /// run code built for later cores.
+    pub fn set_arch_version(&mut self, arch: ArchVersion) {
+        self.arch = arch;
+
+        if !arch.has_dsp_extensions() {
+            self.q = false;
+        }
+    }
+
+    pub fn arch_version(&self) -> ArchVersion {
+        self.arch
+    }
+
+    /// Enable ARM semihosting and send the console output to
+    /// `output`. Semihosting is disabled if `output` is `None`.
+    pub fn set_semihosting_output(&mut self, output: Option<Box<Write>>) {
+        self.semihosting.set_output(output)
+    }
+
+    /// Register `handler` to be called instead of the BIOS when the
+    /// guest executes a SWI with the given `comment` field. If
+    /// `handler` is `None` the BIOS handles the SWI again.
+    pub fn set_hle_swi(&mut self, comment: u32, handler: Option<HleHandler>) {
+        self.hle.set_handler(comment, handler)
+    }
+
+    /// Tell the CPU that the SWI with the given `comment` field is a
+    /// division of R0 by R1, so that `Debugger::on_div_zero` is
+    /// called when the divisor is 0. The SWI is then handled as
+    /// usual. The PocketStation BIOS doesn't have such a SWI, this is
+    /// only useful for programs providing their own.
+    pub fn set_hle_divide(&mut self, comment: Option<u32>) {
+        self.hle.set_divide(comment)
+    }
+
+    /// Replace the emulated state with `state`, typically freshly
+    /// decoded from a savestate. The parts that aren't serialized
+    /// (BIOS, flash contents, frontend callbacks, semihosting output
+    /// and HLE handlers) are kept from the current instance.
+    pub fn restore(&mut self, mut state: Cpu) {
+        state.inter.take_external_state(&mut self.inter);
+        swap(&mut state.semihosting, &mut self.semihosting);
+        swap(&mut state.hle, &mut self.hle);
+
+        *self = state;
+    }
+
     /// Return R0 to R14 and the PC
     pub fn registers(&self) -> &[u32; 16] {
         &self.registers
     }
 
+    /// Set the value of register `r` (0 to 14) in the current mode.
+    /// Use `set_pc` to change the PC.
+    pub fn set_register(&mut self, r: u32, val: u32) {
+        if r >= 15 {
+            panic!("Invalid register index {}", r);
+        }
+
+        self.registers[r as usize] = val;
+    }
+
     pub fn reset(&mut self) {
         self.inter.reset();
 
@@ -107,7 +254,58 @@ impl Cpu {
         self.change_mode(Mode::Supervisor);
 
         // Jump to reset vector
-        self.set_pc(0);
+        let reset_vector = self.reset_vector;
+
+        self.set_pc(reset_vector);
+    }
+
+    /// Reset the console and jump directly to the application code at
+    /// `entry` without running the BIOS. Set bit 0 of `entry` to
+    /// start in Thumb mode. This is only meant to speed up testing,
+    /// the state the BIOS leaves behind is approximated:
+    ///
+    /// * The BIOS is unmapped from address 0 and the flash virtual
+    ///   banks are mapped 1:1 to the physical ones.
+    /// * The CPU runs at full speed.
+    /// * The IRQ, FIQ and supervisor stacks are set up at the top of
+    ///   the RAM and the CPU runs in user mode with the interrupts
+    ///   enabled.
+    ///
+    /// The RTC is left untouched and the application can't rely on
+    /// any of the BIOS' RAM variables.
+    pub fn fast_boot(&mut self, entry: u32) {
+        self.reset();
+
+        // F_CTRL: unmap the BIOS from address 0
+        self.inter.store::<Word>(0x06000000, 3);
+
+        // Map every physical flash bank to the same virtual bank
+        for bank in 0..16 {
+            self.inter.store::<Word>(0x06000100 + bank * 4, bank);
+        }
+
+        // F_BANK_EN
+        self.inter.store::<Word>(0x06000008, 0xffff);
+
+        // CLK_MODE: fastest CPU clock
+        self.inter.store::<Word>(0x0b000000, 7);
+
+        // XXX Those are guesses, check what the BIOS actually uses.
+        // `fast_boot_matches_bios` compares them against a real
+        // boot when a BIOS image is available.
+        self.change_mode(Mode::Fiq);
+        self.registers[13] = 0x800;
+        self.change_mode(Mode::Irq);
+        self.registers[13] = 0x7c0;
+        self.change_mode(Mode::Supervisor);
+        self.registers[13] = 0x780;
+        self.change_mode(Mode::User);
+        self.registers[13] = 0x700;
+
+        self.irq_en = true;
+        self.fiq_en = true;
+
+        self.set_pc_thumb(entry & !1, entry & 1 != 0);
     }
 
     /// Run CPU for `master_ticks` master clock periods
@@ -119,29 +317,126 @@ impl Cpu {
             self.run_next_instruction(debugger);
         }
 
+        // Make sure all the peripherals are up to date (and the DAC
+        // has output all the samples for this frame) before handing
+        // control back to the frontend
+        self.inter.sync();
+
+        self.inter.set_frame_ticks(0);
+    }
+
+    /// Run the CPU until the LCD enters the next vertical blanking
+    /// period and return the number of CPU cycles executed. Since
+    /// instructions aren't interrupted the run may overshoot by a
+    /// few cycles.
+    pub fn run_to_vblank<D: Debugger>(&mut self, debugger: &mut D) -> u32 {
+        // Make sure the LCD scanout position is up to date
+        self.inter.sync();
+
+        let target =
+            self.inter.frame_ticks() + self.inter.lcd().ticks_to_vblank();
+
+        let mut cycles = 0;
+
+        while self.inter.frame_ticks() < target {
+            cycles += self.run_next_instruction(debugger);
+        }
+
+        self.inter.sync();
+
+        cycles
+    }
+
+    /// Run up to `instruction_budget` instructions as fast as
+    /// possible and return the number of master clock ticks
+    /// elapsed. All the peripherals are clocked from the CPU so the
+    /// emulated timings are unaffected, this can be used by the
+    /// frontend to implement fast-forward by calling it repeatedly
+    /// without throttling.
+    pub fn run_unbounded<D: Debugger>(&mut self,
+                                      debugger: &mut D,
+                                      instruction_budget: u32) -> u64 {
+        let mut master_ticks = 0u64;
+
+        for _ in 0..instruction_budget {
+            let start = self.inter.frame_ticks();
+
+            self.run_next_instruction(debugger);
+
+            let elapsed = self.inter.frame_ticks().wrapping_sub(start);
+
+            master_ticks += elapsed as u64;
+        }
+
+        self.inter.sync();
+
         self.inter.set_frame_ticks(0);
+
+        master_ticks
     }
 
-    pub fn run_next_instruction<D>(&mut self, debugger: &mut D)
+    /// Execute the next instruction and return the number of CPU
+    /// cycles it took. Panics if the guest code causes an emulator
+    /// error, see `try_step`.
+    pub fn run_next_instruction<D>(&mut self, debugger: &mut D) -> u32
         where D: Debugger {
-        // Assume each instruction takes exactly one CPU cycle for
-        // now, a gross oversimplification...
-        self.inter.tick(1);
+        match self.try_step(debugger) {
+            Ok(cycles) => cycles,
+            Err(e) => panic!("{}\n{:?}", e, self),
+        }
+    }
 
-        if self.inter.irq_controller().pending() {
-            // FIQs have a high priority than IRQs, so check for them
-            // first
-            if self.fiq_en && self.inter.irq_controller().fiq_pending() {
-                self.fiq();
-            } else if self.irq_en && self.inter.irq_controller().irq_pending() {
-                self.irq();
-            }
+    /// Execute `instruction` as if it had been fetched at the current
+    /// PC, bypassing the memory entirely. In Thumb mode only the low
+    /// 16 bits of `instruction` are used. Meant for differential
+    /// testing and fuzzing of the instruction decoding and
+    /// execution. Returns the number of CPU cycles taken by the
+    /// instruction. Panics like `run_next_instruction` if the
+    /// instruction causes an emulator error.
+    pub fn execute_raw<D>(&mut self, debugger: &mut D, instruction: u32) -> u32
+        where D: Debugger {
+        match self.step_raw(debugger, instruction) {
+            Ok(cycles) => cycles,
+            Err(e) => panic!("{}\n{:?}", e, self),
         }
+    }
+
+    fn step_raw<D>(&mut self,
+                   debugger: &mut D,
+                   instruction: u32) -> Result<u32, StepError>
+        where D: Debugger {
+        try!(self.start_instruction(debugger));
+
+        let decoded =
+            if self.thumb {
+                let (instruction, handler) =
+                    thumbv1_is::decode(instruction as u16);
+
+                Decoded::Thumb(instruction, handler)
+            } else {
+                let (instruction, handler) = armv4_is::decode(instruction);
+
+                Decoded::Arm(instruction, handler)
+            };
+
+        self.execute(debugger, decoded)
+    }
 
+    /// Advance the PC to the next instruction and return the address
+    /// of the instruction about to be executed
+    fn start_instruction<D>(&mut self,
+                            debugger: &mut D) -> Result<u32, StepError>
+        where D: Debugger {
         let pc = self.next_pc;
 
+        self.pipeline_flushed = false;
+
         self.next_pc = self.registers[15];
 
+        self.recent_pcs[self.recent_pcs_index as usize] = pc;
+        self.recent_pcs_index =
+            ((self.recent_pcs_index as usize + 1) % RECENT_PCS) as u8;
+
         debugger.pc_change(self);
 
         if self.thumb {
@@ -151,13 +446,10 @@ impl Cpu {
             self.registers[15] += 2;
 
             if pc & 1 != 0 {
-                panic!("Misaligned PC! {:?}", self);
-            }
-
-            let instruction = self.inter.load::<HalfWord>(pc) as u16;
-
-            thumbv1_is::execute(self, debugger, instruction);
+                let msg = format!("Misaligned Thumb PC 0x{:08x}", pc);
 
+                return Err(StepError::BusError(msg));
+            }
         } else {
             // In ARM mode the PC register (R15) always points to the
             // current instruction's addres + 8, except for STR/STM
@@ -167,13 +459,242 @@ impl Cpu {
             self.registers[15] += 4;
 
             if pc & 3 != 0 {
-                panic!("Misaligned PC! {:?}", self);
+                let msg = format!("Misaligned ARM PC 0x{:08x}", pc);
+
+                return Err(StepError::BusError(msg));
+            }
+        }
+
+        Ok(pc)
+    }
+
+    /// Execute the decoded instruction, update the peripherals and
+    /// handle the interrupts. Returns the number of CPU cycles taken
+    /// by the instruction, or the error raised by the instruction
+    /// handler (see `fault`) in which case the peripherals and
+    /// interrupts are left untouched.
+    fn execute<D>(&mut self,
+                  debugger: &mut D,
+                  decoded: Decoded) -> Result<u32, StepError>
+        where D: Debugger {
+        match decoded {
+            Decoded::Arm(instruction, handler) =>
+                armv4_is::execute_decoded(self, debugger, instruction, handler),
+            Decoded::Thumb(instruction, handler) =>
+                thumbv1_is::execute_decoded(self, debugger, instruction, handler),
+        }
+
+        // Optional bus contention model, see
+        // `Interconnect::set_lcd_contention`
+        let stall = self.inter.take_stall_cycles();
+
+        if let Some(e) = self.fault.0.take() {
+            return Err(e);
+        }
+
+        // Assume each instruction takes exactly one CPU cycle
+        // (including the ones skipped because their condition
+        // failed) except when the PC is written, in which case the
+        // ARM7TDMI has to refill its 3-stage pipeline (2S + 1N
+        // cycles). Still a gross oversimplification since the memory
+        // accesses and multiplications take extra cycles.
+        let pipeline =
+            if self.pipeline_flushed {
+                3
+            } else {
+                1
+            };
+
+        let mut cycles = pipeline + stall;
+
+        self.inter.tick(cycles);
+
+        if self.take_irq() {
+            // The exception entry refills the pipeline from the
+            // vector
+            self.inter.tick(3);
+
+            cycles += 3;
+        }
+
+        Ok(cycles)
+    }
+
+    /// Execute a single instruction, returning the emulator errors
+    /// caused by misbehaving guest code (undefined instructions, bad
+    /// memory accesses...) instead of aborting. Returns the number of
+    /// CPU cycles taken by the instruction.
+    ///
+    /// After an error the CPU state is not guaranteed to be
+    /// consistent (the faulting instruction might have been partially
+    /// executed), the frontend should reset or reload a savestate.
+    pub fn try_step<D>(&mut self, debugger: &mut D) -> Result<u32, StepError>
+        where D: Debugger {
+        let pc = try!(self.start_instruction(debugger));
+
+        if self.exec_protection && !self.inter.is_executable(pc) {
+            self.prefetch_abort(pc);
+
+            // The exception entry refills the pipeline from the
+            // vector
+            self.inter.tick(3);
+
+            return Ok(3);
+        }
+
+        let decoded = try!(self.fetch_decode(pc));
+
+        self.execute(debugger, decoded)
+    }
+
+    /// Abort the instruction being executed with `error`, which is
+    /// then returned by `try_step`. The instruction handlers must
+    /// return right away after calling this. If several errors are
+    /// raised by the same instruction only the first one is kept.
+    fn fault(&mut self, error: StepError) {
+        if self.fault.0.is_none() {
+            self.fault.0 = Some(error);
+        }
+    }
+
+    /// Abort the current instruction because it's undefined or
+    /// unpredictable, see `fault`
+    fn undefined<S: Into<String>>(&mut self, msg: S) {
+        self.fault(StepError::UndefinedInstruction(msg.into()))
+    }
+
+    /// Abort the current instruction because the emulator doesn't
+    /// support it, see `fault`
+    fn unimplemented<S: Into<String>>(&mut self, msg: S) {
+        self.fault(StepError::UnimplementedInstruction(msg.into()))
+    }
+
+    /// Fetch and decode the instruction at `pc` in the current
+    /// instruction set, going through the decode cache.
+    fn fetch_decode(&mut self, pc: u32) -> Result<Decoded, StepError> {
+        let generation = self.inter.code_generation(pc);
+
+        if let Some(decoded) = self.decode_cache.get(pc,
+                                                     self.thumb,
+                                                     generation) {
+            return Ok(decoded);
+        }
+
+        let decoded =
+            if self.thumb {
+                let instruction =
+                    try!(self.inter.try_sync_load::<HalfWord>(pc)
+                         .map_err(StepError::BusError)) as u16;
+
+                let (instruction, handler) = thumbv1_is::decode(instruction);
+
+                Decoded::Thumb(instruction, handler)
+            } else {
+                let instruction =
+                    try!(self.inter.try_sync_load::<Word>(pc)
+                         .map_err(StepError::BusError));
+
+                let (instruction, handler) = armv4_is::decode(instruction);
+
+                Decoded::Arm(instruction, handler)
+            };
+
+        self.decode_cache.insert(pc, generation, decoded);
+
+        Ok(decoded)
+    }
+
+    /// Check for pending interrupts and jump to the corresponding
+    /// vector if they're enabled. Interrupts are only ever taken
+    /// between two instructions so this must only be called once the
+    /// current instruction has completed, even if the interrupt
+    /// became pending while it was executing. Returns `true` if an
+    /// exception was taken.
+    fn take_irq(&mut self) -> bool {
+        if self.inter.irq_controller().pending() {
+            // FIQs have a high priority than IRQs, so check for them
+            // first
+            if self.fiq_en && self.inter.irq_controller().fiq_pending() {
+                self.fiq();
+                return true;
+            } else if self.irq_en && self.inter.irq_controller().irq_pending() {
+                self.irq();
+                return true;
             }
+        }
 
-            let instruction = self.inter.load::<Word>(pc);
+        false
+    }
 
-            armv4_is::execute(self, debugger, instruction);
+    /// Build a listing of `count` instructions starting at `start`
+    /// for a debugger code view, decoded in Thumb mode if `thumb` is
+    /// true. Returns the address, raw instruction and a description
+    /// of each instruction. There's no proper disassembler yet so the
+    /// description is only the instruction class. The memory is read
+    /// with `Interconnect::try_peek` so this has no side effect. The
+    /// raw instruction is `None` and the description `<unmapped>` for
+    /// the addresses which can't be read.
+    pub fn disassemble_range(&self,
+                             start: u32,
+                             count: usize,
+                             thumb: bool)
+                             -> Vec<(u32, Option<u32>, String)> {
+        let mut listing = Vec::with_capacity(count);
+        let mut addr = start;
+
+        for _ in 0..count {
+            let (raw, desc, size) =
+                if thumb {
+                    match self.inter.try_peek::<HalfWord>(addr) {
+                        Ok(raw) =>
+                            (Some(raw), thumbv1_is::describe(raw as u16), 2),
+                        Err(_) => (None, "<unmapped>", 2),
+                    }
+                } else {
+                    match self.inter.try_peek::<Word>(addr) {
+                        Ok(raw) => (Some(raw), armv4_is::describe(raw), 4),
+                        Err(_) => (None, "<unmapped>", 4),
+                    }
+                };
+
+            listing.push((addr, raw, desc.to_string()));
+
+            addr = addr.wrapping_add(size);
         }
+
+        listing
+    }
+
+    /// Return where the control flow goes after the instruction at
+    /// the current PC, for "step over" and flow graphs in debuggers.
+    /// The condition flags are not evaluated, conditional branches
+    /// are always reported as `NextPc::Conditional`. Like
+    /// `disassemble_range` the instruction is read with
+    /// `Interconnect::try_peek` so this has no side effect. Returns
+    /// `None` if the PC points to memory which can't be read.
+    pub fn predict_next_pc(&self) -> Option<NextPc> {
+        let pc = self.next_pc;
+
+        let next =
+            if self.thumb {
+                let instruction =
+                    match self.inter.try_peek::<HalfWord>(pc) {
+                        Ok(i) => i as u16,
+                        Err(_) => return None,
+                    };
+
+                thumbv1_is::next_pc(instruction, pc, self.registers[14])
+            } else {
+                let instruction =
+                    match self.inter.try_peek::<Word>(pc) {
+                        Ok(i) => i,
+                        Err(_) => return None,
+                    };
+
+                armv4_is::next_pc(instruction, pc)
+            };
+
+        Some(next)
     }
 
     pub fn interconnect(&self) -> &Interconnect {
@@ -184,38 +705,85 @@ impl Cpu {
         &mut self.inter
     }
 
-    fn n(&self) -> bool {
+    /// Return true if the CPU is in Thumb mode
+    pub fn is_thumb(&self) -> bool {
+        self.thumb
+    }
+
+    /// Switch between ARM and Thumb mode without moving the PC. Must
+    /// only be called between instructions, for instance to set up
+    /// the CPU state for a test.
+    pub fn set_thumb(&mut self, thumb: bool) {
+        let pc = self.next_pc;
+
+        self.set_pc_thumb(pc, thumb);
+    }
+
+    /// Negative condition flag
+    pub fn n(&self) -> bool {
         self.n
     }
 
-    fn set_n(&mut self, n: bool) {
+    pub fn set_n(&mut self, n: bool) {
         self.n = n
     }
 
-    fn z(&self) -> bool {
+    /// Zero condition flag
+    pub fn z(&self) -> bool {
         self.z
     }
 
-    fn set_z(&mut self, z: bool) {
+    pub fn set_z(&mut self, z: bool) {
         self.z = z
     }
 
-    fn c(&self) -> bool {
+    /// Carry condition flag
+    pub fn c(&self) -> bool {
         self.c
     }
 
-    fn set_c(&mut self, c: bool) {
+    pub fn set_c(&mut self, c: bool) {
         self.c = c
     }
 
-    fn v(&self) -> bool {
+    /// Overflow condition flag
+    pub fn v(&self) -> bool {
         self.v
     }
 
-    fn set_v(&mut self, v: bool) {
+    pub fn set_v(&mut self, v: bool) {
         self.v = v
     }
 
+    /// Return the condition flags packed like in the CPSR's top
+    /// nibble: N in bit 3, Z in bit 2, C in bit 1 and V in bit 0
+    pub fn flags_nibble(&self) -> u32 {
+        self.cpsr() >> 28
+    }
+
+    /// Set the condition flags from a nibble in the format returned
+    /// by `flags_nibble`. Bits above bit 3 are ignored.
+    pub fn set_flags_nibble(&mut self, flags: u32) {
+        self.set_nzcv(flags & 8 != 0,
+                      flags & 4 != 0,
+                      flags & 2 != 0,
+                      flags & 1 != 0);
+    }
+
+    /// Update all four condition flags at once
+    fn set_nzcv(&mut self, n: bool, z: bool, c: bool, v: bool) {
+        self.n = n;
+        self.z = z;
+        self.c = c;
+        self.v = v;
+    }
+
+    /// Set the sticky saturation flag. It can only be cleared by
+    /// writing to the CPSR.
+    fn set_q(&mut self) {
+        self.q = true
+    }
+
     fn reg(&self, r: RegisterIndex) -> u32 {
         self.registers[r.0 as usize]
     }
@@ -240,6 +808,30 @@ impl Cpu {
         }
     }
 
+    /// Write `v`, loaded from memory, to register `r`. See
+    /// `set_pc_load` for how PC loads are handled.
+    fn set_reg_load(&mut self, r: RegisterIndex, v: u32) {
+        if r.is_pc() {
+            self.set_pc_load(v);
+        } else {
+            self.registers[r.0 as usize] = v;
+        }
+    }
+
+    /// Write `pc`, loaded from memory, to the PC. Starting with
+    /// ARMv5 PC loads interwork: bit 0 selects the Thumb state like
+    /// for BX. On ARMv4 the state is unchanged and the low bits are
+    /// ignored.
+    fn set_pc_load(&mut self, pc: u32) {
+        if self.arch.has_load_interworking() {
+            self.set_pc_thumb(pc & !1, pc & 1 != 0);
+        } else if self.thumb {
+            self.set_pc(pc & !1);
+        } else {
+            self.set_pc(pc & !3);
+        }
+    }
+
     /// Return the PC to the currently executed instruction
     pub fn current_pc(&self) -> u32 {
         let off =
@@ -254,6 +846,7 @@ impl Cpu {
 
     pub fn set_pc(&mut self, pc: u32) {
         self.next_pc = pc;
+        self.pipeline_flushed = true;
 
         let r15_offset =
             if self.thumb {
@@ -277,7 +870,11 @@ impl Cpu {
     }
 
     fn set_pc_cpsr(&mut self, pc: u32, cpsr: u32) {
-        let mode = Mode::from_field(cpsr & 0x1f);
+        let mode =
+            match Mode::from_field(cpsr & 0x1f) {
+                Some(m) => m,
+                None => return self.invalid_mode(cpsr),
+            };
 
         self.change_mode(mode);
 
@@ -293,6 +890,8 @@ impl Cpu {
         self.z = (flags & 4) != 0;
         self.n = (flags & 8) != 0;
 
+        self.q = self.arch.has_dsp_extensions() && (cpsr & (1 << 27)) != 0;
+
         self.set_pc(pc);
     }
 
@@ -396,6 +995,7 @@ impl Cpu {
         r |= ((!self.fiq_en) as u32) << 6;
         r |= ((!self.irq_en) as u32) << 7;
 
+        r |= (self.q as u32) << 27;
         r |= (self.v as u32) << 28;
         r |= (self.c as u32) << 29;
         r |= (self.z as u32) << 30;
@@ -404,17 +1004,39 @@ impl Cpu {
         r
     }
 
-    fn spsr(&self) -> u32 {
+    /// Return the SPSR of the current mode or raise an undefined
+    /// instruction error if it doesn't have one (user and system
+    /// modes)
+    fn spsr(&mut self) -> Option<u32> {
         if self.mode.has_spsr() {
-            self.spsr
+            Some(self.spsr)
         } else {
-            panic!("Attempted to access SPSR in {:?} mode",
-                   self.mode);
+            let msg = format!("Attempted to access SPSR in {:?} mode",
+                              self.mode);
+
+            self.undefined(msg);
+
+            None
         }
     }
 
+    /// Abort the current instruction because it attempted to load
+    /// the invalid mode field of `cpsr`, see `fault`
+    fn invalid_mode(&mut self, cpsr: u32) {
+        let msg = format!("Invalid mode 0b{:05b} in CPSR 0x{:08x}",
+                          cpsr & 0x1f, cpsr);
+
+        self.fault(StepError::Other(msg))
+    }
+
     /// Software interrupt, also called "SVC" (supervisor call) in
-    /// modern ARM architectures.
+    /// modern ARM architectures. The CPSR is saved in SPSR_svc, the
+    /// CPU switches to supervisor mode in ARM state with the IRQs
+    /// masked (FIQs are left alone) and LR_svc is set to the address
+    /// of the instruction following the SWI in both ARM and Thumb
+    /// state, so that `MOVS PC, LR` resumes the caller. The
+    /// ARM7TDMI doesn't support high vectors so the SWI vector is
+    /// always at 0x8, `set_reset_vector` only affects the reset.
     fn swi(&mut self) {
         let ra = self.next_pc;
         let spsr = self.cpsr();
@@ -431,6 +1053,27 @@ impl Cpu {
         self.set_pc(0x8)
     }
 
+    /// Prefetch abort, raised when attempting to execute the
+    /// instruction at `pc` with the execution protection enabled (see
+    /// `set_exec_protection`). LR_abt is set to `pc + 4` in both ARM
+    /// and Thumb state, so `SUBS PC, LR, #4` retries the aborted
+    /// instruction.
+    fn prefetch_abort(&mut self, pc: u32) {
+        let ra = pc.wrapping_add(4);
+        let spsr = self.cpsr();
+
+        self.thumb = false;
+        self.irq_en = false;
+
+        self.change_mode(Mode::Abort);
+
+        self.spsr = spsr;
+        self.set_reg(RegisterIndex(14), ra);
+
+        // Jump to prefetch abort vector
+        self.set_pc(0xc)
+    }
+
     /// Fast interrupt request
     fn fiq(&mut self) {
         let ra = self.next_pc + 4;
@@ -449,7 +1092,12 @@ impl Cpu {
         self.set_pc(0x1c)
     }
 
-    /// Interrupt request
+    /// Interrupt request. FIQs are left enabled so they can preempt
+    /// the IRQ handler. Nesting IRQs works like on the real hardware:
+    /// the handler must save its SPSR and LR before re-enabling the
+    /// IRQs since a new interrupt overwrites them. There's no priority
+    /// between the IRQ sources, the handler decides which one to
+    /// service first by looking at the IRQ controller's latch.
     fn irq(&mut self) {
         let ra = self.next_pc + 4;
         let spsr = self.cpsr();
@@ -467,28 +1115,43 @@ impl Cpu {
     }
 
     fn msr_cpsr(&mut self, val: u32, field_mask: u32) {
-        let unalloc_mask = 0x0fffff00;
+        let unalloc_mask =
+            if self.arch.has_dsp_extensions() {
+                // Q flag is bit 27
+                0x07ffff00
+            } else {
+                0x0fffff00
+            };
 
         // The reference manual says it's unpredictable even if those
         // bits aren't set in the field_mask
         if val & unalloc_mask != 0 {
-            panic!("Attempt to set CPSR reserved bits");
+            let msg = format!("Attempt to set CPSR reserved bits \
+                               0x{:08x}", val);
+
+            return self.fault(StepError::Other(msg));
         }
 
         if (field_mask & 1) != 0 && self.mode.is_privileged() {
-            // Set control bits
-            let mode = Mode::from_field((val & 0xf) | 0x10);
-
-            self.change_mode(mode);
-
             let thumb = (val & 0x20) != 0;
 
             if thumb {
                 // MSR is unpredictable if it attempts to change the
                 // execution mode.
-                panic!("Attempted to switch to Thumb mode in MSR");
+                let msg = "Attempted to switch to Thumb mode in MSR";
+
+                return self.fault(StepError::Other(msg.into()));
             }
 
+            // Set control bits
+            let mode =
+                match Mode::from_field((val & 0xf) | 0x10) {
+                    Some(m) => m,
+                    None => return self.invalid_mode(val),
+                };
+
+            self.change_mode(mode);
+
             self.fiq_en = (val & 0x40) == 0;
             self.irq_en = (val & 0x80) == 0;
         }
@@ -501,7 +1164,29 @@ impl Cpu {
             self.c = (flags & 2) != 0;
             self.z = (flags & 4) != 0;
             self.n = (flags & 8) != 0;
+
+            self.q = (val & (1 << 27)) != 0;
+        }
+    }
+
+    /// Update the fields of the current mode's SPSR selected by
+    /// `field_mask` with `val`. Unlike the CPSR all the bits can be
+    /// written, including the T flag, since the SPSR is only used to
+    /// restore the CPSR on exception return.
+    fn msr_spsr(&mut self, val: u32, field_mask: u32) {
+        if self.spsr().is_none() {
+            return;
+        }
+
+        let mut mask = 0;
+
+        for field in 0..4 {
+            if (field_mask & (1 << field)) != 0 {
+                mask |= 0xff << (field * 8);
+            }
         }
+
+        self.spsr = (self.spsr & !mask) | (val & mask);
     }
 
     /// Load a memory location without side-effect, useful for
@@ -514,7 +1199,7 @@ impl Cpu {
         // XXX Remove that when the entire address space is
         // implemented.
         let r = panic::catch_unwind(panic::AssertUnwindSafe(|| {
-            self.inter.load::<A>(addr)
+            self.inter.peek::<A>(addr)
         }));
 
         match r {
@@ -529,16 +1214,33 @@ impl Cpu {
 
         debugger.memory_read(self, addr);
 
-        let align = (A::size() - 1) as u32;
+        if !A::is_aligned(addr) {
+            let msg = format!("Unaligned {}bit load from 0x{:08x}",
+                              A::size() * 8, addr);
 
-        if addr & align != 0 {
-            panic!("Unaligned load{}! 0x{:08x} {:?}",
-                   A::size() * 8,
-                   addr,
-                   self);
+            self.fault(StepError::BusError(msg));
+
+            return 0;
         }
 
-        self.inter.load::<A>(addr)
+        let val =
+            match self.inter.try_sync_load::<A>(addr) {
+                Ok(v) => v,
+                Err(e) => {
+                    self.fault(StepError::BusError(e));
+                    return 0;
+                }
+            };
+
+        debugger.memory_read_value(self, addr, A::size(), val);
+
+        val
+    }
+
+    /// Return true if the word containing `addr` holds one of the
+    /// last `RECENT_PCS` instructions executed
+    fn recently_executed(&self, addr: u32) -> bool {
+        self.recent_pcs.iter().any(|&pc| (pc ^ addr) & !3 == 0)
     }
 
     fn store<A>(&mut self, debugger: &mut Debugger, addr: u32, val: u32)
@@ -546,16 +1248,22 @@ impl Cpu {
 
         debugger.memory_write(self, addr);
 
-        let align = (A::size() - 1) as u32;
+        if !A::is_aligned(addr) {
+            let msg = format!("Unaligned {}bit store to 0x{:08x}",
+                              A::size() * 8, addr);
 
-        if (addr & align) != 0 {
-            panic!("Unaligned store{}! 0x{:08x} {:?}",
-                   A::size() * 8,
-                   addr,
-                   self);
+            return self.fault(StepError::BusError(msg));
         }
 
-        self.inter.store::<A>(addr, val);
+        if self.recently_executed(addr) {
+            debugger.on_smc(self, addr);
+        }
+
+        debugger.memory_write_value(self, addr, A::size(), val);
+
+        if let Err(e) = self.inter.try_sync_store::<A>(addr, val) {
+            self.fault(StepError::BusError(e));
+        }
     }
 }
 
@@ -613,25 +1321,164 @@ impl fmt::Debug for Cpu {
     }
 }
 
-#[derive(Copy, Clone, PartialEq, Eq)]
-struct RegisterIndex(u32);
+/// Control flow of an instruction, returned by
+/// `Cpu::predict_next_pc`
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum NextPc {
+    /// Execution continues with the following instruction
+    Sequential,
+    /// Unconditional branch to the given address
+    Branch(u32),
+    /// Conditional branch to the given address. If the condition
+    /// fails execution continues with the following instruction.
+    Conditional(u32),
+    /// The target depends on a register, memory or an exception
+    /// vector and can't be known without executing the instruction
+    Indirect,
+}
 
-impl RegisterIndex {
-    fn is_pc(self) -> bool {
-        self.0 == 15
-    }
+/// Errors returned by `Cpu::try_step`. Each variant contains the
+/// emulator's description of the failure.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum StepError {
+    /// The CPU attempted to execute an undefined instruction or one
+    /// whose behaviour is unpredictable
+    UndefinedInstruction(String),
+    /// Misaligned memory access or instruction fetch, or execution
+    /// from a non-executable address
+    BusError(String),
+    /// Valid instruction which is not implemented by the emulator
+    UnimplementedInstruction(String),
+    /// The guest attempted to put the CPU in an unsupported state
+    /// (reserved CPSR bits set, invalid mode...)
+    Other(String),
 }
 
-impl fmt::Display for RegisterIndex {
+impl fmt::Display for StepError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        if self.is_pc() {
-            write!(f, "PC")
-        } else {
+        match *self {
+            StepError::UndefinedInstruction(ref m) =>
+                write!(f, "Undefined instruction: {}", m),
+            StepError::BusError(ref m) =>
+                write!(f, "Bus error: {}", m),
+            StepError::UnimplementedInstruction(ref m) =>
+                write!(f, "Unimplemented instruction: {}", m),
+            StepError::Other(ref m) =>
+                write!(f, "Emulator error: {}", m),
+        }
+    }
+}
+
+/// Wrapper around the pending `StepError` for serialization. The
+/// error is always returned by the instruction that raised it so
+/// there's never anything to save.
+struct Fault(Option<StepError>);
+
+impl Encodable for Fault {
+    fn encode<S: Encoder>(&self, s: &mut S) -> Result<(), S::Error> {
+        s.emit_nil()
+    }
+}
+
+impl Decodable for Fault {
+    fn decode<D: Decoder>(d: &mut D) -> Result<Fault, D::Error> {
+        try!(d.read_nil());
+
+        Ok(Fault(None))
+    }
+}
+
+/// Compute `a + b + carry_in` and return the result along with the
+/// resulting N, Z, C and V flags. All the flag-setting additions
+/// (ADDS, ADCS, CMN...) go through this function.
+fn add_with_flags(a: u32,
+                  b: u32,
+                  carry_in: bool) -> (u32, bool, bool, bool, bool) {
+    let wide = a as u64 + b as u64 + carry_in as u64;
+
+    let val = wide as u32;
+
+    let n = (val as i32) < 0;
+    let z = val == 0;
+    // Unsigned overflow
+    let c = wide > 0xffffffff;
+    // Signed overflow: both operands have the same sign and the
+    // result's sign differs
+    let v = ((a ^ val) & (b ^ val)) >> 31 != 0;
+
+    (val, n, z, c, v)
+}
+
+/// Compute `a - b - borrow_in` and return the result along with the
+/// resulting N, Z, C and V flags. Following the ARM convention C is
+/// set if the subtraction did *not* borrow, so SBC-type instructions
+/// should pass `!cpu.c()` as `borrow_in`. All the flag-setting
+/// subtractions (SUBS, SBCS, RSBS, CMP...) go through this function.
+fn sub_with_flags(a: u32,
+                  b: u32,
+                  borrow_in: bool) -> (u32, bool, bool, bool, bool) {
+    let val = a.wrapping_sub(b).wrapping_sub(borrow_in as u32);
+
+    let n = (val as i32) < 0;
+    let z = val == 0;
+    let c = a as u64 >= b as u64 + borrow_in as u64;
+    // Signed overflow: the operands have different signs and the
+    // result's sign differs from `a`
+    let v = ((a ^ b) & (a ^ val)) >> 31 != 0;
+
+    (val, n, z, c, v)
+}
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+struct RegisterIndex(u32);
+
+impl RegisterIndex {
+    fn is_pc(self) -> bool {
+        self.0 == 15
+    }
+}
+
+impl fmt::Display for RegisterIndex {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.is_pc() {
+            write!(f, "PC")
+        } else {
             write!(f, "R{}", self.0)
         }
     }
 }
 
+/// ARM architecture versions supported by the emulator
+#[derive(RustcDecodable, RustcEncodable)]
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum ArchVersion {
+    /// ARMv4T, implemented by the PocketStation's ARM7TDMI
+    V4T,
+    /// ARMv5TE, adds the DSP extensions (saturation flag and
+    /// halfword multiplies)
+    V5TE,
+}
+
+impl ArchVersion {
+    fn has_dsp_extensions(self) -> bool {
+        self == ArchVersion::V5TE
+    }
+
+    /// BLX instructions were introduced with ARMv5
+    fn has_blx(self) -> bool {
+        self == ArchVersion::V5TE
+    }
+
+    /// Loads to the PC (LDR, LDM, POP) interwork since ARMv5
+    fn has_load_interworking(self) -> bool {
+        self == ArchVersion::V5TE
+    }
+}
+
+/// Number of recently executed instructions tracked to detect
+/// self-modifying code
+const RECENT_PCS: usize = 8;
+
 /// CPU modes
 #[derive(RustcDecodable, RustcEncodable)]
 #[derive(Copy, Clone, PartialEq, Eq, Debug)]
@@ -646,17 +1493,20 @@ enum Mode {
 }
 
 impl Mode {
-    fn from_field(mode: u32) -> Mode {
-        match mode {
-            0b10000 => Mode::User,
-            0b10001 => Mode::Fiq,
-            0b10010 => Mode::Irq,
-            0b10011 => Mode::Supervisor,
-            0b10111 => Mode::Abort,
-            0b11011 => Mode::Undefined,
-            0b11111 => Mode::System,
-            _ => panic!("Invalid mode: {:02x}", mode),
-        }
+    fn from_field(mode: u32) -> Option<Mode> {
+        let mode =
+            match mode {
+                0b10000 => Mode::User,
+                0b10001 => Mode::Fiq,
+                0b10010 => Mode::Irq,
+                0b10011 => Mode::Supervisor,
+                0b10111 => Mode::Abort,
+                0b11011 => Mode::Undefined,
+                0b11111 => Mode::System,
+                _ => return None,
+            };
+
+        Some(mode)
     }
 
     fn is_privileged(self) -> bool {
@@ -667,3 +1517,1244 @@ impl Mode {
         self != Mode::User && self != Mode::System
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use memory::Word;
+    use interrupt::Interrupt;
+    use lcd::FRAME_TICKS;
+    use test_util;
+
+    use super::*;
+
+    /// Build a CPU executing an LDM with the IRQs enabled and the
+    /// timer 0 interrupt unmasked, then advance the timer by
+    /// `ticks` master clock ticks
+    fn ldm_irq_cpu(ticks: u32) -> Cpu {
+        let mut program = vec![0; 0x20];
+
+        // MSR CPSR_c, R5
+        program[0] = 0xe121f005;
+        // LDMIA R0!, {R1-R4}
+        program[1] = 0xe8b0001e;
+
+        for i in 0..4 {
+            program[0x10 + i] = 0x1000 + i as u32;
+        }
+
+        let mut cpu = test_util::arm_cpu(&program);
+
+        // Supervisor mode, IRQs and FIQs enabled
+        cpu.set_register(5, 0x13);
+        cpu.set_register(0, 0x40);
+
+        cpu.run_next_instruction(&mut ());
+
+        {
+            let inter = cpu.interconnect_mut();
+
+            // Run the CPU clock at the master clock frequency
+            inter.store::<Word>(0x0b000000, 7);
+            inter.store::<Word>(0x0a000008,
+                                Interrupt::Timer0.mask() as u32);
+            inter.store::<Word>(0x0a800000, 100);
+            inter.store::<Word>(0x0a800008, 4);
+
+            for _ in 0..ticks {
+                inter.tick(1);
+            }
+        }
+
+        cpu
+    }
+
+    #[test]
+    fn irq_taken_after_ldm_completes() {
+        // Find out when the timer fires
+        let mut cpu = ldm_irq_cpu(0);
+        let mut ticks = 0;
+
+        while cpu.interconnect_mut().irq_controller().status() == 0 {
+            cpu.interconnect_mut().tick(1);
+            ticks += 1;
+        }
+
+        // Now make the interrupt become pending while the LDM
+        // executes
+        let mut cpu = ldm_irq_cpu(ticks - 1);
+
+        assert!(!cpu.interconnect_mut().irq_controller().pending());
+
+        cpu.run_next_instruction(&mut ());
+
+        // The LDM completed before the interrupt was taken
+        assert_eq!(cpu.cpsr() & 0x1f, Mode::Irq as u32);
+        assert_eq!(cpu.registers()[15], 0x18 + 4);
+        // LR_irq points after the LDM (+4)
+        assert_eq!(cpu.registers()[14], 0x08 + 4);
+        assert_eq!(cpu.registers()[0], 0x50);
+        assert_eq!(&cpu.registers()[1..5], &[0x1000, 0x1001, 0x1002, 0x1003]);
+    }
+
+    /// Program overwriting its first instruction: `MOV R0, #1` is
+    /// replaced by the value of R1 and the loop runs again
+    const SMC_PROGRAM: [u32; 3] = [
+        // MOV R0, #1
+        0xe3a00001,
+        // STR R1, [R2]
+        0xe5821000,
+        // B 0x00
+        0xeafffffc,
+    ];
+
+    #[test]
+    fn decode_cache_self_modifying_code() {
+        let mut cpu = test_util::arm_cpu(&SMC_PROGRAM);
+
+        cpu.set_decode_cache(true);
+
+        // MOV R0, #2
+        cpu.set_register(1, 0xe3a00002);
+        cpu.set_register(2, 0);
+
+        test_util::run(&mut cpu, 3);
+        assert_eq!(cpu.registers()[0], 1);
+
+        test_util::run(&mut cpu, 1);
+        assert_eq!(cpu.registers()[0], 2);
+    }
+
+    #[test]
+    fn decode_cache_frontend_write() {
+        let mut cpu = test_util::arm_cpu(&SMC_PROGRAM);
+
+        cpu.set_decode_cache(true);
+
+        // Store to the other RAM page, leaving the code alone
+        cpu.set_register(1, 0);
+        cpu.set_register(2, 0x400);
+
+        test_util::run(&mut cpu, 4);
+        assert_eq!(cpu.registers()[0], 1);
+
+        // MOV R0, #3, without flushing the cache
+        cpu.interconnect_mut().ram_mut().store::<Word>(0, 0xe3a00003);
+
+        test_util::run(&mut cpu, 3);
+        assert_eq!(cpu.registers()[0], 3);
+    }
+
+    #[test]
+    fn decode_cache_flash_write() {
+        let mut cpu = test_util::arm_cpu(&[]);
+
+        cpu.set_decode_cache(true);
+
+        // Run the program from the physical view of the flash
+        for (i, &w) in SMC_PROGRAM.iter().enumerate() {
+            cpu.interconnect_mut()
+                .store::<Word>(0x08000000 + i as u32 * 4, w);
+        }
+
+        cpu.set_pc(0x08000000);
+        cpu.set_register(1, 0xe3a00002);
+        cpu.set_register(2, 0x08000000);
+
+        test_util::run(&mut cpu, 3);
+        assert_eq!(cpu.registers()[0], 1);
+
+        test_util::run(&mut cpu, 1);
+        assert_eq!(cpu.registers()[0], 2);
+    }
+
+    /// Run `program` with `try_step` and return the result of the
+    /// last instruction
+    fn try_program(program: &[u32]) -> (Cpu, Result<u32, StepError>) {
+        let mut cpu = test_util::arm_cpu(program);
+
+        for _ in 0..(program.len() - 1) {
+            cpu.try_step(&mut ()).unwrap();
+        }
+
+        let r = cpu.try_step(&mut ());
+
+        (cpu, r)
+    }
+
+    #[test]
+    fn step_error_undefined() {
+        let mut cpu = test_util::arm_cpu(&[
+            // ANDNV R0, R0, R0: the NV condition is unpredictable on
+            // ARMv4
+            0xf0000000,
+            // MOV R0, #1
+            0xe3a00001,
+        ]);
+
+        match cpu.try_step(&mut ()) {
+            Err(StepError::UndefinedInstruction(_)) => (),
+            r => panic!("Unexpected result {:?}", r),
+        }
+
+        // The error doesn't stick around
+        assert_eq!(cpu.try_step(&mut ()), Ok(1));
+        assert_eq!(cpu.registers()[0], 1);
+
+        // Thumb BX with its "should be zero" bits set
+        let mut cpu = test_util::arm_cpu(&[]);
+
+        cpu.set_thumb(true);
+
+        match cpu.step_raw(&mut (), 0x4701) {
+            Err(StepError::UndefinedInstruction(_)) => (),
+            r => panic!("Unexpected result {:?}", r),
+        }
+    }
+
+    #[test]
+    fn step_error_bus_error() {
+        let (_, r) = try_program(&[
+            // MOV R1, #1
+            0xe3a01001,
+            // LDRH R0, [R1]
+            0xe1d100b0,
+        ]);
+
+        match r {
+            Err(StepError::BusError(_)) => (),
+            r => panic!("Unexpected result {:?}", r),
+        }
+
+        let (_, r) = try_program(&[
+            // MOV R1, #3
+            0xe3a01003,
+            // STR R0, [R1]
+            0xe5810000,
+        ]);
+
+        match r {
+            Err(StepError::BusError(_)) => (),
+            r => panic!("Unexpected result {:?}", r),
+        }
+
+        // Misaligned ARM PC
+        let mut cpu = test_util::arm_cpu(&[]);
+
+        cpu.set_pc(2);
+
+        match cpu.try_step(&mut ()) {
+            Err(StepError::BusError(_)) => (),
+            r => panic!("Unexpected result {:?}", r),
+        }
+    }
+
+    #[test]
+    fn step_error_unmapped() {
+        let programs = [
+            // MOV R1, #0x0e000000; LDR R2, [R1]
+            [0xe3a0140e, 0xe5912000],
+            // MOV R1, #0x0e000000; STR R2, [R1]
+            [0xe3a0140e, 0xe5812000],
+            // MOV R1, #0x0d000000; LDR R2, [R1, #8] (LCD status,
+            // disabled by default)
+            [0xe3a0140d, 0xe5912008],
+        ];
+
+        for p in &programs {
+            let (_, r) = try_program(p);
+
+            match r {
+                Err(StepError::BusError(_)) => (),
+                r => panic!("Unexpected result {:?}", r),
+            }
+        }
+
+        // Jump to an unmapped address
+        let mut cpu = test_util::arm_cpu(&[]);
+
+        cpu.set_pc(0x0e000000);
+
+        match cpu.try_step(&mut ()) {
+            Err(StepError::BusError(_)) => (),
+            r => panic!("Unexpected result {:?}", r),
+        }
+    }
+
+    #[test]
+    fn step_error_unimplemented() {
+        // SMULBB R0, R1, R2 doesn't exist on ARMv4T
+        let (_, r) = try_program(&[0xe1600281]);
+
+        match r {
+            Err(StepError::UnimplementedInstruction(_)) => (),
+            r => panic!("Unexpected result {:?}", r),
+        }
+    }
+
+    #[test]
+    fn step_error_other() {
+        let (cpu, r) = try_program(&[
+            // MOV R0, #0xd4
+            0xe3a000d4,
+            // MSR CPSR_c, R0 (invalid mode 0b10100)
+            0xe121f000,
+        ]);
+
+        match r {
+            Err(StepError::Other(_)) => (),
+            r => panic!("Unexpected result {:?}", r),
+        }
+
+        // Still in supervisor mode
+        assert_eq!(cpu.cpsr() & 0x1f, Mode::Supervisor as u32);
+
+        let (_, r) = try_program(&[
+            // MOV R0, #0x100
+            0xe3a00c01,
+            // MSR CPSR_f, R0 (reserved bit 8 set)
+            0xe128f000,
+        ]);
+
+        match r {
+            Err(StepError::Other(_)) => (),
+            r => panic!("Unexpected result {:?}", r),
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "Bus error")]
+    fn step_error_panics_in_run_next_instruction() {
+        let mut cpu = test_util::arm_cpu(&[
+            // MOV R1, #1
+            0xe3a01001,
+            // LDRH R0, [R1]
+            0xe1d100b0,
+        ]);
+
+        test_util::run(&mut cpu, 2);
+    }
+
+    #[test]
+    fn reset_vector() {
+        // MOV R0, #0x42
+        let flash = test_util::words_to_bytes(&[0xe3a00042]);
+
+        let mut cpu = Cpu::new(test_util::interconnect(&flash, &[]));
+
+        assert_eq!(cpu.next_pc, 0);
+
+        // Only takes effect at the next reset
+        cpu.set_reset_vector(0x08000000);
+        assert_eq!(cpu.next_pc, 0);
+
+        cpu.reset();
+        assert_eq!(cpu.next_pc, 0x08000000);
+        assert_eq!(cpu.mode, Mode::Supervisor);
+
+        test_util::run(&mut cpu, 1);
+
+        assert_eq!(cpu.registers()[0], 0x42);
+        assert_eq!(cpu.current_pc(), 0x08000000);
+        assert_eq!(cpu.next_pc, 0x08000004);
+    }
+
+    #[test]
+    fn lcd_contention() {
+        // STMIA R0, {R1-R8}
+        let stm = 0xe88001fe;
+
+        let run_stm = |stall: Option<u32>, vblank: bool| {
+            let mut cpu = test_util::arm_cpu(&[stm]);
+
+            cpu.set_register(0, 0x0d000100);
+            cpu.interconnect_mut().set_lcd_contention(stall);
+
+            if vblank {
+                let inter = cpu.interconnect_mut();
+                let ticks = inter.lcd().ticks_to_vblank();
+                let div = ::MASTER_CLOCK_HZ / inter.effective_cpu_hz();
+
+                // Round up to the next CPU cycle
+                inter.tick_batch((ticks + div - 1) / div);
+
+                assert!(inter.lcd().in_vblank());
+            }
+
+            cpu.run_next_instruction(&mut ())
+        };
+
+        // Disabled by default
+        assert_eq!(run_stm(None, false), 1);
+
+        // One stall per framebuffer access during the scanout
+        assert_eq!(run_stm(Some(2), false), 1 + 8 * 2);
+        assert_eq!(run_stm(Some(2), true), 1);
+
+        // The stall cycles are part of the frame's tick total
+        let mut cpu = test_util::arm_cpu(&[stm, stm]);
+
+        cpu.set_register(0, 0x0d000100);
+        cpu.interconnect_mut().set_lcd_contention(Some(3));
+
+        let start = cpu.interconnect().frame_ticks();
+
+        test_util::run(&mut cpu, 2);
+
+        let master_ticks = cpu.interconnect().frame_ticks() - start;
+        let div = ::MASTER_CLOCK_HZ / cpu.interconnect().effective_cpu_hz();
+
+        let cpu_ticks = master_ticks / div;
+
+        assert_eq!(cpu_ticks, 2 * (1 + 8 * 3));
+
+        // Other accesses are not affected
+        let mut cpu = test_util::arm_cpu(&[stm]);
+
+        cpu.set_register(0, 0x100);
+        cpu.interconnect_mut().set_lcd_contention(Some(3));
+
+        assert_eq!(cpu.run_next_instruction(&mut ()), 1);
+    }
+
+    #[test]
+    fn lcd_contention_cpu_only() {
+        let mut cpu = test_util::arm_cpu(&[]);
+
+        cpu.interconnect_mut().set_lcd_contention(Some(3));
+
+        let inter = cpu.interconnect_mut();
+
+        assert!(!inter.lcd().in_vblank());
+
+        // Frontend and HLE accesses don't stall the CPU
+        inter.store::<Word>(0x0d000100, 0x12345678);
+        inter.load::<Word>(0x0d000100);
+        assert_eq!(inter.take_stall_cycles(), 0);
+
+        // CPU accesses do
+        inter.sync_load::<Word>(0x0d000100);
+        inter.try_sync_store::<Word>(0x0d000100, 0).unwrap();
+        assert_eq!(inter.take_stall_cycles(), 6);
+    }
+
+    /// Return the value of the stack pointer banked for `mode`
+    fn banked_sp(cpu: &mut Cpu, mode: Mode) -> u32 {
+        let cur = cpu.mode;
+
+        cpu.change_mode(mode);
+        let sp = cpu.registers[13];
+        cpu.change_mode(cur);
+
+        sp
+    }
+
+    #[test]
+    fn fast_boot() {
+        // MOV R0, #0x42
+        // ADD R1, PC, #1
+        // BX R1
+        // (Thumb) MOV R2, #0x24
+        let flash = test_util::words_to_bytes(&[0xe3a00042,
+                                                0xe28f1001,
+                                                0xe12fff11,
+                                                0x46c02224]);
+
+        let mut cpu = Cpu::new(test_util::interconnect(&flash, &[]));
+
+        cpu.fast_boot(0x02000000);
+
+        assert_eq!(cpu.next_pc, 0x02000000);
+        assert_eq!(cpu.mode, Mode::User);
+        assert!(cpu.irq_en);
+        assert!(cpu.fiq_en);
+
+        assert_eq!(banked_sp(&mut cpu, Mode::Fiq), 0x800);
+        assert_eq!(banked_sp(&mut cpu, Mode::Irq), 0x7c0);
+        assert_eq!(banked_sp(&mut cpu, Mode::Supervisor), 0x780);
+        assert_eq!(banked_sp(&mut cpu, Mode::User), 0x700);
+
+        // The BIOS is empty, if it ever ran R0 wouldn't be set
+        test_util::run(&mut cpu, 4);
+
+        assert_eq!(cpu.registers()[0], 0x42);
+        assert_eq!(cpu.registers()[2], 0x24);
+        assert!(cpu.thumb);
+        assert_eq!(cpu.current_pc(), 0x0200000c);
+
+        // Thumb entry point
+        let flash = test_util::halfwords_to_bytes(&[0x2024]);
+
+        let mut cpu = Cpu::new(test_util::interconnect(&flash, &[]));
+
+        cpu.fast_boot(0x02000001);
+        test_util::run(&mut cpu, 1);
+
+        assert!(cpu.thumb);
+        assert_eq!(cpu.registers()[0], 0x24);
+        assert_eq!(cpu.mode, Mode::User);
+    }
+
+    /// Boot the real BIOS from the file in `PKSX_BIOS` (with the
+    /// optional flash image in `PKSX_FLASH`) until it jumps to the
+    /// application and check that `fast_boot` leaves the CPU in the
+    /// same state. Ignored since we can't distribute the BIOS.
+    #[test]
+    #[ignore]
+    fn fast_boot_matches_bios() {
+        use std::env;
+        use std::fs::File;
+        use std::io::Read;
+        use dac::Dac;
+        use memory::bios::Bios;
+        use memory::flash::{Flash, FLASH_SIZE};
+
+        let read = |var: &str| {
+            env::var(var).ok().map(|path| {
+                let mut data = Vec::new();
+
+                File::open(&path).and_then(|mut f| f.read_to_end(&mut data))
+                    .unwrap_or_else(|e| panic!("Can't read {}: {}", path, e));
+
+                data
+            })
+        };
+
+        let bios = read("PKSX_BIOS").expect("PKSX_BIOS is not set");
+        let flash = read("PKSX_FLASH").unwrap_or(vec![0xff; FLASH_SIZE]);
+
+        let build = || {
+            let dac = Dac::new(Box::new(test_util::NullBackend));
+
+            Cpu::new(Interconnect::new(Bios::new(&bios).unwrap(),
+                                       Flash::new(&flash).unwrap(),
+                                       dac))
+        };
+
+        let mut boot = build();
+
+        // Run the BIOS until it jumps into the virtual flash
+        let mut steps = 0;
+
+        while boot.next_pc < 0x02000000 || boot.next_pc >= 0x03000000 {
+            assert!(steps < 100_000_000, "BIOS never started the app");
+
+            boot.run_next_instruction(&mut ());
+            steps += 1;
+        }
+
+        let mut fast = build();
+
+        fast.fast_boot(boot.next_pc | boot.thumb as u32);
+
+        assert_eq!(fast.next_pc, boot.next_pc);
+        assert_eq!(fast.thumb, boot.thumb);
+        assert_eq!(fast.mode, boot.mode);
+        assert_eq!(fast.irq_en, boot.irq_en);
+        assert_eq!(fast.fiq_en, boot.fiq_en);
+
+        for &mode in &[Mode::User, Mode::Fiq, Mode::Irq, Mode::Supervisor] {
+            assert_eq!(banked_sp(&mut fast, mode),
+                       banked_sp(&mut boot, mode),
+                       "{:?} stack pointer", mode);
+        }
+    }
+
+    #[test]
+    fn condition_cycles() {
+        // CMP R0, #0, then `instruction`
+        let run = |r0: u32, instruction: u32| {
+            let mut cpu = test_util::arm_cpu(&[0xe3500000, instruction]);
+
+            cpu.set_register(0, r0);
+            cpu.run_next_instruction(&mut ());
+
+            let cycles = cpu.run_next_instruction(&mut ());
+
+            (cycles, cpu.registers()[0], cpu.next_pc)
+        };
+
+        // MOVEQ R0, #2: skipped, then executed
+        let moveq = 0x03a00002;
+
+        assert_eq!(run(1, moveq), (1, 1, 8));
+        assert_eq!(run(0, moveq), (1, 2, 8));
+
+        // BEQ 0x10: a skipped branch doesn't flush the pipeline
+        let beq = 0x0a000001;
+
+        assert_eq!(run(1, beq), (1, 1, 8));
+        assert_eq!(run(0, beq), (3, 0, 0x10));
+    }
+
+    #[test]
+    fn branch_cycles() {
+        // MOV R0, R0
+        let mut cpu = test_util::arm_cpu(&[0xe1a00000]);
+        assert_eq!(cpu.run_next_instruction(&mut ()), 1);
+
+        // B +0
+        let mut cpu = test_util::arm_cpu(&[0xeaffffff]);
+        assert_eq!(cpu.run_next_instruction(&mut ()), 3);
+
+        // BL +0
+        let mut cpu = test_util::arm_cpu(&[0xebffffff]);
+        assert_eq!(cpu.run_next_instruction(&mut ()), 3);
+
+        // BX R1
+        let mut cpu = test_util::arm_cpu(&[0xe12fff11]);
+        cpu.set_register(1, 0x100);
+        assert_eq!(cpu.run_next_instruction(&mut ()), 3);
+
+        // MOV PC, R1
+        let mut cpu = test_util::arm_cpu(&[0xe1a0f001]);
+        cpu.set_register(1, 0x100);
+        assert_eq!(cpu.run_next_instruction(&mut ()), 3);
+        assert_eq!(cpu.next_pc, 0x100);
+    }
+
+    #[test]
+    fn irq_entry_cycles() {
+        // MOV R0, R0
+        let nop = 0xe1a00000;
+
+        let run = |irq: bool| {
+            let mut cpu = test_util::arm_cpu(&[nop]);
+
+            cpu.irq_en = true;
+
+            {
+                let inter = cpu.interconnect_mut();
+
+                inter.store::<Word>(0x0a000008,
+                                    Interrupt::Timer0.mask() as u32);
+                inter.irq_controller_mut()
+                    .set_raw_interrupt(Interrupt::Timer0, irq);
+            }
+
+            let cycles = cpu.run_next_instruction(&mut ());
+
+            (cycles, cpu.next_pc, cpu.mode)
+        };
+
+        assert_eq!(run(false), (1, 4, Mode::Supervisor));
+        // The pipeline is refilled from the IRQ vector
+        assert_eq!(run(true), (1 + 3, 0x18, Mode::Irq));
+    }
+
+    #[test]
+    fn execute_raw() {
+        // The RAM is filled with garbage, execute_raw doesn't fetch
+        let data = test_util::words_to_bytes(&[0xcacacaca, 0x12345678]);
+
+        let mut cpu = Cpu::new(test_util::interconnect(&[], &data));
+
+        cpu.interconnect_mut().store::<Word>(0x06000000, 3);
+        cpu.set_pc(0);
+
+        cpu.set_register(0, 0x1000);
+        cpu.set_register(1, 0x234);
+
+        // ADD R2, R0, R1
+        assert_eq!(cpu.execute_raw(&mut (), 0xe0802001), 1);
+
+        assert_eq!(cpu.registers()[2], 0x1234);
+        assert_eq!(cpu.current_pc(), 0);
+        assert_eq!(cpu.next_pc, 4);
+
+        // LDR R3, [R4, #4]
+        cpu.set_register(4, 0);
+
+        cpu.execute_raw(&mut (), 0xe5943004);
+
+        assert_eq!(cpu.registers()[3], 0x12345678);
+        assert_eq!(cpu.next_pc, 8);
+
+        // Thumb: ADD R0, R0, #1, the high half is ignored
+        cpu.set_thumb(true);
+        cpu.set_pc(0x100);
+
+        cpu.execute_raw(&mut (), 0xffff1c40);
+
+        assert_eq!(cpu.registers()[0], 0x1001);
+        assert_eq!(cpu.next_pc, 0x102);
+    }
+
+    #[test]
+    fn run_unbounded() {
+        use rtc::Bcd;
+
+        // B .
+        let mut cpu = test_util::arm_cpu(&[0xeafffffe]);
+
+        {
+            let inter = cpu.interconnect_mut();
+
+            // Slowest CPU clock, to cover a few seconds with a
+            // reasonable number of instructions
+            inter.store::<Word>(0x0b000000, 0);
+
+            let rtc = inter.rtc_mut();
+
+            rtc.set_hours(Bcd::from_bcd(0x12).unwrap());
+            rtc.set_minutes(Bcd::from_bcd(0x00).unwrap());
+            rtc.set_seconds(Bcd::from_bcd(0x00).unwrap());
+        }
+
+        let div = ::MASTER_CLOCK_HZ / cpu.interconnect().effective_cpu_hz();
+        let budget = 33000;
+
+        let ticks = cpu.run_unbounded(&mut (), budget);
+
+        // Every branch refills the pipeline
+        assert_eq!(ticks, budget as u64 * 3 * div as u64);
+
+        // A bit more than 3 seconds, the RTC ticks at 0.5s, 1.5s and
+        // 2.5s
+        let seconds = ticks as f64 / ::MASTER_CLOCK_HZ as f64;
+
+        assert!(seconds > 3. && seconds < 3.5);
+        let time = cpu.interconnect_mut().load::<Word>(0x0b800008);
+
+        assert_eq!(time & 0xffffff, 0x120003);
+
+        // The frame counter is reset for the next run
+        assert_eq!(cpu.interconnect().frame_ticks(), 0);
+    }
+
+    #[test]
+    fn power_on_state() {
+        let check = |cpu: &Cpu| {
+            // Supervisor mode, IRQ and FIQ disabled, ARM state
+            assert_eq!(cpu.cpsr() & 0xff, 0xd3);
+            assert_eq!(cpu.mode, Mode::Supervisor);
+            assert!(!cpu.irq_en);
+            assert!(!cpu.fiq_en);
+            assert!(!cpu.thumb);
+            assert_eq!(cpu.next_pc, 0);
+        };
+
+        let mut cpu = Cpu::new(test_util::interconnect(&[], &[]));
+
+        check(&cpu);
+
+        // The first instruction is fetched from the reset vector, in
+        // the BIOS
+        assert_eq!(cpu.registers()[15], 4);
+
+        // Reset from an arbitrary state
+        cpu.fast_boot(0x02000001);
+
+        assert!(cpu.irq_en);
+        assert_eq!(cpu.mode, Mode::User);
+
+        cpu.interconnect_mut().store::<Word>(0x0a000008, 0xff);
+
+        cpu.reset();
+
+        check(&cpu);
+
+        // The interconnect has been reset as well
+        assert_eq!(cpu.interconnect_mut().irq_controller().mask(), 0);
+    }
+
+    #[test]
+    fn prefetch_abort() {
+        for &thumb in &[false, true] {
+            let mut cpu = test_util::arm_cpu(&[]);
+
+            cpu.set_exec_protection(true);
+
+            cpu.fast_boot(0x0a000000 | thumb as u32);
+
+            let cpsr = cpu.cpsr();
+
+            assert_eq!(cpu.try_step(&mut ()).unwrap(), 3);
+
+            assert_eq!(cpu.mode, Mode::Abort);
+            assert_eq!(cpu.next_pc, 0xc);
+            assert_eq!(cpu.registers()[14], 0x0a000004);
+            assert_eq!(cpu.spsr, cpsr);
+            assert!(!cpu.thumb);
+            assert!(!cpu.irq_en);
+        }
+    }
+
+    #[test]
+    fn exec_protection_off() {
+        let mut cpu = test_util::arm_cpu(&[]);
+
+        // Execute the IRQ controller registers: the latch reads as 0,
+        // which decodes as ANDEQ R0, R0, R0
+        cpu.set_pc(0x0a000000);
+
+        cpu.run_next_instruction(&mut ());
+
+        assert_eq!(cpu.mode, Mode::Supervisor);
+        assert_eq!(cpu.next_pc, 0x0a000004);
+    }
+
+    #[test]
+    fn thumb_and_flags_api() {
+        let mut cpu = test_util::arm_cpu(&[
+            // MOV R0, #1
+            0xe3a00001,
+            // Thumb: MOVS R0, #2; MOVS R1, #3
+            0x21032002,
+            // MOV R2, #4
+            0xe3a02004,
+        ]);
+
+        cpu.run_next_instruction(&mut ());
+        assert_eq!(cpu.registers()[0], 1);
+        assert_eq!(cpu.next_pc, 4);
+
+        cpu.set_thumb(true);
+        assert!(cpu.is_thumb());
+        assert_eq!(cpu.cpsr() & 0x20, 0x20);
+
+        // Two 16bit instructions in the same word
+        cpu.run_next_instruction(&mut ());
+        assert_eq!(cpu.registers()[0], 2);
+        assert_eq!(cpu.next_pc, 6);
+
+        cpu.run_next_instruction(&mut ());
+        assert_eq!(cpu.registers()[1], 3);
+        assert_eq!(cpu.next_pc, 8);
+
+        cpu.set_thumb(false);
+        assert!(!cpu.is_thumb());
+        assert_eq!(cpu.cpsr() & 0x20, 0);
+
+        cpu.run_next_instruction(&mut ());
+        assert_eq!(cpu.registers()[2], 4);
+        assert_eq!(cpu.next_pc, 0xc);
+
+        // Condition flags
+        for nzcv in 0..16 {
+            cpu.set_n(nzcv & 8 != 0);
+            cpu.set_z(nzcv & 4 != 0);
+            cpu.set_c(nzcv & 2 != 0);
+            cpu.set_v(nzcv & 1 != 0);
+
+            assert_eq!(cpu.cpsr() >> 28, nzcv);
+            assert_eq!((cpu.n(), cpu.z(), cpu.c(), cpu.v()),
+                       (nzcv & 8 != 0,
+                        nzcv & 4 != 0,
+                        nzcv & 2 != 0,
+                        nzcv & 1 != 0));
+        }
+    }
+
+    #[test]
+    fn run_to_vblank() {
+        // B .
+        let mut cpu = test_util::arm_cpu(&[0xeafffffe]);
+
+        // Slowest clock, each CPU cycle is 128 master ticks
+        let cycle_ticks = 1 << 7;
+
+        // The first call only runs to the first vblank, from mid-frame
+        cpu.run_to_vblank(&mut ());
+        assert!(cpu.interconnect().lcd().in_vblank());
+
+        let mut start = cpu.interconnect().frame_ticks();
+
+        for _ in 0..2 {
+            let cycles = cpu.run_to_vblank(&mut ());
+
+            let end = cpu.interconnect().frame_ticks();
+            let elapsed = end - start;
+
+            assert!(cpu.interconnect().lcd().in_vblank());
+            assert_eq!(elapsed, cycles * cycle_ticks);
+
+            // Within one `B` instruction (3 cycles) of a full frame
+            let slack = 3 * cycle_ticks;
+
+            assert!(elapsed + slack >= FRAME_TICKS,
+                    "{} {}", elapsed, FRAME_TICKS);
+            assert!(elapsed <= FRAME_TICKS + slack,
+                    "{} {}", elapsed, FRAME_TICKS);
+
+            start = end;
+        }
+    }
+
+    #[test]
+    fn nested_irq() {
+        let mut program = vec![0; 0x10];
+
+        // Reset: B 0x40
+        program[0x00 / 4] = 0xea00000e;
+        // IRQ vector: B 0x80
+        program[0x18 / 4] = 0xea000018;
+
+        let main = [
+            // 0x40: MOV R2, #0xd2 (IRQ mode, interrupts disabled)
+            0xe3a020d2,
+            // 0x44: MSR CPSR_c, R2
+            0xe121f002,
+            // 0x48: MOV SP, #0x800
+            0xe3a0db02,
+            // 0x4c: MOV R2, #0x5f (System mode, IRQs enabled)
+            0xe3a0205f,
+            // 0x50: MSR CPSR_c, R2
+            0xe121f002,
+            // 0x54: ADD R4, R4, #1
+            0xe2844001,
+            // 0x58: B 0x54
+            0xeafffffd,
+            0, 0, 0, 0, 0, 0, 0, 0, 0,
+        ];
+
+        // Re-entrant IRQ handler: the SPSR and LR are saved before
+        // re-enabling the IRQs
+        let handler = [
+            // 0x80: SUB LR, LR, #4
+            0xe24ee004,
+            // 0x84: PUSH {R0, R1, R2, LR}
+            0xe92d4007,
+            // 0x88: MRS R0, SPSR
+            0xe14f0000,
+            // 0x8c: PUSH {R0}
+            0xe92d0001,
+            // 0x90: MOV R1, #0x0a000000
+            0xe3a0140a,
+            // 0x94: LDR R0, [R1]
+            0xe5910000,
+            // 0x98: STR R0, [R1, #0x10]
+            0xe5810010,
+            // 0x9c: STR R0, [R5], #4
+            0xe4850004,
+            // 0xa0: MOV R2, #0x52 (IRQs enabled)
+            0xe3a02052,
+            // 0xa4: MSR CPSR_c, R2
+            0xe121f002,
+            // 0xa8: NOP
+            0xe1a00000,
+            // 0xac: NOP
+            0xe1a00000,
+            // 0xb0: MOV R2, #0xd2 (IRQs disabled)
+            0xe3a020d2,
+            // 0xb4: MSR CPSR_c, R2
+            0xe121f002,
+            // 0xb8: POP {R0}
+            0xe8bd0001,
+            // 0xbc: MSR SPSR_fsxc, R0
+            0xe16ff000,
+            // 0xc0: LDMFD SP!, {R0, R1, R2, PC}^
+            0xe8fd8007,
+        ];
+
+        program.extend_from_slice(&main);
+        program.extend_from_slice(&handler);
+
+        let mut cpu = test_util::arm_cpu(&program);
+
+        let timer0 = Interrupt::Timer0;
+        let timer1 = Interrupt::Timer1;
+
+        cpu.interconnect_mut()
+            .store::<Word>(0x0a000008, (timer0.mask() | timer1.mask()) as u32);
+
+        cpu.set_register(0, 0x1111);
+        cpu.set_register(1, 0x2222);
+        cpu.set_register(5, 0x700);
+
+        let mut trace = Vec::new();
+
+        for step in 0..200 {
+            let pc = cpu.next_pc;
+
+            // The low priority interrupt fires in the main loop, the
+            // high priority one once its handler re-enabled the IRQs
+            if step == 20 {
+                cpu.interconnect_mut()
+                    .irq_controller_mut()
+                    .set_raw_interrupt(timer0, true);
+            }
+
+            let irqs = trace.iter().filter(|&&p| p == 0x18).count();
+
+            if pc == 0xa8 && irqs == 1 {
+                cpu.interconnect_mut()
+                    .irq_controller_mut()
+                    .set_raw_interrupt(timer1, true);
+            }
+
+            trace.push(pc);
+
+            cpu.run_next_instruction(&mut ());
+        }
+
+        let first = trace.iter().position(|&p| p == 0x18).unwrap();
+        let trace = &trace[first..];
+
+        // Addresses of the instructions in `start..end`
+        let range = |start: u32, end: u32| (start / 4..end / 4).map(|i| i * 4);
+
+        let mut expected = vec![0x18];
+
+        // Low priority handler up to the first NOP
+        expected.extend(range(0x80, 0xac));
+        // Preempted by the high priority handler
+        expected.push(0x18);
+        expected.extend(range(0x80, 0xc4));
+        // Back to the low priority handler
+        expected.extend(range(0xac, 0xc4));
+
+        assert_eq!(&trace[..expected.len()], &expected[..]);
+
+        // Then back to the main loop
+        assert!(trace[expected.len()..].iter()
+                .all(|&p| p == 0x54 || p == 0x58));
+
+        // Both interrupts were serviced in order
+        assert_eq!(cpu.interconnect_mut().load::<Word>(0x700),
+                   timer0.mask() as u32);
+        assert_eq!(cpu.interconnect_mut().load::<Word>(0x704),
+                   timer1.mask() as u32);
+        assert_eq!(cpu.registers()[5], 0x708);
+
+        // The interrupted code's state was restored
+        assert_eq!(cpu.mode, Mode::System);
+        assert!(cpu.irq_en);
+        assert_eq!(cpu.registers()[0], 0x1111);
+        assert_eq!(cpu.registers()[1], 0x2222);
+        assert_eq!(cpu.registers()[2], 0x5f);
+        assert_eq!(banked_sp(&mut cpu, Mode::Irq), 0x800);
+    }
+
+    #[test]
+    fn disassemble_range() {
+        let cpu = test_util::arm_cpu(&[
+            // MOV R0, #1
+            0xe3a00001,
+            // ADD R1, R0, R0
+            0xe0801000,
+            // LDR R2, [R0]
+            0xe5902000,
+            // PUSH {R4, LR}
+            0xe92d4010,
+            // SWI 0x10
+            0xef000010,
+            // B .
+            0xeafffffe,
+            // Thumb: MOVS R0, #1; ADDS R1, R0, R0
+            0x18092001,
+            // Thumb: PUSH {R4, LR}; B .
+            0xe7feb510,
+        ]);
+
+        let arm = cpu.disassemble_range(0, 6, false);
+
+        assert_eq!(arm,
+                   [(0x00, 0xe3a00001, "data processing (immediate)"),
+                    (0x04, 0xe0801000, "data processing (register)"),
+                    (0x08, 0xe5902000, "load/store (immediate offset)"),
+                    (0x0c, 0xe92d4010, "load/store multiple"),
+                    (0x10, 0xef000010, "software interrupt"),
+                    (0x14, 0xeafffffe, "branch")]
+                   .iter()
+                   .map(|&(a, r, d)| (a, Some(r), d.to_string()))
+                   .collect::<Vec<_>>());
+
+        let thumb = cpu.disassemble_range(0x18, 4, true);
+
+        assert_eq!(thumb,
+                   [(0x18, 0x2001, "add/subtract/compare/move immediate"),
+                    (0x1a, 0x1809, "add/subtract"),
+                    (0x1c, 0xb510, "miscellaneous"),
+                    (0x1e, 0xe7fe, "unconditional branch")]
+                   .iter()
+                   .map(|&(a, r, d)| (a, Some(r), d.to_string()))
+                   .collect::<Vec<_>>());
+
+        // Nothing was executed
+        assert_eq!(cpu.next_pc, 0);
+        assert_eq!(cpu.registers()[0], 0xdeadbeef);
+
+        // Unmapped addresses don't panic
+        let unmapped = |a| (a, None, "<unmapped>".to_string());
+
+        let end = cpu.disassemble_range(0x0dfffffc, 2, false);
+
+        assert_eq!(end, [unmapped(0x0dfffffc), unmapped(0x0e000000)]);
+
+        let end = cpu.disassemble_range(0x7fe, 2, true);
+
+        assert!(end[0].1.is_some());
+        assert_eq!(end[1], unmapped(0x800));
+    }
+
+    #[test]
+    fn flags_nibble() {
+        let mut cpu = test_util::arm_cpu(&[]);
+
+        cpu.set_flags_nibble(0);
+        assert_eq!(cpu.flags_nibble(), 0);
+
+        let flags: [(fn(&mut Cpu, bool), u32); 4] = [(Cpu::set_n, 8),
+                                                     (Cpu::set_z, 4),
+                                                     (Cpu::set_c, 2),
+                                                     (Cpu::set_v, 1)];
+
+        for &(set, bit) in &flags {
+            set(&mut cpu, true);
+            assert_eq!(cpu.flags_nibble(), bit);
+            set(&mut cpu, false);
+            assert_eq!(cpu.flags_nibble(), 0);
+        }
+
+        for nzcv in 0..16 {
+            // Only the low nibble is used by the setter
+            cpu.set_flags_nibble(nzcv | 0xfffffff0);
+
+            assert_eq!(cpu.flags_nibble(), nzcv);
+            assert_eq!(cpu.cpsr() >> 28, nzcv);
+            assert_eq!(cpu.cpsr() & 0xff, 0xd3);
+        }
+    }
+
+    #[test]
+    fn predict_next_pc() {
+        let mut cpu = test_util::arm_cpu(&[
+            // B 0x18
+            0xea000004,
+            // BEQ 0x14
+            0x0a000002,
+            // BX R0
+            0xe12fff10,
+            // MOV R0, #1
+            0xe3a00001,
+            // MOV PC, LR
+            0xe1a0f00e,
+        ]);
+
+        let expected = [
+            NextPc::Branch(0x18),
+            NextPc::Conditional(0x14),
+            NextPc::Indirect,
+            NextPc::Sequential,
+            NextPc::Indirect,
+        ];
+
+        for (i, &e) in expected.iter().enumerate() {
+            cpu.set_pc(i as u32 * 4);
+            assert_eq!(cpu.predict_next_pc(), Some(e));
+        }
+
+        let mut cpu = test_util::thumb_cpu(&[
+            // B 0x8
+            0xe002,
+            // BEQ 0x8
+            0xd001,
+            // BX R0
+            0x4700,
+            // MOVS R0, #1
+            0x2001,
+        ]);
+
+        let expected = [
+            NextPc::Branch(0x8),
+            NextPc::Conditional(0x8),
+            NextPc::Indirect,
+            NextPc::Sequential,
+        ];
+
+        for (i, &e) in expected.iter().enumerate() {
+            cpu.set_pc(i as u32 * 2);
+            assert_eq!(cpu.predict_next_pc(), Some(e));
+        }
+
+        // The prediction doesn't depend on the flags and doesn't
+        // execute anything
+        cpu.set_z(false);
+        cpu.set_pc(2);
+        assert_eq!(cpu.predict_next_pc(), Some(NextPc::Conditional(0x8)));
+        assert_eq!(cpu.registers()[0], 0xdeadbeef);
+        assert_eq!(cpu.next_pc, 2);
+
+        // Unmapped memory
+        cpu.set_pc(0x0e000000);
+        assert_eq!(cpu.predict_next_pc(), None);
+
+        cpu.set_thumb(false);
+        cpu.set_pc(0x0e000000);
+        assert_eq!(cpu.predict_next_pc(), None);
+    }
+
+    #[test]
+    fn swi_entry_and_return() {
+        let mut program = vec![0; 0x40];
+
+        // SWI vector: MOVS PC, LR
+        program[0x08 / 4] = 0xe1b0f00e;
+
+        // MOV R0, #1
+        program[0x40 / 4] = 0xe3a00001;
+        // SWI 0x12
+        program[0x44 / 4] = 0xef000012;
+        // MOV R1, #2
+        program[0x48 / 4] = 0xe3a01002;
+        // Thumb: SWI 0x12, MOVS R1, #3
+        program[0x50 / 4] = 0x2103df12;
+
+        let mut cpu = test_util::arm_cpu(&program);
+
+        cpu.fast_boot(0x40);
+        cpu.set_flags_nibble(0x5);
+
+        let user_lr = cpu.registers()[14];
+
+        test_util::run(&mut cpu, 1);
+
+        let caller_cpsr = cpu.cpsr();
+
+        assert_eq!(caller_cpsr & 0xff, 0x10);
+
+        // Entry
+        test_util::run(&mut cpu, 1);
+
+        assert_eq!(cpu.mode, Mode::Supervisor);
+        assert!(!cpu.irq_en);
+        assert!(!cpu.thumb);
+        assert_eq!(cpu.next_pc, 0x08);
+        assert_eq!(cpu.registers()[14], 0x48);
+        assert_eq!(cpu.spsr, caller_cpsr);
+
+        // Return
+        test_util::run(&mut cpu, 1);
+
+        assert_eq!(cpu.cpsr(), caller_cpsr);
+        assert_eq!(cpu.next_pc, 0x48);
+        assert_eq!(cpu.registers()[14], user_lr);
+
+        test_util::run(&mut cpu, 1);
+        assert_eq!(cpu.registers()[1], 2);
+
+        // From Thumb state LR also points to the next instruction
+        cpu.set_thumb(true);
+        cpu.set_pc(0x50);
+
+        let caller_cpsr = cpu.cpsr();
+
+        test_util::run(&mut cpu, 1);
+
+        assert_eq!(cpu.mode, Mode::Supervisor);
+        assert!(!cpu.thumb);
+        assert_eq!(cpu.next_pc, 0x08);
+        assert_eq!(cpu.registers()[14], 0x52);
+        assert_eq!(cpu.spsr, caller_cpsr);
+        assert!(cpu.spsr & 0x20 != 0);
+
+        test_util::run(&mut cpu, 1);
+
+        assert_eq!(cpu.cpsr(), caller_cpsr);
+        assert!(cpu.thumb);
+        assert_eq!(cpu.next_pc, 0x52);
+
+        test_util::run(&mut cpu, 1);
+        assert_eq!(cpu.registers()[1], 3);
+    }
+}
@@ -0,0 +1,208 @@
+//! ARM semihosting support. Lets bare-metal test programs use the
+//! host's console through special SWIs. Only the console output
+//! operations are implemented for now.
+//!
+//! Semihosting calls use `SWI 0x123456` in ARM mode and `SWI 0xab`
+//! in Thumb mode. The operation number is in R0 and its parameter
+//! (or a pointer to a parameter block) is in R1. The `BKPT 0xab`
+//! variant is not supported since BKPT doesn't exist on ARMv4T.
+
+use std::io::Write;
+
+use rustc_serialize::{Decodable, Encodable, Decoder, Encoder};
+
+use memory::Byte;
+
+use super::{Cpu, RegisterIndex};
+
+pub struct Semihosting {
+    /// Sink for the console output. Semihosting is disabled if this
+    /// is `None`.
+    output: Option<Box<Write>>,
+}
+
+impl Semihosting {
+    pub fn new() -> Semihosting {
+        Semihosting {
+            output: None,
+        }
+    }
+
+    pub fn set_output(&mut self, output: Option<Box<Write>>) {
+        self.output = output
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.output.is_some()
+    }
+
+    fn write(&mut self, buf: &[u8]) {
+        if let Some(ref mut output) = self.output {
+            if let Err(e) = output.write_all(buf) {
+                warn!("Semihosting output error: {}", e);
+            }
+        }
+    }
+}
+
+/// Called when the CPU executes a SWI with the given `comment`
+/// field. Returns `true` if it was a semihosting call, in which case
+/// it's been handled and the SWI exception must not be taken.
+pub fn swi(cpu: &mut Cpu, comment: u32) -> bool {
+    if !cpu.semihosting.enabled() {
+        return false;
+    }
+
+    let semihosting_swi =
+        if cpu.thumb {
+            THUMB_SWI
+        } else {
+            ARM_SWI
+        };
+
+    if comment != semihosting_swi {
+        return false;
+    }
+
+    let op = cpu.reg(RegisterIndex(0));
+    let param = cpu.reg(RegisterIndex(1));
+
+    match op {
+        SYS_WRITEC => {
+            let c = cpu.inter.peek::<Byte>(param) as u8;
+
+            cpu.semihosting.write(&[c]);
+        }
+        SYS_WRITE0 => {
+            let mut s = Vec::new();
+            let mut addr = param;
+
+            loop {
+                let c = cpu.inter.peek::<Byte>(addr) as u8;
+
+                if c == 0 {
+                    break;
+                }
+
+                s.push(c);
+                addr = addr.wrapping_add(1);
+            }
+
+            cpu.semihosting.write(&s);
+        }
+        _ => {
+            warn!("Unsupported semihosting operation 0x{:02x}", op);
+            // Report the failure to the caller
+            cpu.set_reg(RegisterIndex(0), !0);
+        }
+    }
+
+    true
+}
+
+impl Encodable for Semihosting {
+    fn encode<S: Encoder>(&self, s: &mut S) -> Result<(), S::Error> {
+        // We don't serialize the output, it'll be up to the frontend
+        // to reset it.
+        s.emit_nil()
+    }
+}
+
+impl Decodable for Semihosting {
+    fn decode<D: Decoder>(d: &mut D) -> Result<Semihosting, D::Error> {
+        try!(d.read_nil());
+
+        Ok(Semihosting::new())
+    }
+}
+
+/// SWI comment field used for semihosting calls in ARM mode
+const ARM_SWI: u32 = 0x123456;
+/// SWI comment field used for semihosting calls in Thumb mode
+const THUMB_SWI: u32 = 0xab;
+
+/// Write the character pointed to by R1 to the console
+const SYS_WRITEC: u32 = 0x03;
+/// Write the NUL-terminated string pointed to by R1 to the console
+const SYS_WRITE0: u32 = 0x04;
+
+#[cfg(test)]
+mod tests {
+    use std::io::{self, Write};
+    use std::rc::Rc;
+    use std::cell::RefCell;
+
+    use test_util;
+
+    /// Writer appending to a shared buffer so the test can inspect
+    /// the output after handing the writer to the CPU
+    struct SharedBuf(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn program() -> Vec<u32> {
+        let mut program = vec![
+            // MOV R0, #4 (SYS_WRITE0)
+            0xe3a00004,
+            // MOV R1, #0x40
+            0xe3a01040,
+            // SWI 0x123456
+            0xef123456,
+            // MOV R0, #3 (SYS_WRITEC)
+            0xe3a00003,
+            // MOV R1, #0x50
+            0xe3a01050,
+            // SWI 0x123456
+            0xef123456,
+            // B .
+            0xeafffffe,
+        ];
+
+        program.resize(0x18, 0);
+
+        // "Hello\0" at 0x40
+        program[0x10] = 0x6c6c6548;
+        program[0x11] = 0x0000006f;
+        // '!' at 0x50
+        program[0x14] = 0x21;
+
+        program
+    }
+
+    #[test]
+    fn write0_and_writec() {
+        let output = Rc::new(RefCell::new(Vec::new()));
+
+        let mut cpu = test_util::arm_cpu(&program());
+
+        cpu.set_semihosting_output(Some(Box::new(SharedBuf(output.clone()))));
+
+        test_util::run(&mut cpu, 7);
+
+        assert_eq!(&output.borrow()[..], b"Hello!");
+
+        // No SWI exception was taken, we're stuck in the final loop
+        assert_eq!(cpu.registers()[15], 0x18 + 4);
+    }
+
+    #[test]
+    fn disabled_by_default() {
+        let mut cpu = test_util::arm_cpu(&program());
+
+        test_util::run(&mut cpu, 3);
+
+        // The SWI exception is taken like any other SWI
+        assert_eq!(cpu.registers()[15], 0x08 + 4);
+        assert_eq!(cpu.registers()[14], 0x0c);
+    }
+}
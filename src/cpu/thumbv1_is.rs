@@ -5,15 +5,94 @@ use std::fmt;
 use memory::{Word, HalfWord, Byte};
 use debugger::Debugger;
 
-use super::{Cpu, RegisterIndex};
-
-pub fn execute<D>(cpu: &mut Cpu, debugger: &mut D, instruction: u16)
+use super::{Cpu, RegisterIndex, NextPc, add_with_flags, sub_with_flags};
+use super::{semihosting, hle};
+
+/// Execute an instruction previously returned by `decode`
+pub fn execute_decoded<D>(cpu: &mut Cpu,
+                          debugger: &mut D,
+                          instruction: Instruction,
+                          handler: Handler)
     where D: Debugger {
+    handler(instruction, debugger, cpu);
+}
+
+/// Look up the handler for `instruction`
+pub fn decode(instruction: u16) -> (Instruction, Handler) {
     let instruction = Instruction(instruction);
 
-    instruction.execute(debugger, cpu);
+    (instruction, OPCODE_LUT[instruction.opcode() as usize])
+}
+
+/// Return a short description of the class of `instruction`. We
+/// don't have a real disassembler yet.
+pub fn describe(instruction: u16) -> &'static str {
+    Instruction(instruction).class()
 }
 
+/// Return the control flow of `instruction` located at address
+/// `pc`, see `Cpu::predict_next_pc`. `lr` is needed for the second
+/// half of BL/BLX pairs since the first half stores the partial
+/// target in it.
+pub fn next_pc(instruction: u16, pc: u32, lr: u32) -> NextPc {
+    let instruction = Instruction(instruction);
+    let i = instruction.0;
+
+    // PC reads return the instruction's address + 4
+    let r15 = pc.wrapping_add(4);
+
+    match i >> 11 {
+        0b11010...0b11011 =>
+            match (i >> 8) & 0xf {
+                // Undefined
+                0b1110 => NextPc::Sequential,
+                // SWI
+                0b1111 => NextPc::Indirect,
+                _ => {
+                    let offset = instruction.signed_imm8() << 1;
+
+                    NextPc::Conditional(r15.wrapping_add(offset))
+                }
+            },
+        0b11100 => {
+            let offset = instruction.signed_imm11() << 1;
+
+            NextPc::Branch(r15.wrapping_add(offset))
+        }
+        0b11111 => {
+            let offset = instruction.b_imm_offset_11() << 1;
+
+            NextPc::Branch(lr.wrapping_add(offset))
+        }
+        0b11101 => {
+            let offset = instruction.b_imm_offset_11() << 1;
+
+            NextPc::Branch(lr.wrapping_add(offset) & !3)
+        }
+        0b01000 if (i >> 10) & 1 != 0 =>
+            match (i >> 8) & 3 {
+                // BX/BLX
+                0b11 => NextPc::Indirect,
+                // Compare
+                0b01 => NextPc::Sequential,
+                // ADD and MOV can write to PC using the high register
+                // forms
+                _ =>
+                    if instruction.reg_0_full().is_pc() {
+                        NextPc::Indirect
+                    } else {
+                        NextPc::Sequential
+                    },
+            },
+        // POP with PC in the list
+        0b10111 if (i >> 8) & 7 == 0b101 => NextPc::Indirect,
+        _ => NextPc::Sequential,
+    }
+}
+
+/// Instruction handler function
+pub type Handler = fn (Instruction, &mut Debugger, &mut Cpu);
+
 impl Instruction {
     /// We decode the instruction based on bits [15:6]. That gives us
     /// 1024 possibilities.
@@ -98,42 +177,58 @@ impl Instruction {
         (self.0 & 0xff) as u32
     }
 
-    fn adds(self, cpu: &mut Cpu, a: u32, b: u32) -> u32 {
-        let val = a.wrapping_add(b);
+    /// Return a short description of the class of this instruction
+    /// based on the encoding tables of the ARM reference manual.
+    fn class(self) -> &'static str {
+        let i = self.0;
+
+        match i >> 11 {
+            0b00011 => "add/subtract",
+            0b00000...0b00010 => "shift by immediate",
+            0b00100...0b00111 => "add/subtract/compare/move immediate",
+            0b01000 =>
+                if (i >> 10) & 1 == 0 {
+                    "data processing (register)"
+                } else {
+                    "special data processing or branch exchange"
+                },
+            0b01001 => "load from literal pool",
+            0b01010...0b01011 => "load/store (register offset)",
+            0b01100...0b01111 => "load/store word/byte (immediate offset)",
+            0b10000...0b10001 => "load/store halfword (immediate offset)",
+            0b10010...0b10011 => "load/store to/from stack",
+            0b10100...0b10101 => "add to SP or PC",
+            0b10110...0b10111 => "miscellaneous",
+            0b11000...0b11001 => "load/store multiple",
+            0b11010...0b11011 =>
+                match (i >> 8) & 0xf {
+                    0b1110 => "undefined",
+                    0b1111 => "software interrupt",
+                    _ => "conditional branch",
+                },
+            0b11100 => "unconditional branch",
+            0b11101 => "BLX suffix",
+            0b11110 => "BL/BLX prefix",
+            0b11111 => "BL suffix",
+            _ => unreachable!(),
+        }
+    }
 
-        let a_neg = (a as i32) < 0;
-        let b_neg = (b as i32) < 0;
-        let v_neg = (val as i32) < 0;
+    fn adds(self, cpu: &mut Cpu, a: u32, b: u32) -> u32 {
+        let (val, n, z, c, v) = add_with_flags(a, b, false);
 
-        cpu.set_n(v_neg);
-        cpu.set_z(val == 0);
-        cpu.set_c(val < a);
-        cpu.set_v((a_neg == b_neg) & (a_neg ^ v_neg));
+        cpu.set_nzcv(n, z, c, v);
 
         val
     }
 
     fn subs(self, cpu: &mut Cpu, a: u32, b: u32) -> u32 {
-        let val = a.wrapping_sub(b);
+        let (val, n, z, c, v) = sub_with_flags(a, b, false);
 
-        let a_neg = (a as i32) < 0;
-        let b_neg = (b as i32) < 0;
-        let v_neg = (val as i32) < 0;
-
-        cpu.set_n(v_neg);
-        cpu.set_z(val == 0);
-        cpu.set_c(a >= b);
-        cpu.set_v((a_neg ^ b_neg) & (a_neg ^ v_neg));
+        cpu.set_nzcv(n, z, c, v);
 
         val
     }
-
-    fn execute<D>(self, debugger: &mut D, cpu: &mut Cpu)
-        where D: Debugger {
-        let handler = OPCODE_LUT[self.opcode() as usize];
-
-        handler(self, debugger, cpu);
-    }
 }
 
 /// Wrapper around a 16bit instruction word
@@ -147,10 +242,12 @@ impl fmt::Display for Instruction {
 }
 
 fn unimplemented(instruction: Instruction, _: &mut Debugger, cpu: &mut Cpu) {
-    panic!("Unimplemented instruction {} ({:03x})\n{:?}",
-           instruction,
-           instruction.opcode(),
-           cpu);
+    let msg = format!("{} ({:03x}, {})",
+                      instruction,
+                      instruction.opcode(),
+                      instruction.class());
+
+    cpu.unimplemented(msg);
 }
 
 fn op00x_lsl_ri5(instruction: Instruction, _: &mut Debugger, cpu: &mut Cpu) {
@@ -302,16 +399,7 @@ fn op0ax_cmp_i8(instruction: Instruction, _: &mut Debugger, cpu: &mut Cpu) {
 
     let a = cpu.reg(rn);
 
-    let val = a.wrapping_sub(b);
-
-    let a_neg = (a as i32) < 0;
-    let b_neg = (b as i32) < 0;
-    let v_neg = (val as i32) < 0;
-
-    cpu.set_n(v_neg);
-    cpu.set_z(val == 0);
-    cpu.set_c(a >= b);
-    cpu.set_v((a_neg ^ b_neg) & (a_neg ^ v_neg));
+    instruction.subs(cpu, a, b);
 }
 
 fn op0cx_add_i8(instruction: Instruction, _: &mut Debugger, cpu: &mut Cpu) {
@@ -471,19 +559,27 @@ fn op105_adc_rr(instruction: Instruction, _: &mut Debugger, cpu: &mut Cpu) {
     let a = cpu.reg(rd);
     let b = cpu.reg(rm);
 
-    // Add with carry
-    let val = a.wrapping_add(b).wrapping_add(cpu.c() as u32);
+    let carry = cpu.c();
 
-    let a_neg = (a as i32) < 0;
-    let b_neg = (b as i32) < 0;
-    let v_neg = (val as i32) < 0;
+    let (val, n, z, c, v) = add_with_flags(a, b, carry);
 
     cpu.set_reg(rd, val);
+    cpu.set_nzcv(n, z, c, v);
+}
 
-    cpu.set_n(v_neg);
-    cpu.set_z(val == 0);
-    cpu.set_c(val < a);
-    cpu.set_v((a_neg == b_neg) & (a_neg ^ v_neg));
+fn op106_sbc_rr(instruction: Instruction, _: &mut Debugger, cpu: &mut Cpu) {
+    let rd = instruction.reg_0();
+    let rm = instruction.reg_3();
+
+    let a = cpu.reg(rd);
+    let b = cpu.reg(rm);
+
+    let borrow = !cpu.c();
+
+    let (val, n, z, c, v) = sub_with_flags(a, b, borrow);
+
+    cpu.set_reg(rd, val);
+    cpu.set_nzcv(n, z, c, v);
 }
 
 fn op107_ror(instruction: Instruction, _: &mut Debugger, cpu: &mut Cpu) {
@@ -636,11 +732,45 @@ fn op11c_bx(instruction: Instruction, _: &mut Debugger, cpu: &mut Cpu) {
 
     if (instruction.0 & 7) != 0 {
         // Should be 0
-        panic!("Invalid BX instruction {}", instruction);
+        let msg = format!("Invalid BX instruction {}", instruction);
+
+        return cpu.undefined(msg);
+    }
+
+    let target = cpu.reg(rm);
+
+    let thumb = (target & 1) != 0;
+
+    cpu.set_pc_thumb(target & !1, thumb);
+}
+
+/// Branch with link to a register, switching to ARM mode if bit 0 of
+/// the target is clear (ARMv5 only)
+fn op11e_blx(instruction: Instruction, debugger: &mut Debugger, cpu: &mut Cpu) {
+    if !cpu.arch_version().has_blx() {
+        return unimplemented(instruction, debugger, cpu);
+    }
+
+    let rm = instruction.reg_3_full();
+
+    if (instruction.0 & 7) != 0 {
+        // Should be 0
+        let msg = format!("Invalid BLX instruction {}", instruction);
+
+        return cpu.undefined(msg);
+    }
+
+    if rm.is_pc() {
+        return cpu.undefined("Unpredictable BLX to PC");
     }
 
+    // Rm may be LR so we have to read the target first
     let target = cpu.reg(rm);
 
+    let ra = cpu.next_pc | 1;
+
+    cpu.set_reg(RegisterIndex(14), ra);
+
     let thumb = (target & 1) != 0;
 
     cpu.set_pc_thumb(target & !1, thumb);
@@ -703,7 +833,7 @@ fn op14x_strh_rr(instruction: Instruction,
     let addr = cpu.reg(rn).wrapping_add(cpu.reg(rm));
 
     if (addr & 1) != 0 {
-        panic!("Unpredictable STRH");
+        return cpu.undefined("Unpredictable STRH");
     }
 
     let val = cpu.reg(rd);
@@ -865,7 +995,7 @@ fn op20x_strh_ri5(instruction: Instruction,
     let addr = cpu.reg(rn).wrapping_add(offset);
 
     if (addr & 1) != 0 {
-        panic!("Unpredictable STRH");
+        return cpu.undefined("Unpredictable STRH");
     }
 
     let val = cpu.reg(rd);
@@ -970,7 +1100,7 @@ fn op2d0_push(instruction: Instruction,
     let num_regs = list.count_ones();
 
     if num_regs == 0 {
-        panic!("Unpredictable PUSH {}", instruction);
+        return cpu.undefined(format!("Unpredictable PUSH {}", instruction));
     }
 
     let start_addr = cpu.reg(sp).wrapping_sub(4 * num_regs);
@@ -1035,7 +1165,7 @@ fn op2f0_pop(instruction: Instruction,
     let num_regs = list.count_ones();
 
     if num_regs == 0 {
-        panic!("Unpredictable PUSH {}", instruction);
+        return cpu.undefined(format!("Unpredictable PUSH {}", instruction));
     }
 
     let mut addr = cpu.reg(sp);
@@ -1079,7 +1209,7 @@ fn op2f4_pop_pc(instruction: Instruction,
 
     // Load PC
     let pc = cpu.load::<Word>(debugger, addr);
-    cpu.set_pc(pc & !1);
+    cpu.set_pc_load(pc);
     addr = addr.wrapping_add(4);
 
     cpu.set_reg(sp, addr);
@@ -1103,7 +1233,9 @@ fn op30x_stmia(instruction: Instruction,
             // first entry then the original value is stored,
             // otherwise it's "unpredictable".
             if !first && reg == rn {
-                panic!("Unpredictable STM! {}", instruction);
+                let msg = format!("Unpredictable STM! {}", instruction);
+
+                return cpu.undefined(msg);
             }
 
             let val = cpu.reg(reg);
@@ -1265,8 +1397,17 @@ fn op374_ble(instruction: Instruction, _: &mut Debugger, cpu: &mut Cpu) {
     }
 }
 
-fn op37c_swi(_: Instruction, _: &mut Debugger, cpu: &mut Cpu) {
-    cpu.swi()
+fn op37c_swi(instruction: Instruction,
+             debugger: &mut Debugger,
+             cpu: &mut Cpu) {
+    // Thumb SWIs only have an 8bit comment field
+    let comment = instruction.imm8();
+
+    debugger.swi(cpu, comment);
+
+    if !semihosting::swi(cpu, comment) && !hle::swi(cpu, debugger, comment) {
+        cpu.swi()
+    }
 }
 
 fn op38x_b(instruction: Instruction, _: &mut Debugger, cpu: &mut Cpu) {
@@ -1309,7 +1450,28 @@ fn op3ex_bl_lo(instruction: Instruction, _: &mut Debugger, cpu: &mut Cpu) {
     cpu.set_pc(target);
 }
 
-static OPCODE_LUT: [fn (Instruction, &mut Debugger, &mut Cpu); 1024] = [
+/// Second half of a BLX pair (ARMv5 only). The first half is the
+/// same as for BL.
+fn op3ax_blx_lo(instruction: Instruction,
+                debugger: &mut Debugger,
+                cpu: &mut Cpu) {
+    if !cpu.arch_version().has_blx() {
+        return unimplemented(instruction, debugger, cpu);
+    }
+
+    let offset_lo = instruction.b_imm_offset_11() << 1;
+
+    // We switch to ARM mode so the target must be word-aligned
+    let target = cpu.reg(RegisterIndex(14)).wrapping_add(offset_lo) & !3;
+
+    let ra = cpu.next_pc | 1;
+
+    cpu.set_reg(RegisterIndex(14), ra);
+
+    cpu.set_pc_thumb(target, false);
+}
+
+static OPCODE_LUT: [Handler; 1024] = [
     // 0x000
     op00x_lsl_ri5, op00x_lsl_ri5, op00x_lsl_ri5, op00x_lsl_ri5,
     op00x_lsl_ri5, op00x_lsl_ri5, op00x_lsl_ri5, op00x_lsl_ri5,
@@ -1408,7 +1570,7 @@ static OPCODE_LUT: [fn (Instruction, &mut Debugger, &mut Cpu); 1024] = [
 
     // 0x100
     op100_and, op101_eor, op102_lsl_r, op103_lsr_r,
-    op104_asr_r, op105_adc_rr, unimplemented, op107_ror,
+    op104_asr_r, op105_adc_rr, op106_sbc_rr, op107_ror,
     op108_tst, op109_neg, op10a_cmp, op10b_cmn,
     op10c_orr, op10d_mul, op10e_bic, op10f_mvn,
 
@@ -1416,7 +1578,7 @@ static OPCODE_LUT: [fn (Instruction, &mut Debugger, &mut Cpu); 1024] = [
     unimplemented, op111_add_hi, op111_add_hi, op111_add_hi,
     unimplemented, op115_cmp_hi, unimplemented, unimplemented,
     op118_cpy, op118_cpy, op118_cpy, op118_cpy,
-    op11c_bx, op11c_bx, unimplemented, unimplemented,
+    op11c_bx, op11c_bx, op11e_blx, op11e_blx,
 
     // 0x120
     op12x_ldr_pc, op12x_ldr_pc, op12x_ldr_pc, op12x_ldr_pc,
@@ -1659,16 +1821,16 @@ static OPCODE_LUT: [fn (Instruction, &mut Debugger, &mut Cpu); 1024] = [
     op38x_b, op38x_b, op38x_b, op38x_b,
 
     // 0x3a0
-    unimplemented, unimplemented, unimplemented, unimplemented,
-    unimplemented, unimplemented, unimplemented, unimplemented,
-    unimplemented, unimplemented, unimplemented, unimplemented,
-    unimplemented, unimplemented, unimplemented, unimplemented,
+    op3ax_blx_lo, op3ax_blx_lo, op3ax_blx_lo, op3ax_blx_lo,
+    op3ax_blx_lo, op3ax_blx_lo, op3ax_blx_lo, op3ax_blx_lo,
+    op3ax_blx_lo, op3ax_blx_lo, op3ax_blx_lo, op3ax_blx_lo,
+    op3ax_blx_lo, op3ax_blx_lo, op3ax_blx_lo, op3ax_blx_lo,
 
     // 0x3b0
-    unimplemented, unimplemented, unimplemented, unimplemented,
-    unimplemented, unimplemented, unimplemented, unimplemented,
-    unimplemented, unimplemented, unimplemented, unimplemented,
-    unimplemented, unimplemented, unimplemented, unimplemented,
+    op3ax_blx_lo, op3ax_blx_lo, op3ax_blx_lo, op3ax_blx_lo,
+    op3ax_blx_lo, op3ax_blx_lo, op3ax_blx_lo, op3ax_blx_lo,
+    op3ax_blx_lo, op3ax_blx_lo, op3ax_blx_lo, op3ax_blx_lo,
+    op3ax_blx_lo, op3ax_blx_lo, op3ax_blx_lo, op3ax_blx_lo,
 
     // 0x3c0
     op3cx_bl_hi, op3cx_bl_hi, op3cx_bl_hi, op3cx_bl_hi,
@@ -1694,3 +1856,213 @@ static OPCODE_LUT: [fn (Instruction, &mut Debugger, &mut Cpu); 1024] = [
     op3ex_bl_lo, op3ex_bl_lo, op3ex_bl_lo, op3ex_bl_lo,
     op3ex_bl_lo, op3ex_bl_lo, op3ex_bl_lo, op3ex_bl_lo,
     ];
+
+#[cfg(test)]
+mod tests {
+    use cpu::{Cpu, ArchVersion, StepError};
+    use memory::Word;
+    use test_util;
+
+    #[test]
+    fn unimplemented_message() {
+        // CBZ R0, +0 (Thumb-2)
+        let mut cpu = test_util::thumb_cpu(&[0xb100]);
+
+        match cpu.try_step(&mut ()) {
+            Err(StepError::UnimplementedInstruction(msg)) =>
+                assert_eq!(msg, "0xb100 (2c4, miscellaneous)"),
+            r => panic!("Unexpected result {:?}", r),
+        }
+    }
+
+    /// Return `true` if the CPU is in Thumb state
+    fn thumb(cpu: &Cpu) -> bool {
+        cpu.cpsr() & (1 << 5) != 0
+    }
+
+    /// Build a V5TE CPU running the Thumb `program` followed by an
+    /// ARM routine at 0x20 setting R0 and returning through `BX LR`
+    fn interworking_cpu(program: &[u16]) -> Cpu {
+        let mut halfwords = program.to_vec();
+
+        halfwords.resize(0x10, 0);
+
+        // MOV R0, #0x42
+        halfwords.push(0x0042);
+        halfwords.push(0xe3a0);
+        // BX LR
+        halfwords.push(0xff1e);
+        halfwords.push(0xe12f);
+
+        let mut cpu = test_util::thumb_cpu(&halfwords);
+
+        cpu.set_arch_version(ArchVersion::V5TE);
+        cpu.set_register(0, 0);
+
+        cpu
+    }
+
+    /// Check that `cpu` is about to execute the ARM routine called
+    /// from the instruction before 0x04, run it and check that we're
+    /// back in Thumb state at 0x04
+    fn check_arm_call(cpu: &mut Cpu) {
+        assert!(!thumb(cpu));
+        assert_eq!(cpu.registers()[15], 0x20 + 4);
+        // Return address in Thumb state
+        assert_eq!(cpu.registers()[14], 0x05);
+
+        test_util::run(cpu, 2);
+
+        assert!(thumb(cpu));
+        assert_eq!(cpu.registers()[0], 0x42);
+        assert_eq!(cpu.registers()[15], 0x04 + 2);
+
+        test_util::run(cpu, 1);
+
+        assert_eq!(cpu.registers()[1], 1);
+    }
+
+    #[test]
+    fn blx_imm_interworking() {
+        let mut cpu = interworking_cpu(&[
+            // BLX 0x20 (first half, same as BL)
+            0xf000,
+            // BLX 0x20 (second half). The target is word-aligned
+            // since we switch to ARM state.
+            0xe80f,
+            // MOV R1, #1
+            0x2101,
+        ]);
+
+        test_util::run(&mut cpu, 2);
+
+        check_arm_call(&mut cpu);
+    }
+
+    #[test]
+    fn blx_reg_interworking() {
+        let mut cpu = interworking_cpu(&[
+            // MOV R2, #0x20
+            0x2220,
+            // BLX R2
+            0x4790,
+            // MOV R1, #1
+            0x2101,
+        ]);
+
+        test_util::run(&mut cpu, 2);
+
+        check_arm_call(&mut cpu);
+    }
+
+    #[test]
+    fn blx_reg_to_thumb() {
+        let mut cpu = interworking_cpu(&[
+            // MOV R2, #0x11
+            0x2211,
+            // BLX R2
+            0x4790,
+        ]);
+
+        test_util::run(&mut cpu, 2);
+
+        // Bit 0 set: stay in Thumb state
+        assert!(thumb(&cpu));
+        assert_eq!(cpu.registers()[15], 0x10 + 2);
+        assert_eq!(cpu.registers()[14], 0x05);
+    }
+
+    #[test]
+    #[should_panic(expected = "Unimplemented instruction")]
+    fn blx_imm_undefined_on_v4t() {
+        let mut cpu = test_util::thumb_cpu(&[0xf000, 0xe80f]);
+
+        test_util::run(&mut cpu, 2);
+    }
+
+    #[test]
+    fn pop_pc_interworking() {
+        for &(arch, target, pc, t) in
+            &[(ArchVersion::V4T, 0x101, 0x100, true),
+              (ArchVersion::V4T, 0x104, 0x104, true),
+              (ArchVersion::V5TE, 0x101, 0x100, true),
+              (ArchVersion::V5TE, 0x104, 0x104, false)] {
+            // POP {PC}
+            let mut cpu = test_util::thumb_cpu(&[0xbd00]);
+
+            cpu.set_arch_version(arch);
+            cpu.set_register(13, 0x40);
+            cpu.interconnect_mut().store::<Word>(0x40, target);
+
+            test_util::run(&mut cpu, 1);
+
+            assert_eq!(cpu.next_pc, pc, "{:?} {:x}", arch, target);
+            assert_eq!(thumb(&cpu), t);
+            assert_eq!(cpu.registers()[13], 0x44);
+        }
+    }
+
+    #[test]
+    fn bx_pc() {
+        // NOP (MOV R8, R8) then BX PC at 0x4
+        let mut cpu = test_util::thumb_cpu(&[0x46c0, 0x46c0, 0x4778]);
+
+        test_util::run(&mut cpu, 3);
+
+        // PC + 4, bit 0 is clear so we switch to ARM state
+        assert_eq!(cpu.next_pc, 0x8);
+        assert!(!thumb(&cpu));
+    }
+    /// Bytes and halfwords with and without their sign bit set, at
+    /// 0x100 in RAM
+    fn extension_data(cpu: &mut Cpu) {
+        // Bytes 0x7f, 0x00, 0x80, 0xff
+        cpu.interconnect_mut().store::<Word>(0x100, 0xff80007f);
+        // Halfwords 0x7fff, 0x8000
+        cpu.interconnect_mut().store::<Word>(0x104, 0x80007fff);
+        // Halfwords 0xffff, 0x0000
+        cpu.interconnect_mut().store::<Word>(0x108, 0x0000ffff);
+    }
+
+    #[test]
+    fn load_extension() {
+        // Register offset forms, Rd = R0, Rn = R1, Rm = R2
+        const LDRSB: u32 = 0x5688;
+        const LDRH: u32 = 0x5a88;
+        const LDRB: u32 = 0x5c88;
+        const LDRSH: u32 = 0x5e88;
+
+        // (opcode, offset, R0)
+        let cases = [
+            (LDRSB, 0x0, 0x0000007f),
+            (LDRSB, 0x1, 0x00000000),
+            (LDRSB, 0x2, 0xffffff80),
+            (LDRSB, 0x3, 0xffffffff),
+            (LDRB,  0x0, 0x0000007f),
+            (LDRB,  0x2, 0x00000080),
+            (LDRB,  0x3, 0x000000ff),
+            (LDRSH, 0x4, 0x00007fff),
+            (LDRSH, 0x6, 0xffff8000),
+            (LDRSH, 0x8, 0xffffffff),
+            (LDRSH, 0xa, 0x00000000),
+            (LDRH,  0x4, 0x00007fff),
+            (LDRH,  0x6, 0x00008000),
+            (LDRH,  0x8, 0x0000ffff),
+        ];
+
+        for &(op, offset, expected) in &cases {
+            let mut cpu = test_util::thumb_cpu(&[]);
+
+            extension_data(&mut cpu);
+
+            cpu.set_register(0, 0x12345678);
+            cpu.set_register(1, 0x100);
+            cpu.set_register(2, offset);
+
+            cpu.execute_raw(&mut (), op);
+
+            assert_eq!(cpu.registers()[0], expected,
+                       "{:04x} offset {}", op, offset);
+        }
+    }
+}
@@ -1,8 +1,13 @@
 //! PocketStation Audio DAC emulation
 
+use std::collections::VecDeque;
+use std::hash::{Hash, Hasher};
+use std::mem::swap;
+
 use rustc_serialize::{Decodable, Encodable, Decoder, Encoder};
 
-use memory::Addressable;
+use memory::{Addressable, register_load, register_store};
+use memory::unwrap_access;
 use MASTER_CLOCK_HZ;
 
 pub struct Dac {
@@ -12,9 +17,20 @@ pub struct Dac {
     /// the real hardware anyway...
     sample: i16,
     enabled: bool,
+    /// True if the speaker has been cut off through the IOP pins,
+    /// in which case we output silence regardless of `enabled`
+    muted: bool,
     backend: Box<Backend>,
     /// Master clock divider
     divider: u32,
+    /// Sample FIFO. The real hardware doesn't have one (the guest is
+    /// expected to write each sample on time, usually from a timer
+    /// interrupt) so it's only used if `fifo_threshold` is set.
+    fifo: VecDeque<i16>,
+    /// When set the sample register writes are queued in `fifo` and
+    /// `sample_request` returns true when the FIFO holds fewer
+    /// samples than the threshold.
+    fifo_threshold: Option<u16>,
 }
 
 impl Dac {
@@ -22,11 +38,28 @@ impl Dac {
         Dac {
             sample: 0,
             enabled: false,
+            muted: false,
             backend: backend,
             divider: MASTER_CLOCK_DIV,
+            fifo: VecDeque::with_capacity(FIFO_DEPTH),
+            fifo_threshold: None,
         }
     }
 
+    /// Reset the DAC registers, the backend is left untouched
+    pub fn reset(&mut self) {
+        self.sample = 0;
+        self.enabled = false;
+        self.muted = false;
+        self.divider = MASTER_CLOCK_DIV;
+        self.fifo.clear();
+    }
+
+    /// Advance the DAC by `master_ticks` master clock periods. The
+    /// output sample rate is derived from the master clock (see
+    /// `MASTER_CLOCK_DIV`), not the CPU clock, so it doesn't depend on
+    /// the CLKMODE setting. Only the rate at which the CPU can update
+    /// the sample changes.
     pub fn tick(&mut self, mut master_ticks: u32) {
 
         while master_ticks > 0 {
@@ -39,9 +72,17 @@ impl Dac {
 
                 self.divider = MASTER_CLOCK_DIV;
 
+                if self.fifo_threshold.is_some() {
+                    // If the FIFO underruns we keep outputting the
+                    // last sample
+                    if let Some(s) = self.fifo.pop_front() {
+                        self.sample = s;
+                    }
+                }
+
                 // Time to generate a sample
                 let sample =
-                    if self.enabled {
+                    if self.enabled && !self.muted {
                         self.sample
                     } else {
                         0
@@ -52,36 +93,111 @@ impl Dac {
         }
     }
 
+    /// Return true if the FIFO is enabled and holds fewer samples
+    /// than the threshold
+    pub fn sample_request(&self) -> bool {
+        match self.fifo_threshold {
+            Some(t) => self.fifo.len() < t as usize,
+            None => false,
+        }
+    }
 
     pub fn store<A: Addressable>(&mut self, offset: u32, val: u32) {
-        if A::size() == 1 {
-            // XXX Brightis uses 16bit dac stores, test if it behaves
-            // exactly like 32bit
-            panic!("Unhandled {}bit DAC store", A::size() * 8);
-        }
+        unwrap_access(self.try_store::<A>(offset, val))
+    }
+
+    /// Same as `store` but unhandled accesses return an error
+    /// instead of panicking
+    pub fn try_store<A: Addressable>(&mut self,
+                                     offset: u32,
+                                     val: u32) -> Result<(), String> {
+        // XXX Brightis uses 16bit dac stores, test if it behaves
+        // exactly like 32bit
+        match offset & !3 {
+            0 => {
+                let v = register_store::<A>(self.enabled as u32, offset, val);
 
-        match offset {
-            0 => self.enabled = (val & 1) != 0,
-            4 => self.sample = val as i16,
-            _ => panic!("Unhandled DAC register {:x}", offset),
+                self.enabled = (v & 1) != 0;
+            }
+            4 => {
+                let v = register_store::<A>(self.sample as u16 as u32,
+                                            offset,
+                                            val);
+
+                if self.fifo_threshold.is_some() {
+                    if self.fifo.len() < FIFO_DEPTH {
+                        self.fifo.push_back(v as i16);
+                    } else {
+                        warn!("DAC FIFO overflow, dropping sample");
+                    }
+                } else {
+                    self.sample = v as i16;
+                }
+            }
+            _ => return Err(format!("Unhandled DAC register {:x}", offset)),
         }
+
+        Ok(())
     }
 
     pub fn load<A: Addressable>(&self, offset: u32) -> u32 {
-        if A::size() != 4 {
-            panic!("Unhandled {}bit DAC store", A::size() * 8);
-        }
+        unwrap_access(self.try_load::<A>(offset))
+    }
+
+    /// Same as `load` but unhandled accesses return an error instead
+    /// of panicking
+    pub fn try_load<A: Addressable>(&self,
+                                    offset: u32) -> Result<u32, String> {
+        let reg =
+            match offset & !3 {
+                0 => self.enabled as u32,
+                4 => self.sample as u16 as u32,
+                _ => return Err(format!("Unhandled DAC register {:x}",
+                                        offset)),
+            };
 
-        match offset {
-            0 => self.enabled as u32,
-            4 => self.sample as u16 as u32,
-            _ => panic!("Unhandled DAC register {:x}", offset),
+        Ok(register_load::<A>(reg, offset))
+    }
+
+    /// Enable the sample FIFO with the given sample request
+    /// threshold, or disable it if `threshold` is `None`. Disabling
+    /// the FIFO discards any queued sample. Since this isn't a real
+    /// hardware feature it's meant to be configured by the frontend
+    /// for homebrew code written with it in mind.
+    ///
+    /// XXX There's no free interrupt line to signal the sample
+    /// requests to the guest, it has to keep feeding the DAC from a
+    /// timer interrupt like on the real hardware. The FIFO only
+    /// smooths out the jitter, the frontend can monitor
+    /// `sample_request` to detect underruns.
+    pub fn set_fifo_threshold(&mut self, threshold: Option<u16>) {
+        if let Some(t) = threshold {
+            if t as usize > FIFO_DEPTH {
+                panic!("Invalid DAC FIFO threshold {}", t);
+            }
+        } else {
+            self.fifo.clear();
         }
+
+        self.fifo_threshold = threshold;
+    }
+
+    pub fn fifo_threshold(&self) -> Option<u16> {
+        self.fifo_threshold
+    }
+
+    pub fn set_muted(&mut self, muted: bool) {
+        self.muted = muted
     }
 
     pub fn set_backend(&mut self, backend: Box<Backend>) {
         self.backend = backend
     }
+
+    /// Exchange the audio backends of `self` and `other`
+    pub fn swap_backend(&mut self, other: &mut Dac) {
+        swap(&mut self.backend, &mut other.backend)
+    }
 }
 
 impl Encodable for Dac {
@@ -89,13 +205,19 @@ impl Encodable for Dac {
         // We don't serialize the backend, it'll be up to the frontend
         // to reset it.
 
-        s.emit_struct("Dac", 3, |s| {
+        s.emit_struct("Dac", 6, |s| {
             try!(s.emit_struct_field("sample", 0,
                                      |s| self.sample.encode(s)));
             try!(s.emit_struct_field("enabled", 1,
                                      |s| self.enabled.encode(s)));
             try!(s.emit_struct_field("divider", 2,
                                      |s| self.divider.encode(s)));
+            try!(s.emit_struct_field("muted", 3,
+                                     |s| self.muted.encode(s)));
+            try!(s.emit_struct_field("fifo", 4,
+                                     |s| self.fifo.encode(s)));
+            try!(s.emit_struct_field("fifo_threshold", 5,
+                                     |s| self.fifo_threshold.encode(s)));
 
             Ok(())
         })
@@ -104,7 +226,7 @@ impl Encodable for Dac {
 
 impl Decodable for Dac {
     fn decode<D: Decoder>(d: &mut D) -> Result<Dac, D::Error> {
-        d.read_struct("Dac", 3, |d| {
+        d.read_struct("Dac", 6, |d| {
             let mut dac = Dac::new(Box::new(DummyBackend));
 
             dac.sample =
@@ -119,12 +241,36 @@ impl Decodable for Dac {
                 try!(d.read_struct_field("divider",
                                          2,
                                          Decodable::decode));
+            dac.muted =
+                try!(d.read_struct_field("muted",
+                                         3,
+                                         Decodable::decode));
+            dac.fifo =
+                try!(d.read_struct_field("fifo",
+                                         4,
+                                         Decodable::decode));
+            dac.fifo_threshold =
+                try!(d.read_struct_field("fifo_threshold",
+                                         5,
+                                         Decodable::decode));
 
             Ok(dac)
         })
     }
 }
 
+impl Hash for Dac {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        // The backend is not part of the emulated state
+        self.sample.hash(state);
+        self.enabled.hash(state);
+        self.divider.hash(state);
+        self.muted.hash(state);
+        self.fifo.hash(state);
+        self.fifo_threshold.hash(state);
+    }
+}
+
 pub trait Backend {
     fn push_sample(&mut self, sample: i16);
 }
@@ -144,5 +290,105 @@ impl Backend for DummyBackend {
 /// quality. Should be more than enough.
 pub const MASTER_CLOCK_DIV: u32 = 90;
 
+/// Number of samples the DAC FIFO can hold
+pub const FIFO_DEPTH: usize = 16;
+
 /// Audio sample rate.
 pub const SAMPLE_RATE_HZ: u32 = MASTER_CLOCK_HZ / MASTER_CLOCK_DIV;
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+    use std::cell::Cell;
+
+    use memory::{Interconnect, Word};
+    use memory::bios::Bios;
+    use memory::flash::Flash;
+    use memory::ram::Ram;
+
+    use super::*;
+
+    /// Backend counting the samples it receives
+    struct CountingBackend(Rc<Cell<u32>>);
+
+    impl Backend for CountingBackend {
+        fn push_sample(&mut self, _: i16) {
+            self.0.set(self.0.get() + 1);
+        }
+    }
+
+    #[test]
+    fn fifo_sample_request() {
+        let mut dac = Dac::new(Box::new(DummyBackend));
+
+        dac.store::<Word>(0, 1);
+
+        // Disabled by default: the samples are output immediately
+        assert!(!dac.sample_request());
+        dac.store::<Word>(4, 0x10);
+        assert_eq!(dac.load::<Word>(4), 0x10);
+
+        dac.set_fifo_threshold(Some(4));
+        assert!(dac.sample_request());
+
+        for s in 0..FIFO_DEPTH as u32 {
+            dac.store::<Word>(4, 0x100 + s);
+        }
+
+        // Overflow, dropped
+        dac.store::<Word>(4, 0xfff);
+
+        assert!(!dac.sample_request());
+
+        // Drain the FIFO until it holds 4 samples. The first sample
+        // is popped once the divider has been exhausted, one tick
+        // later.
+        dac.tick((FIFO_DEPTH as u32 - 4) * MASTER_CLOCK_DIV + 1);
+
+        assert!(!dac.sample_request());
+        assert_eq!(dac.load::<Word>(4), 0x100 + FIFO_DEPTH as u32 - 5);
+
+        dac.tick(MASTER_CLOCK_DIV);
+
+        assert!(dac.sample_request());
+        assert_eq!(dac.load::<Word>(4), 0x100 + FIFO_DEPTH as u32 - 4);
+
+        // On underrun the last sample is repeated
+        dac.tick(10 * MASTER_CLOCK_DIV);
+
+        assert!(dac.sample_request());
+        assert_eq!(dac.load::<Word>(4), 0x100 + FIFO_DEPTH as u32 - 1);
+
+        // Disabling the FIFO clears the request
+        dac.set_fifo_threshold(None);
+        assert!(!dac.sample_request());
+    }
+
+    #[test]
+    fn rate_independent_of_cpu_clock() {
+        // A quarter of a second, a whole number of CPU clock periods
+        // at every CLKMODE setting
+        let master_ticks = MASTER_CLOCK_HZ / 4;
+
+        let counts: Vec<u32> = (0..8).map(|clkmode| {
+            let samples = Rc::new(Cell::new(0));
+            let backend = CountingBackend(samples.clone());
+
+            let mut inter =
+                Interconnect::with_ram(Bios::empty(),
+                                       Flash::with_contents(&[]),
+                                       Dac::new(Box::new(backend)),
+                                       Ram::new());
+
+            inter.store::<Word>(0x0b000000, clkmode);
+            inter.tick_batch(master_ticks >> (7 - clkmode));
+
+            samples.get()
+        }).collect();
+
+        let expected = master_ticks / MASTER_CLOCK_DIV;
+
+        assert!(expected > 0);
+        assert_eq!(counts, [expected; 8]);
+    }
+}
@@ -1,3 +1,6 @@
+use std::collections::{BTreeSet, VecDeque};
+use std::io::{self, Write};
+
 use cpu::Cpu;
 
 /// Trait defining the debugger interface
@@ -16,6 +19,44 @@ pub trait Debugger {
 
     /// Called by the CPU when it's about to write a value to memory.
     fn memory_write(&mut self, cpu: &mut Cpu, addr: u32);
+
+    /// Called by the CPU when it's about to execute a software
+    /// interrupt. `comment` is the immediate value encoded in the
+    /// instruction (24bits in ARM mode, 8bits in Thumb mode) which
+    /// identifies the system call being requested. Does nothing by
+    /// default.
+    fn swi(&mut self, _cpu: &mut Cpu, _comment: u32) {
+    }
+
+    /// Called by the CPU after a successful load of `size` bytes from
+    /// `addr` with the value read. Does nothing by default.
+    fn memory_read_value(&mut self,
+                         _cpu: &mut Cpu,
+                         _addr: u32,
+                         _size: u8,
+                         _val: u32) {
+    }
+
+    /// Called by the CPU just before storing the `size` bytes of `val`
+    /// to `addr`. Does nothing by default.
+    fn memory_write_value(&mut self,
+                          _cpu: &mut Cpu,
+                          _addr: u32,
+                          _size: u8,
+                          _val: u32) {
+    }
+
+    /// Called by the CPU when a store targets one of the most
+    /// recently executed instructions, which is usually a sign of
+    /// unintended self-modifying code. Does nothing by default.
+    fn on_smc(&mut self, _cpu: &mut Cpu, _addr: u32) {
+    }
+
+    /// Called by the CPU when the division SWI registered with
+    /// `Cpu::set_hle_divide` is executed with a zero divisor, before
+    /// the SWI is handled. Does nothing by default.
+    fn on_div_zero(&mut self, _cpu: &mut Cpu, _comment: u32) {
+    }
 }
 
 
@@ -34,3 +75,472 @@ impl Debugger for () {
     fn memory_write(&mut self, _: &mut Cpu, _: u32) {
     }
 }
+
+/// Debugger implementation that keeps track of the last memory
+/// accesses performed by the CPU. Meant to be dumped when the
+/// emulator crashes to see what the guest was doing just before.
+pub struct AccessLog {
+    accesses: VecDeque<Access>,
+    capacity: usize,
+}
+
+impl AccessLog {
+    /// Create a log keeping the last `capacity` accesses
+    pub fn new(capacity: usize) -> AccessLog {
+        AccessLog {
+            accesses: VecDeque::with_capacity(capacity),
+            capacity: capacity,
+        }
+    }
+
+    /// Return the logged accesses, oldest first
+    pub fn accesses(&self) -> &VecDeque<Access> {
+        &self.accesses
+    }
+
+    /// Write the logged accesses to `out`, oldest first
+    pub fn dump(&self, out: &mut Write) -> io::Result<()> {
+        for a in &self.accesses {
+            let kind =
+                if a.write {
+                    "write"
+                } else {
+                    "read"
+                };
+
+            try!(writeln!(out, "[0x{:08x}] {}{} 0x{:08x}: 0x{:08x}",
+                          a.pc, kind, a.size * 8, a.addr, a.value));
+        }
+
+        Ok(())
+    }
+
+    fn log(&mut self,
+           cpu: &Cpu,
+           addr: u32,
+           size: u8,
+           write: bool,
+           value: u32) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        if self.accesses.len() == self.capacity {
+            self.accesses.pop_front();
+        }
+
+        // Byte and halfword stores get the full register value
+        let mask = !0u32 >> (32 - size as u32 * 8);
+
+        self.accesses.push_back(Access {
+            pc: cpu.current_pc(),
+            addr: addr,
+            size: size,
+            write: write,
+            value: value & mask,
+        });
+    }
+}
+
+impl Debugger for AccessLog {
+    fn trigger_break(&mut self) {
+    }
+
+    fn pc_change(&mut self, _: &mut Cpu) {
+    }
+
+    fn memory_read(&mut self, _: &mut Cpu, _: u32) {
+    }
+
+    fn memory_write(&mut self, _: &mut Cpu, _: u32) {
+    }
+
+    fn memory_read_value(&mut self,
+                         cpu: &mut Cpu,
+                         addr: u32,
+                         size: u8,
+                         val: u32) {
+        self.log(cpu, addr, size, false, val);
+    }
+
+    fn memory_write_value(&mut self,
+                          cpu: &mut Cpu,
+                          addr: u32,
+                          size: u8,
+                          val: u32) {
+        self.log(cpu, addr, size, true, val);
+    }
+}
+
+/// Debugger implementation recording the address of every
+/// instruction executed, to find out which parts of the code are
+/// reached.
+pub struct Coverage {
+    executed: BTreeSet<u32>,
+}
+
+impl Coverage {
+    pub fn new() -> Coverage {
+        Coverage {
+            executed: BTreeSet::new(),
+        }
+    }
+
+    /// Return true if the instruction at `pc` has been executed
+    pub fn executed(&self, pc: u32) -> bool {
+        self.executed.contains(&pc)
+    }
+
+    /// Return the addresses of all the instructions executed so far,
+    /// in ascending order
+    pub fn export(&self) -> Vec<u32> {
+        self.executed.iter().cloned().collect()
+    }
+
+    pub fn clear(&mut self) {
+        self.executed.clear()
+    }
+}
+
+impl Debugger for Coverage {
+    fn trigger_break(&mut self) {
+    }
+
+    fn pc_change(&mut self, cpu: &mut Cpu) {
+        self.executed.insert(cpu.current_pc());
+    }
+
+    fn memory_read(&mut self, _: &mut Cpu, _: u32) {
+    }
+
+    fn memory_write(&mut self, _: &mut Cpu, _: u32) {
+    }
+}
+
+/// Debugger implementation checking that the stack pointer stays
+/// within the configured bounds, to catch stack overflows and
+/// underflows. The SP is checked before every instruction in all
+/// CPU modes so the region must contain the stacks of all the modes
+/// used by the code under test.
+pub struct StackGuard {
+    /// Lowest valid SP value
+    low: u32,
+    /// Highest valid SP value (the SP of an empty full-descending
+    /// stack)
+    high: u32,
+    /// Called with the offending SP value when it leaves the bounds
+    hook: Box<FnMut(&mut Cpu, u32)>,
+    /// True if the SP was out of bounds at the last check, used to
+    /// only call the hook once per excursion
+    out_of_bounds: bool,
+}
+
+impl StackGuard {
+    pub fn new(low: u32,
+               high: u32,
+               hook: Box<FnMut(&mut Cpu, u32)>) -> StackGuard {
+        StackGuard {
+            low: low,
+            high: high,
+            hook: hook,
+            out_of_bounds: false,
+        }
+    }
+}
+
+impl Debugger for StackGuard {
+    fn trigger_break(&mut self) {
+    }
+
+    fn pc_change(&mut self, cpu: &mut Cpu) {
+        let sp = cpu.registers()[13];
+
+        let out_of_bounds = sp < self.low || sp > self.high;
+
+        if out_of_bounds && !self.out_of_bounds {
+            (self.hook)(cpu, sp);
+        }
+
+        self.out_of_bounds = out_of_bounds;
+    }
+
+    fn memory_read(&mut self, _: &mut Cpu, _: u32) {
+    }
+
+    fn memory_write(&mut self, _: &mut Cpu, _: u32) {
+    }
+}
+
+/// Memory access recorded by `AccessLog`. Loads are only recorded
+/// once they complete, so a load that crashes the emulator is not in
+/// the log (its address is in the panic message).
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Access {
+    /// Address of the instruction performing the access
+    pub pc: u32,
+    /// Target address
+    pub addr: u32,
+    /// Access size in bytes
+    pub size: u8,
+    /// `true` for stores, `false` for loads
+    pub write: bool,
+    /// Value loaded or stored
+    pub value: u32,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+    use std::cell::RefCell;
+
+    use cpu::Cpu;
+    use memory::Word;
+    use test_util;
+
+    use super::*;
+
+    /// Debugger recording the SWI comments
+    struct SwiLog(Vec<u32>);
+
+    impl Debugger for SwiLog {
+        fn trigger_break(&mut self) {
+        }
+
+        fn pc_change(&mut self, _: &mut Cpu) {
+        }
+
+        fn memory_read(&mut self, _: &mut Cpu, _: u32) {
+        }
+
+        fn memory_write(&mut self, _: &mut Cpu, _: u32) {
+        }
+
+        fn swi(&mut self, _: &mut Cpu, comment: u32) {
+            self.0.push(comment);
+        }
+    }
+
+    #[test]
+    fn swi_comment() {
+        let mut cpu = test_util::arm_cpu(&[]);
+        let mut log = SwiLog(Vec::new());
+
+        // ARM SWIs have a 24bit comment field
+        for &comment in &[0, 1, 0x42, 0xabcdef, 0xffffff] {
+            cpu.execute_raw(&mut log, 0xef000000 | comment);
+        }
+
+        // Thumb SWIs only have 8 bits
+        for &comment in &[0, 0x7f, 0xff] {
+            cpu.set_thumb(true);
+            cpu.execute_raw(&mut log, 0xdf00 | comment);
+        }
+
+        assert_eq!(log.0, [0, 1, 0x42, 0xabcdef, 0xffffff, 0, 0x7f, 0xff]);
+    }
+
+    #[test]
+    fn swi_default_hook() {
+        let mut cpu = test_util::arm_cpu(&[]);
+
+        // The dummy debugger doesn't implement the hook
+        cpu.execute_raw(&mut (), 0xef000012);
+
+        // Jumped to the SWI vector
+        assert_eq!(cpu.registers()[15], 0x08 + 4);
+    }
+
+    /// Debugger recording the addresses passed to `on_smc`
+    struct SmcLog(Vec<u32>);
+
+    impl Debugger for SmcLog {
+        fn trigger_break(&mut self) {
+        }
+
+        fn pc_change(&mut self, _: &mut Cpu) {
+        }
+
+        fn memory_read(&mut self, _: &mut Cpu, _: u32) {
+        }
+
+        fn memory_write(&mut self, _: &mut Cpu, _: u32) {
+        }
+
+        fn on_smc(&mut self, _: &mut Cpu, addr: u32) {
+            self.0.push(addr);
+        }
+    }
+
+    #[test]
+    fn smc_hook() {
+        // MOV R0, #0
+        // STR R1, [R0]
+        // STR R1, [R0, #0x100]
+        // STRB R1, [R0, #5]
+        let mut cpu = test_util::arm_cpu(&[0xe3a00000,
+                                           0xe5801000,
+                                           0xe5801100,
+                                           0xe5c01005]);
+        let mut log = SmcLog(Vec::new());
+
+        cpu.set_register(1, 0xe1a00000);
+
+        for _ in 0..4 {
+            cpu.run_next_instruction(&mut log);
+        }
+
+        // The first store overwrites the MOV, the byte store targets
+        // the first STR. The store to 0x100 doesn't hit code.
+        assert_eq!(log.0, [0, 5]);
+
+        // MOV R0, #0, then 8 NOPs pushing it out of the window
+        let mut program = vec![0xe3a00000];
+
+        program.extend_from_slice(&[0xe1a00000; 8]);
+
+        // STR R1, [R0]
+        program.push(0xe5801000);
+
+        let mut cpu = test_util::arm_cpu(&program);
+        let mut log = SmcLog(Vec::new());
+
+        for _ in 0..program.len() {
+            cpu.run_next_instruction(&mut log);
+        }
+
+        assert_eq!(log.0, []);
+
+        // The default hook does nothing
+        let mut cpu = test_util::arm_cpu(&[0xe3a00000, 0xe5801000]);
+
+        test_util::run(&mut cpu, 2);
+
+        assert_eq!(cpu.interconnect().load::<Word>(0), cpu.registers()[1]);
+    }
+
+    #[test]
+    fn access_log() {
+        // MOV R0, #0x100
+        // STR R1, [R0]
+        // LDR R2, [R0]
+        // STRB R1, [R0, #5]
+        // LDRH R3, [R0, #6]
+        // LDR R4, [R5]
+        // STR R1, [R5]
+        let program = [0xe3a00c01,
+                       0xe5801000,
+                       0xe5902000,
+                       0xe5c01005,
+                       0xe1d030b6,
+                       0xe5954000,
+                       0xe5851000];
+
+        let mut cpu = test_util::arm_cpu(&program);
+        let mut log = AccessLog::new(4);
+
+        cpu.set_register(1, 0x12345678);
+        // Unmapped address
+        cpu.set_register(5, 0x0e000000);
+
+        // Unknown accesses panic by default
+        cpu.interconnect_mut()
+            .set_unknown_access_handler(Box::new(|_, _, _| 0xdeadbeef));
+
+        for _ in 0..program.len() {
+            cpu.run_next_instruction(&mut log);
+        }
+
+        let tuples: Vec<_> =
+            log.accesses().iter()
+            .map(|a| (a.pc, a.addr, a.size, a.write, a.value))
+            .collect();
+
+        // Only the last 4 accesses are kept
+        assert_eq!(tuples,
+                   [(0x0c, 0x105, 1, true, 0x78),
+                    (0x10, 0x106, 2, false, 0xcaca),
+                    (0x14, 0x0e000000, 4, false, 0xdeadbeef),
+                    (0x18, 0x0e000000, 4, true, 0x12345678)]);
+
+        let mut dump = Vec::new();
+
+        log.dump(&mut dump).unwrap();
+
+        let dump = String::from_utf8(dump).unwrap();
+
+        assert_eq!(dump.lines().nth(2).unwrap(),
+                   "[0x00000014] read32 0x0e000000: 0xdeadbeef");
+    }
+
+    #[test]
+    fn coverage() {
+        let mut cpu = test_util::arm_cpu(&[
+            // MOV R0, #1
+            0xe3a00001,
+            // CMP R0, #1
+            0xe3500001,
+            // BEQ 0x14
+            0x0a000001,
+            // MOV R1, #2 (skipped)
+            0xe3a01002,
+            // MOV R1, #3 (skipped)
+            0xe3a01003,
+            // MOV R2, #4
+            0xe3a02004,
+            // B .
+            0xeafffffe,
+        ]);
+
+        let mut coverage = Coverage::new();
+
+        for _ in 0..6 {
+            cpu.run_next_instruction(&mut coverage);
+        }
+
+        assert_eq!(coverage.export(), [0x00, 0x04, 0x08, 0x14, 0x18]);
+
+        assert!(coverage.executed(0x08));
+        assert!(coverage.executed(0x14));
+        assert!(!coverage.executed(0x0c));
+        assert!(!coverage.executed(0x10));
+
+        coverage.clear();
+        assert!(coverage.export().is_empty());
+    }
+
+    #[test]
+    fn stack_guard() {
+        let mut cpu = test_util::arm_cpu(&[
+            // PUSH {R0, R1} x3
+            0xe92d0003,
+            0xe92d0003,
+            0xe92d0003,
+            // POP {R0, R1} x2
+            0xe8bd0003,
+            0xe8bd0003,
+            // PUSH {R0, R1}
+            0xe92d0003,
+            // B .
+            0xeafffffe,
+        ]);
+
+        cpu.set_register(13, 0x108);
+
+        let hits = Rc::new(RefCell::new(Vec::new()));
+
+        let log = hits.clone();
+        let hook = move |cpu: &mut Cpu, sp| {
+            log.borrow_mut().push((cpu.current_pc(), sp));
+        };
+
+        let mut guard = StackGuard::new(0x100, 0x200, Box::new(hook));
+
+        for _ in 0..8 {
+            cpu.run_next_instruction(&mut guard);
+        }
+
+        // The hook is called once when the SP leaves the bounds, then
+        // again after it came back
+        assert_eq!(*hits.borrow(), [(0x08, 0xf8), (0x18, 0xf8)]);
+    }
+}
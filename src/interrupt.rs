@@ -1,6 +1,10 @@
-use memory::Addressable;
+use std::fmt;
+
+use memory::{Addressable, register_load, register_store};
+use memory::unwrap_access;
 
 #[derive(RustcDecodable, RustcEncodable)]
+#[derive(Hash)]
 pub struct IrqController {
     /// Raw interrupt signal levels
     raw: u16,
@@ -21,6 +25,10 @@ impl IrqController {
         }
     }
 
+    pub fn reset(&mut self) {
+        *self = IrqController::new();
+    }
+
     /// Return true if any interrupt (IRQ or FIQ) is pending
     pub fn pending(&self) -> bool {
         (self.latch & self.mask) != 0
@@ -34,42 +42,89 @@ impl IrqController {
         (self.latch & self.mask & Interrupt::fiq_mask()) != 0
     }
 
+    /// Latched interrupts, readable at offset 0x00. There's no
+    /// interrupt priority register, the FIQ/IRQ routing is fixed (see
+    /// `Interrupt::is_fiq`).
+    pub fn status(&self) -> u16 {
+        self.latch
+    }
+
+    /// Raw interrupt signal levels, readable at offset 0x04
+    pub fn raw(&self) -> u16 {
+        self.raw
+    }
+
+    /// Interrupt mask, readable at offset 0x08. Bits are set by
+    /// writing to offset 0x08 and cleared by writing to offset 0x0c.
+    /// Writing to offset 0x10 acknowledges the latched interrupts.
+    pub fn mask(&self) -> u16 {
+        self.mask
+    }
+
+    /// Return the bitmask of all the interrupt sources currently
+    /// raised, regardless of the mask. Meant for debugging.
+    pub fn raw_pending(&self) -> u32 {
+        self.raw as u32
+    }
+
+    /// Return the bitmask of the interrupt sources currently raised
+    /// but masked. Since masked interrupts are not latched they'll be
+    /// missed unless they're still raised when they get unmasked.
+    pub fn masked_pending(&self) -> u32 {
+        (self.raw & !self.mask) as u32
+    }
+
     pub fn store<A: Addressable>(&mut self, offset: u32, val: u32) {
-        if A::size() == 1 {
-            panic!("Unhandled {}bit IRQ store", A::size() * 8);
-        }
+        unwrap_access(self.try_store::<A>(offset, val))
+    }
+
+    /// Same as `store` but unhandled accesses return an error
+    /// instead of panicking
+    pub fn try_store<A: Addressable>(&mut self,
+                                     offset: u32,
+                                     val: u32) -> Result<(), String> {
+        // All the writable registers set, clear or acknowledge the
+        // bits written to 1 so we can treat the bytes not covered by
+        // a sub-word store as zeroes.
+        let val = register_store::<A>(0, offset, val);
 
         // IRQ registers are 16bit wide
         let val = val as u16;
 
-        match offset {
+        match offset & !3 {
             // Interrupt mask set
             0x08 => self.mask |= val,
             // Interrupt mask clear
             0x0c => self.mask &= !val,
             // Interrupt acknowledge
             0x10 => self.latch &= !val,
-            _ => panic!("Unhandled IRQ register {:x}", offset),
+            _ => return Err(format!("Unhandled IRQ register {:x}", offset)),
         }
+
+        Ok(())
     }
 
     pub fn load<A: Addressable>(&self, offset: u32) -> u32 {
-        if A::size() == 1 {
-            panic!("Unhandled {}bit IRQ store", A::size() * 8);
-        }
+        unwrap_access(self.try_load::<A>(offset))
+    }
 
+    /// Same as `load` but unhandled accesses return an error instead
+    /// of panicking
+    pub fn try_load<A: Addressable>(&self,
+                                    offset: u32) -> Result<u32, String> {
         let r =
-            match offset {
+            match offset & !3 {
                 // Interrupt latch
                 0x00 => self.latch,
                 // Interrupt input
                 0x04 => self.raw,
                 // Interrupt mask
                 0x08 => self.mask,
-                _ => panic!("Unhandled IRQ register {:x}", offset),
+                _ => return Err(format!("Unhandled IRQ register {:x}",
+                                        offset)),
             };
 
-        r as u32
+        Ok(register_load::<A>(r as u32, offset))
     }
 
     pub fn ack(&mut self, val: u16) {
@@ -78,13 +133,13 @@ impl IrqController {
 
     /// Return the raw level of an interrupt
     pub fn raw_interrupt(&mut self, irq: Interrupt) -> bool {
-        (self.raw & (1 << (irq as u16))) != 0
+        (self.raw & irq.mask()) != 0
     }
 
     /// Return the raw level of an interrupt, latching it if it goes
     /// through a rising edge.
     pub fn set_raw_interrupt(&mut self, irq: Interrupt, level: bool) {
-        let mask = 1 << (irq as u16);
+        let mask = irq.mask();
 
         if level == true {
             if !self.raw_interrupt(irq) {
@@ -103,8 +158,34 @@ impl IrqController {
     }
 }
 
+/// State of the buttons and dock sense line as seen by the
+/// frontend. These are only visible to the guest through the raw
+/// interrupt levels.
 #[derive(RustcDecodable, RustcEncodable)]
-#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Hash, Default)]
+pub struct Inputs {
+    pub action: bool,
+    pub right: bool,
+    pub left: bool,
+    pub down: bool,
+    pub up: bool,
+    pub docked: bool,
+}
+
+impl Inputs {
+    /// Drive the corresponding raw interrupt levels in `irq`
+    pub fn apply(&self, irq: &mut IrqController) {
+        irq.set_raw_interrupt(Interrupt::ActionButton, self.action);
+        irq.set_raw_interrupt(Interrupt::RightButton, self.right);
+        irq.set_raw_interrupt(Interrupt::LeftButton, self.left);
+        irq.set_raw_interrupt(Interrupt::DownButton, self.down);
+        irq.set_raw_interrupt(Interrupt::UpButton, self.up);
+        irq.set_raw_interrupt(Interrupt::Docked, self.docked);
+    }
+}
+
+#[derive(RustcDecodable, RustcEncodable)]
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Hash)]
 pub enum Interrupt {
     /// [IRQ] "action" button (the big one on the right)
     ActionButton = 0,
@@ -116,6 +197,8 @@ pub enum Interrupt {
     DownButton = 3,
     /// [IRQ] Up directional button
     UpButton = 4,
+    // Bit 5 doesn't seem to be used. In particular there's no DAC
+    // sample request interrupt, see `Dac::set_fifo_threshold`.
     /// [FIQ] COM interrupt
     Com = 6,
     /// [IRQ] Timer 0
@@ -136,11 +219,276 @@ pub enum Interrupt {
 }
 
 impl Interrupt {
+    /// Return all the interrupt sources ordered by index
+    pub fn all() -> &'static [Interrupt] {
+        &ALL_INTERRUPTS
+    }
+
+    /// Return the interrupt with the given bit index in the IRQ
+    /// controller registers, if any.
+    pub fn from_index(index: u16) -> Option<Interrupt> {
+        Interrupt::all().iter().cloned().find(|i| i.index() == index)
+    }
+
+    /// Return the index of the bit corresponding to this interrupt in
+    /// the IRQ controller registers
+    pub fn index(self) -> u16 {
+        self as u16
+    }
+
+    /// Return the mask of this interrupt in the IRQ controller
+    /// registers
+    pub fn mask(self) -> u16 {
+        1 << self.index()
+    }
+
+    /// Return true if this interrupt is routed to the FIQ line
+    pub fn is_fiq(self) -> bool {
+        self.mask() & Interrupt::fiq_mask() != 0
+    }
+
     fn fiq_mask() -> u16 {
-        (1 << (Interrupt::Com as u16)) | (1 << (Interrupt::Timer2 as u16))
+        Interrupt::Com.mask() | Interrupt::Timer2.mask()
     }
 
     fn irq_mask() -> u16 {
         !Interrupt::fiq_mask()
     }
 }
+
+impl fmt::Display for Interrupt {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s =
+            match *self {
+                Interrupt::ActionButton => "Action button",
+                Interrupt::RightButton => "Right button",
+                Interrupt::LeftButton => "Left button",
+                Interrupt::DownButton => "Down button",
+                Interrupt::UpButton => "Up button",
+                Interrupt::Com => "COM",
+                Interrupt::Timer0 => "Timer 0",
+                Interrupt::Timer1 => "Timer 1",
+                Interrupt::Rtc => "RTC",
+                Interrupt::BatteryLow => "Battery low",
+                Interrupt::Docked => "Docked",
+                Interrupt::Irda => "IrDA",
+                Interrupt::Timer2 => "Timer 2",
+            };
+
+        write!(f, "{}", s)
+    }
+}
+
+/// All interrupt sources, ordered by index
+const ALL_INTERRUPTS: [Interrupt; 13] = [
+    Interrupt::ActionButton,
+    Interrupt::RightButton,
+    Interrupt::LeftButton,
+    Interrupt::DownButton,
+    Interrupt::UpButton,
+    Interrupt::Com,
+    Interrupt::Timer0,
+    Interrupt::Timer1,
+    Interrupt::Rtc,
+    Interrupt::BatteryLow,
+    Interrupt::Docked,
+    Interrupt::Irda,
+    Interrupt::Timer2,
+];
+
+#[cfg(test)]
+mod tests {
+    use memory::{Byte, HalfWord, Word};
+    use test_util;
+
+    use super::*;
+
+    /// Return the position of `irq` in the list of variants. The
+    /// match is exhaustive so this fails to build if a variant is
+    /// added without updating the tests.
+    fn variant_position(irq: Interrupt) -> usize {
+        match irq {
+            Interrupt::ActionButton => 0,
+            Interrupt::RightButton => 1,
+            Interrupt::LeftButton => 2,
+            Interrupt::DownButton => 3,
+            Interrupt::UpButton => 4,
+            Interrupt::Com => 5,
+            Interrupt::Timer0 => 6,
+            Interrupt::Timer1 => 7,
+            Interrupt::Rtc => 8,
+            Interrupt::BatteryLow => 9,
+            Interrupt::Docked => 10,
+            Interrupt::Irda => 11,
+            Interrupt::Timer2 => 12,
+        }
+    }
+
+    const VARIANT_COUNT: usize = 13;
+
+    #[test]
+    fn all_covers_every_variant() {
+        let all = Interrupt::all();
+
+        assert_eq!(all.len(), VARIANT_COUNT);
+
+        let mut seen = [false; VARIANT_COUNT];
+
+        for &irq in all {
+            let pos = variant_position(irq);
+
+            assert!(!seen[pos], "{:?} listed twice", irq);
+            seen[pos] = true;
+        }
+
+        // Ordered by index
+        for pair in all.windows(2) {
+            assert!(pair[0].index() < pair[1].index());
+        }
+    }
+
+    #[test]
+    fn index_mapping_is_bijective() {
+        let mut masks = 0u16;
+
+        for &irq in Interrupt::all() {
+            assert!(irq.index() < 16);
+            assert_eq!(Interrupt::from_index(irq.index()), Some(irq));
+            assert_eq!(irq.mask(), 1 << irq.index());
+
+            assert_eq!(masks & irq.mask(), 0);
+            masks |= irq.mask();
+        }
+
+        for index in 0..16 {
+            match Interrupt::from_index(index) {
+                Some(irq) => assert_eq!(irq.index(), index),
+                None => assert_eq!(masks & (1 << index), 0),
+            }
+        }
+    }
+
+    #[test]
+    fn bit_5_unused() {
+        assert_eq!(Interrupt::from_index(5), None);
+        assert_eq!(Interrupt::UpButton.index(), 4);
+        assert_eq!(Interrupt::Com.index(), 6);
+    }
+
+    #[test]
+    fn display() {
+        assert_eq!(Interrupt::Timer0.to_string(), "Timer 0");
+        assert_eq!(Interrupt::Irda.to_string(), "IrDA");
+        assert_eq!(format!("{:?}", Interrupt::Rtc), "Rtc");
+    }
+
+    #[test]
+    fn masked_pending() {
+        let mut irq = IrqController::new();
+
+        let timer0 = Interrupt::Timer0.mask() as u32;
+        let rtc = Interrupt::Rtc.mask() as u32;
+
+        // Only the RTC is unmasked
+        irq.store::<Word>(8, rtc);
+
+        irq.set_raw_interrupt(Interrupt::Timer0, true);
+
+        assert_eq!(irq.raw_pending(), timer0);
+        assert_eq!(irq.masked_pending(), timer0);
+        assert!(!irq.irq_pending());
+
+        irq.set_raw_interrupt(Interrupt::Rtc, true);
+
+        assert_eq!(irq.raw_pending(), timer0 | rtc);
+        assert_eq!(irq.masked_pending(), timer0);
+        assert!(irq.irq_pending());
+
+        // Unmasking the timer clears it from the masked set
+        irq.store::<Word>(8, rtc | timer0);
+
+        assert_eq!(irq.masked_pending(), 0);
+
+        irq.set_raw_interrupt(Interrupt::Timer0, false);
+        irq.set_raw_interrupt(Interrupt::Rtc, false);
+
+        assert_eq!(irq.raw_pending(), 0);
+    }
+
+    #[test]
+    fn register_accessors() {
+        // STR R1, [R0, #8]
+        // STR R2, [R0, #0xc]
+        // LDR R3, [R0, #8]
+        // LDR R4, [R0]
+        // LDR R5, [R0, #4]
+        let mut cpu = test_util::arm_cpu(&[0xe5801008,
+                                           0xe580200c,
+                                           0xe5903008,
+                                           0xe5904000,
+                                           0xe5905004]);
+
+        let timer0 = Interrupt::Timer0.mask();
+        let rtc = Interrupt::Rtc.mask();
+
+        cpu.set_register(0, 0x0a000000);
+        cpu.set_register(1, (timer0 | rtc) as u32);
+        cpu.set_register(2, rtc as u32);
+
+        test_util::run(&mut cpu, 2);
+
+        // Set then clear
+        assert_eq!(cpu.interconnect_mut().irq_controller().mask(), timer0);
+
+        cpu.interconnect_mut()
+            .irq_controller_mut()
+            .set_raw_interrupt(Interrupt::Timer0, true);
+
+        test_util::run(&mut cpu, 3);
+
+        let (mask, status, raw) = {
+            let irq = cpu.interconnect_mut().irq_controller();
+
+            (irq.mask(), irq.status(), irq.raw())
+        };
+
+        assert_eq!(status, timer0);
+        assert_eq!(raw, timer0);
+
+        // The guest sees the same values
+        assert_eq!(cpu.registers()[3], mask as u32);
+        assert_eq!(cpu.registers()[4], status as u32);
+        assert_eq!(cpu.registers()[5], raw as u32);
+    }
+
+    #[test]
+    fn sub_word_accesses() {
+        let mut inter = test_util::interconnect(&[], &[]);
+
+        // Set the low byte of the mask
+        inter.store::<Byte>(0x0a000008, 0x5a);
+        assert_eq!(inter.irq_controller().mask(), 0x005a);
+
+        // Set then clear bits in the high byte only
+        inter.store::<Byte>(0x0a000009, 0x1f);
+        assert_eq!(inter.irq_controller().mask(), 0x1f5a);
+
+        inter.store::<Byte>(0x0a00000d, 0x03);
+        assert_eq!(inter.irq_controller().mask(), 0x1c5a);
+
+        // Clearing the low byte leaves the high byte alone
+        inter.store::<Byte>(0x0a00000c, 0xff);
+        assert_eq!(inter.irq_controller().mask(), 0x1c00);
+
+        inter.store::<HalfWord>(0x0a000008, 0x00a5);
+        assert_eq!(inter.irq_controller().mask(), 0x1ca5);
+
+        // Lane selection on reads
+        assert_eq!(inter.load::<Byte>(0x0a000008), 0xa5);
+        assert_eq!(inter.load::<Byte>(0x0a000009), 0x1c);
+        assert_eq!(inter.load::<HalfWord>(0x0a000008), 0x1ca5);
+        // The registers are only 16bit wide
+        assert_eq!(inter.load::<HalfWord>(0x0a00000a), 0);
+        assert_eq!(inter.load::<Word>(0x0a000008), 0x1ca5);
+    }
+}
@@ -1,11 +1,32 @@
 //! PocketStation infrared I/O emulation
 
-use memory::Addressable;
+use std::collections::VecDeque;
+use std::hash::{Hash, Hasher};
 
+use rustc_serialize::{Decodable, Encodable, Decoder, Encoder};
+
+use memory::{Addressable, unwrap_access};
+
+/// The IrDA "controller" is little more than direct control over the
+/// LED: the framing is bit-banged by the guest software so there's no
+/// hardware frame or checksum format to model here.
+///
+/// The frame queues let frontends exchange protocol-level frames
+/// between linked devices. XXX They're not connected to the LED yet:
+/// the guest can't see them until the pulses are decoded with
+/// accurate timings. Since they're not part of the machine state
+/// they're not saved in savestates and each queue holds at most
+/// `FRAME_QUEUE_DEPTH` frames.
 #[derive(RustcDecodable, RustcEncodable)]
+#[derive(Hash)]
 pub struct Irda {
     mode: u8,
     led_on: bool,
+    /// Frames sent by this device, waiting to be picked up by the
+    /// linked device
+    sent: FrameQueue,
+    /// Frames received from the linked device
+    received: FrameQueue,
 }
 
 impl Irda {
@@ -13,30 +34,220 @@ impl Irda {
         Irda {
             mode: 0,
             led_on: false,
+            sent: FrameQueue::new(),
+            received: FrameQueue::new(),
+        }
+    }
+
+    /// Reset the controller, the frames still in the queues are
+    /// dropped
+    pub fn reset(&mut self) {
+        *self = Irda::new();
+    }
+
+    /// Queue `frame` for transmission to the linked device
+    pub fn send_frame(&mut self, frame: &[u8]) {
+        self.sent.push(frame.to_vec());
+    }
+
+    /// Return the oldest frame sent by this device not yet delivered
+    /// to the linked device
+    pub fn take_sent_frame(&mut self) -> Option<Vec<u8>> {
+        self.sent.0.pop_front()
+    }
+
+    /// Deliver a frame coming from the linked device
+    pub fn receive_frame(&mut self, frame: Vec<u8>) {
+        self.received.push(frame);
+    }
+
+    /// Return the oldest frame received from the linked device
+    pub fn take_received_frame(&mut self) -> Option<Vec<u8>> {
+        self.received.0.pop_front()
+    }
+
+    /// Deliver the pending frames of both devices to each other, in
+    /// the order they were sent
+    pub fn link(&mut self, other: &mut Irda) {
+        while let Some(f) = self.take_sent_frame() {
+            other.receive_frame(f);
+        }
+
+        while let Some(f) = other.take_sent_frame() {
+            self.receive_frame(f);
         }
     }
 
     pub fn store<A: Addressable>(&mut self, offset: u32, val: u32) {
+        unwrap_access(self.try_store::<A>(offset, val))
+    }
+
+    /// Same as `store` but unhandled accesses return an error
+    /// instead of panicking
+    pub fn try_store<A: Addressable>(&mut self,
+                                     offset: u32,
+                                     val: u32) -> Result<(), String> {
         if A::size() != 4 {
-            panic!("Unhandled {}bit IrDA store", A::size() * 8);
+            return Err(format!("Unhandled {}bit IrDA store", A::size() * 8));
         }
 
         match offset {
             0 => self.mode = val as u8,
             4 => self.led_on = (val & 1) != 0,
-            _ => panic!("Unhandled IrDA register {:x}", offset),
+            _ => return Err(format!("Unhandled IrDA register {:x}", offset)),
         }
+
+        Ok(())
     }
 
     pub fn load<A: Addressable>(&self, offset: u32) -> u32 {
+        unwrap_access(self.try_load::<A>(offset))
+    }
+
+    /// Same as `load` but unhandled accesses return an error instead
+    /// of panicking
+    pub fn try_load<A: Addressable>(&self,
+                                    offset: u32) -> Result<u32, String> {
         if A::size() != 4 {
-            panic!("Unhandled {}bit IrDA store", A::size() * 8);
+            return Err(format!("Unhandled {}bit IrDA store", A::size() * 8));
         }
 
-        match offset {
-            0 => self.mode as u32,
-            4 => self.led_on as u32,
-            _ => panic!("Unhandled IrDA register {:x}", offset),
+        let r =
+            match offset {
+                0 => self.mode as u32,
+                4 => self.led_on as u32,
+                _ => return Err(format!("Unhandled IrDA register {:x}",
+                                        offset)),
+            };
+
+        Ok(r)
+    }
+}
+
+/// Queue of protocol-level frames, see `Irda`
+struct FrameQueue(VecDeque<Vec<u8>>);
+
+impl FrameQueue {
+    fn new() -> FrameQueue {
+        FrameQueue(VecDeque::new())
+    }
+
+    /// Append `frame` to the queue, dropping it if the queue is full
+    fn push(&mut self, frame: Vec<u8>) {
+        if self.0.len() < FRAME_QUEUE_DEPTH {
+            self.0.push_back(frame);
+        } else {
+            warn!("IrDA frame queue overflow, dropping frame");
+        }
+    }
+}
+
+impl Encodable for FrameQueue {
+    fn encode<S: Encoder>(&self, s: &mut S) -> Result<(), S::Error> {
+        // The frames are exchanged by the frontends, not part of the
+        // machine state
+        s.emit_nil()
+    }
+}
+
+impl Decodable for FrameQueue {
+    fn decode<D: Decoder>(d: &mut D) -> Result<FrameQueue, D::Error> {
+        try!(d.read_nil());
+
+        Ok(FrameQueue::new())
+    }
+}
+
+impl Hash for FrameQueue {
+    fn hash<H: Hasher>(&self, _: &mut H) {
+        // Not part of the machine state, see `Interconnect::content_hash`
+    }
+}
+
+/// Maximum number of frames held by each of the IrDA frame queues
+pub const FRAME_QUEUE_DEPTH: usize = 16;
+
+#[cfg(test)]
+mod tests {
+    use rustc_serialize::json;
+
+    use test_util;
+
+    use super::*;
+
+    #[test]
+    fn frame_round_trip() {
+        let mut a = Irda::new();
+        let mut b = Irda::new();
+
+        a.send_frame(&[0x01, 0x02, 0x03]);
+        a.send_frame(&[]);
+        b.send_frame(&[0xff; 300]);
+
+        // Nothing is delivered until the devices are linked
+        assert_eq!(b.take_received_frame(), None);
+
+        a.link(&mut b);
+
+        assert_eq!(b.take_received_frame(), Some(vec![0x01, 0x02, 0x03]));
+        assert_eq!(b.take_received_frame(), Some(vec![]));
+        assert_eq!(b.take_received_frame(), None);
+
+        assert_eq!(a.take_received_frame(), Some(vec![0xff; 300]));
+        assert_eq!(a.take_received_frame(), None);
+
+        // Frames can also be relayed by the frontend
+        a.send_frame(&[0x42]);
+
+        let f = a.take_sent_frame().unwrap();
+        b.receive_frame(f);
+
+        assert_eq!(a.take_sent_frame(), None);
+        assert_eq!(b.take_received_frame(), Some(vec![0x42]));
+
+        // Reset drops the pending frames
+        a.send_frame(&[0x42]);
+        a.receive_frame(vec![0x24]);
+        a.reset();
+
+        assert_eq!(a.take_sent_frame(), None);
+        assert_eq!(a.take_received_frame(), None);
+    }
+
+    #[test]
+    fn frame_queues_not_in_state() {
+        let mut inter = test_util::interconnect(&[], &[]);
+        let hash = inter.content_hash();
+
+        {
+            let irda = inter.irda_mut();
+
+            for i in 0..(FRAME_QUEUE_DEPTH + 4) {
+                irda.send_frame(&[i as u8]);
+                irda.receive_frame(vec![i as u8]);
+            }
         }
+
+        // The queues are not part of the machine state
+        assert_eq!(inter.content_hash(), hash);
+
+        let irda = inter.irda_mut();
+        let state = json::encode(irda).unwrap();
+
+        assert_eq!(state, json::encode(&Irda::new()).unwrap());
+
+        let mut decoded: Irda = json::decode(&state).unwrap();
+
+        assert_eq!(decoded.take_sent_frame(), None);
+        assert_eq!(decoded.take_received_frame(), None);
+
+        // The queues are bounded, the frames in excess are dropped
+        for i in 0..FRAME_QUEUE_DEPTH {
+            assert_eq!(irda.take_sent_frame(), Some(vec![i as u8]));
+            assert_eq!(irda.take_received_frame(), Some(vec![i as u8]));
+        }
+
+        assert_eq!(irda.take_sent_frame(), None);
+        assert_eq!(irda.take_received_frame(), None);
     }
 }
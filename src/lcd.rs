@@ -1,12 +1,41 @@
 //! LCD controller emulation
 
-use memory::Addressable;
+use std::ops::Range;
+use std::hash::{Hash, Hasher};
+use std::mem::swap;
+
+use rustc_serialize::{Decodable, Encodable, Decoder, Encoder};
+
+use memory::{Addressable, register_load, register_store};
+use memory::unwrap_access;
+use MASTER_CLOCK_HZ;
 
 #[derive(RustcDecodable, RustcEncodable)]
+#[derive(Hash)]
 pub struct Lcd {
     mode: u8,
     calibration: u8,
+    /// Framebuffer, directly written by the CPU through the LCD
+    /// registers. There's no DMA or transfer engine between the CPU
+    /// and the LCD (or the DAC), see
+    /// `Interconnect::set_lcd_contention` for an experimental model
+    /// of the contention with the scanout.
     fb: [u32; 32],
+    /// Copy of `fb` latched at the end of the last complete frame.
+    /// This is what the frontend displays, so that it never sees a
+    /// frame the guest was in the middle of drawing.
+    displayed: [u32; 32],
+    /// Position within the current frame in master clock ticks, used
+    /// to emulate the scanout position.
+    frame_pos: u32,
+    /// Bitmap of the displayed framebuffer rows modified since the
+    /// last call to `take_dirty_rows`
+    dirty_rows: u32,
+    /// Colors used by `framebuffer_rgb`
+    palette: Palette,
+    /// If true the guessed status register at offset 8 is readable,
+    /// see `set_experimental_status`
+    experimental_status: bool,
 }
 
 impl Lcd {
@@ -15,49 +44,553 @@ impl Lcd {
             mode: 0,
             calibration: 0,
             fb: [0xaaaa5555; 32],
+            displayed: [0xaaaa5555; 32],
+            frame_pos: 0,
+            // Force the frontend to draw the whole screen once
+            dirty_rows: !0,
+            palette: Palette(DEFAULT_PALETTE),
+            experimental_status: false,
         }
     }
 
-    pub fn store<A: Addressable>(&mut self, offset: u32, val: u32) {
-        if A::size() != 4 {
-            panic!("Unhandled {}bit LCD store", A::size() * 8);
+    pub fn reset(&mut self) {
+        let palette = self.palette.0;
+        let experimental_status = self.experimental_status;
+
+        *self = Lcd::new();
+
+        self.palette.0 = palette;
+        self.experimental_status = experimental_status;
+    }
+
+    /// Make the status register at offset 8 readable (scanline in
+    /// bits [5:0], vblank flag in bit 8). This register is not
+    /// documented anywhere and its layout is made up, so it's
+    /// disabled by default and reading it is then treated like any
+    /// other unhandled LCD register.
+    pub fn set_experimental_status(&mut self, enabled: bool) {
+        self.experimental_status = enabled;
+    }
+
+    /// Advance the scanout position by `master_ticks` and return the
+    /// number of frames completed
+    pub fn tick(&mut self, master_ticks: u32) -> u32 {
+        // Use 64bits to avoid overflows with large tick counts
+        let pos = self.frame_pos as u64 + master_ticks as u64;
+
+        self.frame_pos = (pos % FRAME_TICKS as u64) as u32;
+
+        let frames = (pos / FRAME_TICKS as u64) as u32;
+
+        if frames > 0 {
+            self.latch_frame();
         }
 
-        match offset {
-            0 => self.mode = val as u8,
-            4 => self.calibration = val as u8,
-            0x100...0x17c => {
-                let i = (offset & 0x7f) as usize;
+        frames
+    }
 
-                self.fb[i / 4] = val;
+    /// Called at the end of every frame to update the displayed
+    /// framebuffer
+    fn latch_frame(&mut self) {
+        for i in 0..self.fb.len() {
+            if self.displayed[i] != self.fb[i] {
+                self.displayed[i] = self.fb[i];
+                self.dirty_rows |= 1 << i;
             }
-            _ => panic!("Unhandled LCD register {:x}", offset),
         }
     }
 
-    pub fn load<A: Addressable>(&self, offset: u32) -> u32 {
-        if A::size() != 4 {
-            panic!("Unhandled {}bit LCD store", A::size() * 8);
-        }
+    /// Return the number of master clock ticks until the end of the
+    /// current frame
+    pub fn next_event(&self) -> u32 {
+        FRAME_TICKS - self.frame_pos
+    }
+
+    pub fn store<A: Addressable>(&mut self, offset: u32, val: u32) {
+        unwrap_access(self.try_store::<A>(offset, val))
+    }
+
+    /// Same as `store` but unhandled accesses return an error
+    /// instead of panicking
+    pub fn try_store<A: Addressable>(&mut self,
+                                     offset: u32,
+                                     val: u32) -> Result<(), String> {
+        match offset & !3 {
+            0 => {
+                let mode = register_store::<A>(self.mode as u32, offset, val);
+
+                if self.rotated() != (mode & 0x80 != 0) {
+                    // The whole display must be redrawn
+                    self.dirty_rows = !0;
+                }
 
-        match offset {
-            0 => self.mode as u32,
-            4 => self.calibration as u32,
+                self.mode = mode as u8;
+            }
+            4 => {
+                let calibration =
+                    register_store::<A>(self.calibration as u32, offset, val);
+
+                self.calibration = calibration as u8;
+            }
             0x100...0x17c => {
-                let i = (offset & 0x7f) as usize;
+                let i = ((offset & 0x7f) / 4) as usize;
 
-                self.fb[i / 4]
+                // The change will only be displayed at the end of the
+                // frame
+                self.fb[i] = register_store::<A>(self.fb[i], offset, val);
             }
-            _ => panic!("Unhandled LCD register {:x}", offset),
+            _ => return Err(format!("Unhandled LCD register {:x}", offset)),
         }
+
+        Ok(())
+    }
+
+    pub fn load<A: Addressable>(&self, offset: u32) -> u32 {
+        unwrap_access(self.try_load::<A>(offset))
+    }
+
+    /// Same as `load` but unhandled accesses return an error instead
+    /// of panicking
+    pub fn try_load<A: Addressable>(&self,
+                                    offset: u32) -> Result<u32, String> {
+        let reg =
+            match offset & !3 {
+                0 => self.mode as u32,
+                4 => self.calibration as u32,
+                8 if self.experimental_status => self.status(),
+                0x100...0x17c => {
+                    let i = (offset & 0x7f) as usize;
+
+                    self.fb[i / 4]
+                }
+                _ => return Err(format!("Unhandled LCD register {:x}",
+                                        offset)),
+            };
+
+        Ok(register_load::<A>(reg, offset))
     }
 
+    /// Return the framebuffer as of the end of the last complete
+    /// frame
     pub fn framebuffer(&self) -> &[u32; 32] {
-        &self.fb
+        &self.displayed
+    }
+
+    /// Set the colors used by `framebuffer_rgb`: `palette[0]` for the
+    /// pixels that are off and `palette[1]` for the pixels that are
+    /// on. The palette is a frontend setting, it's not saved in
+    /// savestates.
+    pub fn set_palette(&mut self, palette: [Rgb; PALETTE_SIZE]) {
+        self.palette.0 = palette;
+    }
+
+    /// Swap the palettes of `self` and `other`, used to keep the
+    /// frontend setting when loading a savestate
+    pub fn swap_palette(&mut self, other: &mut Lcd) {
+        swap(&mut self.palette, &mut other.palette);
+    }
+
+    /// Convert `framebuffer` to 24bit RGB using the palette set by
+    /// `set_palette`. Returns `LCD_LINES` rows of 32 pixels, 3 bytes
+    /// per pixel. Like `framebuffer` the rows are in framebuffer
+    /// order, the frontend must take the rotation into account.
+    pub fn framebuffer_rgb(&self) -> Vec<u8> {
+        let mut rgb = Vec::with_capacity(32 * 32 * 3);
+
+        for &row in self.displayed.iter() {
+            for x in 0..32 {
+                let (r, g, b) = self.palette.0[((row >> x) & 1) as usize];
+
+                rgb.push(r);
+                rgb.push(g);
+                rgb.push(b);
+            }
+        }
+
+        rgb
+    }
+
+    /// Replace the framebuffer contents with `pattern`. This is a
+    /// debugging helper to test the frontend's display code without
+    /// any guest code running. The pattern is displayed immediately.
+    pub fn fill_test_pattern(&mut self, pattern: TestPattern) {
+        for (y, row) in self.fb.iter_mut().enumerate() {
+            *row =
+                match pattern {
+                    TestPattern::AllOff => 0,
+                    TestPattern::AllOn => !0,
+                    TestPattern::Checkerboard =>
+                        if y & 1 == 0 {
+                            0x55555555
+                        } else {
+                            0xaaaaaaaa
+                        },
+                    // Each row has `y + 1` pixels lit starting from
+                    // the left (bit 0)
+                    TestPattern::Gradient => !0 >> (31 - y),
+                };
+        }
+
+        self.displayed = self.fb;
+        self.dirty_rows = !0;
+    }
+
+    /// Return the ranges of framebuffer rows modified since the last
+    /// call to this function, so that the frontend only needs to
+    /// redraw those. The rows are in framebuffer order, the frontend
+    /// must take the rotation into account.
+    pub fn take_dirty_rows(&mut self) -> Vec<Range<u32>> {
+        let mut ranges = Vec::new();
+        let mut dirty = self.dirty_rows;
+
+        self.dirty_rows = 0;
+
+        let mut row = 0;
+
+        while dirty != 0 {
+            let skip = dirty.trailing_zeros();
+
+            row += skip;
+            dirty >>= skip;
+
+            let len = (!dirty).trailing_zeros();
+
+            ranges.push(row..row + len);
+
+            row += len;
+            // Shifting by 32 would overflow
+            dirty = if len < 32 { dirty >> len } else { 0 };
+        }
+
+        ranges
     }
 
     /// Return true if the screen rotation flag is set
     pub fn rotated(&self) -> bool {
         self.mode & 0x80 != 0
     }
+
+    /// Return the line currently being scanned out. Values greater
+    /// than or equal to `LCD_LINES` mean that we're in the vertical
+    /// blanking period.
+    pub fn scanline(&self) -> u32 {
+        self.frame_pos / LINE_TICKS
+    }
+
+    /// Return the number of master clock ticks until the start of the
+    /// next vertical blanking period
+    pub fn ticks_to_vblank(&self) -> u32 {
+        let vblank_start = LCD_LINES * LINE_TICKS;
+
+        if self.frame_pos < vblank_start {
+            vblank_start - self.frame_pos
+        } else {
+            FRAME_TICKS - self.frame_pos + vblank_start
+        }
+    }
+
+    /// Return true if we're in the vertical blanking period
+    pub fn in_vblank(&self) -> bool {
+        self.scanline() >= LCD_LINES
+    }
+
+    /// Build the value of the experimental status register: current
+    /// scanline in bits [5:0], vblank flag in bit 8.
+    ///
+    /// XXX I haven't found any documentation for this register, the
+    /// layout and timings are guesswork until somebody runs tests on
+    /// the real hardware. See `set_experimental_status`.
+    fn status(&self) -> u32 {
+        self.scanline() | ((self.in_vblank() as u32) << 8)
+    }
+}
+
+/// Patterns for `Lcd::fill_test_pattern`
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum TestPattern {
+    /// All pixels off
+    AllOff,
+    /// All pixels on
+    AllOn,
+    /// Alternating pixels, each pixel differs from its four
+    /// neighbours
+    Checkerboard,
+    /// Triangle getting wider with each row
+    Gradient,
+}
+
+/// 24bit RGB color
+pub type Rgb = (u8, u8, u8);
+
+/// Number of palette entries. The LCD is monochrome so there are only
+/// two levels.
+pub const PALETTE_SIZE: usize = 2;
+
+/// Default palette: black pixels on a white background
+const DEFAULT_PALETTE: [Rgb; PALETTE_SIZE] = [(0xff, 0xff, 0xff),
+                                              (0x00, 0x00, 0x00)];
+
+struct Palette([Rgb; PALETTE_SIZE]);
+
+impl Encodable for Palette {
+    fn encode<S: Encoder>(&self, s: &mut S) -> Result<(), S::Error> {
+        // The palette is a frontend setting, not part of the machine
+        // state
+        s.emit_nil()
+    }
+}
+
+impl Decodable for Palette {
+    fn decode<D: Decoder>(d: &mut D) -> Result<Palette, D::Error> {
+        try!(d.read_nil());
+
+        Ok(Palette(DEFAULT_PALETTE))
+    }
+}
+
+impl Hash for Palette {
+    fn hash<H: Hasher>(&self, _: &mut H) {
+        // Not part of the machine state, see `Interconnect::content_hash`
+    }
+}
+
+/// Number of visible lines on the LCD
+pub const LCD_LINES: u32 = 32;
+
+/// Number of lines in a frame, including the vertical blanking
+/// period.
+pub const FRAME_LINES: u32 = LCD_LINES + 4;
+
+/// Duration of a single line in master clock ticks. This gives a
+/// refresh rate of about 64Hz.
+pub const LINE_TICKS: u32 = MASTER_CLOCK_HZ / (64 * FRAME_LINES);
+
+/// Duration of a full frame in master clock ticks
+pub const FRAME_TICKS: u32 = LINE_TICKS * FRAME_LINES;
+
+#[cfg(test)]
+mod tests {
+    use memory::Word;
+
+    use super::*;
+
+    #[test]
+    #[should_panic(expected = "Unhandled LCD register 8")]
+    fn status_disabled_by_default() {
+        let lcd = Lcd::new();
+
+        lcd.load::<Word>(8);
+    }
+
+    #[test]
+    fn status_scanline() {
+        let mut lcd = Lcd::new();
+
+        lcd.set_experimental_status(true);
+
+        assert_eq!(lcd.load::<Word>(8), 0);
+
+        for line in 1..FRAME_LINES {
+            lcd.tick(LINE_TICKS);
+
+            let vblank = (line >= LCD_LINES) as u32;
+
+            assert_eq!(lcd.load::<Word>(8), line | (vblank << 8));
+        }
+
+        // Back to the first line of the next frame
+        lcd.tick(LINE_TICKS);
+
+        assert_eq!(lcd.load::<Word>(8), 0);
+    }
+
+    #[test]
+    fn status_mid_line() {
+        let mut lcd = Lcd::new();
+
+        lcd.set_experimental_status(true);
+
+        lcd.tick(LINE_TICKS * 5 + LINE_TICKS / 2);
+
+        assert_eq!(lcd.load::<Word>(8), 5);
+
+        // The option survives a reset
+        lcd.reset();
+
+        assert_eq!(lcd.load::<Word>(8), 0);
+    }
+
+    #[test]
+    fn dirty_rows() {
+        let mut lcd = Lcd::new();
+
+        // Everything must be drawn once
+        assert_eq!(lcd.take_dirty_rows(), [0..32]);
+        assert_eq!(lcd.take_dirty_rows(), []);
+
+        lcd.store::<Word>(0x100 + 3 * 4, 0x12345678);
+        lcd.store::<Word>(0x100 + 4 * 4, 0x9abcdef0);
+        lcd.store::<Word>(0x100 + 10 * 4, 0);
+        lcd.store::<Word>(0x100 + 31 * 4, 0xffffffff);
+
+        // Rewriting the current value doesn't change anything
+        lcd.store::<Word>(0x100 + 20 * 4, 0xaaaa5555);
+
+        // The changes only reach the display at the end of the frame
+        assert_eq!(lcd.take_dirty_rows(), []);
+
+        lcd.tick(FRAME_TICKS);
+
+        assert_eq!(lcd.take_dirty_rows(), [3..5, 10..11, 31..32]);
+        assert_eq!(lcd.take_dirty_rows(), []);
+
+        // A row modified and restored within a frame is not dirty
+        lcd.store::<Word>(0x100, 0);
+        lcd.store::<Word>(0x100, 0xaaaa5555);
+        lcd.tick(FRAME_TICKS);
+
+        assert_eq!(lcd.take_dirty_rows(), []);
+
+        // Rotating the display redraws everything
+        lcd.store::<Word>(0, 0x80);
+
+        assert_eq!(lcd.take_dirty_rows(), [0..32]);
+    }
+
+    #[test]
+    fn test_patterns() {
+        let mut lcd = Lcd::new();
+
+        let pixel = |fb: &[u32; 32], x: usize, y: usize| {
+            (fb[y] >> x) & 1 != 0
+        };
+
+        lcd.fill_test_pattern(TestPattern::Checkerboard);
+
+        {
+            let fb = lcd.framebuffer();
+
+            for y in 0..32 {
+                for x in 0..32 {
+                    let p = pixel(fb, x, y);
+
+                    if x < 31 {
+                        assert!(p != pixel(fb, x + 1, y));
+                    }
+
+                    if y < 31 {
+                        assert!(p != pixel(fb, x, y + 1));
+                    }
+                }
+            }
+        }
+
+        // The whole screen needs to be redrawn
+        assert_eq!(lcd.take_dirty_rows(), [0..32]);
+
+        lcd.fill_test_pattern(TestPattern::AllOn);
+        assert!(lcd.framebuffer().iter().all(|&r| r == !0));
+
+        lcd.fill_test_pattern(TestPattern::AllOff);
+        assert!(lcd.framebuffer().iter().all(|&r| r == 0));
+
+        lcd.fill_test_pattern(TestPattern::Gradient);
+
+        for (y, &row) in lcd.framebuffer().iter().enumerate() {
+            assert_eq!(row.count_ones() as usize, y + 1);
+            assert!(row & 1 != 0);
+        }
+    }
+
+    #[test]
+    fn tear_free() {
+        let mut lcd = Lcd::new();
+
+        let draw = |lcd: &mut Lcd, rows: Range<usize>, val: u32| {
+            for y in rows {
+                lcd.store::<Word>(0x100 + y as u32 * 4, val ^ y as u32);
+            }
+        };
+
+        let frame = |val: u32| {
+            let mut fb = [0; 32];
+
+            for (y, row) in fb.iter_mut().enumerate() {
+                *row = val ^ y as u32;
+            }
+
+            fb
+        };
+
+        // First frame
+        draw(&mut lcd, 0..32, 0x11110000);
+        assert_eq!(lcd.tick(FRAME_TICKS), 1);
+        assert_eq!(*lcd.framebuffer(), frame(0x11110000));
+
+        // Draw the top half of the second frame, then tick to
+        // mid-frame
+        draw(&mut lcd, 0..16, 0x22220000);
+        assert_eq!(lcd.tick(FRAME_TICKS / 2), 0);
+
+        // The frontend still sees the last complete frame...
+        assert_eq!(*lcd.framebuffer(), frame(0x11110000));
+
+        // ...while the guest reads back what it wrote
+        assert_eq!(lcd.load::<Word>(0x100), 0x22220000);
+        assert_eq!(lcd.load::<Word>(0x100 + 31 * 4), 0x1111001f);
+
+        // Finish the frame
+        draw(&mut lcd, 16..32, 0x22220000);
+        assert_eq!(*lcd.framebuffer(), frame(0x11110000));
+
+        assert_eq!(lcd.tick(FRAME_TICKS - FRAME_TICKS / 2), 1);
+        assert_eq!(*lcd.framebuffer(), frame(0x22220000));
+    }
+
+    #[test]
+    fn framebuffer_rgb() {
+        let mut lcd = Lcd::new();
+
+        let off = (0x9b, 0xbc, 0x0f);
+        let on = (0x0f, 0x38, 0x0f);
+
+        lcd.set_palette([off, on]);
+
+        // Top left and bottom right pixels on
+        for y in 0..32 {
+            lcd.store::<Word>(0x100 + y * 4, 0);
+        }
+
+        lcd.store::<Word>(0x100, 1);
+        lcd.store::<Word>(0x100 + 31 * 4, 0x80000000);
+        assert_eq!(lcd.tick(FRAME_TICKS), 1);
+
+        let rgb = lcd.framebuffer_rgb();
+
+        assert_eq!(rgb.len(), 32 * 32 * 3);
+
+        let pixel = |x: usize, y: usize| {
+            let p = (y * 32 + x) * 3;
+
+            (rgb[p], rgb[p + 1], rgb[p + 2])
+        };
+
+        for y in 0..32 {
+            for x in 0..32 {
+                let lit = (x, y) == (0, 0) || (x, y) == (31, 31);
+
+                assert_eq!(pixel(x, y), if lit { on } else { off });
+            }
+        }
+
+        // The raw framebuffer is unaffected
+        assert_eq!(lcd.framebuffer()[0], 1);
+        assert_eq!(lcd.framebuffer()[31], 0x80000000);
+
+        // The palette is a frontend setting, it survives a reset
+        lcd.reset();
+        assert_eq!(lcd.framebuffer()[0] & 3, 1);
+        assert_eq!(lcd.framebuffer_rgb()[0..6],
+                   [on.0, on.1, on.2, off.0, off.1, off.2]);
+    }
 }
@@ -1,6 +1,19 @@
 #[macro_use]
 mod box_array;
 
+/// Trace accesses to registers we don't emulate yet. They're logged
+/// at the trace level when the `verbose-io` feature is enabled,
+/// otherwise they're compiled out and a default build stays silent.
+#[cfg(feature = "verbose-io")]
+macro_rules! io_trace {
+    ($($arg:tt)*) => { trace!($($arg)*) }
+}
+
+#[cfg(not(feature = "verbose-io"))]
+macro_rules! io_trace {
+    ($($arg:tt)*) => { { let _ = format_args!($($arg)*); } }
+}
+
 pub mod cpu;
 pub mod memory;
 pub mod lcd;
@@ -8,9 +21,14 @@ pub mod interrupt;
 pub mod dac;
 pub mod rtc;
 pub mod debugger;
-mod irda;
+pub mod rewind;
+pub mod savestate;
+pub mod irda;
 mod timer;
 
+#[cfg(test)]
+mod test_util;
+
 #[macro_use]
 extern crate log;
 extern crate shaman;
@@ -3,7 +3,7 @@ use rustc_serialize::{Decodable, Encodable, Decoder, Encoder};
 use shaman::digest::Digest;
 use shaman::sha2::Sha256;
 
-use super::Addressable;
+use super::{Addressable, unwrap_access};
 
 pub struct Bios {
     data: Box<[u8; BIOS_SIZE]>,
@@ -39,12 +39,29 @@ impl Bios {
         Some(Bios { data: data })
     }
 
+    /// Build a BIOS filled with zeroes, for running programs which
+    /// don't need the real one (see `Cpu::fast_boot`)
+    pub fn empty() -> Bios {
+        Bios { data: box_array![0; BIOS_SIZE] }
+    }
+
     pub fn load<A: Addressable>(&self, offset: u32) -> u32 {
+        unwrap_access(self.try_load::<A>(offset))
+    }
+
+    /// Same as `load` but unsupported accesses return an error
+    /// instead of panicking
+    pub fn try_load<A: Addressable>(&self,
+                                    offset: u32) -> Result<u32, String> {
         let offset = offset as usize;
 
         // BIOS only supports 16 and 32bit acccess
         if A::size() == 1 {
-            panic!("Unsupported 8bit BIOS read");
+            return Err("Unsupported 8bit BIOS read".into());
+        }
+
+        if offset >= BIOS_SIZE {
+            return Err(format!("BIOS read out of range 0x{:x}", offset));
         }
 
         let mut r = 0;
@@ -53,7 +70,7 @@ impl Bios {
             r |= (self.data[offset + i] as u32) << (8 * i)
         }
 
-        r
+        Ok(r)
     }
 }
 
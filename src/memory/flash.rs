@@ -1,8 +1,11 @@
+use std::cmp;
+
 use rustc_serialize::{Decodable, Encodable, Decoder, Encoder};
 
-use super::Addressable;
+use super::{Addressable, register_load, unwrap_access};
 
 #[derive(RustcDecodable, RustcEncodable)]
+#[derive(Hash)]
 pub struct Flash {
     data: Data,
     /// When true the BIOS is mirrored at address 0. Set on reset so
@@ -19,6 +22,20 @@ pub struct Flash {
     f_wait1: u8,
     f_wait2: u8,
     f_ctrl: u8,
+    /// When true all writes to the flash contents are ignored. This
+    /// isn't a hardware feature, it can be used by the frontend to
+    /// prevent the guest from modifying the memory card.
+    write_protect: bool,
+    /// When true the writes can only clear bits (like programming a
+    /// NOR flash), an `erase` is required to set them back to 1. XXX
+    /// The PocketStation software doesn't seem to issue any erase
+    /// command so the flash controller probably erases the sector
+    /// automatically, which is why this is disabled by default.
+    program_clears_only: bool,
+    /// Device serial number, read through the F_SN registers. XXX
+    /// We don't have a dump from a real PocketStation, the BIOS seems
+    /// happy with 0.
+    serial: u32,
 }
 
 impl Flash {
@@ -42,76 +59,162 @@ impl Flash {
             f_wait1: 0,
             f_wait2: 0,
             f_ctrl: 0,
+            write_protect: false,
+            program_clears_only: false,
+            serial: 0,
         })
     }
 
+    /// Build a flash containing `contents`, padded with erased (0xff)
+    /// bytes or truncated to `FLASH_SIZE`. Unlike `new` this never
+    /// fails, it's meant to quickly build a flash for test programs
+    /// which don't need a full memory card image.
+    pub fn with_contents(contents: &[u8]) -> Flash {
+        let mut image = vec![0xff; FLASH_SIZE];
+
+        let len = cmp::min(contents.len(), FLASH_SIZE);
+
+        image[..len].copy_from_slice(&contents[..len]);
+
+        Flash::new(&image).unwrap()
+    }
+
+    /// Put the flash controller back in its power-on state. The
+    /// contents and the frontend settings (write protection, serial
+    /// number...) are preserved.
     pub fn reset(&mut self) {
         self.bios_at_0 = true;
+        self.phys_bank_en = 0;
+        self.phys_to_virt_bank = [0; 16];
+        self.virt_to_phys_bank = [None; 16];
+        self.f_wait1 = 0;
+        self.f_wait2 = 0;
+        self.f_ctrl = 0;
     }
 
-    pub fn load_config<A: Addressable>(&self, offset: u32) -> u32 {
+    /// Set the serial number returned by the F_SN registers
+    pub fn set_serial_number(&mut self, serial: u32) {
+        self.serial = serial;
+    }
 
-        match offset {
-            // The BIOS expects bit 0 to be set, otherwise it gets
-            // stuck in a strang loop waiting for R0 to become 1 (but
-            // it doesn't actually load anything in R0 in the loop, so
-            // I don't understand how it's ever supposed to exit
-            // it). This loop is at offset 0x2e16 and 0x2e18 in the
-            // BIOS.
-            //
-            // XXX Run tests on real hardware to figure out what's
-            // read from here exactly.
-            0x00 => (self.f_ctrl | 1) as u32,
-            // XXX figure out what this register does exactly, No$
-            // calls it "F_STAT".
-            0x04 => 0,
-            0x0c => self.f_wait1 as u32,
-            0x10 => (self.f_wait2 | 4) as u32,
-            0x08 => self.phys_bank_en as u32,
-            0x100...0x13c => {
-                let phys_bank = (offset & 0x3f) >> 2;
+    pub fn serial_number(&self) -> u32 {
+        self.serial
+    }
 
-                self.phys_to_virt_bank[phys_bank as usize] as u32
-            }
-            // F_SN_LO, XXX dump it from real pocketstation
-            0x300 => 0,
-            // F_SN_HI, XXX dump it from real pocketstation
-            0x302 => 0,
-            // F_CAL. XXX Need to dump a value from a real
-            // PocketStation.
-            0x308 => 0xca1,
-            _ => panic!("Unhandled flash config register {:x}", offset),
-        }
+    /// Load from the flash controller registers, mapped at
+    /// 0x06000000. `offset` is relative to the start of the register
+    /// range.
+    pub fn load_config<A: Addressable>(&self, offset: u32) -> u32 {
+        unwrap_access(self.try_load_config::<A>(offset))
+    }
+
+    /// Same as `load_config` but unhandled registers return an error
+    /// instead of panicking
+    pub fn try_load_config<A: Addressable>(&self,
+                                           offset: u32)
+                                           -> Result<u32, String> {
+        let r =
+            match offset {
+                // The BIOS expects bit 0 to be set, otherwise it gets
+                // stuck in a strang loop waiting for R0 to become 1 (but
+                // it doesn't actually load anything in R0 in the loop, so
+                // I don't understand how it's ever supposed to exit
+                // it). This loop is at offset 0x2e16 and 0x2e18 in the
+                // BIOS.
+                //
+                // XXX Run tests on real hardware to figure out what's
+                // read from here exactly.
+                0x00 => (self.f_ctrl | 1) as u32,
+                // XXX figure out what this register does exactly, No$
+                // calls it "F_STAT".
+                0x04 => 0,
+                0x0c => self.f_wait1 as u32,
+                0x10 => (self.f_wait2 | 4) as u32,
+                0x08 => self.phys_bank_en as u32,
+                0x100...0x13c => {
+                    let phys_bank = (offset & 0x3f) >> 2;
+
+                    self.phys_to_virt_bank[phys_bank as usize] as u32
+                }
+                // F_SN_LO and F_SN_HI
+                0x300...0x303 => register_load::<A>(self.serial, offset),
+                // F_CAL. XXX Need to dump a value from a real
+                // PocketStation.
+                0x308 => 0xca1,
+                _ => return Err(format!("Unhandled flash config register \
+                                         {:x}", offset)),
+            };
+
+        Ok(r)
     }
 
+    /// Store to the flash controller registers, mapped at
+    /// 0x06000000. This is where the bank mapping used by
+    /// `load_virtual` is configured.
     pub fn store_config<A: Addressable>(&mut self, offset: u32, val: u32) {
+        unwrap_access(self.try_store_config::<A>(offset, val))
+    }
+
+    /// Same as `store_config` but unhandled registers and invalid
+    /// bank mappings return an error instead of panicking. An invalid
+    /// mapping leaves the configuration unchanged.
+    pub fn try_store_config<A: Addressable>(&mut self,
+                                            offset: u32,
+                                            val: u32) -> Result<(), String> {
 
         match offset {
             0x00 => self.set_f_ctrl::<A>(val),
             0x08 => {
+                let prev = self.phys_bank_en;
+
                 self.phys_bank_en = val as u16;
-                self.rebuild_virt_mapping();
+
+                if let Err(e) = self.rebuild_virt_mapping() {
+                    self.phys_bank_en = prev;
+                    return Err(e);
+                }
             }
             0x0c => self.f_wait1 = val as u8,
             0x10 => self.f_wait2 = val as u8,
             0x100...0x13c => {
-                let phys_bank = (offset & 0x3f) >> 2;
+                let phys_bank = ((offset & 0x3f) >> 2) as usize;
                 let virt_bank = val & 0xf;
+                let prev = self.phys_to_virt_bank[phys_bank];
 
-                self.phys_to_virt_bank[phys_bank as usize] = virt_bank as u8;
+                self.phys_to_virt_bank[phys_bank] = virt_bank as u8;
 
-                self.rebuild_virt_mapping();
+                if let Err(e) = self.rebuild_virt_mapping() {
+                    self.phys_to_virt_bank[phys_bank] = prev;
+                    return Err(e);
+                }
             }
-            _ => panic!("Unhandled flash config register {:x}", offset),
+            _ => return Err(format!("Unhandled flash config register {:x}",
+                                    offset)),
         }
+
+        Ok(())
     }
 
+    /// Load from the physical view of the flash, mapped at
+    /// 0x08000000. `offset` is the physical offset in the flash,
+    /// regardless of the bank mapping.
     pub fn load_raw<A: Addressable>(&self, offset: u32) -> u32 {
+        unwrap_access(self.try_load_raw::<A>(offset))
+    }
+
+    /// Same as `load_raw` but unsupported accesses return an error
+    /// instead of panicking
+    pub fn try_load_raw<A: Addressable>(&self,
+                                        offset: u32) -> Result<u32, String> {
         let offset = offset as usize;
 
         // Flash only supports 16 and 32bit acccess
         if A::size() == 1 {
-            panic!("Unsupported 8bit FLASH read");
+            return Err("Unsupported 8bit FLASH read".into());
+        }
+
+        if offset >= FLASH_SIZE {
+            return Err(format!("FLASH read out of range 0x{:x}", offset));
         }
 
         let mut r = 0;
@@ -120,29 +223,75 @@ impl Flash {
             r |= (self.data[offset + i] as u32) << (8 * i)
         }
 
-        r
+        Ok(r)
     }
 
+    /// Store to the physical view of the flash, see `load_raw`. The
+    /// change is visible through every virtual bank mapped to the
+    /// same physical bank.
     pub fn store_raw<A: Addressable>(&mut self, offset: u32, val: u32) {
+        unwrap_access(self.try_store_raw::<A>(offset, val))
+    }
+
+    /// Same as `store_raw` but out-of-range accesses return an error
+    /// instead of panicking
+    pub fn try_store_raw<A: Addressable>(&mut self,
+                                         offset: u32,
+                                         val: u32) -> Result<(), String> {
         let offset = offset as usize;
 
+        if offset >= FLASH_SIZE {
+            return Err(format!("FLASH write out of range 0x{:x}", offset));
+        }
+
+        if self.write_protect {
+            debug!("Ignoring write to protected flash at 0x{:x}", offset);
+            return Ok(());
+        }
+
         for i in 0..A::size() as usize {
-            self.data[offset + i] = (val >> (i * 8)) as u8;
+            let b = (val >> (i * 8)) as u8;
+
+            if self.program_clears_only {
+                self.data[offset + i] &= b;
+            } else {
+                self.data[offset + i] = b;
+            }
         }
+
+        Ok(())
     }
 
+    /// Load from the virtual view of the flash, mapped at
+    /// 0x02000000. The flash is divided in 16 banks of 8KB and each
+    /// virtual bank maps to the physical bank configured through
+    /// `store_config`, so that the currently running application
+    /// appears contiguous even if its blocks are scattered in the
+    /// memory card. Panics if the virtual bank is not mapped.
     pub fn load_virtual<A: Addressable>(&self, offset: u32) -> u32 {
+        unwrap_access(self.try_load_virtual::<A>(offset))
+    }
+
+    /// Same as `load_virtual` but unmapped banks return an error
+    /// instead of panicking
+    pub fn try_load_virtual<A: Addressable>(&self,
+                                            offset: u32)
+                                            -> Result<u32, String> {
         // Resolve the physical bank (each bank is 8KB)
         let virt_bank = offset >> 13;
         let bank_off = offset & 0x1fff;
 
-        match self.virt_to_phys_bank[virt_bank as usize] {
+        let mapping =
+            self.virt_to_phys_bank.get(virt_bank as usize).and_then(|&p| p);
+
+        match mapping {
             Some(p) => {
                 let phys = ((p as u32) << 13) | bank_off;
 
-                self.load_raw::<A>(phys)
+                self.try_load_raw::<A>(phys)
             }
-            None => panic!("read from unmapped virtual bank {}", virt_bank),
+            None => Err(format!("read from unmapped virtual bank {}",
+                                virt_bank)),
         }
     }
 
@@ -150,10 +299,67 @@ impl Flash {
         self.bios_at_0
     }
 
+    pub fn set_write_protect(&mut self, protected: bool) {
+        self.write_protect = protected
+    }
+
+    pub fn write_protected(&self) -> bool {
+        self.write_protect
+    }
+
+    pub fn set_program_clears_only(&mut self, enabled: bool) {
+        self.program_clears_only = enabled
+    }
+
+    /// Set `len` bytes starting at `offset` back to 0xff. Like
+    /// `write_image_at` this is a host operation.
+    pub fn erase(&mut self,
+                 offset: u32,
+                 len: usize) -> Result<(), FlashError> {
+        let start = offset as usize;
+        let end = start.checked_add(len);
+
+        match end {
+            Some(end) if end <= FLASH_SIZE => {
+                for b in &mut self.data[start..end] {
+                    *b = 0xff;
+                }
+
+                Ok(())
+            }
+            _ => Err(FlashError::OutOfRange),
+        }
+    }
+
     pub fn data(&self) -> &Data {
         &self.data
     }
 
+    /// Exchange the flash contents of `self` and `other`, leaving the
+    /// configuration untouched
+    pub fn swap_data(&mut self, other: &mut Flash) {
+        ::std::mem::swap(&mut self.data, &mut other.data)
+    }
+
+    /// Overwrite the flash contents starting at `offset` with
+    /// `data`. This is a host operation: it ignores the write
+    /// protection and the bank mapping.
+    pub fn write_image_at(&mut self,
+                          offset: u32,
+                          data: &[u8]) -> Result<(), FlashError> {
+        let start = offset as usize;
+        let end = start.checked_add(data.len());
+
+        match end {
+            Some(end) if end <= FLASH_SIZE => {
+                self.data[start..end].copy_from_slice(data);
+
+                Ok(())
+            }
+            _ => Err(FlashError::OutOfRange),
+        }
+    }
+
     pub fn set_data(&mut self, data: Data) {
         self.data = data
     }
@@ -166,31 +372,40 @@ impl Flash {
         }
     }
 
-    fn rebuild_virt_mapping(&mut self) {
+    /// Rebuild `virt_to_phys_bank` from the configuration registers.
+    /// Returns an error and leaves the mapping untouched if two
+    /// enabled physical banks map to the same virtual one.
+    fn rebuild_virt_mapping(&mut self) -> Result<(), String> {
         // XXX this is mostly guesswork, I don't know exactly what
         // happens when two different physical banks are mapped to the
         // same virtual one, I don't know how the enable bits are
         // handled exactly.
 
-        self.virt_to_phys_bank = [None; 16];
+        let mut virt_to_phys_bank = [None; 16];
 
         for (p, &v) in self.phys_to_virt_bank.iter().enumerate() {
             // Check if the bank is enabled
             if (self.phys_bank_en & (1u16 << p)) != 0 {
-                let vbank = &mut self.virt_to_phys_bank[v as usize];
+                let vbank = &mut virt_to_phys_bank[v as usize];
 
                 match vbank {
                     &mut None => *vbank = Some(p as u8),
                     &mut Some(other) =>
-                        panic!("Virtual bank {} is mapped twice: {} and {}",
-                               v, other, p)
+                        return Err(format!("Virtual bank {} is mapped \
+                                            twice: {} and {}",
+                                           v, other, p)),
                 }
             }
         }
+
+        self.virt_to_phys_bank = virt_to_phys_bank;
+
+        Ok(())
     }
 }
 
 /// Wrapper around the raw flash contents for serialization
+#[derive(Hash)]
 pub struct Data(Box<[u8; FLASH_SIZE]>);
 
 impl ::std::ops::Deref for Data {
@@ -241,5 +456,283 @@ impl ::std::clone::Clone for Data {
     }
 }
 
+/// Error returned by the host-side flash operations
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum FlashError {
+    /// The operation would access data past the end of the flash
+    OutOfRange,
+}
+
 /// FLASH size in bytes
 pub const FLASH_SIZE: usize = 128 * 1024;
+
+#[cfg(test)]
+mod tests {
+    use memory::{Word, HalfWord};
+    use test_util;
+
+    use super::*;
+
+    #[test]
+    fn write_protect() {
+        let mut flash = Flash::with_contents(&[]);
+
+        flash.set_write_protect(true);
+        assert!(flash.write_protected());
+
+        flash.store_raw::<Word>(0x100, 0x12345678);
+        flash.store_raw::<HalfWord>(0x106, 0xabcd);
+
+        assert_eq!(flash.load_raw::<Word>(0x100), 0xffffffff);
+        assert_eq!(flash.load_raw::<Word>(0x104), 0xffffffff);
+
+        flash.set_write_protect(false);
+
+        flash.store_raw::<Word>(0x100, 0x12345678);
+        flash.store_raw::<HalfWord>(0x106, 0xabcd);
+
+        assert_eq!(flash.load_raw::<Word>(0x100), 0x12345678);
+        assert_eq!(flash.load_raw::<Word>(0x104), 0xabcdffff);
+    }
+
+    #[test]
+    fn write_protect_guest_store() {
+        let mut inter = test_util::interconnect(&[0x11, 0x22, 0x33, 0x44],
+                                                &[]);
+
+        inter.flash_mut().set_write_protect(true);
+
+        inter.store::<Word>(0x08000000, 0xdeadbeef);
+
+        assert_eq!(inter.load::<Word>(0x08000000), 0x44332211);
+
+        inter.flash_mut().set_write_protect(false);
+
+        inter.store::<Word>(0x08000000, 0xdeadbeef);
+
+        assert_eq!(inter.load::<Word>(0x08000000), 0xdeadbeef);
+    }
+
+    #[test]
+    fn write_image_at() {
+        let mut flash = Flash::with_contents(&[]);
+
+        flash.write_image_at(0x1001, &[0x11, 0x22, 0x33, 0x44, 0x55])
+            .unwrap();
+
+        // The surrounding bytes are untouched (erased)
+        assert_eq!(flash.load_raw::<Word>(0x1000), 0x332211ff);
+        assert_eq!(flash.load_raw::<Word>(0x1004), 0xffff5544);
+        assert_eq!(flash.load_raw::<Word>(0x0ffc), 0xffffffff);
+        assert_eq!(flash.load_raw::<Word>(0x1008), 0xffffffff);
+
+        // Write protection only applies to the guest
+        flash.set_write_protect(true);
+        flash.write_image_at(0x1004, &[0x66]).unwrap();
+        assert_eq!(flash.load_raw::<Word>(0x1004), 0xffff5566);
+
+        // Up to the very end of the flash
+        let end = FLASH_SIZE as u32;
+
+        flash.write_image_at(end - 2, &[0xaa, 0xbb]).unwrap();
+        assert_eq!(flash.load_raw::<HalfWord>(end - 2), 0xbbaa);
+        flash.write_image_at(end, &[]).unwrap();
+
+        // Out of range writes are rejected without modifying anything
+        assert_eq!(flash.write_image_at(end - 1, &[0, 0]),
+                   Err(FlashError::OutOfRange));
+        assert_eq!(flash.write_image_at(!0, &[0]),
+                   Err(FlashError::OutOfRange));
+        assert_eq!(flash.load_raw::<HalfWord>(end - 2), 0xbbaa);
+    }
+
+    /// Flash where each word contains its bank number in the high
+    /// half and its offset in the bank in the low half
+    fn banked_flash() -> Flash {
+        let words: Vec<u32> =
+            (0..FLASH_SIZE as u32 / 4)
+            .map(|w| w * 4)
+            .map(|off| ((off >> 13) << 16) | (off & 0x1fff))
+            .collect();
+
+        Flash::new(&test_util::words_to_bytes(&words)).unwrap()
+    }
+
+    #[test]
+    fn views() {
+        let mut flash = banked_flash();
+
+        // Config view: map physical banks 3 and 5 to virtual banks 0
+        // and 1
+        flash.store_config::<Word>(0x10c, 0);
+        flash.store_config::<Word>(0x114, 1);
+        flash.store_config::<Word>(0x08, (1 << 3) | (1 << 5));
+
+        assert_eq!(flash.load_config::<Word>(0x08), (1 << 3) | (1 << 5));
+        assert_eq!(flash.load_config::<Word>(0x10c), 0);
+        assert_eq!(flash.load_config::<Word>(0x114), 1);
+
+        // Raw view: physical offsets regardless of the mapping
+        assert_eq!(flash.load_raw::<Word>(0x0000), 0x00000000);
+        assert_eq!(flash.load_raw::<Word>(0x6010), 0x00030010);
+        assert_eq!(flash.load_raw::<HalfWord>(0xa012), 0x0005);
+
+        // Virtual view: remapped through the config
+        assert_eq!(flash.load_virtual::<Word>(0x0000), 0x00030000);
+        assert_eq!(flash.load_virtual::<Word>(0x1ffc), 0x00031ffc);
+        assert_eq!(flash.load_virtual::<Word>(0x2000), 0x00050000);
+        assert_eq!(flash.load_virtual::<HalfWord>(0x2012), 0x0005);
+
+        // A raw store is visible at the corresponding virtual address
+        flash.store_raw::<Word>(0x6010, 0x12345678);
+        assert_eq!(flash.load_virtual::<Word>(0x0010), 0x12345678);
+
+        flash.store_raw::<HalfWord>(0xa100, 0xabcd);
+        assert_eq!(flash.load_virtual::<Word>(0x2100), 0x0005abcd);
+
+        // Remapping changes the virtual view but not the raw one
+        flash.store_config::<Word>(0x08, 1 << 5);
+        flash.store_config::<Word>(0x114, 0);
+        assert_eq!(flash.load_virtual::<Word>(0x0100), 0x0005abcd);
+        assert_eq!(flash.load_raw::<Word>(0x6010), 0x12345678);
+    }
+
+    #[test]
+    #[should_panic(expected = "read from unmapped virtual bank 2")]
+    fn unmapped_virtual_bank() {
+        let mut flash = banked_flash();
+
+        flash.store_config::<Word>(0x10c, 0);
+        flash.store_config::<Word>(0x08, 1 << 3);
+
+        flash.load_virtual::<Word>(0x4000);
+    }
+
+    #[test]
+    fn interconnect_views() {
+        let mut inter = test_util::interconnect(&[], &[]);
+
+        *inter.flash_mut() = banked_flash();
+
+        // Map physical bank 7 to virtual bank 2, then enable it
+        inter.store::<Word>(0x0600011c, 2);
+        inter.store::<Word>(0x06000008, 1 << 7);
+
+        assert_eq!(inter.load::<Word>(0x06000008), 1 << 7);
+        assert_eq!(inter.load::<Word>(0x0600011c), 2);
+
+        assert_eq!(inter.load::<Word>(0x08004008), 0x00020008);
+        assert_eq!(inter.load::<Word>(0x02004008), 0x00070008);
+
+        inter.store::<Word>(0x0800e008, 0xcafef00d);
+        assert_eq!(inter.load::<Word>(0x02004008), 0xcafef00d);
+    }
+
+    #[test]
+    fn program_clears_only() {
+        let mut flash = Flash::with_contents(&[]);
+
+        // Disabled by default: stores overwrite the contents
+        flash.store_raw::<HalfWord>(0x200, 0x000f);
+        flash.store_raw::<HalfWord>(0x200, 0x00ff);
+        assert_eq!(flash.load_raw::<HalfWord>(0x200), 0x00ff);
+
+        flash.set_program_clears_only(true);
+
+        // Programming 0x0f over 0xff clears the high nibble
+        flash.store_raw::<HalfWord>(0x100, 0xff0f);
+        assert_eq!(flash.load_raw::<HalfWord>(0x100), 0xff0f);
+
+        // Programming 0xff over 0x0f can't set the bits back
+        flash.store_raw::<HalfWord>(0x100, 0xffff);
+        assert_eq!(flash.load_raw::<HalfWord>(0x100), 0xff0f);
+
+        // Only the bits cleared by both writes end up cleared
+        flash.store_raw::<Word>(0x104, 0x0f0f0f0f);
+        flash.store_raw::<Word>(0x104, 0x00ff00ff);
+        assert_eq!(flash.load_raw::<Word>(0x104), 0x000f000f);
+
+        // An erase sets everything back to 0xff
+        flash.erase(0x100, 8).unwrap();
+        assert_eq!(flash.load_raw::<Word>(0x100), 0xffffffff);
+        assert_eq!(flash.load_raw::<Word>(0x104), 0xffffffff);
+
+        flash.store_raw::<HalfWord>(0x100, 0x1234);
+        assert_eq!(flash.load_raw::<HalfWord>(0x100), 0x1234);
+
+        // The erase doesn't touch the surrounding bytes
+        assert_eq!(flash.load_raw::<HalfWord>(0x200), 0x00ff);
+
+        assert_eq!(flash.erase(FLASH_SIZE as u32 - 4, 8),
+                   Err(FlashError::OutOfRange));
+    }
+
+    #[test]
+    fn chip_id() {
+        let program = [
+            // LDR R1, [R0]
+            0xe5901000,
+            // LDRH R2, [R0]
+            0xe1d020b0,
+            // LDRH R3, [R0, #2]
+            0xe1d030b2,
+        ];
+
+        let run = |id: Option<u32>| {
+            let mut cpu = test_util::arm_cpu(&program);
+
+            if let Some(id) = id {
+                cpu.interconnect_mut().set_chip_id(id);
+            }
+
+            // F_SN
+            cpu.set_register(0, 0x06000300);
+
+            test_util::run(&mut cpu, 3);
+
+            let r = cpu.registers();
+
+            (r[1], r[2], r[3])
+        };
+
+        // XXX The serial of a real PocketStation hasn't been dumped,
+        // the BIOS is happy with 0
+        assert_eq!(run(None), (0, 0, 0));
+
+        assert_eq!(run(Some(0x12345678)), (0x12345678, 0x5678, 0x1234));
+    }
+
+    #[test]
+    fn with_contents() {
+        let mut contents = vec![0; 0x2004];
+
+        contents[..8].copy_from_slice(&[1, 2, 3, 4, 5, 6, 7, 8]);
+        contents[0x2000..].copy_from_slice(&[0xaa, 0xbb, 0xcc, 0xdd]);
+
+        let mut flash = Flash::with_contents(&contents);
+
+        assert_eq!(flash.load_raw::<Word>(0), 0x04030201);
+        assert_eq!(flash.load_raw::<HalfWord>(6), 0x0807);
+        assert_eq!(flash.load_raw::<Word>(0x2000), 0xddccbbaa);
+
+        // Padded with erased bytes
+        assert_eq!(flash.load_raw::<Word>(0x2004), 0xffffffff);
+        assert_eq!(flash.load_raw::<Word>(FLASH_SIZE as u32 - 4),
+                   0xffffffff);
+
+        // Map physical bank 1 to virtual bank 0
+        flash.store_config::<Word>(0x104, 0);
+        flash.store_config::<Word>(0x08, 1 << 1);
+
+        assert_eq!(flash.load_virtual::<Word>(0), 0xddccbbaa);
+        assert_eq!(flash.load_virtual::<HalfWord>(4), 0xffff);
+
+        // Truncated to the size of the flash
+        let contents = vec![0x5a; FLASH_SIZE + 8];
+
+        let flash = Flash::with_contents(&contents);
+
+        assert_eq!(flash.load_raw::<Word>(FLASH_SIZE as u32 - 4),
+                   0x5a5a5a5a);
+    }
+}
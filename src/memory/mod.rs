@@ -1,11 +1,18 @@
-use interrupt::{Interrupt, IrqController};
+use std::cell::RefCell;
+use std::hash::{Hash, Hasher};
+use std::mem::swap;
+
+use interrupt::{Interrupt, IrqController, Inputs};
 use lcd::Lcd;
 use dac::Dac;
 use irda::Irda;
 use rtc::Rtc;
 use timer::Timer;
+use MASTER_CLOCK_HZ;
+
+use rustc_serialize::{Decodable, Encodable, Decoder, Encoder};
 
-use self::ram::Ram;
+use self::ram::{Ram, DIRTY_PAGE_SIZE, RAM_PAGES};
 use self::bios::Bios;
 use self::flash::Flash;
 
@@ -24,17 +31,70 @@ pub struct Interconnect {
     lcd: Lcd,
     dac: Dac,
     irda: Irda,
+    /// Log2 of the number of master clock ticks per CPU tick. Note
+    /// that this is the opposite of the CLKMODE register value: a
+    /// CLKMODE of `n` is stored as `7 - n`.
     cpu_clk_div: u8,
+    /// Master clock ticks until the CPU clock is reported as locked
+    /// again after a CLKMODE change
+    clk_lock_ticks: u32,
     frame_ticks: u32,
-    iop_ctrl: u8,
+    /// Master clock ticks elapsed since the peripherals were last
+    /// brought up to date by `sync`
+    pending_ticks: u32,
+    /// Master clock ticks already sent to the master clock
+    /// peripherals but not yet to the timers because they don't add
+    /// up to a full CPU clock period. Only `tick_one` can leave a
+    /// remainder.
+    timer_remainder: u32,
+    /// Number of master clock ticks until the next peripheral event
+    /// (timer expiration, RTC toggle...). We don't need to run the
+    /// peripherals before that unless their registers are accessed.
+    next_event: u32,
+    /// IOP pin direction, bit `n` is set if pin `n` is configured as
+    /// an output
+    iop_ctrl: u16,
+    /// Level driven on the IOP pins configured as outputs. Set by
+    /// IOP START and cleared by IOP STOP.
+    iop_data: u16,
+    /// Callback handling the accesses to unknown addresses
+    unknown_access: UnknownAccess,
+    /// Frontend callback called at the end of every LCD frame
+    frame_callback: OnFrame,
+    /// Inputs set by the frontend, applied at the start of the next
+    /// LCD frame so that input logs replay deterministically
+    /// regardless of when the frontend polled them.
+    pending_inputs: Option<Inputs>,
+    /// Incremented every time the code in the BIOS or flash might
+    /// have been modified or remapped, see `code_generation`
+    code_generation: u32,
+    /// Same as `code_generation` for each RAM page, so that storing
+    /// data in the RAM doesn't invalidate the code running elsewhere
+    ram_generation: [u32; RAM_PAGES],
+    /// CPU cycles lost on every framebuffer access during the LCD
+    /// scanout, see `set_lcd_contention`. `None` if disabled.
+    lcd_contention: Option<u32>,
+    /// Stall cycles accumulated by the current instruction, see
+    /// `take_stall_cycles`
+    stall_cycles: u32,
 }
 
 impl Interconnect {
     pub fn new(bios: Bios, flash: Flash, dac: Dac) -> Interconnect {
+        Interconnect::with_ram(bios, flash, dac, Ram::new())
+    }
+
+    /// Build an interconnect using a pre-populated `ram`, for instance
+    /// to load a RAM-resident test program. Note that the RAM is only
+    /// visible at address 0 once the BIOS has been unmapped.
+    pub fn with_ram(bios: Bios,
+                    flash: Flash,
+                    dac: Dac,
+                    ram: Ram) -> Interconnect {
         Interconnect {
             bios: bios,
             flash: flash,
-            ram: Ram::new(),
+            ram: ram,
             irq_controller: IrqController::new(),
             timers: [Timer::new(Interrupt::Timer0),
                      Timer::new(Interrupt::Timer1),
@@ -44,13 +104,86 @@ impl Interconnect {
             dac: dac,
             irda: Irda::new(),
             cpu_clk_div: 7,
+            clk_lock_ticks: 0,
             frame_ticks: 0,
+            pending_ticks: 0,
+            timer_remainder: 0,
+            next_event: 0,
             iop_ctrl: 0,
+            iop_data: 0,
+            unknown_access: UnknownAccess::new(),
+            frame_callback: OnFrame(None),
+            pending_inputs: None,
+            code_generation: 0,
+            ram_generation: [0; RAM_PAGES],
+            lcd_contention: None,
+            stall_cycles: 0,
         }
     }
 
     pub fn reset(&mut self) {
         self.flash.reset();
+
+        for p in self.peripherals_mut().iter_mut() {
+            p.reset();
+        }
+
+        self.cpu_clk_div = 7;
+        self.clk_lock_ticks = 0;
+        self.pending_ticks = 0;
+        self.timer_remainder = 0;
+        self.next_event = 0;
+        self.iop_ctrl = 0;
+        self.iop_data = 0;
+        self.pending_inputs = None;
+        self.stall_cycles = 0;
+
+        // The BIOS is mapped back at address 0
+        self.code_changed();
+    }
+
+    /// Return all the memory-mapped peripherals. The peripherals are
+    /// not synchronized, `sync` should be called first if their state
+    /// needs to be up to date.
+    pub fn peripherals_mut(&mut self) -> [&mut Peripheral; 8] {
+        let (t0, t12) = self.timers.split_at_mut(1);
+        let (t1, t2) = t12.split_at_mut(1);
+
+        [&mut self.irq_controller,
+         &mut self.rtc,
+         &mut self.lcd,
+         &mut self.dac,
+         &mut self.irda,
+         &mut t0[0],
+         &mut t1[0],
+         &mut t2[0]]
+    }
+
+    /// Compute a hash of the RAM, flash and peripheral state. Two
+    /// machines in the same state always return the same value, so
+    /// frontends can use it to cheaply detect whether anything
+    /// changed between two frames (for rewind or savestate
+    /// deduplication). The BIOS is not included since it's
+    /// read-only.
+    pub fn content_hash(&self) -> u64 {
+        let mut hasher = Fnv1aHasher::new();
+
+        self.ram.hash(&mut hasher);
+        self.flash.hash(&mut hasher);
+        self.irq_controller.hash(&mut hasher);
+        self.timers.hash(&mut hasher);
+        self.rtc.hash(&mut hasher);
+        self.lcd.hash(&mut hasher);
+        self.dac.hash(&mut hasher);
+        self.irda.hash(&mut hasher);
+        self.cpu_clk_div.hash(&mut hasher);
+        self.clk_lock_ticks.hash(&mut hasher);
+        self.pending_ticks.hash(&mut hasher);
+        self.timer_remainder.hash(&mut hasher);
+        self.iop_ctrl.hash(&mut hasher);
+        self.iop_data.hash(&mut hasher);
+
+        hasher.finish()
     }
 
     pub fn irq_pending(&self) -> bool {
@@ -65,10 +198,22 @@ impl Interconnect {
         self.frame_ticks = ticks
     }
 
+    /// Return the current CPU clock frequency in Hz. The CPU runs at
+    /// `MASTER_CLOCK_HZ >> cpu_clk_div`, so the highest CLKMODE
+    /// setting (7) runs the CPU at the master clock frequency and
+    /// CLKMODE 0 divides it by 128.
+    pub fn effective_cpu_hz(&self) -> u32 {
+        MASTER_CLOCK_HZ >> self.cpu_clk_div
+    }
+
     pub fn lcd(&self) -> &Lcd {
         &self.lcd
     }
 
+    pub fn lcd_mut(&mut self) -> &mut Lcd {
+        &mut self.lcd
+    }
+
     pub fn irq_controller(&mut self) -> &IrqController {
         &self.irq_controller
     }
@@ -77,6 +222,31 @@ impl Interconnect {
         &mut self.irq_controller
     }
 
+    /// Set the state of the buttons. The new `inputs` only become
+    /// visible to the guest at the end of the current LCD frame. If
+    /// this is called several times during a frame only the last
+    /// value is used.
+    pub fn set_inputs(&mut self, inputs: Inputs) {
+        self.pending_inputs = Some(inputs);
+    }
+
+    /// Set the device serial number, which is the only
+    /// identification register on the PocketStation. The software
+    /// can read it from the flash controller's F_SN registers. It
+    /// defaults to 0.
+    pub fn set_chip_id(&mut self, id: u32) {
+        self.flash.set_serial_number(id);
+    }
+
+    pub fn ram_mut(&mut self) -> &mut Ram {
+        // The frontend might modify the code
+        for g in self.ram_generation.iter_mut() {
+            *g = g.wrapping_add(1);
+        }
+
+        &mut self.ram
+    }
+
     pub fn rtc_mut(&mut self) -> &mut Rtc {
         &mut self.rtc
     }
@@ -86,6 +256,9 @@ impl Interconnect {
     }
 
     pub fn flash_mut(&mut self) -> &mut Flash {
+        // The frontend might modify the code
+        self.code_changed();
+
         &mut self.flash
     }
 
@@ -93,52 +266,376 @@ impl Interconnect {
         &mut self.dac
     }
 
+    pub fn irda_mut(&mut self) -> &mut Irda {
+        &mut self.irda
+    }
+
     pub fn set_bios(&mut self, bios: Bios) {
         self.bios = bios;
+
+        self.code_changed();
+    }
+
+    /// Return a value that changes every time the code at `addr`
+    /// might have been modified: store to the same RAM page, flash
+    /// contents or bank mapping changed, BIOS remapped... Used by the
+    /// CPU to keep its decoded instruction cache coherent.
+    pub fn code_generation(&self, addr: u32) -> u32 {
+        if addr >> 24 == 0 && !self.flash.bios_at_0() {
+            let page = ((addr & 0xffffff) as usize / DIRTY_PAGE_SIZE) %
+                RAM_PAGES;
+
+            // Both counters only ever increase so the sum changes
+            // whenever one of them does
+            self.code_generation.wrapping_add(self.ram_generation[page])
+        } else {
+            self.code_generation
+        }
+    }
+
+    /// Invalidate all the decoded instructions, see `code_generation`
+    fn code_changed(&mut self) {
+        self.code_generation = self.code_generation.wrapping_add(1);
+    }
+
+    /// Move the parts of the state which are not serialized in
+    /// savestates (BIOS, flash contents, audio backend and unknown
+    /// access handler) from `other` into `self`. `other` gets
+    /// `self`'s in exchange.
+    pub fn take_external_state(&mut self, other: &mut Interconnect) {
+        swap(&mut self.bios, &mut other.bios);
+        self.flash.swap_data(&mut other.flash);
+        self.dac.swap_backend(&mut other.dac);
+        self.lcd.swap_palette(&mut other.lcd);
+        swap(&mut self.unknown_access, &mut other.unknown_access);
+        swap(&mut self.frame_callback, &mut other.frame_callback);
+    }
+
+    /// Install a callback called every time the LCD completes a
+    /// frame, so that the frontend doesn't have to poll. Remove it if
+    /// `callback` is `None`.
+    pub fn set_frame_callback(&mut self, callback: Option<FrameCallback>) {
+        self.frame_callback = OnFrame(callback);
+    }
+
+    /// Install a callback to handle the loads and stores to
+    /// addresses the emulator doesn't know about. Without a handler
+    /// these accesses panic. Useful to investigate how the software
+    /// reacts to unknown registers, see also `set_open_bus_value`.
+    pub fn set_unknown_access_handler(&mut self,
+                                      handler: UnknownAccessHandler) {
+        self.unknown_access.handler = RefCell::new(Some(handler));
+    }
+
+    /// Tolerate the accesses to unknown addresses instead of
+    /// panicking: loads return `val` and stores are ignored, with a
+    /// warning. Shortcut for installing `open_bus_handler(val)`.
+    pub fn set_open_bus_value(&mut self, val: u32) {
+        self.set_unknown_access_handler(open_bus_handler(val));
+    }
+
+    /// Return `true` if the red LED is lit
+    pub fn led_on(&self) -> bool {
+        self.iop_output_high(IOP_LED)
+    }
+
+    /// Return `true` if the IOP `pin` is configured as an output and
+    /// driven high
+    fn iop_output_high(&self, pin: u16) -> bool {
+        self.iop_ctrl & self.iop_data & pin != 0
+    }
+
+    /// Return `true` if the IOP `pin` is configured as an output and
+    /// driven low
+    fn iop_output_low(&self, pin: u16) -> bool {
+        self.iop_ctrl & !self.iop_data & pin != 0
+    }
+
+    /// Update the peripherals controlled through the IOP pins
+    fn iop_update(&mut self) {
+        let muted = self.iop_output_low(IOP_SPEAKER);
+
+        self.dac.set_muted(muted);
     }
 
     pub fn tick(&mut self, cpu_ticks: u32) {
         let master_ticks = cpu_ticks << self.cpu_clk_div;
 
-        self.rtc.tick(&mut self.irq_controller, master_ticks);
-        self.dac.tick(master_ticks);
+        self.frame_ticks += master_ticks;
+        self.pending_ticks += master_ticks;
+
+        if self.pending_ticks >= self.next_event {
+            self.sync();
+        }
+    }
 
-        self.timers[0].tick(&mut self.irq_controller, cpu_ticks);
-        self.timers[1].tick(&mut self.irq_controller, cpu_ticks);
-        self.timers[2].tick(&mut self.irq_controller, cpu_ticks);
+    /// Advance the peripherals by a single master clock tick,
+    /// independently of the CPU. Meant to study the timings of the
+    /// peripherals in isolation. The timers only advance once enough
+    /// master ticks have elapsed to make up a CPU clock period.
+    pub fn tick_one(&mut self) {
+        self.frame_ticks += 1;
+        self.pending_ticks += 1;
 
-        self.frame_ticks += master_ticks;
+        self.sync();
+    }
+
+    /// Return the number of master clock ticks until the next
+    /// scheduled peripheral event (RTC toggle, timer expiration...).
+    /// Since the RTC is always running there's always an upcoming
+    /// event.
+    pub fn ticks_to_next_event(&self) -> u32 {
+        self.next_event.saturating_sub(self.pending_ticks)
+    }
+
+    /// Advance the emulation by a potentially large number of CPU
+    /// cycles at once, for instance to fast-forward while the CPU is
+    /// idle. The batch is split at each scheduled peripheral event so
+    /// that every interrupt edge (timer expiration, RTC toggle...)
+    /// is processed exactly like it would be when ticking one
+    /// instruction at a time. The peripherals are up to date when
+    /// this function returns.
+    pub fn tick_batch(&mut self, mut cpu_ticks: u32) {
+        while cpu_ticks > 0 {
+            let remaining = self.ticks_to_next_event();
+
+            // Number of CPU ticks until the next event, rounded up.
+            // Use 64bits to avoid overflows at low CPU frequencies.
+            let period = 1u64 << self.cpu_clk_div;
+            let to_event = (remaining as u64 + period - 1) >> self.cpu_clk_div;
+
+            let step =
+                if to_event == 0 {
+                    1
+                } else if to_event < cpu_ticks as u64 {
+                    to_event as u32
+                } else {
+                    cpu_ticks
+                };
+
+            self.tick(step);
+
+            cpu_ticks -= step;
+        }
+
+        self.sync();
+    }
+
+    /// Bring all the peripherals up to date and schedule the next
+    /// event.
+    pub fn sync(&mut self) {
+        let master_ticks = self.pending_ticks;
+
+        if master_ticks > 0 {
+            // `cpu_clk_div` can't change without a store which syncs
+            // the peripherals first, so `master_ticks` is always a
+            // multiple of the CPU clock period here unless
+            // `tick_one` was used.
+            let ticks = self.timer_remainder + master_ticks;
+            let cpu_ticks = ticks >> self.cpu_clk_div;
+
+            self.timer_remainder = ticks & ((1 << self.cpu_clk_div) - 1);
+            self.pending_ticks = 0;
+            self.clk_lock_ticks =
+                self.clk_lock_ticks.saturating_sub(master_ticks);
+
+            self.rtc.tick(&mut self.irq_controller, master_ticks);
+            self.dac.tick(master_ticks);
+            let frames = self.lcd.tick(master_ticks);
+
+            if frames > 0 {
+                self.ram.end_frame();
+
+                if let Some(inputs) = self.pending_inputs.take() {
+                    inputs.apply(&mut self.irq_controller);
+                }
+            }
+
+            if let Some(ref mut callback) = self.frame_callback.0 {
+                for _ in 0..frames {
+                    callback(&self.lcd);
+                }
+            }
+
+            self.timers[0].tick(&mut self.irq_controller, cpu_ticks);
+            self.timers[1].tick(&mut self.irq_controller, cpu_ticks);
+            self.timers[2].tick(&mut self.irq_controller, cpu_ticks);
+        }
+
+        // The DAC and LCD don't generate any interrupt but the LCD
+        // needs to latch the framebuffer at the end of every frame
+        let mut next_event = self.rtc.next_event();
+
+        let lcd_event = self.lcd.next_event();
+
+        if lcd_event < next_event {
+            next_event = lcd_event;
+        }
+
+        for timer in &self.timers {
+            if let Some(cpu_ticks) = timer.next_event() {
+                // Use 64bits to avoid overflows with long timer
+                // periods
+                let master_ticks = (cpu_ticks as u64) << self.cpu_clk_div;
+
+                if master_ticks < next_event as u64 {
+                    next_event = master_ticks as u32;
+                }
+            }
+        }
+
+        self.next_event = next_event;
+    }
+
+    /// Return `true` if `addr` is in a memory region code can be
+    /// executed from (BIOS, flash or RAM), `false` for the
+    /// peripheral registers and unmapped regions.
+    pub fn is_executable(&self, addr: u32) -> bool {
+        match addr >> 24 {
+            // RAM or BIOS mirror
+            0x00 => true,
+            // Flash virtual view
+            0x02 => true,
+            // BIOS
+            0x04 => true,
+            // Flash physical view
+            0x08 => true,
+            _ => false,
+        }
+    }
+
+    /// Load a value from memory, bringing the peripherals up to date
+    /// first if we're accessing one of their registers. This is what
+    /// the CPU uses.
+    pub fn sync_load<A: Addressable>(&mut self, addr: u32) -> u32 {
+        unwrap_access(self.try_sync_load::<A>(addr))
+    }
+
+    /// Same as `sync_load` but returns an error instead of panicking,
+    /// see `try_load`
+    pub fn try_sync_load<A: Addressable>(&mut self,
+                                         addr: u32) -> Result<u32, String> {
+        if addr >= PERIPHERALS_START {
+            self.sync();
+            self.bus_contention(addr);
+        }
+
+        self.try_load::<A>(addr)
+    }
+
+    /// Same as `try_store` but also accounts for the bus contention
+    /// stall cycles, like `sync_load`. This is what the CPU uses,
+    /// stores from the frontend or the HLE code don't stall the CPU.
+    pub fn try_sync_store<A: Addressable>(&mut self,
+                                          addr: u32,
+                                          val: u32) -> Result<(), String> {
+        if addr >= PERIPHERALS_START {
+            self.sync();
+            self.bus_contention(addr);
+        }
+
+        self.try_store::<A>(addr, val)
+    }
+
+    /// Experimental model of the bus contention between the CPU and
+    /// the LCD controller: when `stall` is set every CPU access to the
+    /// framebuffer while the LCD is scanning it out (outside of the
+    /// vertical blanking) stalls the CPU for `stall` cycles. Disabled
+    /// by default.
+    ///
+    /// XXX The PocketStation has no DMA or transfer engine and this
+    /// contention has never been measured on the real hardware, it's
+    /// only meant to experiment with timing-sensitive code.
+    pub fn set_lcd_contention(&mut self, stall: Option<u32>) {
+        self.lcd_contention = stall;
+    }
+
+    /// Return the number of stall cycles accumulated since the last
+    /// call and reset the counter. Called by the CPU after each
+    /// instruction.
+    pub fn take_stall_cycles(&mut self) -> u32 {
+        let stall = self.stall_cycles;
+
+        self.stall_cycles = 0;
+
+        stall
+    }
+
+    /// Account for the stall cycles of a CPU access to `addr`. The
+    /// peripherals must be synchronized.
+    fn bus_contention(&mut self, addr: u32) {
+        if let Some(stall) = self.lcd_contention {
+            let framebuffer = addr >= 0x0d000100 && addr <= 0x0d00017f;
+
+            if framebuffer && !self.lcd.in_vblank() {
+                self.stall_cycles += stall;
+            }
+        }
+    }
+
+    /// Load a value from memory without synchronizing the
+    /// peripherals, which means that the timing-dependant registers
+    /// might return stale values. Useful for debugging.
+    pub fn peek<A: Addressable>(&self, addr: u32) -> u32 {
+        self.load::<A>(addr)
     }
 
+    /// Same as `peek` but the accesses which can't be handled return
+    /// an error instead of panicking, see `try_load`
+    pub fn try_peek<A: Addressable>(&self, addr: u32) -> Result<u32, String> {
+        self.try_load::<A>(addr)
+    }
+
+    /// Load a value from memory. The peripherals are not
+    /// synchronized, see `sync_load` and `peek`. Panics if the access
+    /// can't be handled and no `UnknownAccessHandler` is installed.
     pub fn load<A: Addressable>(&self, addr: u32) -> u32 {
-        let region = addr >> 24;
-        let offset = addr & 0xffffff;
+        unwrap_access(self.try_load::<A>(addr))
+    }
+
+    /// Same as `load` but the accesses which can't be handled return
+    /// an error instead of panicking if no `UnknownAccessHandler` is
+    /// installed. This is what lets `Cpu::try_step` report a bus error
+    /// when the guest accesses an unmapped address or an unemulated
+    /// register.
+    pub fn try_load<A: Addressable>(&self, addr: u32) -> Result<u32, String> {
+        if !A::is_aligned(addr) {
+            return Err(format!("Missaligned {}bit load at 0x{:08x}",
+                               A::size() * 8, addr));
+        }
 
-        if (addr & (A::size() as u32 - 1)) != 0 {
-            panic!("Missaligned {}bit load at 0x{:08x}", A::size() * 8, addr);
+        match self.dispatch_load::<A>(addr) {
+            Ok(v) => Ok(v),
+            // Truncate the handler's value to the size of the access
+            Err(e) => self.unknown_access.load(addr, e).map(|v| v & A::mask()),
         }
+    }
+
+    fn dispatch_load<A: Addressable>(&self, addr: u32) -> Result<u32, String> {
+        let region = addr >> 24;
+        let offset = addr & 0xffffff;
 
         let unimplemented =
-            || panic!("unhandled load address 0x{:08x}", addr);
+            || Err(format!("unhandled load address 0x{:08x}", addr));
 
         match region {
             0x00 =>
                 if self.flash.bios_at_0() {
-                    self.bios.load::<A>(offset)
+                    self.bios.try_load::<A>(offset)
                 } else {
-                    self.ram.load::<A>(offset)
+                    self.ram.try_load::<A>(offset)
                 },
-            0x02 => self.flash.load_virtual::<A>(offset),
-            0x04 => self.bios.load::<A>(offset),
-            0x06 => self.flash.load_config::<A>(offset),
-            0x08 => self.flash.load_raw::<A>(offset),
+            0x02 => self.flash.try_load_virtual::<A>(offset),
+            0x04 => self.bios.try_load::<A>(offset),
+            0x06 => self.flash.try_load_config::<A>(offset),
+            0x08 => self.flash.try_load_raw::<A>(offset),
             0x0a =>
                 match offset {
-                    0x00...0x10 => self.irq_controller.load::<A>(offset),
+                    0x00...0x13 => self.irq_controller.try_load::<A>(offset),
                     0x800000...0x800028 => {
-                        let timer = (offset >> 8) & 3;
+                        let timer = (offset >> 4) & 3;
 
-                        self.timers[timer as usize].load::<A>(offset & 0xf)
+                        self.timers[timer as usize].try_load::<A>(offset & 0xf)
                     }
                     _ => unimplemented(),
                 },
@@ -148,73 +645,151 @@ impl Interconnect {
                     0 => {
                         let div = 7 - self.cpu_clk_div;
 
-                        // Reply that the clock is ready (locked?)
-                        0x10 | div as u32
+                        // Bit 4 is set when the clock is ready
+                        // (locked?)
+                        let locked = (self.clk_lock_ticks == 0) as u32;
+
+                        Ok((locked << 4) | div as u32)
                     }
-                    0x800000...0x80000c => self.rtc.load::<A>(offset & 0xf),
+                    0x800000...0x80000c =>
+                        self.rtc.try_load::<A>(offset & 0xf),
+                    // As far as I know there's no watchdog timer in
+                    // this region (or anywhere else), neither the
+                    // BIOS nor the games I've looked at seem to kick
+                    // one so there's nothing to model. The
+                    // `no_watchdog` test pins these offsets as
+                    // unknown accesses.
                     _ => unimplemented(),
                 },
             0x0c =>
                 match offset {
-                    0x800000 => self.irda.load::<A>(0),
-                    0x800004 => self.irda.load::<A>(4),
+                    0x800000 => self.irda.try_load::<A>(0),
+                    0x800004 => self.irda.try_load::<A>(4),
                     _ => unimplemented(),
                 },
             0x0d =>
                 match offset {
-                    0...0x1ff => self.lcd.load::<A>(offset),
-                    0x800000 => self.iop_ctrl as u32,
+                    0...0x1ff => self.lcd.try_load::<A>(offset),
+                    0x800000 => Ok(self.iop_ctrl as u32),
                     // XXX Figure out what this register is exactly
-                    0x800004 => 0,
+                    0x800004 => Ok(0),
                     // XXX Figure out what this register is exactly
-                    0x80000c => 0,
-                    0x800010 => self.dac.load::<A>(0),
-                    0x800014 => self.dac.load::<A>(4),
+                    0x80000c => Ok(0),
+                    0x800010...0x800017 => self.dac.try_load::<A>(offset & 7),
                     // XXX BATT CTRL
-                    0x800020 => 0,
+                    0x800020 => Ok(0),
+                    // There's no hardware RNG on the PocketStation,
+                    // games seed their own PRNG (typically from the
+                    // RTC) so nothing to model here.
                     _ => unimplemented(),
                 },
             _ => unimplemented(),
         }
     }
 
+    /// Same as `load` with the access width chosen at runtime.
+    /// Useful for debuggers.
+    pub fn load_dyn(&self, addr: u32, width: AccessWidth) -> u32 {
+        match width {
+            AccessWidth::Byte => self.load::<Byte>(addr),
+            AccessWidth::HalfWord => self.load::<HalfWord>(addr),
+            AccessWidth::Word => self.load::<Word>(addr),
+        }
+    }
+
+    /// Same as `store` with the access width chosen at runtime.
+    /// Useful for debuggers.
+    pub fn store_dyn(&mut self, addr: u32, width: AccessWidth, val: u32) {
+        match width {
+            AccessWidth::Byte => self.store::<Byte>(addr, val),
+            AccessWidth::HalfWord => self.store::<HalfWord>(addr, val),
+            AccessWidth::Word => self.store::<Word>(addr, val),
+        }
+    }
+
+    /// Store a value to memory. Panics if the access can't be handled
+    /// and no `UnknownAccessHandler` is installed.
     pub fn store<A: Addressable>(&mut self, addr: u32, val: u32) {
-        let region = addr >> 24;
-        let offset = addr & 0xffffff;
+        unwrap_access(self.try_store::<A>(addr, val))
+    }
+
+    /// Same as `store` but the accesses which can't be handled return
+    /// an error instead of panicking, see `try_load`.
+    pub fn try_store<A: Addressable>(&mut self,
+                                     addr: u32,
+                                     val: u32) -> Result<(), String> {
+        if addr >= PERIPHERALS_START {
+            self.sync();
+        }
 
-        if (addr & (A::size() as u32 - 1)) != 0 {
-            panic!("Missaligned {}bit store at 0x{:08x}",
-                   A::size() * 8, addr);
+        if !A::is_aligned(addr) {
+            return Err(format!("Missaligned {}bit store at 0x{:08x}",
+                               A::size() * 8, addr));
         }
 
+        let r =
+            match self.dispatch_store::<A>(addr, val) {
+                Ok(()) => Ok(()),
+                Err(e) => self.unknown_access.store(addr, val, e),
+            };
+
+        if addr >= PERIPHERALS_START {
+            // The store might have changed the scheduling (timer
+            // enabled, CPU clock divider changed, DAC FIFO
+            // refilled...), recompute the next event
+            self.sync();
+        }
+
+        r
+    }
+
+    fn dispatch_store<A: Addressable>(&mut self,
+                                      addr: u32,
+                                      val: u32) -> Result<(), String> {
+        let region = addr >> 24;
+        let offset = addr & 0xffffff;
+
         let unimplemented =
-            || panic!("unhandled store address 0x{:08x}", addr);
+            || Err(format!("unhandled store address 0x{:08x}", addr));
 
         match region {
             0x00 =>
                 if !self.flash.bios_at_0() {
-                    self.ram.store::<A>(offset, val);
+                    try!(self.ram.try_store::<A>(offset, val));
+
+                    let page = (offset as usize / DIRTY_PAGE_SIZE) % RAM_PAGES;
+                    let generation = &mut self.ram_generation[page];
+
+                    *generation = generation.wrapping_add(1);
                 },
-            0x06 => self.flash.store_config::<A>(offset, val),
+            0x06 => {
+                // Might change the bank mapping or unmap the BIOS
+                self.code_changed();
+                try!(self.flash.try_store_config::<A>(offset, val));
+            }
             0x08 => {
+                self.code_changed();
+
                 match offset {
                     // F_KEY1
                     0x2a54 => (),
                     // F_KEY2
                     0x55aa => (),
-                    _ => self.flash.store_raw::<A>(offset, val),
+                    _ => try!(self.flash.try_store_raw::<A>(offset, val)),
                 }
             }
             0x0a =>
                 match offset {
-                    0x00...0x10 => self.irq_controller.store::<A>(offset, val),
+                    0x00...0x13 =>
+                        try!(self.irq_controller.try_store::<A>(offset, val)),
                     0x800000...0x800028 => {
                         let timer = (offset >> 4) & 3;
 
-                        self.timers[timer as usize].store::<A>(offset & 0xf,
-                                                               val);
+                        let timer = &mut self.timers[timer as usize];
+
+                        try!(timer.try_store::<A>(offset & 0xf, val));
                     }
-                    _ => unimplemented(),
+                    _ => return unimplemented(),
                 },
             0x0b =>
                 match offset {
@@ -222,44 +797,309 @@ impl Interconnect {
                     // values greater than 8 are possible but treated
                     // like 8. I need to run some tests on the real
                     // hardware to make sure.
-                    0 => self.cpu_clk_div = 7 - (val & 0x7) as u8,
-                    0x800000...0x80000c => self.rtc.store::<A>(offset & 0xf,
-                                                               val),
-                    _ => unimplemented(),
+                    0 => {
+                        self.cpu_clk_div = 7 - (val & 0x7) as u8;
+                        self.clk_lock_ticks = CLK_LOCK_TICKS;
+                    }
+                    0x800000...0x80000c =>
+                        try!(self.rtc.try_store::<A>(offset & 0xf, val)),
+                    _ => return unimplemented(),
                 },
             0x0c =>
                 match offset {
-                    0x00 => println!("COM MODE 0x{:08x}", val),
-                    0x08 => println!("COM DATA 0x{:08x}", val),
-                    0x10 => println!("COM CTRL1 0x{:08x}", val),
-                    0x18 => println!("COM CTRL2 0x{:08x}", val),
-                    0x800000 => self.irda.store::<A>(0, val),
-                    0x800004 => self.irda.store::<A>(4, val),
-                    _ => unimplemented(),
+                    0x00 => io_trace!("COM MODE 0x{:08x}", val),
+                    0x08 => io_trace!("COM DATA 0x{:08x}", val),
+                    0x10 => io_trace!("COM CTRL1 0x{:08x}", val),
+                    0x18 => io_trace!("COM CTRL2 0x{:08x}", val),
+                    0x800000 => try!(self.irda.try_store::<A>(0, val)),
+                    0x800004 => try!(self.irda.try_store::<A>(4, val)),
+                    _ => return unimplemented(),
                 },
             0x0d =>
                 match offset {
-                    0...0x1ff => self.lcd.store::<A>(offset, val),
+                    0...0x1ff => try!(self.lcd.try_store::<A>(offset, val)),
                     0x800000 => {
-                        println!("IOP CTRL 0x{:08x}", val);
-                        self.iop_ctrl = val as u8;
+                        self.iop_ctrl = (val as u16) & IOP_PIN_MASK;
+                        self.iop_update();
                     }
-                    0x800004 => println!("IOP STOP 0x{:08x}", val),
-                    0x800008 => println!("IOP START 0x{:08x}", val),
-                    0x800010 => self.dac.store::<A>(0, val),
-                    0x800014 => self.dac.store::<A>(4, val),
-                    0x800020 => println!("BATT CTRL 0x{:08x}", val),
-                    _ => unimplemented(),
+                    0x800004 => {
+                        self.iop_data &= !((val as u16) & IOP_PIN_MASK);
+                        self.iop_update();
+                    }
+                    0x800008 => {
+                        self.iop_data |= (val as u16) & IOP_PIN_MASK;
+                        self.iop_update();
+                    }
+                    0x800010...0x800017 =>
+                        try!(self.dac.try_store::<A>(offset & 7, val)),
+                    0x800020 => io_trace!("BATT CTRL 0x{:08x}", val),
+                    _ => return unimplemented(),
                 },
-            _ => unimplemented(),
+            _ => return unimplemented(),
+        }
+
+        Ok(())
+    }
+}
+
+/// Callback invoked for the accesses to unknown addresses. Its
+/// parameters are the address, `true` for stores and the value
+/// being stored, if any. The return value is used as the result of
+/// loads and is ignored for stores.
+pub type UnknownAccessHandler = Box<FnMut(u32, bool, Option<u32>) -> u32>;
+
+/// Build the lenient `UnknownAccessHandler`: loads return `open_bus`
+/// and stores are ignored, with a warning. XXX I don't know what the
+/// real hardware returns for unmapped addresses, some buses float
+/// high (0xffffffff) and others low (0).
+pub fn open_bus_handler(open_bus: u32) -> UnknownAccessHandler {
+    let handler = move |addr, write, _| {
+        if write {
+            warn!("Ignoring store to unknown address 0x{:08x}", addr);
+        } else {
+            warn!("Load from unknown address 0x{:08x}", addr);
+        }
+
+        open_bus
+    };
+
+    Box::new(handler)
+}
+
+/// Handling of the accesses to unknown addresses
+struct UnknownAccess {
+    /// Frontend handler, if any. We use a `RefCell` so that the
+    /// handler can be called from `peek`.
+    handler: RefCell<Option<UnknownAccessHandler>>,
+}
+
+impl UnknownAccess {
+    /// Without a handler the unknown accesses are errors
+    fn new() -> UnknownAccess {
+        UnknownAccess {
+            handler: RefCell::new(None),
+        }
+    }
+
+    /// Handle a load from `addr` which couldn't be serviced, `err`
+    /// describes why. Without a handler `err` is returned.
+    fn load(&self, addr: u32, err: String) -> Result<u32, String> {
+        match *self.handler.borrow_mut() {
+            Some(ref mut handler) => Ok(handler(addr, false, None)),
+            None => Err(err),
+        }
+    }
+
+    /// Handle a store to `addr` which couldn't be serviced, see
+    /// `load`
+    fn store(&self, addr: u32, val: u32, err: String) -> Result<(), String> {
+        match *self.handler.borrow_mut() {
+            Some(ref mut handler) => {
+                handler(addr, true, Some(val));
+                Ok(())
             }
+            None => Err(err),
+        }
+    }
+}
+
+impl Encodable for UnknownAccess {
+    fn encode<S: Encoder>(&self, s: &mut S) -> Result<(), S::Error> {
+        // The handler is set by the frontend, we can't serialize it
+        s.emit_nil()
+    }
+}
+
+impl Decodable for UnknownAccess {
+    fn decode<D: Decoder>(d: &mut D) -> Result<UnknownAccess, D::Error> {
+        try!(d.read_nil());
+
+        Ok(UnknownAccess::new())
+    }
+}
+
+/// Callback called at the end of every LCD frame, see
+/// `Interconnect::set_frame_callback`.
+pub type FrameCallback = Box<FnMut(&Lcd)>;
+
+/// Wrapper around the optional `FrameCallback` for serialization
+struct OnFrame(Option<FrameCallback>);
+
+impl Encodable for OnFrame {
+    fn encode<S: Encoder>(&self, s: &mut S) -> Result<(), S::Error> {
+        // The callback is set by the frontend, we can't serialize it
+        s.emit_nil()
+    }
+}
+
+impl Decodable for OnFrame {
+    fn decode<D: Decoder>(d: &mut D) -> Result<OnFrame, D::Error> {
+        try!(d.read_nil());
+
+        Ok(OnFrame(None))
+    }
+}
+
+/// Duration of the "clock not ready" window after a CLKMODE write,
+/// in master clock ticks. XXX This is a guess, it needs to be
+/// measured on the real hardware. Keep it short, the software may
+/// busy-wait on the ready bit after changing the clock.
+const CLK_LOCK_TICKS: u32 = 64;
+
+/// The IOP controller has 9 general purpose I/O pins, the other
+/// register bits are not implemented
+const IOP_PIN_MASK: u16 = 0x1ff;
+/// IOP pin driving the red LED (1 = lit)
+///
+/// XXX Pin assignments come from the No$ documentation and haven't
+/// been checked on the real hardware.
+const IOP_LED: u16 = 1 << 1;
+/// IOP pin powering the speaker. The speaker is silent when the pin
+/// is configured as an output and driven low.
+const IOP_SPEAKER: u16 = 1 << 5;
+
+/// 64bit FNV-1a hasher used by `content_hash`. Unlike the standard
+/// library's `DefaultHasher` it's fast and its output is guaranteed
+/// not to change across Rust releases.
+struct Fnv1aHasher(u64);
+
+impl Fnv1aHasher {
+    fn new() -> Fnv1aHasher {
+        Fnv1aHasher(0xcbf29ce484222325)
     }
 }
 
+impl Hasher for Fnv1aHasher {
+    fn write(&mut self, bytes: &[u8]) {
+        for &b in bytes {
+            self.0 ^= b as u64;
+            self.0 = self.0.wrapping_mul(0x100000001b3);
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+/// Start of the memory-mapped peripheral registers. Accesses above
+/// this address require the peripherals to be up to date.
+const PERIPHERALS_START: u32 = 0x0a000000;
+
 /// Trait representing the attributes of a memory access
 pub trait Addressable {
     /// Retreive the size of the access in bytes
     fn size() -> u8;
+
+    /// Mask covering the bits of a value of this size
+    fn mask() -> u32 {
+        !0 >> (32 - 8 * Self::size() as u32)
+    }
+
+    /// Return `true` if `addr` is correctly aligned for an access of
+    /// this size. Byte accesses are always aligned, halfword accesses
+    /// must be at even addresses and word accesses at multiples of 4.
+    fn is_aligned(addr: u32) -> bool {
+        addr & (Self::size() as u32 - 1) == 0
+    }
+}
+
+/// Unwrap the result of a `try_load` or `try_store`, panicking with
+/// the error message if the access couldn't be handled
+pub fn unwrap_access<T>(r: Result<T, String>) -> T {
+    match r {
+        Ok(v) => v,
+        Err(e) => panic!("{}", e),
+    }
+}
+
+/// Extract the value of an `A`-sized load at byte `offset` from the
+/// 32bit register `reg`. The access is assumed to be aligned.
+pub fn register_load<A: Addressable>(reg: u32, offset: u32) -> u32 {
+    let shift = (offset & 3) * 8;
+
+    (reg >> shift) & A::mask()
+}
+
+/// Return the new value of the 32bit register `reg` after an
+/// `A`-sized store of `val` at byte `offset`. The bytes not covered
+/// by the store are left untouched. The access is assumed to be
+/// aligned.
+pub fn register_store<A: Addressable>(reg: u32, offset: u32, val: u32) -> u32 {
+    let shift = (offset & 3) * 8;
+    let mask = A::mask() << shift;
+
+    (reg & !mask) | ((val << shift) & mask)
+}
+
+/// Interface common to all the memory-mapped peripherals. `offset`
+/// is relative to the start of the peripheral's register range.
+pub trait Peripheral {
+    fn load(&self, width: AccessWidth, offset: u32) -> u32;
+
+    fn store(&mut self, width: AccessWidth, offset: u32, val: u32);
+
+    /// Put the peripheral back in its power-on state
+    fn reset(&mut self);
+}
+
+/// Implement `Peripheral` for a type with the usual generic
+/// `load`/`store` and `reset` methods
+macro_rules! impl_peripheral {
+    ($t: ty) => {
+        impl Peripheral for $t {
+            fn load(&self, width: AccessWidth, offset: u32) -> u32 {
+                match width {
+                    AccessWidth::Byte => <$t>::load::<Byte>(self, offset),
+                    AccessWidth::HalfWord =>
+                        <$t>::load::<HalfWord>(self, offset),
+                    AccessWidth::Word => <$t>::load::<Word>(self, offset),
+                }
+            }
+
+            fn store(&mut self, width: AccessWidth, offset: u32, val: u32) {
+                match width {
+                    AccessWidth::Byte =>
+                        <$t>::store::<Byte>(self, offset, val),
+                    AccessWidth::HalfWord =>
+                        <$t>::store::<HalfWord>(self, offset, val),
+                    AccessWidth::Word =>
+                        <$t>::store::<Word>(self, offset, val),
+                }
+            }
+
+            fn reset(&mut self) {
+                <$t>::reset(self)
+            }
+        }
+    }
+}
+
+impl_peripheral!(IrqController);
+impl_peripheral!(Rtc);
+impl_peripheral!(Lcd);
+impl_peripheral!(Dac);
+impl_peripheral!(Irda);
+impl_peripheral!(Timer);
+
+/// Runtime equivalent of the `Addressable` markers, for code that
+/// chooses the access width dynamically
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum AccessWidth {
+    Byte,
+    HalfWord,
+    Word,
+}
+
+impl AccessWidth {
+    /// Retreive the size of the access in bytes
+    pub fn size(self) -> u8 {
+        match self {
+            AccessWidth::Byte => Byte::size(),
+            AccessWidth::HalfWord => HalfWord::size(),
+            AccessWidth::Word => Word::size(),
+        }
+    }
 }
 
 /// Marker for Byte (8bit) access
@@ -288,3 +1128,1031 @@ impl Addressable for Word {
         4
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::panic;
+    use std::rc::Rc;
+    use std::cell::RefCell;
+
+    use log::Level;
+
+    use dac::{Backend, MASTER_CLOCK_DIV};
+    use interrupt::{Interrupt, IrqController, Inputs};
+    use rtc::Rtc;
+    use timer::Timer;
+    use test_util;
+    use MASTER_CLOCK_HZ;
+
+    use super::*;
+
+    /// The scheduler must deliver the RTC and timer interrupts at
+    /// the exact same master tick as running every peripheral at
+    /// every tick
+    #[test]
+    fn scheduler_matches_per_tick_loop() {
+        let mask = Interrupt::Rtc.mask() | Interrupt::Timer0.mask();
+
+        // Reference: tick every peripheral at every master tick
+        let mut irq = IrqController::new();
+        let mut rtc = Rtc::new();
+        let mut timer = Timer::new(Interrupt::Timer0);
+
+        irq.store::<Word>(0x08, mask as u32);
+        timer.store::<Word>(0, 1000);
+        timer.store::<Word>(8, 4);
+
+        // Scheduled: run the CPU clock at the master clock frequency
+        // so that each `tick` is a single master tick
+        let mut inter = test_util::interconnect(&[], &[]);
+
+        inter.store::<Word>(0x0b000000, 7);
+        inter.store::<Word>(0x0a000008, mask as u32);
+        inter.store::<Word>(0x0a800000, 1000);
+        inter.store::<Word>(0x0a800008, 4);
+
+        let mut reference_events = Vec::new();
+        let mut scheduled_events = Vec::new();
+
+        // A bit more than two RTC periods
+        for t in 0..(MASTER_CLOCK_HZ * 2 + 1000) {
+            rtc.tick(&mut irq, 1);
+            timer.tick(&mut irq, 1);
+
+            let status = irq.status();
+
+            if status != 0 {
+                reference_events.push((t, status));
+                irq.ack(status);
+            }
+
+            inter.tick(1);
+
+            let status = inter.irq_controller().status();
+
+            if status != 0 {
+                scheduled_events.push((t, status));
+                inter.irq_controller_mut().ack(status);
+            }
+        }
+
+        let rtc_events =
+            reference_events.iter()
+            .filter(|&&(_, s)| s & Interrupt::Rtc.mask() != 0)
+            .count();
+
+        assert_eq!(rtc_events, 2);
+        assert!(reference_events.len() > 1000);
+        assert_eq!(scheduled_events, reference_events);
+    }
+
+    #[test]
+    fn tick_batch_matches_per_instruction() {
+        let mask = Interrupt::Rtc.mask() | Interrupt::Timer1.mask();
+
+        let setup = || {
+            let mut inter = test_util::interconnect(&[], &[]);
+
+            inter.store::<Word>(0x0b000000, 4);
+            inter.store::<Word>(0x0a000008, mask as u32);
+            inter.store::<Word>(0x0a800010, 5000);
+            inter.store::<Word>(0x0a800018, 6);
+
+            inter
+        };
+
+        let mut single = setup();
+        let mut batched = setup();
+
+        // Not a divisor of any peripheral period so that the RTC
+        // edges end up in the middle of the batches
+        const BATCH: u32 = 99991;
+
+        let mut rtc_edges = 0;
+
+        // A bit more than two RTC periods
+        let batches = single.effective_cpu_hz() * 5 / 2 / BATCH;
+
+        for _ in 0..batches {
+            for _ in 0..BATCH {
+                single.tick(1);
+            }
+            single.sync();
+
+            batched.tick_batch(BATCH);
+
+            assert_eq!(batched.content_hash(), single.content_hash());
+
+            let status = single.irq_controller().status();
+
+            assert_eq!(batched.irq_controller().status(), status);
+
+            if status & Interrupt::Rtc.mask() != 0 {
+                rtc_edges += 1;
+            }
+
+            single.irq_controller_mut().ack(status);
+            batched.irq_controller_mut().ack(status);
+        }
+
+        assert_eq!(rtc_edges, 2);
+    }
+
+    #[test]
+    fn effective_cpu_hz() {
+        let mut inter = test_util::interconnect(&[], &[]);
+
+        // Slowest clock on reset
+        assert_eq!(inter.effective_cpu_hz(), MASTER_CLOCK_HZ / 128);
+
+        for clkmode in 0..8 {
+            inter.store::<Word>(0x0b000000, clkmode);
+
+            assert_eq!(inter.effective_cpu_hz(),
+                       MASTER_CLOCK_HZ / (1 << (7 - clkmode)));
+        }
+
+        assert_eq!(inter.effective_cpu_hz(), MASTER_CLOCK_HZ);
+    }
+
+    #[test]
+    fn content_hash() {
+        let ram = [0x12, 0x34, 0x56, 0x78];
+
+        let mut a = test_util::interconnect(b"flash", &ram);
+        let mut b = test_util::interconnect(b"flash", &ram);
+
+        // Unmap the BIOS to make the RAM writable at address 0
+        a.store::<Word>(0x06000000, 3);
+        b.store::<Word>(0x06000000, 3);
+
+        let hash = a.content_hash();
+
+        assert_eq!(hash, b.content_hash());
+        // Hashing doesn't modify the state
+        assert_eq!(hash, a.content_hash());
+
+        // Writing the same value back doesn't change the hash
+        let v = a.load::<Byte>(0x100);
+
+        a.store::<Byte>(0x100, v);
+        assert_eq!(a.content_hash(), hash);
+
+        a.store::<Byte>(0x100, v ^ 1);
+        assert!(a.content_hash() != hash);
+
+        b.store::<Byte>(0x100, v ^ 1);
+        assert_eq!(a.content_hash(), b.content_hash());
+
+        // Peripheral state is covered as well
+        b.store::<Word>(0x0a800000, 1000);
+        assert!(a.content_hash() != b.content_hash());
+    }
+
+    #[test]
+    fn register_lanes() {
+        let reg = 0x44332211;
+
+        assert_eq!(register_load::<Word>(reg, 0), reg);
+
+        assert_eq!(register_load::<HalfWord>(reg, 0), 0x2211);
+        assert_eq!(register_load::<HalfWord>(reg, 2), 0x4433);
+
+        assert_eq!(register_load::<Byte>(reg, 0), 0x11);
+        assert_eq!(register_load::<Byte>(reg, 1), 0x22);
+        assert_eq!(register_load::<Byte>(reg, 2), 0x33);
+        assert_eq!(register_load::<Byte>(reg, 3), 0x44);
+
+        // Only the offset within the register matters
+        assert_eq!(register_load::<Byte>(reg, 0x17), 0x44);
+
+        assert_eq!(register_store::<Word>(reg, 0, 0xaabbccdd), 0xaabbccdd);
+
+        // The upper bits of `val` are ignored for narrow stores
+        assert_eq!(register_store::<HalfWord>(reg, 0, 0xffffaaaa),
+                   0x4433aaaa);
+        assert_eq!(register_store::<HalfWord>(reg, 2, 0xffffaaaa),
+                   0xaaaa2211);
+
+        assert_eq!(register_store::<Byte>(reg, 0, 0xffffffaa), 0x443322aa);
+        assert_eq!(register_store::<Byte>(reg, 1, 0xffffffaa), 0x4433aa11);
+        assert_eq!(register_store::<Byte>(reg, 2, 0xffffffaa), 0x44aa2211);
+        assert_eq!(register_store::<Byte>(reg, 3, 0xffffffaa), 0xaa332211);
+    }
+
+    /// Sub-word accesses to a peripheral register go through the
+    /// helpers
+    #[test]
+    fn peripheral_register_lanes() {
+        let mut inter = test_util::interconnect(&[], &[]);
+
+        // DAC sample register
+        inter.store::<Word>(0x0d800014, 0x1234);
+
+        assert_eq!(inter.load::<Byte>(0x0d800014), 0x34);
+        assert_eq!(inter.load::<Byte>(0x0d800015), 0x12);
+        assert_eq!(inter.load::<HalfWord>(0x0d800014), 0x1234);
+        // The register is 16bit wide
+        assert_eq!(inter.load::<HalfWord>(0x0d800016), 0);
+
+        inter.store::<Byte>(0x0d800015, 0xab);
+        assert_eq!(inter.load::<Word>(0x0d800014), 0xab34);
+
+        inter.store::<Byte>(0x0d800014, 0xcd);
+        assert_eq!(inter.load::<Word>(0x0d800014), 0xabcd);
+
+        inter.store::<HalfWord>(0x0d800014, 0x5678);
+        assert_eq!(inter.load::<Word>(0x0d800014), 0x5678);
+    }
+
+    #[test]
+    fn reset() {
+        fn hash<T: Hash>(t: &T) -> u64 {
+            let mut hasher = Fnv1aHasher::new();
+
+            t.hash(&mut hasher);
+
+            hasher.finish()
+        }
+
+        let fresh = test_util::interconnect(&[], &[]);
+        let mut inter = test_util::interconnect(&[], &[]);
+
+        // F_CTRL: unmap the BIOS
+        inter.store::<Word>(0x06000000, 3);
+        // CLKMODE
+        inter.store::<Word>(0x0b000000, 5);
+        // IRQ mask
+        inter.store::<Word>(0x0a000008, 0xffff);
+
+        for t in 0..3 {
+            inter.store::<Word>(0x0a800000 + t * 0x10, 1000);
+            inter.store::<Word>(0x0a800008 + t * 0x10, 4);
+        }
+
+        // Pause the RTC. We don't touch the time and date since they
+        // survive the reset.
+        inter.store::<Word>(0x0b800000, 1);
+        // LCD mode and framebuffer
+        inter.store::<Word>(0x0d000000, 0xf0);
+        inter.store::<Word>(0x0d000100, 0xffffffff);
+        // DAC enable and sample
+        inter.store::<Word>(0x0d800010, 1);
+        inter.store::<Word>(0x0d800014, 0x1234);
+        // IrDA mode and LED
+        inter.store::<Word>(0x0c800000, 1);
+        inter.store::<Word>(0x0c800004, 1);
+        // IOP
+        inter.store::<Word>(0x0d800000, IOP_LED as u32);
+        inter.store::<Word>(0x0d800008, IOP_LED as u32);
+
+        inter.tick_batch(5000);
+
+        assert!(hash(&inter.flash) != hash(&fresh.flash));
+        assert!(hash(&inter.irq_controller) != hash(&fresh.irq_controller));
+        assert!(hash(&inter.rtc) != hash(&fresh.rtc));
+        assert!(hash(&inter.lcd) != hash(&fresh.lcd));
+        assert!(hash(&inter.dac) != hash(&fresh.dac));
+        assert!(hash(&inter.irda) != hash(&fresh.irda));
+
+        for t in 0..3 {
+            assert!(hash(&inter.timers[t]) != hash(&fresh.timers[t]));
+        }
+
+        assert!(inter.cpu_clk_div != fresh.cpu_clk_div);
+        assert!(inter.led_on());
+
+        inter.reset();
+
+        assert_eq!(hash(&inter.flash), hash(&fresh.flash));
+        assert_eq!(hash(&inter.irq_controller),
+                   hash(&fresh.irq_controller));
+        assert_eq!(hash(&inter.rtc), hash(&fresh.rtc));
+        assert_eq!(hash(&inter.lcd), hash(&fresh.lcd));
+        assert_eq!(hash(&inter.dac), hash(&fresh.dac));
+        assert_eq!(hash(&inter.irda), hash(&fresh.irda));
+
+        for t in 0..3 {
+            assert_eq!(hash(&inter.timers[t]), hash(&fresh.timers[t]));
+        }
+
+        assert_eq!(inter.cpu_clk_div, fresh.cpu_clk_div);
+        assert_eq!(inter.iop_ctrl, fresh.iop_ctrl);
+        assert_eq!(inter.iop_data, fresh.iop_data);
+        assert!(!inter.led_on());
+    }
+
+    /// Audio backend recording the samples it receives
+    struct SampleLog(Rc<RefCell<Vec<i16>>>);
+
+    impl Backend for SampleLog {
+        fn push_sample(&mut self, sample: i16) {
+            self.0.borrow_mut().push(sample);
+        }
+    }
+
+    #[test]
+    fn iop_ctrl() {
+        let samples = Rc::new(RefCell::new(Vec::new()));
+        let mut inter = test_util::interconnect(&[], &[]);
+
+        inter.dac_mut().set_backend(Box::new(SampleLog(samples.clone())));
+
+        // CPU clock == master clock
+        inter.store::<Word>(0x0b000000, 7);
+        // Enable the DAC and output a constant sample
+        inter.store::<Word>(0x0d800010, 1);
+        inter.store::<Word>(0x0d800014, 100);
+
+        // Only the pins can be configured
+        inter.store::<Word>(0x0d800000, 0xffff);
+        assert_eq!(inter.load::<Word>(0x0d800000), IOP_PIN_MASK as u32);
+
+        let run = |inter: &mut Interconnect| {
+            samples.borrow_mut().clear();
+
+            inter.tick_batch(MASTER_CLOCK_DIV * 4);
+
+            let samples = samples.borrow();
+
+            assert!(samples.len() >= 3);
+            assert!(samples.iter().all(|&s| s == samples[0]));
+
+            samples[0]
+        };
+
+        // All pins are outputs driven low: the speaker is off
+        assert_eq!(run(&mut inter), 0);
+        assert!(!inter.led_on());
+
+        // Drive the speaker pin high
+        inter.store::<Word>(0x0d800008, IOP_SPEAKER as u32);
+        assert_eq!(run(&mut inter), 100);
+        assert!(!inter.led_on());
+
+        // Drive it low again
+        inter.store::<Word>(0x0d800004, IOP_SPEAKER as u32);
+        assert_eq!(run(&mut inter), 0);
+
+        // Switch it to an input, the speaker is no longer cut off
+        inter.store::<Word>(0x0d800000, IOP_LED as u32);
+        assert_eq!(run(&mut inter), 100);
+
+        // The LED follows its own pin
+        assert!(!inter.led_on());
+        inter.store::<Word>(0x0d800008, IOP_LED as u32);
+        assert!(inter.led_on());
+        assert_eq!(run(&mut inter), 100);
+
+        // Data bits are ignored for input pins
+        inter.store::<Word>(0x0d800000, 0);
+        assert!(!inter.led_on());
+    }
+
+    #[test]
+    #[should_panic(expected = "unhandled load address 0x0e000000")]
+    fn unknown_load_panics_by_default() {
+        let inter = test_util::interconnect(&[], &[]);
+
+        inter.load::<Word>(0x0e000000);
+    }
+
+    #[test]
+    #[should_panic(expected = "unhandled store address 0x0b000004")]
+    fn unknown_store_panics_by_default() {
+        let mut inter = test_util::interconnect(&[], &[]);
+
+        inter.store::<Word>(0x0b000004, 0);
+    }
+
+    #[test]
+    fn open_bus_value() {
+        let mut inter = test_util::interconnect(&[], &[]);
+
+        inter.set_open_bus_value(0xffffffff);
+        assert_eq!(inter.load::<Word>(0x0e000000), 0xffffffff);
+        assert_eq!(inter.load::<Word>(0x0b000004), 0xffffffff);
+
+        // Stores are ignored
+        inter.store::<Word>(0x0e000000, 0x1234);
+        assert_eq!(inter.load::<Word>(0x0e000000), 0xffffffff);
+
+        inter.set_open_bus_value(0);
+        assert_eq!(inter.load::<Word>(0x0e000000), 0);
+
+        // Known registers are not affected
+        inter.store::<Word>(0x0d800000, 0x3);
+        assert_eq!(inter.load::<Word>(0x0d800000), 0x3);
+    }
+
+    #[test]
+    fn com_stores_are_silent() {
+        let mut inter = test_util::interconnect(&[], &[]);
+
+        let logs = test_util::capture_logs(|| {
+            inter.store::<Word>(0x0c000000, 0x1);
+            inter.store::<Word>(0x0c000008, 0x42);
+        });
+
+        if cfg!(feature = "verbose-io") {
+            assert_eq!(logs,
+                       [(Level::Trace, "COM MODE 0x00000001".to_string()),
+                        (Level::Trace, "COM DATA 0x00000042".to_string())]);
+        } else {
+            assert!(logs.is_empty());
+        }
+    }
+
+    #[test]
+    fn unknown_access_handler() {
+        let accesses = Rc::new(RefCell::new(Vec::new()));
+
+        let mut cpu = test_util::arm_cpu(&[
+            // MOV R1, #0x0e000000
+            0xe3a0140e,
+            // LDR R0, [R1]
+            0xe5910000,
+            // STR R0, [R1, #4]
+            0xe5810004,
+        ]);
+
+        let log = accesses.clone();
+        let handler = move |addr, write, val| {
+            log.borrow_mut().push((addr, write, val));
+
+            0xcafef00d
+        };
+
+        cpu.interconnect_mut()
+            .set_unknown_access_handler(Box::new(handler));
+
+        test_util::run(&mut cpu, 3);
+
+        assert_eq!(cpu.registers()[0], 0xcafef00d);
+        assert_eq!(*accesses.borrow(),
+                   [(0x0e000000, false, None),
+                    (0x0e000004, true, Some(0xcafef00d))]);
+    }
+
+    /// Return `true` if `f` panics because of a misaligned access.
+    /// Other panics (unsupported access widths...) are ignored.
+    fn misaligned<F: FnOnce()>(f: F) -> bool {
+        match panic::catch_unwind(panic::AssertUnwindSafe(f)) {
+            Ok(_) => false,
+            Err(e) =>
+                match e.downcast_ref::<String>() {
+                    Some(msg) => msg.starts_with("Missaligned"),
+                    None => false,
+                },
+        }
+    }
+
+    #[test]
+    fn alignment() {
+        let mut inter = test_util::interconnect(&[0; 0x100], &[]);
+
+        let mut load_regions = vec![];
+
+        // Every region, mapped or not: the alignment is checked
+        // before the address is decoded
+        for region in 0..0x0e {
+            load_regions.push(region << 24);
+        }
+
+        for &base in &load_regions {
+            for offset in 0..8 {
+                let addr = base + offset;
+
+                assert!(!misaligned(|| { inter.load::<Byte>(addr); }));
+                assert_eq!(misaligned(|| { inter.load::<HalfWord>(addr); }),
+                           offset & 1 != 0);
+                assert_eq!(misaligned(|| { inter.load::<Word>(addr); }),
+                           offset & 3 != 0);
+            }
+        }
+
+        // Unmap the BIOS to test the RAM stores
+        inter.store::<Word>(0x06000000, 3);
+
+        for offset in 0..8 {
+            assert!(!misaligned(|| inter.store::<Byte>(offset, 0)));
+            assert_eq!(misaligned(|| inter.store::<HalfWord>(offset, 0)),
+                       offset & 1 != 0);
+            assert_eq!(misaligned(|| inter.store::<Word>(offset, 0)),
+                       offset & 3 != 0);
+        }
+    }
+
+    /// Apply the same register setup to `inter` through `store` and
+    /// `store_dyn`
+    fn dyn_setup(inter: &mut Interconnect, dynamic: bool) {
+        let stores = [
+            // Unmap the BIOS
+            (0x06000000, AccessWidth::Word, 3),
+            (0x00000010, AccessWidth::Word, 0x12345678),
+            (0x00000016, AccessWidth::HalfWord, 0xbeef),
+            (0x00000019, AccessWidth::Byte, 0x42),
+            (0x0a000008, AccessWidth::Word, 0x1234),
+            // Different reload values and modes for every timer
+            (0x0a800000, AccessWidth::Word, 100),
+            (0x0a800008, AccessWidth::Word, 4),
+            (0x0a800010, AccessWidth::HalfWord, 200),
+            (0x0a800018, AccessWidth::HalfWord, 5),
+            (0x0a800020, AccessWidth::Word, 300),
+            (0x0a800028, AccessWidth::Word, 6),
+        ];
+
+        for &(addr, width, val) in &stores {
+            if dynamic {
+                inter.store_dyn(addr, width, val);
+            } else {
+                match width {
+                    AccessWidth::Byte => inter.store::<Byte>(addr, val),
+                    AccessWidth::HalfWord =>
+                        inter.store::<HalfWord>(addr, val),
+                    AccessWidth::Word => inter.store::<Word>(addr, val),
+                }
+            }
+        }
+
+        inter.tick_batch(1000);
+    }
+
+    #[test]
+    fn dynamic_access() {
+        let mut stat = test_util::interconnect(b"flash contents", &[]);
+        let mut dynamic = test_util::interconnect(b"flash contents", &[]);
+
+        dyn_setup(&mut stat, false);
+        dyn_setup(&mut dynamic, true);
+
+        assert_eq!(stat.content_hash(), dynamic.content_hash());
+
+        for addr in 0x10..0x1c {
+            assert_eq!(dynamic.load_dyn(addr, AccessWidth::Byte),
+                       stat.load::<Byte>(addr));
+        }
+
+        for addr in (0x10..0x1c).filter(|a| a % 2 == 0) {
+            assert_eq!(dynamic.load_dyn(addr, AccessWidth::HalfWord),
+                       stat.load::<HalfWord>(addr));
+        }
+
+        assert_eq!(dynamic.load_dyn(0x08000006, AccessWidth::HalfWord),
+                   stat.load::<HalfWord>(0x08000006));
+        assert_eq!(dynamic.load_dyn(0x08000000, AccessWidth::Word),
+                   stat.load::<Word>(0x08000000));
+
+        let peripherals = [0x00000010, 0x0a000008,
+                           0x0a800004, 0x0a800008,
+                           0x0a800014, 0x0a800018,
+                           0x0a800024, 0x0a800028];
+
+        for &addr in &peripherals {
+            assert_eq!(dynamic.load_dyn(addr, AccessWidth::Word),
+                       stat.load::<Word>(addr));
+            assert_eq!(dynamic.load_dyn(addr, AccessWidth::HalfWord),
+                       stat.load::<HalfWord>(addr));
+        }
+
+        // Each timer is accessed at its own address
+        assert_eq!(stat.load::<Word>(0x0a800008), 4);
+        assert_eq!(stat.load::<Word>(0x0a800018), 5);
+        assert_eq!(stat.load::<Word>(0x0a800028), 6);
+    }
+
+    #[test]
+    fn region_0d_constant_reads() {
+        let mut inter = test_util::interconnect(&[], &[]);
+
+        // The registers we don't emulate read as 0, there's no RNG
+        // or other source of entropy in this region
+        for _ in 0..4 {
+            for &addr in &[0x0d800004, 0x0d80000c, 0x0d800020] {
+                assert_eq!(inter.load::<Word>(addr), 0);
+            }
+
+            inter.tick_batch(12345);
+        }
+
+        // The rest of the region is unknown
+        let unknown = Rc::new(RefCell::new(Vec::new()));
+        let log = unknown.clone();
+
+        inter.set_unknown_access_handler(Box::new(move |addr, _, _| {
+            log.borrow_mut().push(addr);
+            0
+        }));
+
+        for &addr in &[0x0d800018, 0x0d800024, 0x0d800030, 0x0dfffffc] {
+            inter.load::<Word>(addr);
+        }
+
+        assert_eq!(*unknown.borrow(),
+                   [0x0d800018, 0x0d800024, 0x0d800030, 0x0dfffffc]);
+    }
+
+    #[test]
+    fn reset_all_peripherals() {
+        // IRQ mask, timer counters and modes
+        let regs = [0x0a000008,
+                    0x0a800004, 0x0a800008,
+                    0x0a800014, 0x0a800018,
+                    0x0a800024, 0x0a800028];
+
+        let mut inter = test_util::interconnect(&[], &[]);
+
+        let power_on: Vec<u32> =
+            regs.iter().map(|&r| inter.load::<Word>(r)).collect();
+
+        inter.store::<Word>(0x0a000008, 0x1234);
+
+        for t in 0..3 {
+            // Reload value and mode (enabled)
+            inter.store::<Word>(0x0a800000 + t * 0x10, 0x100 + t);
+            inter.store::<Word>(0x0a800008 + t * 0x10, 4 | t);
+        }
+
+        inter.tick_batch(1000);
+        inter.sync();
+
+        let modified: Vec<u32> =
+            regs.iter().map(|&r| inter.load::<Word>(r)).collect();
+
+        assert!(modified.iter().zip(&power_on).all(|(m, p)| m != p));
+
+        let mut count = 0;
+
+        for p in inter.peripherals_mut().iter_mut() {
+            p.reset();
+            count += 1;
+        }
+
+        assert_eq!(count, 8);
+
+        let after: Vec<u32> =
+            regs.iter().map(|&r| inter.load::<Word>(r)).collect();
+
+        assert_eq!(after, power_on);
+
+        // The trait objects see the registers relative to the
+        // peripheral: timer 1's mode
+        let peripherals = inter.peripherals_mut();
+
+        peripherals[6].store(AccessWidth::Word, 8, 5);
+        assert_eq!(peripherals[6].load(AccessWidth::Word, 8), 5);
+        assert_eq!(inter.load::<Word>(0x0a800018), 5);
+    }
+
+    #[test]
+    fn frame_callback() {
+        use lcd::FRAME_TICKS;
+
+        let mut inter = test_util::interconnect(&[], &[]);
+
+        let frames = Rc::new(RefCell::new(0));
+
+        {
+            let frames = frames.clone();
+
+            inter.set_frame_callback(Some(Box::new(move |lcd| {
+                // The LCD is readable from the callback
+                assert_eq!(lcd.framebuffer().len(), 32);
+
+                *frames.borrow_mut() += 1;
+            })));
+        }
+
+        let div = MASTER_CLOCK_HZ / inter.effective_cpu_hz();
+
+        // Round up to the CPU tick ending the frame
+        let frame_cpu_ticks = (FRAME_TICKS + div - 1) / div;
+
+        // The callback fires from the lazy tick, without explicit
+        // syncs
+        for _ in 0..(frame_cpu_ticks - 1) {
+            inter.tick(1);
+        }
+
+        assert_eq!(*frames.borrow(), 0);
+
+        inter.tick(1);
+        assert_eq!(*frames.borrow(), 1);
+
+        for _ in 0..frame_cpu_ticks {
+            inter.tick(1);
+        }
+
+        assert_eq!(*frames.borrow(), 2);
+
+        inter.set_frame_callback(None);
+
+        for _ in 0..frame_cpu_ticks {
+            inter.tick(1);
+        }
+
+        inter.sync();
+
+        assert_eq!(*frames.borrow(), 2);
+    }
+
+    #[test]
+    fn no_watchdog() {
+        let accesses = Rc::new(RefCell::new(Vec::new()));
+
+        let mut inter = test_util::interconnect(&[], &[]);
+
+        let log = accesses.clone();
+        let handler = move |addr, _, _| {
+            log.borrow_mut().push(addr);
+
+            0
+        };
+
+        inter.set_unknown_access_handler(Box::new(handler));
+
+        // The unhandled offsets around the IRQ controller, the timers,
+        // CLKMODE and the RTC, where a watchdog would be expected
+        let mut unknown = Vec::new();
+
+        for off in (0x04..0x40).filter(|o| o % 4 == 0) {
+            unknown.push(0x0a000010 + off);
+            unknown.push(0x0a800030 + off);
+            unknown.push(0x0b000000 + off);
+            unknown.push(0x0b80000c + off);
+        }
+
+        for &addr in &unknown {
+            inter.load::<Word>(addr);
+            inter.store::<Word>(addr, 0);
+        }
+
+        let expected: Vec<u32> =
+            unknown.iter().flat_map(|&a| vec![a, a]).collect();
+
+        assert_eq!(*accesses.borrow(), expected);
+    }
+
+    #[test]
+    fn ticks_to_next_event() {
+        let mut inter = test_util::interconnect(&[], &[]);
+
+        let timer1 = Interrupt::Timer1.mask();
+
+        // Run at full speed so that CPU and master ticks are the same
+        inter.store::<Word>(0x0b000000, 7);
+        inter.store::<Word>(0x0a000008, timer1 as u32);
+
+        // With all the timers stopped the next event comes from the
+        // RTC or the LCD
+        let idle = inter.ticks_to_next_event();
+
+        inter.tick(10);
+        assert_eq!(inter.ticks_to_next_event(), idle - 10);
+
+        // Timer 1: reload 9 with the /2 divider
+        inter.store::<Word>(0x0a800010, 9);
+        inter.store::<Word>(0x0a800018, 4);
+
+        let first = inter.ticks_to_next_event();
+
+        assert!(first < idle - 10);
+
+        inter.tick(first - 1);
+        assert_eq!(inter.irq_controller().status() & timer1, 0);
+        assert_eq!(inter.ticks_to_next_event(), 1);
+
+        inter.tick(1);
+        assert_eq!(inter.irq_controller().status() & timer1, timer1);
+
+        inter.store::<Word>(0x0a000010, timer1 as u32);
+
+        // Once reloaded the timer expires at a fixed period
+        let period = inter.ticks_to_next_event();
+
+        assert!(period <= 20);
+
+        for _ in 0..3 {
+            assert_eq!(inter.ticks_to_next_event(), period);
+
+            inter.tick(period - 1);
+            assert_eq!(inter.irq_controller().status() & timer1, 0);
+
+            inter.tick(1);
+            assert_eq!(inter.irq_controller().status() & timer1, timer1);
+
+            inter.store::<Word>(0x0a000010, timer1 as u32);
+        }
+
+        // Stopping the timer reschedules the next event
+        inter.store::<Word>(0x0a800018, 0);
+        assert!(inter.ticks_to_next_event() > period);
+    }
+
+    #[test]
+    fn open_bus_guest_loads() {
+        for &open_bus in &[0xffffffff, 0, 0x12345678] {
+            let mut cpu = test_util::arm_cpu(&[
+                // MOV R1, #0x0e000000
+                0xe3a0140e,
+                // LDR R2, [R1]
+                0xe5912000,
+                // LDRH R3, [R1]
+                0xe1d130b0,
+                // LDRB R4, [R1]
+                0xe5d14000,
+                // LDRSB R5, [R1]
+                0xe1d150d0,
+                // STR R2, [R1]
+                0xe5812000,
+            ]);
+
+            cpu.interconnect_mut().set_open_bus_value(open_bus);
+
+            test_util::run(&mut cpu, 6);
+
+            let r = cpu.registers();
+
+            assert_eq!(r[2], open_bus);
+            assert_eq!(r[3], open_bus & 0xffff);
+            assert_eq!(r[4], open_bus & 0xff);
+            assert_eq!(r[5], open_bus as u8 as i8 as u32);
+        }
+    }
+
+    /// Run one LCD frame per entry of `inputs`, setting the inputs
+    /// `delay` master ticks into the frame. Returns the final content
+    /// hash and the button levels seen by the guest during each frame.
+    fn replay_inputs(inputs: &[Inputs], delay: u32) -> (u64, Vec<u32>) {
+        let mut inter = test_util::interconnect(&[], &[]);
+
+        // Run at full speed so that CPU and master ticks are the same,
+        // unmask all the interrupts
+        inter.store::<Word>(0x0b000000, 7);
+        inter.store::<Word>(0x0a000008, 0xffff);
+
+        let mut levels = Vec::new();
+
+        for &i in inputs {
+            inter.tick(delay);
+
+            inter.set_inputs(i);
+
+            // Not visible before the end of the frame
+            inter.tick(::lcd::FRAME_TICKS - delay - 1);
+            // Buttons and dock sense
+            levels.push(inter.irq_controller().raw() as u32 & 0x81f);
+
+            inter.tick(1);
+        }
+
+        (inter.content_hash(), levels)
+    }
+
+    #[test]
+    fn inputs_latched_at_frame_end() {
+        let press = |action, up, docked| {
+            Inputs {
+                action: action,
+                up: up,
+                docked: docked,
+                ..Inputs::default()
+            }
+        };
+
+        let sequence = [
+            press(true, false, false),
+            press(true, true, false),
+            press(false, true, true),
+            press(false, false, true),
+            press(false, false, false),
+        ];
+
+        let (hash, levels) = replay_inputs(&sequence, 10);
+
+        // Each frame sees the inputs set during the previous one
+        let action = Interrupt::ActionButton.mask() as u32;
+        let up = Interrupt::UpButton.mask() as u32;
+        let docked = Interrupt::Docked.mask() as u32;
+
+        assert_eq!(levels,
+                   [0,
+                    action,
+                    action | up,
+                    up | docked,
+                    docked]);
+
+        // The same log replays identically regardless of when the
+        // frontend sets the inputs within the frame
+        for &delay in &[0, 1, 1000, ::lcd::FRAME_TICKS - 1] {
+            assert_eq!(replay_inputs(&sequence, delay),
+                       (hash, levels.clone()));
+        }
+
+        // A different log gives a different state
+        let mut other = sequence;
+        other[1].left = true;
+
+        assert!(replay_inputs(&other, 10).0 != hash);
+    }
+
+    #[test]
+    fn tick_one() {
+        // Period of the RTC signal toggle in master ticks
+        let rtc_period = MASTER_CLOCK_HZ / 2;
+        let rtc = Interrupt::Rtc.mask() as u32;
+
+        let mut inter = test_util::interconnect(&[], &[]);
+
+        // Timer 0 running at the slowest CPU clock, one CPU tick is
+        // 128 master ticks
+        inter.store::<Word>(0x0a800000, 1000);
+        inter.store::<Word>(0x0a800008, 4);
+
+        let counter = inter.load::<Word>(0x0a800004);
+        let raw = inter.load::<Word>(0x0a000004) & rtc;
+
+        for _ in 0..127 {
+            inter.tick_one();
+        }
+
+        assert_eq!(inter.load::<Word>(0x0a800004), counter);
+
+        inter.tick_one();
+
+        assert_eq!(inter.load::<Word>(0x0a800004), counter - 1);
+
+        // The RTC signal toggles exactly once per period. The first
+        // period is one tick longer because the RTC divider must
+        // underflow.
+        for _ in 128..rtc_period {
+            inter.tick_one();
+        }
+
+        assert_eq!(inter.load::<Word>(0x0a000004) & rtc, raw);
+
+        inter.tick_one();
+
+        assert_eq!(inter.load::<Word>(0x0a000004) & rtc, raw ^ rtc);
+
+        for _ in 0..(rtc_period - 1) {
+            inter.tick_one();
+        }
+
+        assert_eq!(inter.load::<Word>(0x0a000004) & rtc, raw ^ rtc);
+
+        inter.tick_one();
+
+        assert_eq!(inter.load::<Word>(0x0a000004) & rtc, raw);
+
+        // Single ticks end up in the same state as CPU ticks
+        let mut reference = test_util::interconnect(&[], &[]);
+
+        reference.store::<Word>(0x0a800000, 1000);
+        reference.store::<Word>(0x0a800008, 4);
+        reference.tick((rtc_period * 2) >> 7);
+
+        let mut single = test_util::interconnect(&[], &[]);
+
+        single.store::<Word>(0x0a800000, 1000);
+        single.store::<Word>(0x0a800008, 4);
+
+        for _ in 0..((rtc_period * 2) & !0x7f) {
+            single.tick_one();
+        }
+
+        assert_eq!(single.content_hash(), reference.content_hash());
+    }
+
+    #[test]
+    fn clock_lock() {
+        let mut inter = test_util::interconnect(&[], &[]);
+
+        // The peripherals are synced lazily, go through the CPU load
+        // path to see the current state
+        let status = |inter: &mut Interconnect| {
+            inter.sync_load::<Word>(0x0b000000)
+        };
+
+        // Locked at power on
+        assert_eq!(status(&mut inter), 0x10);
+
+        // Full speed: CPU ticks are master ticks
+        inter.store::<Word>(0x0b000000, 7);
+        assert_eq!(status(&mut inter), 0x07);
+
+        inter.tick(CLK_LOCK_TICKS - 1);
+        assert_eq!(status(&mut inter), 0x07);
+
+        inter.tick(1);
+        assert_eq!(status(&mut inter), 0x17);
+
+        // Back to the slowest clock, a single CPU tick is 128 master
+        // ticks
+        inter.store::<Word>(0x0b000000, 0);
+        assert_eq!(status(&mut inter), 0x00);
+
+        inter.tick(1);
+        assert_eq!(status(&mut inter), 0x10);
+    }
+}
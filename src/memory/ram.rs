@@ -1,36 +1,116 @@
+use std::hash::{Hash, Hasher};
+
 use rustc_serialize::{Decodable, Encodable, Decoder, Encoder};
 
-use super::Addressable;
+use super::{Addressable, unwrap_access};
 
 pub struct Ram {
     data: Box<[u8; RAM_SIZE]>,
+    /// Bitmap of the pages written to since the start of the current
+    /// frame, bit `n` is set if page `n` was modified
+    dirty: u16,
+    /// Value of `dirty` at the end of the last frame, consumed by
+    /// `take_dirty_pages`
+    frame_dirty: u16,
 }
 
 impl Ram {
     pub fn new() -> Ram {
         Ram {
             data: box_array![0xca; RAM_SIZE],
+            dirty: 0,
+            frame_dirty: 0,
+        }
+    }
+
+    /// Build a RAM initialized with `image`. If `image` is smaller
+    /// than the RAM the remaining bytes are initialized like in
+    /// `new`. Returns `None` if `image` is too big.
+    pub fn from_bytes(image: &[u8]) -> Option<Ram> {
+        if image.len() > RAM_SIZE {
+            return None;
         }
+
+        let mut ram = Ram::new();
+
+        ram.data[..image.len()].copy_from_slice(image);
+
+        Some(ram)
     }
 
     pub fn load<A: Addressable>(&self, offset: u32) -> u32 {
+        unwrap_access(self.try_load::<A>(offset))
+    }
+
+    /// Same as `load` but out-of-range accesses return an error
+    /// instead of panicking
+    pub fn try_load<A: Addressable>(&self,
+                                    offset: u32) -> Result<u32, String> {
         let offset = offset as usize;
 
+        if offset >= RAM_SIZE {
+            return Err(format!("RAM load out of range 0x{:x}", offset));
+        }
+
         let mut r = 0;
 
         for i in 0..A::size() as usize {
             r |= (self.data[offset + i] as u32) << (8 * i)
         }
 
-        r
+        Ok(r)
     }
 
     pub fn store<A: Addressable>(&mut self, offset: u32, val: u32) {
+        unwrap_access(self.try_store::<A>(offset, val))
+    }
+
+    /// Same as `store` but out-of-range accesses return an error
+    /// instead of panicking
+    pub fn try_store<A: Addressable>(&mut self,
+                                     offset: u32,
+                                     val: u32) -> Result<(), String> {
         let offset = offset as usize;
 
+        if offset >= RAM_SIZE {
+            return Err(format!("RAM store out of range 0x{:x}", offset));
+        }
+
         for i in 0..A::size() as usize {
             self.data[offset + i] = (val >> (i * 8)) as u8;
         }
+
+        // Accesses are aligned so they never straddle two pages
+        self.dirty |= 1 << (offset / DIRTY_PAGE_SIZE);
+
+        Ok(())
+    }
+
+    /// Called by the interconnect at the end of every LCD frame
+    pub fn end_frame(&mut self) {
+        self.frame_dirty = self.dirty;
+        self.dirty = 0;
+    }
+
+    /// Return the offsets of the `DIRTY_PAGE_SIZE`-byte pages written
+    /// to during the last complete frame. The pages are only
+    /// reported once.
+    pub fn take_dirty_pages(&mut self) -> Vec<u32> {
+        let dirty = self.frame_dirty;
+
+        self.frame_dirty = 0;
+
+        (0..RAM_PAGES)
+            .filter(|&p| (dirty & (1 << p)) != 0)
+            .map(|p| (p * DIRTY_PAGE_SIZE) as u32)
+            .collect()
+    }
+}
+
+impl Hash for Ram {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        // The dirty page tracking is not part of the machine state
+        self.data.hash(state);
     }
 }
 
@@ -66,4 +146,114 @@ impl Decodable for Ram {
 }
 
 /// RAM size in bytes
-const RAM_SIZE: usize = 2 * 1024;
+pub const RAM_SIZE: usize = 2 * 1024;
+
+/// Granularity of the dirty page tracking in bytes. We need the page
+/// count to fit in the `u16` bitmaps.
+pub const DIRTY_PAGE_SIZE: usize = 128;
+
+/// Number of `DIRTY_PAGE_SIZE`-byte pages in the RAM
+pub const RAM_PAGES: usize = RAM_SIZE / DIRTY_PAGE_SIZE;
+
+#[cfg(test)]
+mod tests {
+    use cpu::Cpu;
+    use dac::Dac;
+    use memory::{Interconnect, Word, HalfWord, Byte};
+    use memory::bios::Bios;
+    use memory::flash::Flash;
+    use test_util;
+
+    use super::*;
+
+    #[test]
+    fn from_bytes() {
+        assert!(Ram::from_bytes(&[0; RAM_SIZE + 1]).is_none());
+
+        let ram = Ram::from_bytes(&[0; RAM_SIZE]).unwrap();
+        assert_eq!(ram.load::<Word>(RAM_SIZE as u32 - 4), 0);
+
+        // LDR R1, [R0]
+        // LDRB R2, [R0, #5]
+        // LDRH R3, [R0, #6]
+        let mut image = test_util::words_to_bytes(&[0xe5901000,
+                                                    0xe5d02005,
+                                                    0xe1d030b6]);
+
+        image.resize(0x100, 0);
+        image.extend_from_slice(&[0x78, 0x56, 0x34, 0x12,
+                                  0xaa, 0xbb, 0xcc, 0xdd]);
+
+        let dac = Dac::new(Box::new(test_util::NullBackend));
+
+        let inter = Interconnect::with_ram(Bios::empty(),
+                                           Flash::with_contents(&[]),
+                                           dac,
+                                           Ram::from_bytes(&image).unwrap());
+
+        let mut cpu = Cpu::new(inter);
+
+        // F_CTRL: unmap the BIOS from address 0
+        cpu.interconnect_mut().store::<Word>(0x06000000, 3);
+        cpu.set_pc(0);
+        cpu.set_register(0, 0x100);
+
+        test_util::run(&mut cpu, 3);
+
+        assert_eq!(cpu.registers()[1], 0x12345678);
+        assert_eq!(cpu.registers()[2], 0xbb);
+        assert_eq!(cpu.registers()[3], 0xddcc);
+
+        // The rest of the RAM is initialized like `Ram::new`
+        let end = cpu.interconnect_mut().load::<Word>(0x108);
+        assert_eq!(end, 0xcacacaca);
+    }
+
+    #[test]
+    fn dirty_pages() {
+        let mut ram = Ram::new();
+
+        ram.store::<Word>(0x10, 0x12345678);
+        ram.store::<HalfWord>(0x3fe, 0xabcd);
+        ram.store::<Byte>(0x14, 0xff);
+
+        // Nothing is reported until the end of the frame
+        assert_eq!(ram.take_dirty_pages(), []);
+
+        ram.end_frame();
+
+        assert_eq!(ram.take_dirty_pages(), [0, 0x380]);
+        assert_eq!(ram.take_dirty_pages(), []);
+
+        // Loads don't dirty the pages
+        ram.load::<Word>(0x7fc);
+        ram.end_frame();
+
+        assert_eq!(ram.take_dirty_pages(), []);
+    }
+
+    #[test]
+    fn dirty_pages_frame_boundary() {
+        use lcd::FRAME_TICKS;
+
+        let mut inter = test_util::interconnect(&[], &[]);
+
+        // CLKMODE: full speed
+        inter.store::<Word>(0x0b000000, 7);
+        // F_CTRL: unmap the BIOS from address 0
+        inter.store::<Word>(0x06000000, 3);
+
+        inter.store::<Word>(0x100, 0x12345678);
+        inter.store::<Byte>(0x7ff, 0x42);
+
+        inter.tick(FRAME_TICKS - 1);
+        assert_eq!(inter.ram_mut().take_dirty_pages(), []);
+
+        inter.tick(1);
+        assert_eq!(inter.ram_mut().take_dirty_pages(), [0x100, 0x780]);
+
+        // The next frame starts clean
+        inter.tick(FRAME_TICKS);
+        assert_eq!(inter.ram_mut().take_dirty_pages(), []);
+    }
+}
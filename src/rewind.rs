@@ -0,0 +1,207 @@
+//! Rewind support through periodic savestate snapshots
+
+use std::collections::VecDeque;
+
+use cpu::Cpu;
+use savestate::{save_state_binary, load_state_binary};
+
+/// Ring buffer of savestate snapshots taken at regular intervals.
+///
+/// Since the flash contents are not part of savestates they're not
+/// rewound: anything the game saved to the memory card after the
+/// snapshot was taken remains.
+pub struct RewindBuffer {
+    /// Binary snapshots, oldest first
+    snapshots: VecDeque<Vec<u8>>,
+    /// Maximum number of snapshots kept in the buffer
+    capacity: usize,
+    /// Number of frames between two snapshots
+    interval: u32,
+    /// Number of frames elapsed since the last snapshot
+    frames: u32,
+}
+
+impl RewindBuffer {
+    /// Create a buffer holding up to `capacity` snapshots, taken
+    /// every `interval` frames.
+    pub fn new(capacity: usize, interval: u32) -> RewindBuffer {
+        if capacity == 0 || interval == 0 {
+            panic!("Invalid rewind buffer configuration");
+        }
+
+        RewindBuffer {
+            snapshots: VecDeque::with_capacity(capacity),
+            capacity: capacity,
+            interval: interval,
+            frames: 0,
+        }
+    }
+
+    /// Must be called by the frontend once per frame, takes a new
+    /// snapshot of `cpu` every `interval` frames. The oldest snapshot
+    /// is dropped when the buffer is full.
+    pub fn frame(&mut self, cpu: &Cpu) {
+        self.frames += 1;
+
+        if self.frames < self.interval {
+            return;
+        }
+
+        self.frames = 0;
+
+        let snapshot = save_state_binary(cpu);
+
+        if self.snapshots.len() == self.capacity {
+            self.snapshots.pop_front();
+        }
+
+        self.snapshots.push_back(snapshot);
+    }
+
+    /// Restore the most recent snapshot at least `frames_back` frames
+    /// old. The snapshots more recent than the one restored are
+    /// discarded. Returns `false` and leaves `cpu` untouched if the
+    /// buffer doesn't go back that far.
+    pub fn rewind(&mut self, cpu: &mut Cpu, frames_back: u32) -> bool {
+        // Number of snapshots to skip, starting from the most recent
+        // one
+        let skip =
+            if frames_back > self.frames {
+                let frames_back = frames_back - self.frames;
+
+                ((frames_back + self.interval - 1) / self.interval) as usize
+            } else {
+                0
+            };
+
+        if skip >= self.snapshots.len() {
+            return false;
+        }
+
+        let keep = self.snapshots.len() - skip;
+
+        self.snapshots.truncate(keep);
+
+        if let Err(e) = load_state_binary(cpu, &self.snapshots[keep - 1]) {
+            panic!("Couldn't decode rewind snapshot: {}", e);
+        }
+
+        self.frames = 0;
+
+        true
+    }
+
+    /// Number of snapshots currently held in the buffer
+    pub fn len(&self) -> usize {
+        self.snapshots.len()
+    }
+
+    /// Drop all the snapshots, should be called when a savestate is
+    /// loaded or the console is reset.
+    pub fn clear(&mut self) {
+        self.snapshots.clear();
+        self.frames = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cpu::Cpu;
+    use savestate::save_state_binary;
+    use test_util;
+
+    use super::*;
+
+    /// Number of instructions executed per emulated frame
+    const FRAME_INSTRUCTIONS: u32 = 10;
+
+    fn counter_cpu() -> Cpu {
+        test_util::arm_cpu(&[
+            // ADD R0, R0, #1
+            0xe2800001,
+            // B 0
+            0xeafffffd,
+        ])
+    }
+
+    /// Run one frame and return the state at the end of it
+    fn run_frame(cpu: &mut Cpu, buffer: &mut RewindBuffer) -> Vec<u8> {
+        test_util::run(cpu, FRAME_INSTRUCTIONS);
+
+        buffer.frame(cpu);
+
+        save_state_binary(cpu)
+    }
+
+    #[test]
+    fn snapshots_are_evicted() {
+        let mut cpu = counter_cpu();
+        let mut buffer = RewindBuffer::new(3, 2);
+
+        assert_eq!(buffer.len(), 0);
+
+        // Snapshots at frames 2, 4, 6 and 8, the first one is evicted
+        let mut states = vec![save_state_binary(&cpu)];
+
+        for frame in 1..9 {
+            states.push(run_frame(&mut cpu, &mut buffer));
+
+            assert_eq!(buffer.len(), ::std::cmp::min(frame / 2, 3));
+        }
+
+        let current = save_state_binary(&cpu);
+
+        // Going back to frame 2 would require the evicted snapshot
+        assert!(!buffer.rewind(&mut cpu, 6));
+        assert_eq!(save_state_binary(&cpu), current);
+        assert_eq!(buffer.len(), 3);
+
+        // Frame 4 is still there
+        assert!(buffer.rewind(&mut cpu, 4));
+        assert_eq!(save_state_binary(&cpu), states[4]);
+        assert_eq!(buffer.len(), 1);
+    }
+
+    #[test]
+    fn rewind_restores_snapshot() {
+        let mut cpu = counter_cpu();
+        let mut buffer = RewindBuffer::new(4, 2);
+
+        let mut states = vec![save_state_binary(&cpu)];
+
+        for _ in 0..7 {
+            states.push(run_frame(&mut cpu, &mut buffer));
+        }
+
+        // Snapshots at frames 2, 4 and 6, we're one frame past the
+        // last one
+        assert_eq!(buffer.len(), 3);
+
+        let counter = cpu.registers()[0];
+
+        assert!(buffer.rewind(&mut cpu, 1));
+        assert_eq!(save_state_binary(&cpu), states[6]);
+        assert!(cpu.registers()[0] < counter);
+        // The restored snapshot is kept, only the newer ones go
+        assert_eq!(buffer.len(), 3);
+
+        // Rewinding 3 frames from frame 6 lands on frame 2
+        assert!(buffer.rewind(&mut cpu, 3));
+        assert_eq!(save_state_binary(&cpu), states[2]);
+        assert_eq!(buffer.len(), 1);
+
+        // The emulation resumes from the restored state and new
+        // snapshots are pushed after it
+        for _ in 0..2 {
+            run_frame(&mut cpu, &mut buffer);
+        }
+
+        assert_eq!(save_state_binary(&cpu), states[4]);
+        assert_eq!(buffer.len(), 2);
+
+        buffer.clear();
+
+        assert_eq!(buffer.len(), 0);
+        assert!(!buffer.rewind(&mut cpu, 0));
+    }
+}
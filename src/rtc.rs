@@ -1,13 +1,15 @@
 use std::fmt;
 
 use interrupt::{IrqController, Interrupt};
-use memory::Addressable;
+use memory::{Addressable, unwrap_access};
 
 use MASTER_CLOCK_HZ;
 
 #[derive(RustcDecodable, RustcEncodable)]
+#[derive(Hash)]
 pub struct Rtc {
-    /// True if the RTC is paused
+    /// True if the RTC is paused. Only the time and date are frozen,
+    /// the 2Hz interrupt keeps toggling.
     paused: bool,
     /// Master clock divider counter to get a 2Hz signal
     divider: u32,
@@ -33,6 +35,8 @@ pub struct Rtc {
     /// any odd number which causes a deadlock. Need to investigate
     /// how the real hardware behaves in this situation.
     skip: bool,
+    /// Set when the date changes, cleared by `take_day_rollover`
+    day_rollover: bool,
 }
 
 impl Rtc {
@@ -49,9 +53,20 @@ impl Rtc {
             year: Bcd::from_bcd(0x99).unwrap(),
             adjust: 0,
             skip: false,
+            day_rollover: false,
         }
     }
 
+    /// Reset the RTC control state. The current date and time are
+    /// preserved since the clock keeps running on the real hardware.
+    pub fn reset(&mut self) {
+        self.paused = false;
+        self.divider = MASTER_DIVIDER;
+        self.adjust = 0;
+        self.skip = false;
+        self.day_rollover = false;
+    }
+
     pub fn tick(&mut self,
                 irq: &mut IrqController,
                 mut master_ticks: u32) {
@@ -66,11 +81,20 @@ impl Rtc {
 
                 self.divider = MASTER_DIVIDER;
 
-                // We exhausted the divider, toggle the RTC signal
+                // We exhausted the divider, toggle the RTC
+                // signal. The guest only gets an interrupt on the
+                // rising edge, so once per second.
                 let level = !irq.raw_interrupt(Interrupt::Rtc);
 
                 if level == true {
-                    // XXX Not sure how the paused bit is handled
+                    // The paused bit only stops the time from
+                    // advancing, the 2Hz signal keeps toggling. The
+                    // BIOS pauses the clock while the user is
+                    // setting it and still needs a periodic interrupt
+                    // to drive the UI.
+                    //
+                    // XXX This needs to be confirmed on the real
+                    // hardware.
                     if !self.paused {
                         self.second_elapsed();
                         debug!("RTC: {:?}", self);
@@ -82,74 +106,148 @@ impl Rtc {
         }
     }
 
+    /// Return the number of master clock ticks until the next RTC
+    /// signal toggle
+    pub fn next_event(&self) -> u32 {
+        self.divider + 1
+    }
+
     pub fn store<A: Addressable>(&mut self, offset: u32, val: u32) {
+        unwrap_access(self.try_store::<A>(offset, val))
+    }
+
+    /// Same as `store` but unhandled accesses return an error
+    /// instead of panicking
+    pub fn try_store<A: Addressable>(&mut self,
+                                     offset: u32,
+                                     val: u32) -> Result<(), String> {
         match offset {
             0 => self.set_mode(val),
-            4 => self.set_adjust(val),
-            _ => panic!("Unhandled RTC register {:x}", offset),
+            4 => try!(self.set_adjust(val)),
+            _ => return Err(format!("Unhandled RTC register {:x}", offset)),
         }
+
+        Ok(())
     }
 
     pub fn load<A: Addressable>(&self, offset: u32) -> u32 {
-        match offset {
-            0x8 => self.time(),
-            0xc => self.date(),
-            _ => panic!("Unhandled RTC register {:x}", offset),
-        }
+        unwrap_access(self.try_load::<A>(offset))
+    }
+
+    /// Same as `load` but unhandled accesses return an error instead
+    /// of panicking
+    pub fn try_load<A: Addressable>(&self,
+                                    offset: u32) -> Result<u32, String> {
+        let r =
+            match offset {
+                0x8 => self.time(),
+                0xc => self.date(),
+                _ => return Err(format!("Unhandled RTC register {:x}",
+                                        offset)),
+            };
+
+        Ok(r)
     }
 
     pub fn set_seconds(&mut self, bcd: Bcd) {
-        let v = bcd.bcd();
+        self.try_set_seconds(bcd).unwrap()
+    }
 
-        assert!(v <= 0x59);
+    /// Set the seconds if `bcd` is in the range [00...59]
+    pub fn try_set_seconds(&mut self, bcd: Bcd) -> Result<(), RtcError> {
+        self.seconds = try!(check_range(bcd, 0x00, 0x59));
 
-        self.seconds = bcd;
+        Ok(())
     }
 
     pub fn set_minutes(&mut self, bcd: Bcd) {
-        let v = bcd.bcd();
+        self.try_set_minutes(bcd).unwrap()
+    }
 
-        assert!(v <= 0x59);
+    /// Set the minutes if `bcd` is in the range [00...59]
+    pub fn try_set_minutes(&mut self, bcd: Bcd) -> Result<(), RtcError> {
+        self.minutes = try!(check_range(bcd, 0x00, 0x59));
 
-        self.minutes = bcd;
+        Ok(())
     }
 
     pub fn set_hours(&mut self, bcd: Bcd) {
-        let v = bcd.bcd();
+        self.try_set_hours(bcd).unwrap()
+    }
 
-        assert!(v <= 0x23);
+    /// Set the hours if `bcd` is in the range [00...23]
+    pub fn try_set_hours(&mut self, bcd: Bcd) -> Result<(), RtcError> {
+        self.hours = try!(check_range(bcd, 0x00, 0x23));
 
-        self.hours = bcd;
+        Ok(())
     }
 
     pub fn set_week_day(&mut self, bcd: Bcd) {
-        let v = bcd.bcd();
+        self.try_set_week_day(bcd).unwrap()
+    }
 
-        assert!(v >= 0x01 && v <= 0x07);
+    /// Set the week day if `bcd` is in the range [01...07]
+    pub fn try_set_week_day(&mut self, bcd: Bcd) -> Result<(), RtcError> {
+        self.week_day = try!(check_range(bcd, 0x01, 0x07));
 
-        self.week_day = bcd;
+        Ok(())
     }
 
     pub fn set_day(&mut self, bcd: Bcd) {
-        let v = bcd.bcd();
+        self.try_set_day(bcd).unwrap()
+    }
 
-        assert!(v >= 0x01 && v <= 0x31);
+    /// Set the day if `bcd` is in the range [01...31]
+    pub fn try_set_day(&mut self, bcd: Bcd) -> Result<(), RtcError> {
+        self.day = try!(check_range(bcd, 0x01, 0x31));
 
-        self.day = bcd;
+        Ok(())
     }
 
     pub fn set_month(&mut self, bcd: Bcd) {
-        let v = bcd.bcd();
+        self.try_set_month(bcd).unwrap()
+    }
 
-        assert!(v >= 0x01 && v <= 0x12);
+    /// Set the month if `bcd` is in the range [01...12]
+    pub fn try_set_month(&mut self, bcd: Bcd) -> Result<(), RtcError> {
+        self.month = try!(check_range(bcd, 0x01, 0x12));
 
-        self.month = bcd;
+        Ok(())
     }
 
     pub fn set_year(&mut self, bcd: Bcd) {
         self.year = bcd;
     }
 
+    /// Advance the clock by exactly one second, bypassing the master
+    /// clock divider and the pause bit. Useful to exercise the date
+    /// rollover logic without having to simulate `MASTER_CLOCK_HZ`
+    /// ticks for every second.
+    pub fn force_second_elapsed(&mut self) {
+        self.second_elapsed();
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    /// Pause or resume the clock. See the `paused` field for the
+    /// exact semantics.
+    pub fn set_paused(&mut self, paused: bool) {
+        self.paused = paused
+    }
+
+    /// Return `true` if the date changed since the last call to this
+    /// function. Lets the frontend react to the emulated midnight
+    /// without having to poll the date.
+    pub fn take_day_rollover(&mut self) -> bool {
+        let r = self.day_rollover;
+
+        self.day_rollover = false;
+
+        r
+    }
+
     fn time(&self) -> u32 {
         let seconds = self.seconds.bcd() as u32;
         let minutes = self.minutes.bcd() as u32;
@@ -164,21 +262,42 @@ impl Rtc {
         let month = self.month.bcd() as u32;
         let year = self.year.bcd() as u32;
 
-        // XXX What is the high byte exactly?
-        day | (month << 8) | (year << 16)
+        day | (month << 8) | (year << 16) | (DATE_HIGH_BYTE << 24)
     }
 
+    /// Mode register: bit 0 pauses the clock and bits [3:1] select
+    /// the counter modified by the adjust register. The RTC has no
+    /// alarm, the only interrupt source is the 1Hz signal. Guests
+    /// implement alarms in software by comparing the time in the RTC
+    /// interrupt handler. Alarm registers are deliberately not
+    /// emulated since nothing suggests they exist: writes to the
+    /// unknown mode bits are only logged so that we notice if some
+    /// code ever uses them.
     fn set_mode(&mut self, val: u32) {
+        if val & !0xf != 0 {
+            warn!("RTC mode: unknown bits set: 0x{:x}", val);
+        }
+
         self.paused = (val & 1) != 0;
 
         self.adjust = ((val >> 1) & 7) as u8;
     }
 
-    fn set_adjust(&mut self, _val: u32) {
+    fn set_adjust(&mut self, val: u32) -> Result<(), String> {
+        // Log the writes so that the sequence used by the BIOS can be
+        // captured in a trace, we'll need it to figure out how this
+        // register really works and get rid of the `skip` hack.
+        //
+        // XXX No such capture is available yet so there's no
+        // regression test replaying the real BIOS sequence, the
+        // `adjust_converges` test pins the current behaviour instead.
+        debug!("RTC adjust 0x{:x} (adjust: {}, skip: {})",
+               val, self.adjust, self.skip);
+
         // XXX ugly hack, fix me.
         if self.skip {
             self.skip = false;
-            return;
+            return Ok(());
         }
 
         // I don't understand how that register works, I just reset it
@@ -193,7 +312,8 @@ impl Rtc {
                 4 => (&mut self.day, 0x01, 0x31),
                 5 => (&mut self.month, 0x01, 0x31),
                 6 => (&mut self.year, 0x00, 0x99),
-                _ => panic!("Unsupported adjust {:x}", self.adjust),
+                _ => return Err(format!("Unsupported adjust {:x}",
+                                        self.adjust)),
             };
 
         *counter =
@@ -204,6 +324,8 @@ impl Rtc {
             };
 
         self.skip = true;
+
+        Ok(())
     }
 
     fn second_elapsed(&mut self) {
@@ -242,6 +364,8 @@ impl Rtc {
             }
         };
 
+        self.day_rollover = true;
+
         inc_overflow(&mut self.week_day, 0x07, 0x01);
 
         let days_in_month =
@@ -283,10 +407,27 @@ impl fmt::Debug for Rtc {
 }
 
 
+/// Error returned by the `try_set_*` methods of the RTC
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum RtcError {
+    /// The value is outside of the range of the field
+    OutOfRange,
+}
+
+fn check_range(bcd: Bcd, min: u8, max: u8) -> Result<Bcd, RtcError> {
+    let v = bcd.bcd();
+
+    if v >= min && v <= max {
+        Ok(bcd)
+    } else {
+        Err(RtcError::OutOfRange)
+    }
+}
+
 /// A single packed BCD value in the range 0-99 (2 digits, 4bits per
 /// digit).
 #[derive(RustcDecodable, RustcEncodable)]
-#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct Bcd(u8);
 
 impl Bcd {
@@ -345,4 +486,419 @@ impl fmt::Display for Bcd {
     }
 }
 
+/// The RTC signal toggles every time this divider expires, that is
+/// at 2Hz. Since the IRQ controller only latches on the rising edge
+/// the guest sees a 1Hz square wave and gets one interrupt per second,
+/// coinciding with the seconds counter increment.
 const MASTER_DIVIDER: u32 = MASTER_CLOCK_HZ / 2;
+
+/// Number of RTC interrupts received by the guest every second. XXX
+/// No$ describes the RTC interrupt as 1Hz but that hasn't been
+/// confirmed on the real hardware.
+pub const INTERRUPT_HZ: u32 = 1;
+
+/// Value of the top byte of the DATE register. There's no century
+/// counter in the RTC (the year wraps from 99 to 00) and the leap
+/// years can be computed from the year alone, so I assume it always
+/// reads as zero like the other unused register bits.
+///
+/// XXX This needs to be checked on the real hardware.
+const DATE_HIGH_BYTE: u32 = 0;
+
+#[cfg(test)]
+mod tests {
+    use std::cmp;
+
+    use log::Level;
+
+    use interrupt::IrqController;
+    use memory::Word;
+    use test_util;
+
+    use super::*;
+
+    fn bcd(b: u8) -> Bcd {
+        Bcd::from_bcd(b).unwrap()
+    }
+
+    /// Set the clock to `year.month.day hours:minutes:seconds`, all in
+    /// BCD
+    fn set_clock(rtc: &mut Rtc, date: (u8, u8, u8), time: (u8, u8, u8)) {
+        rtc.set_year(bcd(date.0));
+        rtc.set_month(bcd(date.1));
+        rtc.set_day(bcd(date.2));
+        rtc.set_hours(bcd(time.0));
+        rtc.set_minutes(bcd(time.1));
+        rtc.set_seconds(bcd(time.2));
+    }
+
+    #[test]
+    fn minute_rollover() {
+        let mut rtc = Rtc::new();
+
+        set_clock(&mut rtc, (0x17, 0x06, 0x15), (0x12, 0x34, 0x59));
+
+        rtc.force_second_elapsed();
+
+        assert_eq!(rtc.time() & 0xffffff, 0x123500);
+        assert_eq!(rtc.date() & 0xffffff, 0x170615);
+        assert!(!rtc.take_day_rollover());
+    }
+
+    #[test]
+    fn month_rollover() {
+        let mut rtc = Rtc::new();
+
+        set_clock(&mut rtc, (0x17, 0x04, 0x30), (0x23, 0x59, 0x59));
+        rtc.set_week_day(bcd(0x07));
+
+        rtc.force_second_elapsed();
+
+        // Saturday to Sunday
+        assert_eq!(rtc.time(), 0x01000000);
+        assert_eq!(rtc.date() & 0xffffff, 0x170501);
+        assert!(rtc.take_day_rollover());
+        assert!(!rtc.take_day_rollover());
+    }
+
+    #[test]
+    fn february_rollover() {
+        let mut rtc = Rtc::new();
+
+        // 2016 is a leap year but the RTC doesn't know about them
+        set_clock(&mut rtc, (0x16, 0x02, 0x28), (0x23, 0x59, 0x59));
+
+        rtc.force_second_elapsed();
+
+        assert_eq!(rtc.date() & 0xffffff, 0x160301);
+    }
+
+    #[test]
+    fn year_rollover() {
+        let mut rtc = Rtc::new();
+
+        set_clock(&mut rtc, (0x17, 0x12, 0x31), (0x23, 0x59, 0x59));
+
+        rtc.force_second_elapsed();
+
+        assert_eq!(rtc.time() & 0xffffff, 0);
+        assert_eq!(rtc.date() & 0xffffff, 0x180101);
+
+        // The year wraps around after 99
+        set_clock(&mut rtc, (0x99, 0x12, 0x31), (0x23, 0x59, 0x59));
+
+        rtc.force_second_elapsed();
+
+        assert_eq!(rtc.date() & 0xffffff, 0x000101);
+    }
+
+    #[test]
+    fn full_year() {
+        let mut rtc = Rtc::new();
+
+        set_clock(&mut rtc, (0x17, 0x01, 0x01), (0x23, 0x59, 0x59));
+
+        // Walk through every day of the year, one call per day
+        let mut days = 0;
+
+        loop {
+            rtc.force_second_elapsed();
+            days += 1;
+
+            assert!(rtc.take_day_rollover());
+
+            if rtc.date() & 0xffffff == 0x180101 {
+                break;
+            }
+
+            rtc.set_hours(bcd(0x23));
+            rtc.set_minutes(bcd(0x59));
+            rtc.set_seconds(bcd(0x59));
+        }
+
+        assert_eq!(days, 365);
+    }
+
+    #[test]
+    fn paused_still_forced() {
+        let mut rtc = Rtc::new();
+
+        rtc.set_paused(true);
+
+        rtc.force_second_elapsed();
+
+        assert_eq!(rtc.time() & 0xff, 0x01);
+    }
+
+    #[test]
+    fn reset_restores_control_state() {
+        let mut rtc = Rtc::new();
+        let mut irq = IrqController::new();
+
+        // Pause and select the hours for adjustment
+        rtc.store::<Word>(0, 1 | (2 << 1));
+        rtc.store::<Word>(4, 0);
+        rtc.tick(&mut irq, 1000);
+        rtc.take_day_rollover();
+
+        assert!(rtc.paused);
+        assert!(rtc.skip);
+        assert_eq!(rtc.adjust, 2);
+        assert_eq!(rtc.divider, MASTER_DIVIDER - 1000);
+
+        let time = rtc.time();
+
+        rtc.reset();
+
+        assert!(!rtc.paused);
+        assert!(!rtc.skip);
+        assert!(!rtc.day_rollover);
+        assert_eq!(rtc.adjust, 0);
+        assert_eq!(rtc.divider, MASTER_DIVIDER);
+
+        // The clock keeps running across resets
+        assert_eq!(rtc.time(), time);
+        assert_eq!(rtc.time() & 0xff0000, 0x010000);
+    }
+
+    #[test]
+    fn date_high_byte() {
+        let mut rtc = Rtc::new();
+
+        set_clock(&mut rtc, (0x17, 0x06, 0x15), (0x12, 0x34, 0x56));
+
+        let date = rtc.load::<Word>(0xc);
+
+        assert_eq!(date >> 24, DATE_HIGH_BYTE);
+        assert_eq!(date, 0x00170615);
+    }
+
+    #[test]
+    fn day_rollover_once() {
+        let mut rtc = Rtc::new();
+        let mut irq = IrqController::new();
+
+        set_clock(&mut rtc, (0x17, 0x06, 0x15), (0x23, 0x59, 0x55));
+
+        assert!(!rtc.take_day_rollover());
+
+        let mut rollovers = 0;
+
+        // Run the clock for 10 seconds, half a second at a time
+        for _ in 0..20 {
+            rtc.tick(&mut irq, MASTER_DIVIDER + 1);
+
+            if rtc.take_day_rollover() {
+                rollovers += 1;
+
+                assert_eq!(rtc.time() & 0xffffff, 0x000000);
+                assert_eq!(rtc.date() & 0xffffff, 0x170616);
+            }
+        }
+
+        assert_eq!(rollovers, 1);
+        assert_eq!(rtc.time() & 0xffffff, 0x000005);
+
+        // A paused clock only rolls over when forced
+        rtc.set_paused(true);
+        set_clock(&mut rtc, (0x17, 0x06, 0x16), (0x23, 0x59, 0x59));
+
+        for _ in 0..4 {
+            rtc.tick(&mut irq, MASTER_DIVIDER + 1);
+        }
+
+        assert!(!rtc.take_day_rollover());
+
+        rtc.force_second_elapsed();
+
+        assert!(rtc.take_day_rollover());
+        assert!(!rtc.take_day_rollover());
+    }
+
+    #[test]
+    fn paused_keeps_interrupt() {
+        let mut rtc = Rtc::new();
+        let mut irq = IrqController::new();
+
+        irq.store::<Word>(8, Interrupt::Rtc.mask() as u32);
+
+        set_clock(&mut rtc, (0x17, 0x06, 0x15), (0x12, 0x00, 0x00));
+
+        rtc.set_paused(true);
+        assert!(rtc.is_paused());
+
+        let mut edges = 0;
+
+        // Run for 5 seconds
+        for _ in 0..10 {
+            rtc.tick(&mut irq, MASTER_DIVIDER + 1);
+
+            let status = irq.status();
+
+            if status & Interrupt::Rtc.mask() != 0 {
+                edges += 1;
+                irq.ack(status);
+            }
+        }
+
+        // The time is frozen but the interrupt keeps firing once per
+        // second
+        assert_eq!(edges, 5);
+        assert_eq!(rtc.time() & 0xffffff, 0x120000);
+
+        // The guest sees the same state through the MODE register
+        rtc.set_paused(false);
+        assert!(!rtc.is_paused());
+        rtc.store::<Word>(0, 1);
+        assert!(rtc.is_paused());
+        rtc.store::<Word>(0, 0);
+
+        for _ in 0..10 {
+            rtc.tick(&mut irq, MASTER_DIVIDER + 1);
+        }
+
+        assert_eq!(rtc.time() & 0xffffff, 0x120005);
+    }
+
+    #[test]
+    fn interrupts_per_second() {
+        let mut rtc = Rtc::new();
+        let mut irq = IrqController::new();
+
+        irq.store::<Word>(8, Interrupt::Rtc.mask() as u32);
+
+        set_clock(&mut rtc, (0x17, 0x06, 0x15), (0x12, 0x00, 0x00));
+
+        let seconds = 10;
+        // Small steps, not a multiple of the divider
+        let step = 4099;
+
+        let total = seconds * MASTER_CLOCK_HZ;
+
+        let mut interrupts = 0;
+        let mut elapsed = 0;
+
+        while elapsed < total {
+            let ticks = cmp::min(step, total - elapsed);
+
+            rtc.tick(&mut irq, ticks);
+            elapsed += ticks;
+
+            let status = irq.status();
+
+            if status & Interrupt::Rtc.mask() != 0 {
+                interrupts += 1;
+                irq.ack(status);
+            }
+        }
+
+        assert_eq!(interrupts, seconds * INTERRUPT_HZ);
+
+        // One interrupt per seconds counter increment
+        assert_eq!(rtc.time() & 0xffffff, 0x120010);
+    }
+
+    #[test]
+    fn from_bcd_exhaustive() {
+        let mut valid = 0;
+
+        for b in 0..0x100u32 {
+            let b = b as u8;
+
+            let tens = b >> 4;
+            let units = b & 0xf;
+
+            match Bcd::from_bcd(b) {
+                Some(bcd) => {
+                    assert!(tens <= 9 && units <= 9, "0x{:02x} accepted", b);
+                    assert_eq!(bcd.bcd(), b);
+                    valid += 1;
+                }
+                None => assert!(tens > 9 || units > 9, "0x{:02x} rejected", b),
+            }
+        }
+
+        assert_eq!(valid, 100);
+    }
+
+    #[test]
+    fn adjust_converges() {
+        // Like the BIOS, adjust the seconds two writes at a time until
+        // they reach the target. Without the `skip` hack the odd
+        // values would never be reached.
+        for target in 0..60 {
+            let target = Bcd::from_binary(target).unwrap();
+
+            let mut rtc = Rtc::new();
+
+            rtc.store::<Word>(0, 1);
+
+            let mut writes = 0;
+
+            while rtc.time() & 0xff != target.bcd() as u32 {
+                assert!(writes < 200, "Deadlock adjusting to {}", target);
+
+                rtc.store::<Word>(4, 1);
+                rtc.store::<Word>(4, 1);
+                writes += 2;
+            }
+        }
+    }
+
+    #[test]
+    fn unknown_mode_bits() {
+        let mut rtc = Rtc::new();
+
+        let logs = test_util::capture_logs(|| rtc.store::<Word>(0, 0x7));
+        assert!(logs.iter().all(|&(level, _)| level != Level::Warn));
+
+        assert!(rtc.paused);
+        assert_eq!(rtc.adjust, 3);
+
+        // There's no alarm, the unknown bits are ignored with a
+        // warning
+        let logs = test_util::capture_logs(|| rtc.store::<Word>(0, 0x12));
+
+        assert_eq!(logs,
+                   [(Level::Warn,
+                     "RTC mode: unknown bits set: 0x12".to_string())]);
+
+        assert!(!rtc.paused);
+        assert_eq!(rtc.adjust, 1);
+    }
+
+    #[test]
+    fn try_set_out_of_range() {
+        type Setter = fn(&mut Rtc, Bcd) -> Result<(), RtcError>;
+        type Getter = fn(&Rtc) -> Bcd;
+
+        // (setter, getter, min, max)
+        let fields: &[(Setter, Getter, u8, u8)] = &[
+            (Rtc::try_set_seconds, |r| r.seconds, 0x00, 0x59),
+            (Rtc::try_set_minutes, |r| r.minutes, 0x00, 0x59),
+            (Rtc::try_set_hours, |r| r.hours, 0x00, 0x23),
+            (Rtc::try_set_week_day, |r| r.week_day, 0x01, 0x07),
+            (Rtc::try_set_day, |r| r.day, 0x01, 0x31),
+            (Rtc::try_set_month, |r| r.month, 0x01, 0x12),
+        ];
+
+        for &(set, get, min, max) in fields {
+            let mut rtc = Rtc::new();
+
+            for b in (0..0x9a).filter_map(Bcd::from_bcd) {
+                let prev = get(&rtc);
+                let v = b.bcd();
+
+                if v >= min && v <= max {
+                    assert_eq!(set(&mut rtc, b), Ok(()));
+                    assert!(get(&rtc) == b);
+                } else {
+                    // Rejected without panicking, the field is left
+                    // untouched
+                    assert_eq!(set(&mut rtc, b), Err(RtcError::OutOfRange));
+                    assert!(get(&rtc) == prev);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,792 @@
+//! Savestate serialization. States can be encoded either as JSON
+//! (human readable but large, every byte of RAM ends up as a
+//! decimal number) or in a compact little-endian binary format.
+//!
+//! Encoding the same state twice must produce identical output (the
+//! rewind buffer and frontends deduplicating savestates rely on it),
+//! so the serialized state must not contain any collection with an
+//! unspecified iteration order such as `HashMap`: use a `BTreeMap` or
+//! a sorted `Vec` instead. The only `HashMap` in the emulator, the
+//! HLE SWI handler table, is not serialized.
+
+use std::fmt;
+
+use rustc_serialize::{Decodable, Encodable, Decoder, Encoder};
+use rustc_serialize::json;
+
+use cpu::Cpu;
+
+/// Serialize the state of `cpu` as JSON
+pub fn save_state(cpu: &Cpu) -> String {
+    match json::encode(cpu) {
+        Ok(s) => s,
+        Err(e) => panic!("Couldn't encode savestate: {}", e),
+    }
+}
+
+/// Restore a state previously returned by `save_state`. The BIOS,
+/// flash contents and frontend callbacks of `cpu` are preserved.
+pub fn load_state(cpu: &mut Cpu,
+                  state: &str) -> Result<(), json::DecoderError> {
+    let state = try!(json::decode(state));
+
+    cpu.restore(state);
+
+    Ok(())
+}
+
+/// Serialize the state of `cpu` in the compact binary format
+pub fn save_state_binary(cpu: &Cpu) -> Vec<u8> {
+    let mut encoder = BinaryEncoder { buf: MAGIC.to_vec() };
+
+    match cpu.encode(&mut encoder) {
+        Ok(()) => encoder.buf,
+        Err(e) => panic!("Couldn't encode savestate: {}", e),
+    }
+}
+
+/// Restore a state previously returned by `save_state_binary`. The
+/// BIOS, flash contents and frontend callbacks of `cpu` are
+/// preserved.
+pub fn load_state_binary(cpu: &mut Cpu,
+                         state: &[u8]) -> Result<(), BinaryError> {
+    if state.len() < MAGIC.len() || state[..MAGIC.len()] != MAGIC {
+        return Err(BinaryError::BadMagic);
+    }
+
+    let mut decoder = BinaryDecoder { buf: &state[MAGIC.len()..] };
+
+    let state = try!(Cpu::decode(&mut decoder));
+
+    if !decoder.buf.is_empty() {
+        return Err(BinaryError::TrailingData);
+    }
+
+    cpu.restore(state);
+
+    Ok(())
+}
+
+/// Errors returned by the binary savestate decoder
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum BinaryError {
+    /// The data doesn't start with the savestate magic
+    BadMagic,
+    /// The data ended in the middle of the state
+    UnexpectedEnd,
+    /// There's unused data past the end of the state
+    TrailingData,
+    /// The state contains an invalid value
+    Invalid(String),
+}
+
+impl fmt::Display for BinaryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            BinaryError::BadMagic => write!(f, "Not a binary savestate"),
+            BinaryError::UnexpectedEnd => write!(f, "Truncated savestate"),
+            BinaryError::TrailingData =>
+                write!(f, "Trailing data after savestate"),
+            BinaryError::Invalid(ref m) =>
+                write!(f, "Invalid savestate: {}", m),
+        }
+    }
+}
+
+/// Encoder for the binary format. Integers are stored little-endian
+/// using their natural size, sequences and strings are prefixed with
+/// their length as a 32bit integer, enum variants are identified by
+/// a 32bit index. Structs and tuples have no framing at all.
+struct BinaryEncoder {
+    buf: Vec<u8>,
+}
+
+impl BinaryEncoder {
+    fn emit_bytes(&mut self, v: u64, len: usize) -> Result<(), BinaryError> {
+        for i in 0..len {
+            self.buf.push((v >> (i * 8)) as u8);
+        }
+
+        Ok(())
+    }
+
+    fn emit_len(&mut self, len: usize) -> Result<(), BinaryError> {
+        if len > 0xffffffff {
+            return Err(BinaryError::Invalid(format!("length {}", len)));
+        }
+
+        self.emit_bytes(len as u64, 4)
+    }
+}
+
+impl Encoder for BinaryEncoder {
+    type Error = BinaryError;
+
+    fn emit_nil(&mut self) -> Result<(), BinaryError> {
+        Ok(())
+    }
+
+    fn emit_usize(&mut self, v: usize) -> Result<(), BinaryError> {
+        self.emit_bytes(v as u64, 8)
+    }
+
+    fn emit_u64(&mut self, v: u64) -> Result<(), BinaryError> {
+        self.emit_bytes(v, 8)
+    }
+
+    fn emit_u32(&mut self, v: u32) -> Result<(), BinaryError> {
+        self.emit_bytes(v as u64, 4)
+    }
+
+    fn emit_u16(&mut self, v: u16) -> Result<(), BinaryError> {
+        self.emit_bytes(v as u64, 2)
+    }
+
+    fn emit_u8(&mut self, v: u8) -> Result<(), BinaryError> {
+        self.emit_bytes(v as u64, 1)
+    }
+
+    fn emit_isize(&mut self, v: isize) -> Result<(), BinaryError> {
+        self.emit_bytes(v as u64, 8)
+    }
+
+    fn emit_i64(&mut self, v: i64) -> Result<(), BinaryError> {
+        self.emit_bytes(v as u64, 8)
+    }
+
+    fn emit_i32(&mut self, v: i32) -> Result<(), BinaryError> {
+        self.emit_bytes(v as u32 as u64, 4)
+    }
+
+    fn emit_i16(&mut self, v: i16) -> Result<(), BinaryError> {
+        self.emit_bytes(v as u16 as u64, 2)
+    }
+
+    fn emit_i8(&mut self, v: i8) -> Result<(), BinaryError> {
+        self.emit_bytes(v as u8 as u64, 1)
+    }
+
+    fn emit_bool(&mut self, v: bool) -> Result<(), BinaryError> {
+        self.emit_bytes(v as u64, 1)
+    }
+
+    fn emit_f64(&mut self, v: f64) -> Result<(), BinaryError> {
+        self.emit_bytes(v.to_bits(), 8)
+    }
+
+    fn emit_f32(&mut self, v: f32) -> Result<(), BinaryError> {
+        self.emit_bytes(v.to_bits() as u64, 4)
+    }
+
+    fn emit_char(&mut self, v: char) -> Result<(), BinaryError> {
+        self.emit_bytes(v as u64, 4)
+    }
+
+    fn emit_str(&mut self, v: &str) -> Result<(), BinaryError> {
+        try!(self.emit_len(v.len()));
+
+        self.buf.extend_from_slice(v.as_bytes());
+
+        Ok(())
+    }
+
+    fn emit_enum<F>(&mut self, _: &str, f: F) -> Result<(), BinaryError>
+        where F: FnOnce(&mut Self) -> Result<(), BinaryError> {
+        f(self)
+    }
+
+    fn emit_enum_variant<F>(&mut self,
+                            _: &str,
+                            v_id: usize,
+                            _: usize,
+                            f: F) -> Result<(), BinaryError>
+        where F: FnOnce(&mut Self) -> Result<(), BinaryError> {
+        try!(self.emit_len(v_id));
+
+        f(self)
+    }
+
+    fn emit_enum_variant_arg<F>(&mut self,
+                                _: usize,
+                                f: F) -> Result<(), BinaryError>
+        where F: FnOnce(&mut Self) -> Result<(), BinaryError> {
+        f(self)
+    }
+
+    fn emit_enum_struct_variant<F>(&mut self,
+                                   v_name: &str,
+                                   v_id: usize,
+                                   len: usize,
+                                   f: F) -> Result<(), BinaryError>
+        where F: FnOnce(&mut Self) -> Result<(), BinaryError> {
+        self.emit_enum_variant(v_name, v_id, len, f)
+    }
+
+    fn emit_enum_struct_variant_field<F>(&mut self,
+                                         _: &str,
+                                         _: usize,
+                                         f: F) -> Result<(), BinaryError>
+        where F: FnOnce(&mut Self) -> Result<(), BinaryError> {
+        f(self)
+    }
+
+    fn emit_struct<F>(&mut self,
+                      _: &str,
+                      _: usize,
+                      f: F) -> Result<(), BinaryError>
+        where F: FnOnce(&mut Self) -> Result<(), BinaryError> {
+        f(self)
+    }
+
+    fn emit_struct_field<F>(&mut self,
+                            _: &str,
+                            _: usize,
+                            f: F) -> Result<(), BinaryError>
+        where F: FnOnce(&mut Self) -> Result<(), BinaryError> {
+        f(self)
+    }
+
+    fn emit_tuple<F>(&mut self, _: usize, f: F) -> Result<(), BinaryError>
+        where F: FnOnce(&mut Self) -> Result<(), BinaryError> {
+        f(self)
+    }
+
+    fn emit_tuple_arg<F>(&mut self, _: usize, f: F) -> Result<(), BinaryError>
+        where F: FnOnce(&mut Self) -> Result<(), BinaryError> {
+        f(self)
+    }
+
+    fn emit_tuple_struct<F>(&mut self,
+                            _: &str,
+                            _: usize,
+                            f: F) -> Result<(), BinaryError>
+        where F: FnOnce(&mut Self) -> Result<(), BinaryError> {
+        f(self)
+    }
+
+    fn emit_tuple_struct_arg<F>(&mut self,
+                                _: usize,
+                                f: F) -> Result<(), BinaryError>
+        where F: FnOnce(&mut Self) -> Result<(), BinaryError> {
+        f(self)
+    }
+
+    fn emit_option<F>(&mut self, f: F) -> Result<(), BinaryError>
+        where F: FnOnce(&mut Self) -> Result<(), BinaryError> {
+        f(self)
+    }
+
+    fn emit_option_none(&mut self) -> Result<(), BinaryError> {
+        self.emit_bool(false)
+    }
+
+    fn emit_option_some<F>(&mut self, f: F) -> Result<(), BinaryError>
+        where F: FnOnce(&mut Self) -> Result<(), BinaryError> {
+        try!(self.emit_bool(true));
+
+        f(self)
+    }
+
+    fn emit_seq<F>(&mut self, len: usize, f: F) -> Result<(), BinaryError>
+        where F: FnOnce(&mut Self) -> Result<(), BinaryError> {
+        try!(self.emit_len(len));
+
+        f(self)
+    }
+
+    fn emit_seq_elt<F>(&mut self, _: usize, f: F) -> Result<(), BinaryError>
+        where F: FnOnce(&mut Self) -> Result<(), BinaryError> {
+        f(self)
+    }
+
+    fn emit_map<F>(&mut self, len: usize, f: F) -> Result<(), BinaryError>
+        where F: FnOnce(&mut Self) -> Result<(), BinaryError> {
+        try!(self.emit_len(len));
+
+        f(self)
+    }
+
+    fn emit_map_elt_key<F>(&mut self,
+                           _: usize,
+                           f: F) -> Result<(), BinaryError>
+        where F: FnOnce(&mut Self) -> Result<(), BinaryError> {
+        f(self)
+    }
+
+    fn emit_map_elt_val<F>(&mut self,
+                           _: usize,
+                           f: F) -> Result<(), BinaryError>
+        where F: FnOnce(&mut Self) -> Result<(), BinaryError> {
+        f(self)
+    }
+}
+
+/// Decoder for the format generated by `BinaryEncoder`
+struct BinaryDecoder<'a> {
+    /// Data left to decode
+    buf: &'a [u8],
+}
+
+impl<'a> BinaryDecoder<'a> {
+    fn read_bytes(&mut self, len: usize) -> Result<u64, BinaryError> {
+        if self.buf.len() < len {
+            return Err(BinaryError::UnexpectedEnd);
+        }
+
+        let mut v = 0;
+
+        for (i, &b) in self.buf[..len].iter().enumerate() {
+            v |= (b as u64) << (i * 8);
+        }
+
+        self.buf = &self.buf[len..];
+
+        Ok(v)
+    }
+
+    fn read_len(&mut self) -> Result<usize, BinaryError> {
+        self.read_bytes(4).map(|v| v as usize)
+    }
+}
+
+impl<'a> Decoder for BinaryDecoder<'a> {
+    type Error = BinaryError;
+
+    fn read_nil(&mut self) -> Result<(), BinaryError> {
+        Ok(())
+    }
+
+    fn read_usize(&mut self) -> Result<usize, BinaryError> {
+        self.read_bytes(8).map(|v| v as usize)
+    }
+
+    fn read_u64(&mut self) -> Result<u64, BinaryError> {
+        self.read_bytes(8)
+    }
+
+    fn read_u32(&mut self) -> Result<u32, BinaryError> {
+        self.read_bytes(4).map(|v| v as u32)
+    }
+
+    fn read_u16(&mut self) -> Result<u16, BinaryError> {
+        self.read_bytes(2).map(|v| v as u16)
+    }
+
+    fn read_u8(&mut self) -> Result<u8, BinaryError> {
+        self.read_bytes(1).map(|v| v as u8)
+    }
+
+    fn read_isize(&mut self) -> Result<isize, BinaryError> {
+        self.read_bytes(8).map(|v| v as isize)
+    }
+
+    fn read_i64(&mut self) -> Result<i64, BinaryError> {
+        self.read_bytes(8).map(|v| v as i64)
+    }
+
+    fn read_i32(&mut self) -> Result<i32, BinaryError> {
+        self.read_bytes(4).map(|v| v as i32)
+    }
+
+    fn read_i16(&mut self) -> Result<i16, BinaryError> {
+        self.read_bytes(2).map(|v| v as i16)
+    }
+
+    fn read_i8(&mut self) -> Result<i8, BinaryError> {
+        self.read_bytes(1).map(|v| v as i8)
+    }
+
+    fn read_bool(&mut self) -> Result<bool, BinaryError> {
+        match try!(self.read_bytes(1)) {
+            0 => Ok(false),
+            1 => Ok(true),
+            v => Err(BinaryError::Invalid(format!("bool {}", v))),
+        }
+    }
+
+    fn read_f64(&mut self) -> Result<f64, BinaryError> {
+        self.read_bytes(8).map(f64::from_bits)
+    }
+
+    fn read_f32(&mut self) -> Result<f32, BinaryError> {
+        self.read_bytes(4).map(|v| f32::from_bits(v as u32))
+    }
+
+    fn read_char(&mut self) -> Result<char, BinaryError> {
+        let v = try!(self.read_bytes(4)) as u32;
+
+        match ::std::char::from_u32(v) {
+            Some(c) => Ok(c),
+            None => Err(BinaryError::Invalid(format!("char 0x{:x}", v))),
+        }
+    }
+
+    fn read_str(&mut self) -> Result<String, BinaryError> {
+        let len = try!(self.read_len());
+
+        if self.buf.len() < len {
+            return Err(BinaryError::UnexpectedEnd);
+        }
+
+        let s = match String::from_utf8(self.buf[..len].to_vec()) {
+            Ok(s) => s,
+            Err(_) => return Err(BinaryError::Invalid("string".to_string())),
+        };
+
+        self.buf = &self.buf[len..];
+
+        Ok(s)
+    }
+
+    fn read_enum<T, F>(&mut self, _: &str, f: F) -> Result<T, BinaryError>
+        where F: FnOnce(&mut Self) -> Result<T, BinaryError> {
+        f(self)
+    }
+
+    fn read_enum_variant<T, F>(&mut self,
+                               names: &[&str],
+                               mut f: F) -> Result<T, BinaryError>
+        where F: FnMut(&mut Self, usize) -> Result<T, BinaryError> {
+        let id = try!(self.read_len());
+
+        if id >= names.len() {
+            return Err(BinaryError::Invalid(format!("enum variant {}", id)));
+        }
+
+        f(self, id)
+    }
+
+    fn read_enum_variant_arg<T, F>(&mut self,
+                                   _: usize,
+                                   f: F) -> Result<T, BinaryError>
+        where F: FnOnce(&mut Self) -> Result<T, BinaryError> {
+        f(self)
+    }
+
+    fn read_enum_struct_variant<T, F>(&mut self,
+                                      names: &[&str],
+                                      f: F) -> Result<T, BinaryError>
+        where F: FnMut(&mut Self, usize) -> Result<T, BinaryError> {
+        self.read_enum_variant(names, f)
+    }
+
+    fn read_enum_struct_variant_field<T, F>(&mut self,
+                                            _: &str,
+                                            _: usize,
+                                            f: F) -> Result<T, BinaryError>
+        where F: FnOnce(&mut Self) -> Result<T, BinaryError> {
+        f(self)
+    }
+
+    fn read_struct<T, F>(&mut self,
+                         _: &str,
+                         _: usize,
+                         f: F) -> Result<T, BinaryError>
+        where F: FnOnce(&mut Self) -> Result<T, BinaryError> {
+        f(self)
+    }
+
+    fn read_struct_field<T, F>(&mut self,
+                               _: &str,
+                               _: usize,
+                               f: F) -> Result<T, BinaryError>
+        where F: FnOnce(&mut Self) -> Result<T, BinaryError> {
+        f(self)
+    }
+
+    fn read_tuple<T, F>(&mut self, _: usize, f: F) -> Result<T, BinaryError>
+        where F: FnOnce(&mut Self) -> Result<T, BinaryError> {
+        f(self)
+    }
+
+    fn read_tuple_arg<T, F>(&mut self,
+                            _: usize,
+                            f: F) -> Result<T, BinaryError>
+        where F: FnOnce(&mut Self) -> Result<T, BinaryError> {
+        f(self)
+    }
+
+    fn read_tuple_struct<T, F>(&mut self,
+                               _: &str,
+                               _: usize,
+                               f: F) -> Result<T, BinaryError>
+        where F: FnOnce(&mut Self) -> Result<T, BinaryError> {
+        f(self)
+    }
+
+    fn read_tuple_struct_arg<T, F>(&mut self,
+                                   _: usize,
+                                   f: F) -> Result<T, BinaryError>
+        where F: FnOnce(&mut Self) -> Result<T, BinaryError> {
+        f(self)
+    }
+
+    fn read_option<T, F>(&mut self, mut f: F) -> Result<T, BinaryError>
+        where F: FnMut(&mut Self, bool) -> Result<T, BinaryError> {
+        let is_some = try!(self.read_bool());
+
+        f(self, is_some)
+    }
+
+    fn read_seq<T, F>(&mut self, f: F) -> Result<T, BinaryError>
+        where F: FnOnce(&mut Self, usize) -> Result<T, BinaryError> {
+        let len = try!(self.read_len());
+
+        f(self, len)
+    }
+
+    fn read_seq_elt<T, F>(&mut self, _: usize, f: F) -> Result<T, BinaryError>
+        where F: FnOnce(&mut Self) -> Result<T, BinaryError> {
+        f(self)
+    }
+
+    fn read_map<T, F>(&mut self, f: F) -> Result<T, BinaryError>
+        where F: FnOnce(&mut Self, usize) -> Result<T, BinaryError> {
+        let len = try!(self.read_len());
+
+        f(self, len)
+    }
+
+    fn read_map_elt_key<T, F>(&mut self,
+                              _: usize,
+                              f: F) -> Result<T, BinaryError>
+        where F: FnOnce(&mut Self) -> Result<T, BinaryError> {
+        f(self)
+    }
+
+    fn read_map_elt_val<T, F>(&mut self,
+                              _: usize,
+                              f: F) -> Result<T, BinaryError>
+        where F: FnOnce(&mut Self) -> Result<T, BinaryError> {
+        f(self)
+    }
+
+    fn error(&mut self, err: &str) -> BinaryError {
+        BinaryError::Invalid(err.to_string())
+    }
+}
+
+/// Magic value at the start of binary savestates
+const MAGIC: [u8; 4] = [b'P', b'K', b'S', b'T'];
+
+#[cfg(test)]
+mod tests {
+    use rustc_serialize::{Decodable, Encodable};
+    use rustc_serialize::json;
+
+    use dac::Dac;
+    use interrupt::{Interrupt, IrqController};
+    use irda::Irda;
+    use lcd::{Lcd, TestPattern};
+    use memory::Word;
+    use memory::flash::Flash;
+    use memory::ram::{Ram, RAM_SIZE};
+    use rtc::{Rtc, Bcd};
+    use test_util;
+    use timer::Timer;
+
+    use super::*;
+
+    fn encode<T: Encodable>(v: &T) -> Vec<u8> {
+        let mut encoder = BinaryEncoder { buf: Vec::new() };
+
+        v.encode(&mut encoder).unwrap();
+
+        encoder.buf
+    }
+
+    fn decode<T: Decodable>(buf: &[u8]) -> T {
+        let mut decoder = BinaryDecoder { buf: buf };
+
+        let v = T::decode(&mut decoder).unwrap();
+
+        assert!(decoder.buf.is_empty());
+
+        v
+    }
+
+    /// Check that `v` survives a binary round-trip and that its
+    /// binary encoding is smaller than the JSON one. Returns the
+    /// size of the binary encoding.
+    fn round_trip<T: Encodable + Decodable>(v: &T) -> usize {
+        let bin = encode(v);
+        let decoded: T = decode(&bin);
+
+        assert_eq!(encode(&decoded), bin);
+        assert!(bin.len() < json::encode(v).unwrap().len());
+
+        bin.len()
+    }
+
+    #[test]
+    fn peripherals_round_trip() {
+        let mut irq = IrqController::new();
+        irq.store::<Word>(8, 0x1234);
+        irq.set_raw_interrupt(Interrupt::Timer0, true);
+        // raw, latch and mask
+        assert_eq!(round_trip(&irq), 3 * 2);
+
+        let mut timer = Timer::new(Interrupt::Timer1);
+        timer.store::<Word>(0, 0x4321);
+        timer.store::<Word>(8, 0x6);
+        timer.tick(&mut irq, 100);
+        assert!(round_trip(&timer) <= 16);
+
+        let mut rtc = Rtc::new();
+        rtc.set_hours(Bcd::from_bcd(0x12).unwrap());
+        rtc.store::<Word>(0, 1);
+        assert!(round_trip(&rtc) <= 32);
+
+        let mut irda = Irda::new();
+        irda.store::<Word>(0, 3);
+        irda.store::<Word>(4, 1);
+        irda.send_frame(&[1, 2, 3]);
+        // mode and LED, the frame queues are not saved
+        assert_eq!(round_trip(&irda), 2);
+
+        let mut dac = Dac::new(Box::new(test_util::NullBackend));
+        dac.set_muted(true);
+        dac.set_fifo_threshold(Some(4));
+        assert!(round_trip(&dac) <= 64);
+
+        // Two framebuffers of 32 words plus a few registers
+        let mut lcd = Lcd::new();
+        lcd.fill_test_pattern(TestPattern::Checkerboard);
+        lcd.store::<Word>(0x104, 0xf00f00f0);
+        let size = round_trip(&lcd);
+        assert!(size >= 2 * 32 * 4 && size <= 2 * 32 * 4 + 64);
+
+        // The RAM is stored as raw bytes with a length prefix
+        let mut ram = Ram::new();
+        ram.store::<Word>(0x10, 0x12345678);
+        let size = round_trip(&ram);
+        assert!(size >= RAM_SIZE && size <= RAM_SIZE + 8);
+
+        // The flash contents are not part of the savestate, only the
+        // controller configuration
+        let mut flash = Flash::with_contents(b"flash");
+        flash.store_config::<Word>(0, 3);
+        assert!(round_trip(&flash) <= 128);
+    }
+
+    #[test]
+    fn cpu_round_trip() {
+        let mut cpu = test_util::arm_cpu(&[
+            // MOV R0, #0x40
+            0xe3a00040,
+            // STR R0, [R0]
+            0xe5800000,
+        ]);
+
+        test_util::run(&mut cpu, 2);
+
+        let state = save_state_binary(&cpu);
+
+        // The whole state is about the size of the RAM, JSON needs
+        // several bytes per RAM byte
+        assert!(state.len() > RAM_SIZE);
+        assert!(state.len() < RAM_SIZE + 2048);
+        assert!(save_state(&cpu).len() > 3 * RAM_SIZE);
+
+        let mut restored = test_util::arm_cpu(&[]);
+
+        load_state_binary(&mut restored, &state).unwrap();
+
+        assert_eq!(save_state_binary(&restored), state);
+        assert_eq!(restored.registers()[0], 0x40);
+        assert_eq!(restored.current_pc(), cpu.current_pc());
+        assert_eq!(restored.interconnect().peek::<Word>(0x40), 0x40);
+    }
+
+    #[test]
+    fn bad_binary_state() {
+        let mut cpu = test_util::arm_cpu(&[]);
+
+        let state = save_state_binary(&cpu);
+
+        match load_state_binary(&mut cpu, b"JSON") {
+            Err(BinaryError::BadMagic) => (),
+            _ => panic!("Expected BadMagic"),
+        }
+
+        let mut long = state.clone();
+        long.push(0);
+
+        match load_state_binary(&mut cpu, &long) {
+            Err(BinaryError::TrailingData) => (),
+            _ => panic!("Expected TrailingData"),
+        }
+
+        match load_state_binary(&mut cpu, &state[..state.len() - 1]) {
+            Err(BinaryError::UnexpectedEnd) => (),
+            _ => panic!("Expected UnexpectedEnd"),
+        }
+    }
+
+    #[test]
+    fn deterministic() {
+        let program = [
+            // MOV R0, #0x40
+            0xe3a00040,
+            // STR R0, [R0]
+            0xe5800000,
+            // SWI 0x10
+            0xef000010,
+            // B .
+            0xeafffffe,
+        ];
+
+        // Two CPUs in the same state but with their HLE handlers (the
+        // only `HashMap`) registered in a different order
+        let build = |comments: &[u32]| {
+            let mut cpu = test_util::arm_cpu(&program);
+
+            for &c in comments {
+                cpu.set_hle_swi(c, Some(Box::new(move |cpu: &mut Cpu| {
+                    cpu.set_register(0, c);
+                })));
+            }
+
+            // Some peripheral state
+            cpu.interconnect_mut().store::<Word>(0x0a000008, 0x1ff);
+            cpu.interconnect_mut().store::<Word>(0x0a800000, 0x1234);
+            cpu.interconnect_mut().store::<Word>(0x0a800008, 0x5);
+            cpu.interconnect_mut().store::<Word>(0x0d000104, 0xf0f0f0f0);
+
+            test_util::run(&mut cpu, 6);
+
+            cpu
+        };
+
+        let comments: Vec<u32> = (0..64).collect();
+        let reversed: Vec<u32> = comments.iter().rev().cloned().collect();
+
+        let a = build(&comments);
+        let b = build(&reversed);
+
+        assert_eq!(a.registers()[0], 0x10);
+
+        // Encoding the same state twice gives the same output
+        let bin = save_state_binary(&a);
+        let json = save_state(&a);
+
+        assert_eq!(save_state_binary(&a), bin);
+        assert_eq!(save_state(&a), json);
+
+        assert_eq!(save_state_binary(&b), bin);
+        assert_eq!(save_state(&b), json);
+        assert_eq!(a.interconnect().content_hash(),
+                   b.interconnect().content_hash());
+
+        // Save -> load -> save is byte-identical
+        let mut restored = build(&[3, 1, 2]);
+
+        load_state_binary(&mut restored, &bin).unwrap();
+        assert_eq!(save_state_binary(&restored), bin);
+
+        let mut restored = build(&[]);
+
+        load_state(&mut restored, &json).unwrap();
+        assert_eq!(save_state(&restored), json);
+    }
+}
@@ -0,0 +1,138 @@
+//! Helpers shared by the unit tests
+
+use std::cell::RefCell;
+use std::sync::Once;
+
+use log::{self, Log, Level, LevelFilter, Metadata, Record};
+
+use cpu::Cpu;
+use dac::{Dac, Backend};
+use memory::{Interconnect, Word};
+use memory::bios::Bios;
+use memory::flash::Flash;
+use memory::ram::Ram;
+
+/// Audio backend discarding all the samples
+pub struct NullBackend;
+
+impl Backend for NullBackend {
+    fn push_sample(&mut self, _: i16) {
+    }
+}
+
+/// Build an interconnect with an empty BIOS, `flash` at the start of
+/// the flash memory and a RAM initialized with `ram`
+pub fn interconnect(flash: &[u8], ram: &[u8]) -> Interconnect {
+    let dac = Dac::new(Box::new(NullBackend));
+
+    Interconnect::with_ram(Bios::empty(),
+                           Flash::with_contents(flash),
+                           dac,
+                           Ram::from_bytes(ram).unwrap())
+}
+
+/// Convert a list of words to little-endian bytes
+pub fn words_to_bytes(words: &[u32]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(words.len() * 4);
+
+    for &w in words {
+        for i in 0..4 {
+            bytes.push((w >> (i * 8)) as u8);
+        }
+    }
+
+    bytes
+}
+
+/// Convert a list of halfwords to little-endian bytes
+pub fn halfwords_to_bytes(halfwords: &[u16]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(halfwords.len() * 2);
+
+    for &h in halfwords {
+        bytes.push(h as u8);
+        bytes.push((h >> 8) as u8);
+    }
+
+    bytes
+}
+
+/// Build a CPU running `program` from address 0 in RAM. The BIOS is
+/// unmapped and the CPU is in supervisor mode with the interrupts
+/// disabled, like after a reset.
+pub fn ram_cpu(program: &[u8]) -> Cpu {
+    let mut cpu = Cpu::new(interconnect(&[], program));
+
+    // F_CTRL: unmap the BIOS from address 0
+    cpu.interconnect_mut().store::<Word>(0x06000000, 3);
+
+    cpu.set_pc(0);
+
+    cpu
+}
+
+/// Build a CPU running the ARM `program` from address 0 in RAM, see
+/// `ram_cpu`
+pub fn arm_cpu(program: &[u32]) -> Cpu {
+    ram_cpu(&words_to_bytes(program))
+}
+
+/// Build a CPU running the Thumb `program` from address 0 in RAM,
+/// see `ram_cpu`
+pub fn thumb_cpu(program: &[u16]) -> Cpu {
+    let mut cpu = ram_cpu(&halfwords_to_bytes(program));
+
+    cpu.set_thumb(true);
+    cpu.set_pc(0);
+
+    cpu
+}
+
+/// Execute `n` instructions without a debugger
+pub fn run(cpu: &mut Cpu, n: u32) {
+    for _ in 0..n {
+        cpu.run_next_instruction(&mut ());
+    }
+}
+
+/// Logger recording the messages logged by the threads running
+/// `capture_logs`
+struct CaptureLogger;
+
+thread_local!(static CAPTURED: RefCell<Option<Vec<(Level, String)>>> =
+              RefCell::new(None));
+
+impl Log for CaptureLogger {
+    fn enabled(&self, _: &Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &Record) {
+        CAPTURED.with(|captured| {
+            if let Some(ref mut logs) = *captured.borrow_mut() {
+                logs.push((record.level(), format!("{}", record.args())));
+            }
+        })
+    }
+
+    fn flush(&self) {
+    }
+}
+
+static LOGGER: CaptureLogger = CaptureLogger;
+static LOGGER_INIT: Once = Once::new();
+
+/// Run `f` and return the level and text of the messages it logged.
+/// The tests run in parallel so only the messages logged by the
+/// current thread are captured.
+pub fn capture_logs<F: FnOnce()>(f: F) -> Vec<(Level, String)> {
+    LOGGER_INIT.call_once(|| {
+        log::set_logger(&LOGGER).unwrap();
+        log::set_max_level(LevelFilter::Trace);
+    });
+
+    CAPTURED.with(|captured| *captured.borrow_mut() = Some(Vec::new()));
+
+    f();
+
+    CAPTURED.with(|captured| captured.borrow_mut().take().unwrap())
+}
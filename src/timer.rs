@@ -1,7 +1,8 @@
 use interrupt::{Interrupt, IrqController};
-use memory::Addressable;
+use memory::{Addressable, unwrap_access};
 
 #[derive(RustcDecodable, RustcEncodable)]
+#[derive(Hash)]
 pub struct Timer {
     enabled: bool,
     /// In order to save a few cycles I merge the pre-divider and
@@ -30,6 +31,15 @@ impl Timer {
         }
     }
 
+    pub fn reset(&mut self) {
+        *self = Timer::new(self.interrupt);
+    }
+
+    /// Advance the timer by `cpu_ticks`. A large batch spanning
+    /// several reload periods behaves exactly like many single-tick
+    /// calls: the interrupt signal pulses high at every expiration
+    /// (the IRQ controller latches the rising edge) and the counter
+    /// ends up with the same value.
     pub fn tick(&mut self,
                 irq: &mut IrqController,
                 mut cpu_ticks: u32) {
@@ -58,28 +68,59 @@ impl Timer {
         }
     }
 
+    /// Return the number of CPU ticks until the timer expires, or
+    /// `None` if the timer is disabled
+    pub fn next_event(&self) -> Option<u32> {
+        if self.enabled {
+            Some(self.counter + 1)
+        } else {
+            None
+        }
+    }
+
     pub fn store<A: Addressable>(&mut self, offset: u32, val: u32) {
+        unwrap_access(self.try_store::<A>(offset, val))
+    }
+
+    /// Same as `store` but unhandled accesses return an error
+    /// instead of panicking
+    pub fn try_store<A: Addressable>(&mut self,
+                                     offset: u32,
+                                     val: u32) -> Result<(), String> {
         if A::size() == 1 {
-            panic!("Unhandled {}bit timer store", A::size() * 8);
+            return Err(format!("Unhandled {}bit timer store", A::size() * 8));
         }
 
         match offset {
             0 => self.reload = val as u16,
             8 => self.set_mode(val as u8),
-            _ => panic!("Unhandled timer register {:x}", offset),
+            _ => return Err(format!("Unhandled timer register {:x}", offset)),
         }
+
+        Ok(())
     }
 
     pub fn load<A: Addressable>(&self, offset: u32) -> u32 {
+        unwrap_access(self.try_load::<A>(offset))
+    }
+
+    /// Same as `load` but unhandled accesses return an error instead
+    /// of panicking
+    pub fn try_load<A: Addressable>(&self,
+                                    offset: u32) -> Result<u32, String> {
         if A::size() == 1 {
-            panic!("Unhandled {}bit timer store", A::size() * 8);
+            return Err(format!("Unhandled {}bit timer store", A::size() * 8));
         }
 
-        match offset {
-            4 => self.counter() as u32,
-            8 => self.mode() as u32,
-            _ => panic!("Unhandled timer register {:x}", offset),
-        }
+        let r =
+            match offset {
+                4 => self.counter() as u32,
+                8 => self.mode() as u32,
+                _ => return Err(format!("Unhandled timer register {:x}",
+                                        offset)),
+            };
+
+        Ok(r)
     }
 
     fn counter(&self) -> u16 {
@@ -121,3 +162,77 @@ impl Timer {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use interrupt::{Interrupt, IrqController};
+    use memory::Word;
+
+    use super::*;
+
+    fn timer(reload: u32, divider: u32) -> (Timer, IrqController) {
+        let mut timer = Timer::new(Interrupt::Timer0);
+        let mut irq = IrqController::new();
+
+        irq.store::<Word>(8, Interrupt::Timer0.mask() as u32);
+
+        timer.store::<Word>(0, reload);
+        timer.store::<Word>(8, 4 | divider);
+
+        (timer, irq)
+    }
+
+    #[test]
+    fn large_batch() {
+        let mask = Interrupt::Timer0.mask();
+
+        for divider in 0..4 {
+            for &reload in &[0, 1, 9, 100] {
+                for &ticks in &[1, 2, 7, 20, 333, 5000] {
+                    let (mut batch, mut batch_irq) = timer(reload, divider);
+                    let (mut inc, mut inc_irq) = timer(reload, divider);
+
+                    batch.tick(&mut batch_irq, ticks);
+
+                    let mut expirations = 0;
+
+                    for _ in 0..ticks {
+                        inc.tick(&mut inc_irq, 1);
+
+                        if inc_irq.status() & mask != 0 {
+                            expirations += 1;
+                            inc_irq.ack(mask);
+                        }
+                    }
+
+                    let desc = format!("reload {} divider {} ticks {}",
+                                       reload, divider, ticks);
+
+                    assert_eq!(batch.counter, inc.counter, "{}", desc);
+                    assert_eq!(batch_irq.raw(), inc_irq.raw(), "{}", desc);
+                    assert_eq!(batch_irq.status() != 0,
+                               expirations > 0,
+                               "{}", desc);
+
+                    // The timer expires every time `next_event` ticks
+                    // have elapsed
+                    let (mut t, mut t_irq) = timer(reload, divider);
+                    let mut remaining = ticks;
+                    let mut expected = 0;
+
+                    while let Some(n) = t.next_event() {
+                        if n > remaining {
+                            break;
+                        }
+
+                        t.tick(&mut t_irq, n);
+                        remaining -= n;
+                        expected += 1;
+                    }
+
+                    assert_eq!(expirations, expected, "{}", desc);
+                }
+            }
+        }
+    }
+}